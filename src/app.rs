@@ -1,13 +1,134 @@
+#[cfg(not(target_arch = "wasm32"))]
+use std::path::PathBuf;
+use std::sync::Arc;
+
 use ron::Options;
 use serde::{Deserialize, Serialize};
 
-use crate::painting::Painting;
+use crate::{
+    bug_report::{platform_info, BugReport, BugReportDialog},
+    chunks::ChunkStore,
+    clipboard::ClipboardText,
+    painting::Painting,
+    presets::{BrushPreset, PRESET_KEYS},
+    settings::{PendingProfileImport, SettingsProfile},
+};
+#[cfg(target_arch = "wasm32")]
+use crate::{
+    chunks::OpeningStore,
+    deep_link::DeepLink,
+    format::{self, SaveFormat},
+    web_file::PickedFile,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{
+    format::SaveFormat,
+    recovery::{Autosave, ChangeJournal},
+};
+
+/// Storage key of the painting's tree when it is not kept in a chunk store.
+const CANVAS_KEY: &str = "canvas";
+
+/// Name of the IndexedDB database the painting's tree is kept in on the web.
+#[cfg(target_arch = "wasm32")]
+const CANVAS_DATABASE: &str = "canvas";
+
+/// How many files File → Open Recent remembers.
+#[cfg(not(target_arch = "wasm32"))]
+const RECENT_FILES: usize = 10;
+
+/// A painting open in a tab after the first, and the file it belongs to.
+#[derive(Deserialize, Serialize, Default)]
+#[serde(default)]
+struct Tab {
+    painting: Painting,
+    #[cfg(not(target_arch = "wasm32"))]
+    file: Option<PathBuf>,
+}
+
+/// What is held back until the user decides about unsaved changes.
+#[derive(Clone, Copy)]
+enum Closing {
+    #[cfg(not(target_arch = "wasm32"))]
+    Window,
+    /// The tab at this index, counting the first painting as 0.
+    Tab(usize),
+}
 
 /// We derive Deserialize/Serialize so we can persist app state on shutdown.
 #[derive(Deserialize, Serialize, Default)]
 pub struct TemplateApp {
     // Example stuff:
+    /// The painting in the first tab, whose tree is kept in the chunk store or under its own key.
     painting: Painting,
+    /// Paintings open in the other tabs, saved whole with the app state.
+    #[serde(default)]
+    tabs: Vec<Tab>,
+    /// The tab shown: 0 for `painting`, otherwise one past its index in `tabs`.
+    #[serde(default)]
+    active_tab: usize,
+    #[serde(default)]
+    brush_presets: Vec<BrushPreset>,
+    #[serde(skip)]
+    new_preset_name: String,
+    #[serde(skip)]
+    pending_profile_import: Option<PendingProfileImport>,
+    /// A settings profile being read from the clipboard to import.
+    #[serde(skip)]
+    profile_read: Option<ClipboardText>,
+    #[serde(skip)]
+    bug_report: BugReportDialog,
+    /// Where the painting's tree is kept rather than with the rest of the app state: natively
+    /// a folder beside it, and on the web IndexedDB.
+    #[serde(skip)]
+    chunk_store: Option<Arc<ChunkStore>>,
+    /// The chunk store while it is read from IndexedDB, before the tree is loaded from it.
+    #[cfg(target_arch = "wasm32")]
+    #[serde(skip)]
+    opening_store: Option<OpeningStore>,
+    /// The file the first painting was last opened from or saved to, which Save writes to.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[serde(default)]
+    file: Option<PathBuf>,
+    /// Files opened or saved most recently, newest first.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[serde(default)]
+    recent_files: Vec<PathBuf>,
+    #[cfg(not(target_arch = "wasm32"))]
+    #[serde(default)]
+    autosave: Autosave,
+    /// The paintings autosaved by a run that did not exit cleanly, by tab index, until the user
+    /// decides whether to restore them.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[serde(skip)]
+    pending_recovery: Vec<(usize, PathBuf)>,
+    /// Changes to the paintings since they were last saved, kept on disk in case the app
+    /// crashes.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[serde(skip)]
+    change_journal: ChangeJournal,
+    /// What was held back to ask about unsaved changes.
+    #[serde(skip)]
+    confirm_close: Option<Closing>,
+    /// Set once the user agreed to close the window despite unsaved changes.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[serde(skip)]
+    allow_close: bool,
+    /// The window title last sent, so it is only sent again when it changes.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[serde(skip)]
+    title: String,
+    /// Why the last file could not be opened or saved, until the dialog saying so is closed.
+    #[serde(skip)]
+    file_error: Option<String>,
+    /// A painting file being picked and read in the browser, to open in a new tab.
+    #[cfg(target_arch = "wasm32")]
+    #[serde(skip)]
+    opening: Option<PickedFile>,
+    /// The view written into the page's address, to link to it.
+    #[cfg(target_arch = "wasm32")]
+    #[serde(skip)]
+    deep_link: DeepLink,
 }
 
 impl TemplateApp {
@@ -18,8 +139,9 @@ impl TemplateApp {
 
         // Load previous app state (if any).
         // Note that you must enable the `persistence` feature for this to work.
-        if let Some(storage) = cc.storage {
-            return {
+        let mut app: TemplateApp = cc
+            .storage
+            .and_then(|storage| {
                 let key = eframe::APP_KEY;
                 storage.get_string(key).and_then(|value| {
                     let mut deserializer = ron::de::Deserializer::from_str_with_options(
@@ -37,11 +159,836 @@ impl TemplateApp {
                         }
                     }
                 })
-            }
+            })
             .unwrap_or_default();
+        app.active_tab = app.active_tab.min(app.tabs.len());
+        // Emptied on the web once the tree has moved to the chunk store.
+        if let Some(tree) = cc
+            .storage
+            .and_then(|storage| storage.get_string(CANVAS_KEY))
+            .filter(|tree| !tree.is_empty())
+        {
+            if let Err(err) = app.painting.load_tree_ron(&tree) {
+                log::error!("Failed to load the canvas: {err}");
+            }
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            app.open_chunk_store();
+            if let Some(dir) = eframe::storage_dir("eframe template") {
+                let dir = dir.join("recovery");
+                app.pending_recovery = app.autosave.start(dir.clone());
+                let paintings = std::iter::once(&mut app.painting)
+                    .chain(app.tabs.iter_mut().map(|tab| &mut tab.painting))
+                    .collect();
+                for painting in app.change_journal.start(&dir, paintings) {
+                    app.tabs.push(Tab {
+                        painting,
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+        #[cfg(target_arch = "wasm32")]
+        {
+            app.opening_store = Some(ChunkStore::open(CANVAS_DATABASE, &cc.egui_ctx));
+        }
+        app
+    }
+
+    /// Saves the painting's tree apart from the rest of the app state, to the chunk store if
+    /// there is one and otherwise under its own key, unless it is unchanged. Returns whether
+    /// the tree is saved.
+    fn save_tree(&mut self, storage: &mut dyn eframe::Storage) -> bool {
+        #[cfg(target_arch = "wasm32")]
+        if self.opening_store.is_some() {
+            // The stored tree is not loaded yet, so it is left as it is.
+            return true;
+        }
+        if let Some(store) = &self.chunk_store {
+            let saved = self
+                .painting
+                .store_tree(store)
+                .inspect_err(|err| log::error!("Failed to save the canvas to its store: {err}"))
+                .is_ok();
+            // Browsers only give the app state a few megabytes, which a tree left under its
+            // own key by earlier versions would keep taking.
+            #[cfg(target_arch = "wasm32")]
+            if saved {
+                storage.set_string(CANVAS_KEY, String::new());
+            }
+            return saved;
+        }
+        if !self.painting.tree_unsaved() {
+            return true;
+        }
+        match self.painting.tree_to_ron() {
+            Ok(tree) => {
+                storage.set_string(CANVAS_KEY, tree);
+                self.painting.mark_tree_saved();
+                true
+            }
+            Err(err) => {
+                log::error!("Failed to encode the canvas: {err}");
+                false
+            }
+        }
+    }
+
+    /// Loads the painting's tree from the chunk store next to the app state. A tree saved with
+    /// the app state by earlier versions is moved into the store on the next save.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn open_chunk_store(&mut self) {
+        // The name `main` runs the app under.
+        let Some(dir) = eframe::storage_dir("eframe template") else {
+            return;
+        };
+        self.use_chunk_store(ChunkStore::open(&dir.join("canvas")));
+    }
+
+    /// Loads the painting's tree from IndexedDB once the chunk store has been read from it. A
+    /// tree saved with the app state by earlier versions is moved into the store on the next
+    /// save.
+    #[cfg(target_arch = "wasm32")]
+    fn finish_opening_store(&mut self) {
+        let Some(store) = self.opening_store.as_ref().and_then(OpeningStore::take) else {
+            return;
+        };
+        self.opening_store = None;
+        self.use_chunk_store(store);
+    }
+
+    /// Loads the painting's tree from the chunk store just opened, and keeps it there from now
+    /// on.
+    fn use_chunk_store(&mut self, store: Result<ChunkStore, String>) {
+        let store = match store {
+            Ok(store) => Arc::new(store),
+            Err(err) => {
+                log::error!("Failed to open the canvas store: {err}");
+                return;
+            }
+        };
+        // Without the store the tree is saved with the app state instead, rather than
+        // overwriting chunks that failed to load.
+        match self.painting.load_tree(&store) {
+            Ok(()) => self.chunk_store = Some(store),
+            Err(err) => log::error!("Failed to load the canvas from its store: {err}"),
+        }
+    }
+
+    /// The painting in the tab at `index`, counting the first painting as 0.
+    fn painting_at(&self, index: usize) -> &Painting {
+        match index.checked_sub(1) {
+            Some(index) => &self.tabs[index].painting,
+            None => &self.painting,
+        }
+    }
+
+    /// The paintings in every tab, by tab index.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn paintings(&self) -> Vec<&Painting> {
+        (0..=self.tabs.len())
+            .map(|index| self.painting_at(index))
+            .collect()
+    }
+
+    fn painting_at_mut(&mut self, index: usize) -> &mut Painting {
+        match index.checked_sub(1) {
+            Some(index) => &mut self.tabs[index].painting,
+            None => &mut self.painting,
+        }
+    }
+
+    /// The painting in the tab shown.
+    fn active_painting(&mut self) -> &mut Painting {
+        self.painting_at_mut(self.active_tab)
+    }
+
+    /// The file the painting in the tab at `index` was last opened from or saved to.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn file_at(&self, index: usize) -> Option<&PathBuf> {
+        match index.checked_sub(1) {
+            Some(index) => self.tabs[index].file.as_ref(),
+            None => self.file.as_ref(),
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn active_file(&self) -> Option<&PathBuf> {
+        self.file_at(self.active_tab)
+    }
+
+    fn tab_name(&self, index: usize) -> String {
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(name) = self.file_at(index).and_then(|file| file.file_name()) {
+            return name.to_string_lossy().into_owned();
+        }
+        format!("Canvas {}", index + 1)
+    }
+
+    /// Whether closing `closing` would lose unsaved changes.
+    fn is_modified(&self, closing: Closing) -> bool {
+        match closing {
+            #[cfg(not(target_arch = "wasm32"))]
+            Closing::Window => {
+                (0..=self.tabs.len()).any(|index| self.painting_at(index).is_modified())
+            }
+            Closing::Tab(index) => self.painting_at(index).is_modified(),
+        }
+    }
+
+    fn new_tab(&mut self) {
+        self.tabs.push(Tab::default());
+        self.active_tab = self.tabs.len();
+    }
+
+    /// Closes the tab at `index`, after asking about its unsaved changes if it has any.
+    fn close_tab(&mut self, index: usize) {
+        if self.is_modified(Closing::Tab(index)) {
+            self.confirm_close = Some(Closing::Tab(index));
+        } else {
+            self.remove_tab(index);
+        }
+    }
+
+    fn remove_tab(&mut self, index: usize) {
+        // The first painting is kept in storage of its own, so its tab stays open.
+        let Some(tab) = index.checked_sub(1) else {
+            return;
+        };
+        self.tabs.remove(tab);
+        if self.active_tab >= index {
+            self.active_tab -= 1;
+        }
+    }
+
+    fn tab_bar(&mut self, ui: &mut egui::Ui) {
+        let mut close = None;
+        ui.horizontal_wrapped(|ui| {
+            for index in 0..=self.tabs.len() {
+                let marker = if self.painting_at(index).is_modified() {
+                    "● "
+                } else {
+                    ""
+                };
+                let name = format!("{marker}{}", self.tab_name(index));
+                if ui
+                    .selectable_label(self.active_tab == index, name)
+                    .clicked()
+                {
+                    self.active_tab = index;
+                }
+                if index > 0 && ui.small_button("×").on_hover_text("Close tab").clicked() {
+                    close = Some(index);
+                }
+                ui.add_space(8.0);
+            }
+            if ui.button("+").on_hover_text("New tab").clicked() {
+                self.new_tab();
+            }
+        });
+        if let Some(index) = close {
+            self.close_tab(index);
+        }
+    }
+
+    fn settings_profile(&self, ctx: &egui::Context) -> SettingsProfile {
+        let mut profile = SettingsProfile::default();
+        profile.insert("theme", &ctx.options(|options| options.theme_preference));
+        profile.insert("brush_presets", &self.brush_presets);
+        self.painting_at(self.active_tab)
+            .write_settings(&mut profile);
+        profile
+    }
+
+    fn apply_settings_profile(&mut self, ctx: &egui::Context, profile: &SettingsProfile) {
+        if let Some(theme) = profile.get::<egui::ThemePreference>("theme") {
+            ctx.set_theme(theme);
+        }
+        if let Some(brush_presets) = profile.get("brush_presets") {
+            self.brush_presets = brush_presets;
+        }
+        self.active_painting().read_settings(profile);
+    }
+
+    fn import_settings_profile(&mut self, ctx: &egui::Context, value: &str) {
+        let incoming = match SettingsProfile::from_ron(value) {
+            Ok(incoming) => incoming,
+            Err(err) => {
+                log::debug!("Failed to decode settings profile: {err}");
+                return;
+            }
+        };
+        let pending = PendingProfileImport::new(self.settings_profile(ctx), incoming);
+        if pending.use_incoming.is_empty() {
+            self.apply_settings_profile(ctx, &pending.resolved());
+        } else {
+            self.pending_profile_import = Some(pending);
+        }
+    }
+
+    fn bug_report_window(&mut self, ctx: &egui::Context) {
+        let mut open = self.bug_report.open;
+        let mut create = false;
+        egui::Window::new("Report a problem")
+            .open(&mut open)
+            .collapsible(false)
+            .show(ctx, |ui| create = self.bug_report.ui(ui));
+        self.bug_report.open = open;
+        if create {
+            if self.bug_report.include_screenshot {
+                ctx.send_viewport_cmd(egui::ViewportCommand::Screenshot(Default::default()));
+                self.bug_report.awaiting_screenshot = true;
+            } else {
+                self.create_bug_report(ctx, None);
+            }
+        }
+        if self.bug_report.awaiting_screenshot {
+            let screenshot = ctx.input(|input| {
+                input.events.iter().find_map(|event| match event {
+                    egui::Event::Screenshot { image, .. } => Some(image.clone()),
+                    _ => None,
+                })
+            });
+            if let Some(screenshot) = screenshot {
+                self.bug_report.awaiting_screenshot = false;
+                self.create_bug_report(ctx, Some(&screenshot));
+            }
+        }
+    }
+
+    fn create_bug_report(&mut self, ctx: &egui::Context, screenshot: Option<&egui::ColorImage>) {
+        let mut report = BugReport::default();
+        if !self.bug_report.description.is_empty() {
+            report.add("description.txt", self.bug_report.description.clone());
+        }
+        report.add("document.txt", self.active_painting().document_report());
+        report.add(
+            "recent_changes.txt",
+            self.active_painting().recent_changes(200),
+        );
+        let mut settings = self.settings_profile(ctx);
+        settings.remove("author");
+        report.add("settings.ron", settings.to_ron());
+        report.add("platform.txt", platform_info(ctx));
+        if let Some(screenshot) = screenshot {
+            report.add_screenshot(screenshot);
+        }
+        self.bug_report.status = Some(match report.save() {
+            Ok(saved) => saved,
+            Err(err) => format!("Failed to save report: {err}"),
+        });
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn file_menu(&mut self, ui: &mut egui::Ui) {
+        let shortcut = |modifiers, key| {
+            ui.ctx()
+                .format_shortcut(&egui::KeyboardShortcut::new(modifiers, key))
+        };
+        let open = egui::Button::new("Open…")
+            .shortcut_text(shortcut(egui::Modifiers::COMMAND, egui::Key::O));
+        let save = egui::Button::new("Save")
+            .shortcut_text(shortcut(egui::Modifiers::COMMAND, egui::Key::S));
+        let save_as = egui::Button::new("Save As…").shortcut_text(shortcut(
+            egui::Modifiers::COMMAND | egui::Modifiers::SHIFT,
+            egui::Key::S,
+        ));
+        if ui.button("New Tab").clicked() {
+            ui.close_menu();
+            self.new_tab();
+        }
+        if ui.add(open).clicked() {
+            ui.close_menu();
+            self.open_file();
+        }
+        ui.add_enabled_ui(!self.recent_files.is_empty(), |ui| {
+            ui.menu_button("Open Recent", |ui| self.recent_files_menu(ui));
+        });
+        if ui.add(save).clicked() {
+            ui.close_menu();
+            self.save_file();
+        }
+        if ui.add(save_as).clicked() {
+            ui.close_menu();
+            self.save_file_as();
+        }
+        ui.separator();
+        if ui.button("Quit").clicked() {
+            ui.ctx().send_viewport_cmd(egui::ViewportCommand::Close);
+        }
+    }
+
+    /// The browser's file menu, which opens files through its file dialog and saves them as
+    /// downloads.
+    #[cfg(target_arch = "wasm32")]
+    fn file_menu(&mut self, ui: &mut egui::Ui) {
+        if ui.button("New Tab").clicked() {
+            ui.close_menu();
+            self.new_tab();
+        }
+        if ui.button("Open…").clicked() {
+            ui.close_menu();
+            let extensions = SaveFormat::ALL.map(|format| format.extension());
+            match PickedFile::pick(ui.ctx(), &extensions) {
+                Ok(picked) => self.opening = Some(picked),
+                Err(err) => self.file_error = Some(format!("Could not open a file:\n{err}")),
+            }
+        }
+        if ui
+            .button("Save")
+            .on_hover_text("Download the painting as a file")
+            .clicked()
+        {
+            ui.close_menu();
+            self.download_file();
+        }
+    }
+
+    /// Opens the painting picked in the browser's file dialog in a new tab, once it is read.
+    #[cfg(target_arch = "wasm32")]
+    fn open_picked_file(&mut self) {
+        let Some((name, data)) = self.opening.as_ref().and_then(PickedFile::take) else {
+            return;
+        };
+        self.opening = None;
+        match format::decode(&data) {
+            Ok(painting) => {
+                self.tabs.push(Tab { painting });
+                self.active_tab = self.tabs.len();
+            }
+            Err(err) => {
+                log::warn!("Failed to open {name}: {err}");
+                self.file_error = Some(format!("Could not open {name}:\n{err}"));
+            }
+        }
+    }
+
+    /// Downloads the painting in the tab shown, in its save format.
+    #[cfg(target_arch = "wasm32")]
+    fn download_file(&mut self) {
+        let painting = self.painting_at(self.active_tab);
+        let format = painting.save_format();
+        let name = format!("canvas.{}", format.extension());
+        match format
+            .encode(painting)
+            .and_then(|data| crate::export::save(&name, &data))
+        {
+            Ok(()) => self.active_painting().mark_file_saved(),
+            Err(err) => {
+                log::error!("Failed to save {name}: {err}");
+                self.file_error = Some(format!("Could not save {name}:\n{err}"));
+            }
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn recent_files_menu(&mut self, ui: &mut egui::Ui) {
+        let mut open = None;
+        for path in &self.recent_files {
+            let name = path.file_name().unwrap_or(path.as_os_str());
+            if ui
+                .button(name.to_string_lossy().into_owned())
+                .on_hover_text(path.display().to_string())
+                .clicked()
+            {
+                open = Some(path.clone());
+            }
+        }
+        ui.separator();
+        if ui.button("Clear list").clicked() {
+            self.recent_files.clear();
+            ui.close_menu();
+        }
+        if let Some(path) = open {
+            ui.close_menu();
+            self.open_path(path);
+        }
+    }
+
+    fn brushes_menu(&mut self, ui: &mut egui::Ui) {
+        let mut apply = None;
+        let mut remove = None;
+        for (index, preset) in self.brush_presets.iter().enumerate() {
+            ui.horizontal(|ui| {
+                let label = match PRESET_KEYS.get(index) {
+                    Some(_) => format!("{}. {}", index + 1, preset.name),
+                    None => preset.name.clone(),
+                };
+                if ui.button(label).clicked() {
+                    apply = Some(preset.clone());
+                    ui.close_menu();
+                }
+                if ui
+                    .small_button("🗑")
+                    .on_hover_text("Delete preset")
+                    .clicked()
+                {
+                    remove = Some(index);
+                }
+            });
+        }
+        if let Some(preset) = apply {
+            self.active_painting().apply_brush_preset(&preset);
+        }
+        if let Some(index) = remove {
+            self.brush_presets.remove(index);
+        }
+        if !self.brush_presets.is_empty() {
+            ui.separator();
+        }
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::TextEdit::singleline(&mut self.new_preset_name)
+                    .hint_text("Preset name")
+                    .desired_width(120.0),
+            );
+            if ui
+                .add_enabled(
+                    !self.new_preset_name.is_empty(),
+                    egui::Button::new("Save current"),
+                )
+                .clicked()
+            {
+                let name = std::mem::take(&mut self.new_preset_name);
+                let preset = self.active_painting().brush_preset(name);
+                self.brush_presets.push(preset);
+            }
+        });
+    }
+
+    fn handle_preset_keys(&mut self, ctx: &egui::Context) {
+        if ctx.wants_keyboard_input() {
+            return;
+        }
+        let pressed = PRESET_KEYS
+            .iter()
+            .zip(&self.brush_presets)
+            .filter(|(key, _)| ctx.input(|input| input.key_pressed(**key)))
+            .map(|(_, preset)| preset.clone())
+            .collect::<Vec<_>>();
+        for preset in pressed {
+            self.active_painting().apply_brush_preset(&preset);
+        }
+    }
+
+    /// A file dialog for painting files, starting next to the current file.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn canvas_file_dialog(&self) -> rfd::FileDialog {
+        let extensions = SaveFormat::ALL.map(|format| format.extension());
+        let dialog = rfd::FileDialog::new().add_filter("Canvas", &extensions);
+        match self.active_file().and_then(|file| file.parent()) {
+            Some(dir) => dialog.set_directory(dir),
+            None => dialog,
+        }
+    }
+
+    /// Asks for a painting file and opens it in a new tab.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn open_file(&mut self) {
+        if let Some(path) = self.canvas_file_dialog().pick_file() {
+            self.open_path(path);
+        }
+    }
+
+    /// Opens the painting in `path` in a new tab, or shows the tab it is already open in. A
+    /// file that cannot be opened is dropped from the recent files.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn open_path(&mut self, path: PathBuf) {
+        if let Some(index) = (0..=self.tabs.len()).find(|&index| self.file_at(index) == Some(&path))
+        {
+            self.active_tab = index;
+            self.set_file(path);
+            return;
+        }
+        match Painting::read_file(&path) {
+            Ok(painting) => {
+                self.tabs.push(Tab {
+                    painting,
+                    file: None,
+                });
+                self.active_tab = self.tabs.len();
+                self.set_file(path);
+            }
+            Err(err) => {
+                log::warn!("Failed to open {}: {err}", path.display());
+                self.recent_files.retain(|recent| *recent != path);
+                self.file_error = Some(format!("Could not open {}:\n{err}", path.display()));
+            }
+        }
+    }
+
+    /// Writes the painting to the current file, asking for one if there is none yet.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn save_file(&mut self) {
+        match self.active_file().cloned() {
+            Some(path) => self.write_file(path),
+            None => self.save_file_as(),
+        }
+    }
+
+    /// Asks for a file to write the painting to, which becomes the current file.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn save_file_as(&mut self) {
+        let name = match self.active_file().and_then(|file| file.file_name()) {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => format!(
+                "canvas.{}",
+                self.painting_at(self.active_tab).save_format().extension()
+            ),
+        };
+        let Some(path) = self.canvas_file_dialog().set_file_name(name).save_file() else {
+            return;
+        };
+        self.write_file(path);
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn write_file(&mut self, path: PathBuf) {
+        match self.painting_at(self.active_tab).write_file(&path) {
+            Ok(()) => {
+                log::info!("Saved the painting to {}", path.display());
+                self.active_painting().mark_file_saved();
+                self.set_file(path);
+            }
+            Err(err) => {
+                log::error!("Failed to save to {}: {err}", path.display());
+                self.file_error = Some(format!("Could not save to {}:\n{err}", path.display()));
+            }
+        }
+    }
+
+    /// Makes `path` the file of the tab shown and the most recent one.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn set_file(&mut self, path: PathBuf) {
+        self.recent_files.retain(|recent| *recent != path);
+        self.recent_files.insert(0, path.clone());
+        self.recent_files.truncate(RECENT_FILES);
+        match self.active_tab.checked_sub(1) {
+            Some(index) => self.tabs[index].file = Some(path),
+            None => self.file = Some(path),
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn handle_file_keys(&mut self, ctx: &egui::Context) {
+        let (open, save_as, save) = ctx.input_mut(|input| {
+            (
+                input.consume_key(egui::Modifiers::COMMAND, egui::Key::O),
+                input.consume_key(
+                    egui::Modifiers::COMMAND | egui::Modifiers::SHIFT,
+                    egui::Key::S,
+                ),
+                input.consume_key(egui::Modifiers::COMMAND, egui::Key::S),
+            )
+        });
+        if open {
+            self.open_file();
+        } else if save_as {
+            self.save_file_as();
+        } else if save {
+            self.save_file();
+        }
+    }
+
+    /// Names the tab shown in the window title, marked while it has unsaved changes.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn update_title(&mut self, ctx: &egui::Context) {
+        let name = self.tab_name(self.active_tab);
+        let marker = if self.painting_at(self.active_tab).is_modified() {
+            "● "
+        } else {
+            ""
+        };
+        let title = format!("{marker}{name} — True Infinite Canvas");
+        if title != self.title {
+            ctx.send_viewport_cmd(egui::ViewportCommand::Title(title.clone()));
+            self.title = title;
+        }
+    }
+
+    /// Holds back closing the window while a painting has unsaved changes, to ask whether to
+    /// save them first.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn handle_close_request(&mut self, ctx: &egui::Context) {
+        if ctx.input(|input| input.viewport().close_requested())
+            && self.is_modified(Closing::Window)
+            && !self.allow_close
+        {
+            ctx.send_viewport_cmd(egui::ViewportCommand::CancelClose);
+            self.confirm_close = Some(Closing::Window);
+        }
+    }
+
+    fn confirm_close_window(&mut self, ctx: &egui::Context) {
+        let Some(closing) = self.confirm_close else {
+            return;
+        };
+        let question = match closing {
+            #[cfg(not(target_arch = "wasm32"))]
+            Closing::Window => "Save changes to the paintings before closing?".to_owned(),
+            Closing::Tab(index) => format!(
+                "Save changes to {} before closing it?",
+                self.tab_name(index)
+            ),
+        };
+        #[cfg_attr(target_arch = "wasm32", allow(unused_mut))]
+        let mut save = false;
+        let mut discard = false;
+        let mut cancel = false;
+        egui::Window::new("Unsaved changes")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.label(question);
+                ui.horizontal(|ui| {
+                    #[cfg(not(target_arch = "wasm32"))]
+                    {
+                        save = ui.button("Save").clicked();
+                    }
+                    discard = ui.button("Don't save").clicked();
+                    cancel = ui.button("Cancel").clicked();
+                });
+            });
+        #[cfg(not(target_arch = "wasm32"))]
+        if save {
+            let indices = match closing {
+                Closing::Window => (0..=self.tabs.len()).collect(),
+                Closing::Tab(index) => vec![index],
+            };
+            for index in indices {
+                if self.painting_at(index).is_modified() {
+                    self.active_tab = index;
+                    self.save_file();
+                }
+            }
+            // Saving was cancelled or failed if the changes are still unsaved.
+            discard = !self.is_modified(closing);
+        }
+        if discard {
+            match closing {
+                #[cfg(not(target_arch = "wasm32"))]
+                Closing::Window => {
+                    self.allow_close = true;
+                    ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+                }
+                Closing::Tab(index) => self.remove_tab(index),
+            }
+        }
+        if save || discard || cancel {
+            self.confirm_close = None;
+        }
+    }
+
+    /// Explains why the last file could not be opened or saved.
+    fn file_error_window(&mut self, ctx: &egui::Context) {
+        let Some(err) = &self.file_error else {
+            return;
+        };
+        let mut open = true;
+        let mut close = false;
+        egui::Window::new("File error")
+            .open(&mut open)
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.label(err);
+                close = ui.button("OK").clicked();
+            });
+        if !open || close {
+            self.file_error = None;
+        }
+    }
+
+    /// Offers to restore the paintings autosaved before the app last crashed, each to the tab
+    /// it was in.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn recovery_window(&mut self, ctx: &egui::Context) {
+        if self.pending_recovery.is_empty() {
+            return;
+        }
+        let mut restore = false;
+        let mut discard = false;
+        egui::Window::new("Recover painting")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.label("The app did not close properly last time.");
+                ui.label(match self.pending_recovery.len() {
+                    1 => "Restore the painting as it was autosaved before then?".to_string(),
+                    count => {
+                        format!("Restore the {count} paintings as they were autosaved before then?")
+                    }
+                });
+                ui.horizontal(|ui| {
+                    restore = ui.button("Restore").clicked();
+                    discard = ui.button("Discard").clicked();
+                });
+            });
+        if restore {
+            for (index, path) in std::mem::take(&mut self.pending_recovery) {
+                match Painting::read_file(&path) {
+                    Ok(painting) if index <= self.tabs.len() => {
+                        self.painting_at_mut(index)
+                            .replace(painting, "Restored autosave");
+                        self.active_tab = index;
+                    }
+                    // Opened after the app state was last saved.
+                    Ok(painting) => {
+                        self.tabs.push(Tab {
+                            painting,
+                            ..Default::default()
+                        });
+                        self.active_tab = self.tabs.len();
+                    }
+                    Err(err) => {
+                        log::warn!("Failed to restore the autosave of tab {index}: {err}");
+                        self.file_error = Some(format!("Could not restore the autosave:\n{err}"));
+                    }
+                }
+            }
         }
+        if discard {
+            self.pending_recovery.clear();
+        }
+    }
 
-        Default::default()
+    fn profile_import_window(&mut self, ctx: &egui::Context) {
+        let Some(pending) = self.pending_profile_import.as_mut() else {
+            return;
+        };
+        let mut open = true;
+        let mut apply = false;
+        egui::Window::new("Import settings profile")
+            .open(&mut open)
+            .collapsible(false)
+            .show(ctx, |ui| {
+                ui.label("These settings differ from your current ones:");
+                egui::Grid::new("profile_conflicts")
+                    .striped(true)
+                    .show(ui, |ui| {
+                        for (key, use_incoming) in pending.use_incoming.iter_mut() {
+                            ui.label(key);
+                            ui.radio_value(use_incoming, false, "Keep current")
+                                .on_hover_text(pending.current.raw(key).unwrap_or_default());
+                            ui.radio_value(use_incoming, true, "Use imported")
+                                .on_hover_text(pending.incoming.raw(key).unwrap_or_default());
+                            ui.end_row();
+                        }
+                    });
+                ui.separator();
+                apply = ui.button("Apply").clicked();
+            });
+        if apply {
+            let profile = pending.resolved();
+            self.apply_settings_profile(ctx, &profile);
+        }
+        if apply || !open {
+            self.pending_profile_import = None;
+        }
     }
 }
 
@@ -57,10 +1004,40 @@ impl eframe::App for TemplateApp {
         )
         .unwrap();
         let serializer = serde_stacker::Serializer::new(&mut serializer);
+        // Serializing the whole tree on every save stalls large canvases, so it is saved on its
+        // own, and only what changed.
+        let tree = self.save_tree(storage).then(|| self.painting.detach_tree());
         match self.serialize(serializer) {
-            Ok(_) => storage.set_string(key, String::from_utf8(out).expect("Ron should be utf-8")),
+            Ok(_) => {
+                storage.set_string(key, String::from_utf8(out).expect("Ron should be utf-8"));
+                #[cfg(not(target_arch = "wasm32"))]
+                {
+                    let mut change_journal = std::mem::take(&mut self.change_journal);
+                    change_journal.saved(&self.paintings());
+                    self.change_journal = change_journal;
+                }
+                // The saved trees hold every change recorded so far.
+                for index in 0..=self.tabs.len() {
+                    self.painting_at_mut(index).compact_op_log();
+                }
+            }
             Err(err) => log::error!("eframe failed to encode data using ron: {}", err),
         }
+        if let Some(tree) = tree {
+            self.painting.attach_tree(tree);
+        }
+    }
+
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        // The last save may still be written in the background.
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            if let Some(store) = &self.chunk_store {
+                self.painting.finish_storing(store);
+            }
+            self.autosave.finish();
+            self.change_journal.finish();
+        }
     }
 
     /// Called each time the UI needs repainting, which may be many times per second.
@@ -68,35 +1045,135 @@ impl eframe::App for TemplateApp {
         // Put your widgets into a `SidePanel`, `TopBottomPanel`, `CentralPanel`, `Window` or `Area`.
         // For inspiration and more examples, go to https://emilk.github.io/egui
 
+        self.handle_preset_keys(ctx);
+        if let Some(profile) = self.profile_read.as_ref().and_then(ClipboardText::take) {
+            self.profile_read = None;
+            self.import_settings_profile(ctx, &profile);
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            self.handle_close_request(ctx);
+            self.handle_file_keys(ctx);
+        }
+        #[cfg(target_arch = "wasm32")]
+        {
+            let painting = match self.active_tab.checked_sub(1) {
+                Some(index) => &mut self.tabs[index].painting,
+                None => &mut self.painting,
+            };
+            self.deep_link.sync(ctx, painting);
+            self.open_picked_file();
+            self.finish_opening_store();
+        }
+
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
             // The top panel is often a good place for a menu bar:
 
             egui::menu::bar(ui, |ui| {
                 // NOTE: no File->Quit on web pages!
-                let is_web = cfg!(target_arch = "wasm32");
-                if !is_web {
-                    ui.menu_button("File", |ui| {
-                        if ui.button("Quit").clicked() {
-                            ctx.send_viewport_cmd(egui::ViewportCommand::Close);
-                        }
+                ui.menu_button("File", |ui| self.file_menu(ui));
+                ui.add_space(16.0);
+
+                ui.menu_button("Brushes", |ui| self.brushes_menu(ui));
+                ui.add_space(16.0);
+
+                ui.menu_button("View", |ui| {
+                    if ui.button("New window").clicked() {
+                        self.active_painting().open_view();
+                        ui.close_menu();
+                    }
+                });
+                ui.add_space(16.0);
+
+                ui.menu_button("Settings", |ui| {
+                    if ui.button("Export profile").clicked() {
+                        let profile = self.settings_profile(ctx).to_ron();
+                        ui.output_mut(|output| output.copied_text = profile);
+                        ui.close_menu();
+                    }
+                    if ui.button("Import profile").clicked() {
+                        self.profile_read = Some(ClipboardText::read(ctx));
+                        ui.close_menu();
+                    }
+                    ui.separator();
+                    ui.menu_button("Mouse buttons", |ui| {
+                        self.active_painting().mouse_mappings_ui(ui)
                     });
-                    ui.add_space(16.0);
-                }
+                    #[cfg(not(target_arch = "wasm32"))]
+                    ui.horizontal(|ui| {
+                        ui.label("Autosave every");
+                        ui.add(
+                            egui::DragValue::new(&mut self.autosave.minutes)
+                                .range(0..=60)
+                                .suffix(" min"),
+                        )
+                        .on_hover_text("0 turns autosave off");
+                    });
+                });
+                ui.add_space(16.0);
+
+                ui.menu_button("Help", |ui| {
+                    if ui.button("Report a problem…").clicked() {
+                        self.bug_report.open = true;
+                        ui.close_menu();
+                    }
+                });
+                ui.add_space(16.0);
 
                 egui::widgets::global_theme_preference_buttons(ui);
             });
         });
 
+        egui::TopBottomPanel::top("tab_bar").show(ctx, |ui| self.tab_bar(ui));
+
+        self.active_painting().inspector_panel(ctx);
+        self.active_painting().participants_panel(ctx);
+
         egui::CentralPanel::default().show(ctx, |ui| {
             // The central panel the region left after adding TopPanel's and SidePanel's
-            self.painting.ui_control(ui);
-            self.painting.ui_content(ui);
+            let painting = self.active_painting();
+            painting.ui_control(ui);
+            painting.ui_content(ui);
 
             ui.with_layout(egui::Layout::bottom_up(egui::Align::LEFT), |ui| {
                 powered_by_egui_and_eframe(ui);
                 egui::warn_if_debug_build(ui);
             });
         });
+
+        if let Some(branch) = self.active_painting().take_branch() {
+            self.tabs.push(Tab {
+                painting: branch,
+                #[cfg(not(target_arch = "wasm32"))]
+                file: None,
+            });
+            self.active_tab = self.tabs.len();
+        }
+
+        self.profile_import_window(ctx);
+        self.bug_report_window(ctx);
+        self.confirm_close_window(ctx);
+        for index in 0..=self.tabs.len() {
+            let name = self.tab_name(index);
+            self.painting_at_mut(index).show_views(ctx, &name);
+        }
+        self.file_error_window(ctx);
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            self.recovery_window(ctx);
+            self.update_title(ctx);
+            let (mut autosave, mut change_journal) = (
+                std::mem::take(&mut self.autosave),
+                std::mem::take(&mut self.change_journal),
+            );
+            let paintings = self.paintings();
+            // Autosaving now would overwrite the copies that may yet be restored.
+            if self.pending_recovery.is_empty() {
+                autosave.update(&paintings);
+            }
+            change_journal.update(&paintings);
+            (self.autosave, self.change_journal) = (autosave, change_journal);
+        }
     }
 }
 
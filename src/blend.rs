@@ -0,0 +1,343 @@
+use std::{cell::RefCell, mem::offset_of, sync::Arc};
+
+use eframe::{
+    egui_glow,
+    glow::{self, HasContext},
+};
+use egui::{
+    emath,
+    epaint::{ClippedPrimitive, ClippedShape, Primitive, Vertex},
+    pos2, vec2, ColorImage, Context, PaintCallback, PaintCallbackInfo, Painter, Rect,
+};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::{layers::Layers, raster, structure::StrokeEntry};
+
+const STANDARD_COORD_BOUNDS: Rect = Rect::from_min_max(pos2(-1.0, -1.0), pos2(1.0, 1.0));
+
+/// How a layer's strokes combine with what is drawn beneath them.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum BlendMode {
+    #[default]
+    Normal,
+    /// Darkens what is beneath by the stroke color, like ink on paper.
+    Multiply,
+}
+
+impl BlendMode {
+    pub const ALL: [BlendMode; 2] = [BlendMode::Normal, BlendMode::Multiply];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BlendMode::Normal => "Normal",
+            BlendMode::Multiply => "Multiply",
+        }
+    }
+}
+
+/// Draws strokes sorted bottom to top, applying the opacity and blend mode of their layers.
+/// Multiply layers are drawn through a glow paint callback, so they are missing when the host
+/// app renders with another backend.
+pub fn paint_strokes(painter: &Painter, layers: &Layers, strokes: &[(StrokeEntry, Rect)]) {
+    for (layer, strokes) in &strokes.iter().chunk_by(|(stroke, _)| stroke.layer) {
+        let mut painter = painter.clone();
+        painter.multiply_opacity(layers.opacity(layer));
+        let draw = |painter: &Painter| {
+            for (stroke, screen_rect) in strokes {
+                let to_screen = emath::RectTransform::from_to(STANDARD_COORD_BOUNDS, *screen_rect);
+                stroke.drawable.draw(painter, to_screen);
+            }
+        };
+        match layers.blend(layer) {
+            BlendMode::Normal => draw(&painter),
+            BlendMode::Multiply => {
+                let shapes =
+                    raster::capture_shapes(painter.ctx(), painter.clip_rect(), |capture| {
+                        let mut capture = capture.clone();
+                        capture.multiply_opacity(layers.opacity(layer));
+                        draw(&capture);
+                    });
+                paint_multiplied(&painter, shapes);
+            }
+        }
+    }
+}
+
+/// Draws strokes sorted bottom to top, positioned in pixels, onto `image` without the GPU, as
+/// `paint_strokes` would draw them on screen.
+pub fn rasterize_strokes(
+    ctx: &Context,
+    image: &mut ColorImage,
+    layers: &Layers,
+    strokes: &[(StrokeEntry, Rect)],
+) {
+    let image_rect = Rect::from_min_size(
+        pos2(0.0, 0.0),
+        vec2(image.width() as f32, image.height() as f32),
+    );
+    for (layer, strokes) in &strokes.iter().chunk_by(|(stroke, _)| stroke.layer) {
+        let shapes = raster::capture_shapes(ctx, image_rect, |painter| {
+            let mut painter = painter.clone();
+            painter.multiply_opacity(layers.opacity(layer));
+            for (stroke, rect) in strokes {
+                let to_image = emath::RectTransform::from_to(STANDARD_COORD_BOUNDS, *rect);
+                stroke.drawable.draw(&painter, to_image);
+            }
+        });
+        let meshes = ctx
+            .tessellate(shapes, 1.0)
+            .into_iter()
+            .filter_map(|primitive| match primitive.primitive {
+                Primitive::Mesh(mesh) => Some(mesh),
+                Primitive::Callback(_) => None,
+            })
+            .collect_vec();
+        match layers.blend(layer) {
+            BlendMode::Normal => raster::rasterize_onto(image, &meshes),
+            BlendMode::Multiply => raster::multiply(image, &raster::rasterize(&meshes, image.size)),
+        }
+    }
+}
+
+/// Paints `shapes` multiplied onto whatever is already drawn beneath them.
+fn paint_multiplied(painter: &Painter, shapes: Vec<ClippedShape>) {
+    let ctx = painter.ctx();
+    let primitives = ctx.tessellate(shapes, ctx.pixels_per_point());
+    let callback = egui_glow::CallbackFn::new(move |info, glow_painter| {
+        MULTIPLY_PROGRAM.with(|program| {
+            let mut program = program.borrow_mut();
+            let program = program.get_or_insert_with(|| {
+                let program = unsafe { MultiplyProgram::new(glow_painter.gl()) };
+                if let Err(err) = &program {
+                    log::error!("Failed to build multiply blend shader: {err}");
+                }
+                program
+            });
+            if let Ok(program) = program {
+                unsafe { program.paint(glow_painter, &info, &primitives) };
+            }
+        });
+    });
+    painter.add(PaintCallback {
+        rect: painter.clip_rect(),
+        callback: Arc::new(callback),
+    });
+}
+
+thread_local! {
+    /// Built the first time a multiply layer is drawn. Keeps the error if building failed so it
+    /// is only reported once.
+    static MULTIPLY_PROGRAM: RefCell<Option<Result<MultiplyProgram, String>>> =
+        const { RefCell::new(None) };
+}
+
+const VERTEX_SHADER: &str = r#"
+#if NEW_SHADER_INTERFACE
+    #define I in
+    #define O out
+#else
+    #define I attribute
+    #define O varying
+#endif
+#ifdef GL_ES
+    precision mediump float;
+#endif
+uniform vec2 u_screen_size;
+I vec2 a_pos;
+I vec2 a_tc;
+I vec4 a_srgba;
+O vec4 v_rgba;
+O vec2 v_tc;
+void main() {
+    gl_Position = vec4(
+        2.0 * a_pos.x / u_screen_size.x - 1.0,
+        1.0 - 2.0 * a_pos.y / u_screen_size.y,
+        0.0,
+        1.0);
+    v_rgba = a_srgba / 255.0;
+    v_tc = a_tc;
+}
+"#;
+
+const FRAGMENT_SHADER: &str = r#"
+#ifdef GL_ES
+    precision mediump float;
+#endif
+uniform sampler2D u_sampler;
+#if NEW_SHADER_INTERFACE
+    in vec4 v_rgba;
+    in vec2 v_tc;
+    out vec4 f_color;
+    #define gl_FragColor f_color
+    #define texture2D texture
+#else
+    varying vec4 v_rgba;
+    varying vec2 v_tc;
+#endif
+void main() {
+    gl_FragColor = v_rgba * texture2D(u_sampler, v_tc);
+}
+"#;
+
+/// Draws egui meshes like egui_glow does, but blending with `DST_COLOR` so the premultiplied
+/// colors scale what is beneath them.
+struct MultiplyProgram {
+    program: glow::Program,
+    /// Missing where vertex array objects are unsupported, which is fine as long as the
+    /// attributes are set up before every draw.
+    vertex_array: Option<glow::VertexArray>,
+    vertex_buffer: glow::Buffer,
+    element_buffer: glow::Buffer,
+    u_screen_size: Option<glow::UniformLocation>,
+    u_sampler: Option<glow::UniformLocation>,
+    attributes: [(u32, i32, u32, usize); 3],
+}
+
+impl MultiplyProgram {
+    unsafe fn new(gl: &glow::Context) -> Result<Self, String> {
+        let version = egui_glow::ShaderVersion::get(gl);
+        let program = gl.create_program()?;
+        let mut shaders = vec![];
+        for (shader_type, source) in [
+            (glow::VERTEX_SHADER, VERTEX_SHADER),
+            (glow::FRAGMENT_SHADER, FRAGMENT_SHADER),
+        ] {
+            let shader = gl.create_shader(shader_type)?;
+            gl.shader_source(
+                shader,
+                &format!(
+                    "{}\n#define NEW_SHADER_INTERFACE {}\n{}",
+                    version.version_declaration(),
+                    version.is_new_shader_interface() as i32,
+                    source
+                ),
+            );
+            gl.compile_shader(shader);
+            if !gl.get_shader_compile_status(shader) {
+                return Err(gl.get_shader_info_log(shader));
+            }
+            gl.attach_shader(program, shader);
+            shaders.push(shader);
+        }
+        gl.link_program(program);
+        if !gl.get_program_link_status(program) {
+            return Err(gl.get_program_info_log(program));
+        }
+        for shader in shaders {
+            gl.detach_shader(program, shader);
+            gl.delete_shader(shader);
+        }
+        let attribute = |name: &str| {
+            gl.get_attrib_location(program, name)
+                .ok_or_else(|| format!("Missing attribute {name}"))
+        };
+        let attributes = [
+            (attribute("a_pos")?, 2, glow::FLOAT, offset_of!(Vertex, pos)),
+            (attribute("a_tc")?, 2, glow::FLOAT, offset_of!(Vertex, uv)),
+            (
+                attribute("a_srgba")?,
+                4,
+                glow::UNSIGNED_BYTE,
+                offset_of!(Vertex, color),
+            ),
+        ];
+        Ok(Self {
+            program,
+            vertex_array: gl.create_vertex_array().ok(),
+            vertex_buffer: gl.create_buffer()?,
+            element_buffer: gl.create_buffer()?,
+            u_screen_size: gl.get_uniform_location(program, "u_screen_size"),
+            u_sampler: gl.get_uniform_location(program, "u_sampler"),
+            attributes,
+        })
+    }
+
+    /// egui_glow restores its own state after a callback, so this leaves whatever it changes.
+    unsafe fn paint(
+        &self,
+        glow_painter: &egui_glow::Painter,
+        info: &PaintCallbackInfo,
+        primitives: &[ClippedPrimitive],
+    ) {
+        let gl = glow_painter.gl();
+        let [width_px, height_px] = info.screen_size_px;
+        let pixels_per_point = info.pixels_per_point;
+        gl.viewport(0, 0, width_px as i32, height_px as i32);
+        gl.use_program(Some(self.program));
+        gl.uniform_2_f32(
+            self.u_screen_size.as_ref(),
+            width_px as f32 / pixels_per_point,
+            height_px as f32 / pixels_per_point,
+        );
+        gl.uniform_1_i32(self.u_sampler.as_ref(), 0);
+        gl.active_texture(glow::TEXTURE0);
+        gl.blend_equation(glow::FUNC_ADD);
+        gl.blend_func_separate(
+            glow::DST_COLOR,
+            glow::ONE_MINUS_SRC_ALPHA,
+            glow::ZERO,
+            glow::ONE,
+        );
+        gl.bind_vertex_array(self.vertex_array);
+        gl.bind_buffer(glow::ARRAY_BUFFER, Some(self.vertex_buffer));
+        gl.bind_buffer(glow::ELEMENT_ARRAY_BUFFER, Some(self.element_buffer));
+        for (location, size, data_type, offset) in self.attributes {
+            gl.vertex_attrib_pointer_f32(
+                location,
+                size,
+                data_type,
+                false,
+                size_of::<Vertex>() as i32,
+                offset as i32,
+            );
+            gl.enable_vertex_attrib_array(location);
+        }
+        for ClippedPrimitive {
+            clip_rect,
+            primitive,
+        } in primitives
+        {
+            let Primitive::Mesh(mesh) = primitive else {
+                continue;
+            };
+            let Some(texture) = glow_painter.texture(mesh.texture_id) else {
+                continue;
+            };
+            let min_x = (pixels_per_point * clip_rect.min.x).round().max(0.0) as i32;
+            let min_y = (pixels_per_point * clip_rect.min.y).round().max(0.0) as i32;
+            let max_x = ((pixels_per_point * clip_rect.max.x).round() as i32).min(width_px as i32);
+            let max_y = ((pixels_per_point * clip_rect.max.y).round() as i32).min(height_px as i32);
+            if max_x <= min_x || max_y <= min_y {
+                continue;
+            }
+            gl.scissor(
+                min_x,
+                height_px as i32 - max_y,
+                max_x - min_x,
+                max_y - min_y,
+            );
+            gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+            gl.buffer_data_u8_slice(
+                glow::ARRAY_BUFFER,
+                bytemuck::cast_slice(&mesh.vertices),
+                glow::STREAM_DRAW,
+            );
+            gl.buffer_data_u8_slice(
+                glow::ELEMENT_ARRAY_BUFFER,
+                bytemuck::cast_slice(&mesh.indices),
+                glow::STREAM_DRAW,
+            );
+            gl.draw_elements(
+                glow::TRIANGLES,
+                mesh.indices.len() as i32,
+                glow::UNSIGNED_INT,
+                0,
+            );
+        }
+        for (location, ..) in self.attributes {
+            gl.disable_vertex_attrib_array(location);
+        }
+        gl.bind_vertex_array(None);
+    }
+}
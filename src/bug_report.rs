@@ -0,0 +1,189 @@
+use std::io::Write;
+
+use chrono::{Datelike, Local, Timelike};
+use egui::Ui;
+use flate2::{write::DeflateEncoder, Compression};
+
+use crate::raster;
+
+/// Files gathered to describe a problem, written out as a zip archive the user can attach to an
+/// issue.
+#[derive(Default)]
+pub struct BugReport {
+    files: Vec<(String, Vec<u8>)>,
+}
+
+impl BugReport {
+    pub fn add(&mut self, name: &str, contents: impl Into<Vec<u8>>) {
+        self.files.push((name.to_string(), contents.into()));
+    }
+
+    pub fn add_screenshot(&mut self, image: &egui::ColorImage) {
+        match raster::encode_png(image) {
+            Ok(png) => self.add("screenshot.png", png),
+            Err(err) => log::error!("Failed to encode screenshot: {err}"),
+        }
+    }
+
+    /// Packs the files into a zip archive, each deflated.
+    pub fn to_zip(&self) -> std::io::Result<Vec<u8>> {
+        let now = Local::now();
+        let time = (now.hour() << 11 | now.minute() << 5 | (now.second() / 2)) as u16;
+        let date =
+            (((now.year().max(1980) - 1980) as u32) << 9 | now.month() << 5 | now.day()) as u16;
+        let mut out = Vec::new();
+        let mut directory = Vec::new();
+        for (name, contents) in &self.files {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(contents)?;
+            let compressed = encoder.finish()?;
+            let crc = crc32fast::hash(contents);
+            let offset = out.len() as u32;
+            // Fields shared by the local header and the central directory entry, from the
+            // version needed to extract through the name's length.
+            let mut common = Vec::new();
+            put_u16(&mut common, 20);
+            // Names are UTF-8.
+            put_u16(&mut common, 1 << 11);
+            // Deflate.
+            put_u16(&mut common, 8);
+            put_u16(&mut common, time);
+            put_u16(&mut common, date);
+            put_u32(&mut common, crc);
+            put_u32(&mut common, compressed.len() as u32);
+            put_u32(&mut common, contents.len() as u32);
+            put_u16(&mut common, name.len() as u16);
+
+            put_u32(&mut out, 0x04034b50);
+            out.extend_from_slice(&common);
+            // Extra field length.
+            put_u16(&mut out, 0);
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(&compressed);
+
+            put_u32(&mut directory, 0x02014b50);
+            // Version made by.
+            put_u16(&mut directory, 20);
+            directory.extend_from_slice(&common);
+            // Extra field and comment lengths, disk number, internal and external attributes.
+            put_u16(&mut directory, 0);
+            put_u16(&mut directory, 0);
+            put_u16(&mut directory, 0);
+            put_u16(&mut directory, 0);
+            put_u32(&mut directory, 0);
+            put_u32(&mut directory, offset);
+            directory.extend_from_slice(name.as_bytes());
+        }
+        let directory_offset = out.len() as u32;
+        out.extend_from_slice(&directory);
+        put_u32(&mut out, 0x06054b50);
+        // This disk and the disk the directory starts on.
+        put_u16(&mut out, 0);
+        put_u16(&mut out, 0);
+        put_u16(&mut out, self.files.len() as u16);
+        put_u16(&mut out, self.files.len() as u16);
+        put_u32(&mut out, directory.len() as u32);
+        put_u32(&mut out, directory_offset);
+        // Comment length.
+        put_u16(&mut out, 0);
+        Ok(out)
+    }
+
+    /// Writes the archive to the working directory, or on the web hands it to the browser as a
+    /// download, returning where it went.
+    pub fn save(&self) -> Result<String, String> {
+        let name = format!("canvas-report-{}.zip", Local::now().format("%Y%m%d-%H%M%S"));
+        let zip = self.to_zip().map_err(|err| err.to_string())?;
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let path = std::env::current_dir()
+                .map_err(|err| err.to_string())?
+                .join(name);
+            std::fs::write(&path, zip).map_err(|err| err.to_string())?;
+            Ok(format!("Saved to {}", path.display()))
+        }
+        #[cfg(target_arch = "wasm32")]
+        {
+            crate::export::save(&name, &zip)?;
+            Ok(format!("Downloaded {name}"))
+        }
+    }
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Describes the build and the system it is running on.
+pub fn platform_info(ctx: &egui::Context) -> String {
+    let screen = ctx.screen_rect();
+    format!(
+        "version: {}\nbuild: {}\nos: {}\narch: {}\npixels per point: {}\nscreen: {} × {} points\n",
+        env!("CARGO_PKG_VERSION"),
+        if cfg!(debug_assertions) {
+            "debug"
+        } else {
+            "release"
+        },
+        std::env::consts::OS,
+        std::env::consts::ARCH,
+        ctx.pixels_per_point(),
+        screen.width(),
+        screen.height(),
+    )
+}
+
+/// State of the "Report a problem" window.
+pub struct BugReportDialog {
+    pub open: bool,
+    pub description: String,
+    pub include_screenshot: bool,
+    /// Whether a report is waiting on the screenshot requested for it.
+    pub awaiting_screenshot: bool,
+    /// Outcome of the last report, shown under the button.
+    pub status: Option<String>,
+}
+
+impl Default for BugReportDialog {
+    fn default() -> Self {
+        Self {
+            open: false,
+            description: String::new(),
+            include_screenshot: true,
+            awaiting_screenshot: false,
+            status: None,
+        }
+    }
+}
+
+impl BugReportDialog {
+    /// Returns whether a report should be created.
+    pub fn ui(&mut self, ui: &mut Ui) -> bool {
+        ui.label("What went wrong?");
+        ui.add(
+            egui::TextEdit::multiline(&mut self.description)
+                .hint_text("Optional")
+                .desired_rows(4),
+        );
+        ui.checkbox(&mut self.include_screenshot, "Include screenshot");
+        ui.weak(
+            "The report holds counts describing the canvas, the kinds of recent changes, your \
+             settings and platform. Stroke content and your name are left out.",
+        );
+        ui.separator();
+        let create = ui
+            .add_enabled(
+                !self.awaiting_screenshot,
+                egui::Button::new("Create report"),
+            )
+            .clicked();
+        if let Some(status) = &self.status {
+            ui.label(status);
+        }
+        create
+    }
+}
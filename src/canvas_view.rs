@@ -0,0 +1,101 @@
+use egui::{Response, Sense, Ui, Vec2};
+
+use crate::{
+    blend,
+    layers::Layers,
+    painting::Painting,
+    structure::{CanvasTree, NodeId, StrokeEntry},
+};
+
+/// A read-only view of a saved canvas, for embedding previews in other egui apps.
+///
+/// ```no_run
+/// # fn show(ui: &mut egui::Ui, saved: &str) {
+/// let view = true_infinite_canvas::CanvasView::from_ron(saved).unwrap();
+/// ui.add(&view);
+/// # }
+/// ```
+pub struct CanvasView {
+    tree: CanvasTree,
+    center: NodeId,
+    pan: Vec2,
+    zoom: f32,
+    detail: u32,
+    layers: Layers,
+}
+
+impl CanvasView {
+    /// Loads a canvas in the format produced by the app's Export button, showing the region
+    /// that was in view when it was saved.
+    pub fn from_ron(value: &str) -> Result<Self, ron::Error> {
+        Painting::from_ron(value).map(Painting::into_canvas_view)
+    }
+
+    /// A view of `tree` centered on the cell `center`.
+    pub(crate) fn new(
+        tree: CanvasTree,
+        center: NodeId,
+        pan: Vec2,
+        zoom: f32,
+        layers: Layers,
+    ) -> Self {
+        Self {
+            tree,
+            center,
+            pan,
+            zoom,
+            detail: 14,
+            layers,
+        }
+    }
+
+    /// Overrides the saved pan and zoom, relative to the saved center cell.
+    pub fn with_view(mut self, pan: Vec2, zoom: f32) -> Self {
+        self.pan = pan;
+        self.zoom = zoom;
+        self
+    }
+
+    /// How many levels below the visible area are drawn.
+    pub fn with_detail(mut self, detail: u32) -> Self {
+        self.detail = detail;
+        self
+    }
+}
+
+impl egui::Widget for &CanvasView {
+    fn ui(self, ui: &mut Ui) -> Response {
+        let (response, painter) =
+            ui.allocate_painter(ui.available_size_before_wrap(), Sense::hover());
+        let rect = response.rect;
+
+        let tree = &self.tree;
+        let mut node = self.center;
+        let mut node_rect = rect
+            .scale_from_center(self.zoom)
+            .translate(self.zoom * -self.pan * rect.size());
+        let mut levels_up = 0;
+        while !node_rect.contains_rect(rect) {
+            let Some(parent) = tree[node].parent else {
+                break;
+            };
+            node_rect = tree[node].get_parent_rect(node_rect);
+            node = parent;
+            levels_up += 1;
+        }
+
+        let visible = |stroke: &StrokeEntry| self.layers.is_visible(stroke.layer);
+        let mut strokes = tree.get_strokes(node, node_rect, levels_up + self.detail, &visible);
+        let mut ancestor_rect = node_rect;
+        while let Some(parent) = tree[node].parent {
+            ancestor_rect = tree[node].get_parent_rect(ancestor_rect);
+            strokes.extend(tree[parent].get_own_strokes(ancestor_rect, &visible));
+            node = parent;
+        }
+
+        strokes.sort_by_key(|(stroke, _)| (self.layers.rank(stroke.layer), stroke.sort_key()));
+        blend::paint_strokes(&painter, &self.layers, &strokes);
+
+        response
+    }
+}
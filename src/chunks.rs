@@ -0,0 +1,825 @@
+use std::sync::{Arc, Mutex};
+#[cfg(target_arch = "wasm32")]
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    rc::Rc,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+#[cfg(not(target_arch = "wasm32"))]
+use std::{path::Path, thread::JoinHandle};
+
+use egui::Rect;
+use serde::{Deserialize, Serialize};
+
+#[cfg(target_arch = "wasm32")]
+use crate::indexed_db;
+use crate::{
+    format::{self, SaveFormat},
+    lod::NodeSummary,
+    paging::{self, Page},
+    structure::{CanvasTree, DrawNode, NodeId, StrokeEntry},
+};
+
+/// Levels of the tree stored together in one chunk. Nodes further down start chunks of their
+/// own.
+const CHUNK_DEPTH: usize = 6;
+
+/// Key of the path to the center cell, as `get_or_create_path` takes it.
+const CENTER_KEY: &str = "center";
+/// Key of the name of the tree holding the chunks.
+#[cfg(not(target_arch = "wasm32"))]
+const CHUNKS_KEY: &str = "chunks";
+
+/// Corners leading to a node, outermost first.
+type NodePath = Vec<(u8, u8)>;
+
+type ChunkChildren = [[Option<Box<ChunkNode>>; 2]; 2];
+
+/// Keeps a painting's tree on disk in chunks, each a few levels of a subtree stored under the
+/// path from the outermost node to its root. Opening a canvas only loads the outermost chunk,
+/// and the rest are loaded as the view reaches into them, so a canvas need not fit in memory.
+///
+/// In the browser the chunks are kept in IndexedDB, which can only be read asynchronously. Only
+/// the chunks along the path to the center cell are read when the store is opened. The rest are
+/// fetched in the background once the view reaches into them, staying stubs until they arrive,
+/// and chunks are written through to the database when saved.
+pub struct ChunkStore {
+    #[cfg(not(target_arch = "wasm32"))]
+    db: sled::Db,
+    /// Replaced by a new tree whenever chunks need to move to other paths.
+    #[cfg(not(target_arch = "wasm32"))]
+    chunks: Mutex<sled::Tree>,
+    /// Name of the database the chunks are written to.
+    #[cfg(target_arch = "wasm32")]
+    db: String,
+    /// Repainted once a chunk was fetched.
+    #[cfg(target_arch = "wasm32")]
+    ctx: egui::Context,
+    /// The chunks read from the database and not loaded into the tree yet, compressed, by key.
+    #[cfg(target_arch = "wasm32")]
+    chunks: Mutex<BTreeMap<Vec<u8>, Vec<u8>>>,
+    /// Keys of the chunks being fetched.
+    #[cfg(target_arch = "wasm32")]
+    fetching: Mutex<BTreeSet<Vec<u8>>>,
+    /// Why the chunks that could not be fetched could not, by key, until the next save.
+    #[cfg(target_arch = "wasm32")]
+    unfetched: Mutex<BTreeMap<Vec<u8>, String>>,
+    /// The stored path to the center cell, as kept under `CENTER_KEY`.
+    #[cfg(target_arch = "wasm32")]
+    center: Mutex<Option<Vec<u8>>>,
+    /// Saves queued and not written yet.
+    #[cfg(target_arch = "wasm32")]
+    writing: AtomicUsize,
+    /// Saves that failed to be written, written again before the next.
+    #[cfg(target_arch = "wasm32")]
+    unwritten: Mutex<Vec<Write>>,
+    /// The outermost node when the store was last loaded or saved, where the paths of the
+    /// stored chunks start.
+    root: Mutex<Option<NodeId>>,
+    #[cfg(not(target_arch = "wasm32"))]
+    saving: Mutex<Option<BackgroundSave>>,
+}
+
+/// A chunk store being read from IndexedDB, which finishes some frames after it was asked for.
+#[cfg(target_arch = "wasm32")]
+#[derive(Clone, Default)]
+pub struct OpeningStore(Rc<RefCell<Option<Result<ChunkStore, String>>>>);
+
+#[cfg(target_arch = "wasm32")]
+impl OpeningStore {
+    /// The store once it has been read. It is handed out only once.
+    pub fn take(&self) -> Option<Result<ChunkStore, String>> {
+        self.0.borrow_mut().take()
+    }
+}
+
+/// A save written to IndexedDB once the work queued before it is done.
+#[cfg(target_arch = "wasm32")]
+struct Write {
+    /// Key the chunks already stored move below first, as they all do when the tree grew
+    /// outward past the node their paths start at.
+    move_below: Option<Vec<u8>>,
+    /// Whether the chunks already stored are dropped first, as the store never held the tree.
+    clear: bool,
+    puts: Vec<(&'static str, Vec<u8>, Vec<u8>)>,
+}
+
+#[cfg(target_arch = "wasm32")]
+impl Write {
+    async fn run(&self, name: &str) -> Result<(), String> {
+        let mut puts = vec![];
+        if let Some(below) = &self.move_below {
+            // Moving them in place could overwrite ones yet to be moved, so they are all read
+            // and written again. This only happens when the tree grows past its outermost node.
+            let db = indexed_db::open(name).await?;
+            let stored = indexed_db::read_all(&db, indexed_db::CHUNKS).await;
+            db.close();
+            puts.extend(
+                stored?
+                    .into_iter()
+                    .map(|(key, data)| (indexed_db::CHUNKS, [&below[..], &key[..]].concat(), data)),
+            );
+        }
+        puts.extend(self.puts.iter().cloned());
+        let clear: &[&str] = if self.clear || self.move_below.is_some() {
+            &[indexed_db::CHUNKS]
+        } else {
+            &[]
+        };
+        indexed_db::write(name, clear, puts).await
+    }
+}
+
+/// A save being written on another thread.
+#[cfg(not(target_arch = "wasm32"))]
+struct BackgroundSave {
+    thread: JoinHandle<Result<(), String>>,
+    top_level: NodeId,
+    /// Roots of the chunks being written, marked unsaved again if writing them fails.
+    roots: Vec<NodeId>,
+    /// The tree the chunks are written to instead of the current one, if they move.
+    target: Option<sled::Tree>,
+    /// Path the stored chunks move below.
+    prefix: NodePath,
+}
+
+/// What a background save writes, copied out of the tree.
+#[cfg(not(target_arch = "wasm32"))]
+struct SaveJob {
+    db: sled::Db,
+    current: sled::Tree,
+    target: Option<sled::Tree>,
+    /// Path the chunks in `current` are copied below in `target`, if they are.
+    copy_below: Option<NodePath>,
+    chunks: Vec<(Vec<u8>, ChunkNode)>,
+    format: SaveFormat,
+    center: String,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl SaveJob {
+    fn run(self) -> Result<(), String> {
+        let SaveJob {
+            db,
+            current,
+            target,
+            copy_below,
+            chunks,
+            format,
+            center,
+        } = self;
+        let tree = target.as_ref().unwrap_or(&current);
+        if let Some(prefix) = copy_below {
+            for entry in current.iter() {
+                let (key, value) = entry.map_err(|err| err.to_string())?;
+                let mut moved_key = chunk_key(&prefix);
+                moved_key.extend_from_slice(&key);
+                tree.insert(moved_key, value)
+                    .map_err(|err| err.to_string())?;
+            }
+        }
+        let mut batch = sled::Batch::default();
+        for (key, chunk) in chunks {
+            let data = format.encode(&chunk)?;
+            let data = paging::compress(&data).map_err(|err| err.to_string())?;
+            batch.insert(key, data);
+        }
+        tree.apply_batch(batch).map_err(|err| err.to_string())?;
+        db.insert(CENTER_KEY, center.as_bytes())
+            .map_err(|err| err.to_string())?;
+        if let Some(target) = &target {
+            db.insert(CHUNKS_KEY, &*target.name())
+                .map_err(|err| err.to_string())?;
+        }
+        db.flush().map_err(|err| err.to_string())?;
+        Ok(())
+    }
+}
+
+/// A node as stored in a chunk.
+#[derive(Deserialize, Serialize)]
+struct ChunkNode {
+    children: ChunkChildren,
+    strokes: Vec<StrokeEntry>,
+    hash: Option<u64>,
+    /// Set on the roots of the chunks below this one, which hold their children and strokes.
+    stub: Option<ChunkStub>,
+}
+
+/// What is needed to draw a node from a distance while its chunk is not loaded.
+#[derive(Deserialize, Serialize)]
+struct ChunkStub {
+    summary: NodeSummary,
+    bounds: Option<Rect>,
+}
+
+/// A subtree still in a chunk store, loaded once something reaches into it.
+pub struct ChunkPage {
+    store: Arc<ChunkStore>,
+    /// Path of the chunk's root when it was stored.
+    path: NodePath,
+}
+
+impl ChunkPage {
+    pub fn load(&self) -> Result<CanvasTree, String> {
+        self.store.load_chunk(&self.path)
+    }
+
+    /// Whether the chunk can be loaded now, which it always can be from disk.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn fetch(&self) -> bool {
+        true
+    }
+
+    /// Whether the chunk can be loaded now. If not, it is fetched in the background.
+    #[cfg(target_arch = "wasm32")]
+    pub fn fetch(&self) -> bool {
+        self.store.fetch(&self.path)
+    }
+
+    /// Drops the chunk kept in memory, once it was loaded into the tree.
+    pub fn loaded(self) {
+        #[cfg(target_arch = "wasm32")]
+        self.store.forget(&self.path, false);
+    }
+}
+
+fn chunk_key(path: &[(u8, u8)]) -> Vec<u8> {
+    path.iter().map(|(x, y)| x + 2 * y).collect()
+}
+
+impl ChunkStore {
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn open(path: &Path) -> Result<ChunkStore, String> {
+        let db = sled::open(path).map_err(|err| err.to_string())?;
+        let name = db
+            .get(CHUNKS_KEY)
+            .map_err(|err| err.to_string())?
+            .map_or_else(|| b"chunks".to_vec(), |name| name.to_vec());
+        let chunks = db.open_tree(name).map_err(|err| err.to_string())?;
+        Ok(ChunkStore {
+            db,
+            chunks: Mutex::new(chunks),
+            root: Mutex::new(None),
+            saving: Mutex::new(None),
+        })
+    }
+
+    /// Starts reading the store kept in the IndexedDB database `name`, repainting `ctx` once it
+    /// has been read.
+    #[cfg(target_arch = "wasm32")]
+    pub fn open(name: &str, ctx: &egui::Context) -> OpeningStore {
+        let opening = OpeningStore::default();
+        let (result, ctx, name) = (opening.clone(), ctx.clone(), name.to_owned());
+        wasm_bindgen_futures::spawn_local(async move {
+            *result.0.borrow_mut() = Some(Self::read(name, ctx.clone()).await);
+            ctx.request_repaint();
+        });
+        opening
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    async fn read(name: String, ctx: egui::Context) -> Result<ChunkStore, String> {
+        let db = indexed_db::open(&name).await?;
+        let read = Self::read_center(&db).await;
+        db.close();
+        let (center, chunks) = read?;
+        Ok(ChunkStore {
+            db: name,
+            ctx,
+            chunks: Mutex::new(chunks),
+            fetching: Mutex::default(),
+            unfetched: Mutex::default(),
+            center: Mutex::new(center),
+            writing: AtomicUsize::new(0),
+            unwritten: Mutex::default(),
+            root: Mutex::new(None),
+        })
+    }
+
+    /// Reads the stored path to the center cell, and the chunks along it by key, which are those
+    /// the view starts in.
+    #[cfg(target_arch = "wasm32")]
+    async fn read_center(
+        db: &web_sys::IdbDatabase,
+    ) -> Result<(Option<Vec<u8>>, BTreeMap<Vec<u8>, Vec<u8>>), String> {
+        let center = indexed_db::read(db, indexed_db::META, &[CENTER_KEY.as_bytes().to_vec()])
+            .await?
+            .pop()
+            .flatten();
+        let Some(center) = center else {
+            return Ok((None, BTreeMap::new()));
+        };
+        let center_path: NodePath = ron::de::from_bytes(&center).map_err(|err| err.to_string())?;
+        // Chunks are keyed by their paths outermost first, and can start at any depth.
+        let outward = center_path.into_iter().rev().collect::<Vec<_>>();
+        let keys = (0..=outward.len())
+            .map(|depth| chunk_key(&outward[..depth]))
+            .collect::<Vec<_>>();
+        let values = indexed_db::read(db, indexed_db::CHUNKS, &keys).await?;
+        let chunks = keys
+            .into_iter()
+            .zip(values)
+            .filter_map(|(key, value)| Some((key, value?)))
+            .collect();
+        Ok((Some(center), chunks))
+    }
+
+    /// Loads the outermost chunk, and the path to the center cell as `get_or_create_path`
+    /// takes it. None if the store is still empty.
+    pub fn load(self: &Arc<Self>) -> Result<Option<(CanvasTree, NodePath)>, String> {
+        #[cfg(not(target_arch = "wasm32"))]
+        let center = self.db.get(CENTER_KEY).map_err(|err| err.to_string())?;
+        #[cfg(target_arch = "wasm32")]
+        let center = self.center.lock().unwrap().clone();
+        let Some(center) = center else {
+            return Ok(None);
+        };
+        let center_path = ron::de::from_bytes(&center).map_err(|err| err.to_string())?;
+        let top_level = self.load_chunk(&[])?;
+        *self.root.lock().unwrap() = Some(top_level.root());
+        Ok(Some((top_level, center_path)))
+    }
+
+    fn load_chunk(self: &Arc<Self>, path: &[(u8, u8)]) -> Result<CanvasTree, String> {
+        #[cfg(not(target_arch = "wasm32"))]
+        let data = self
+            .chunks
+            .lock()
+            .unwrap()
+            .get(chunk_key(path))
+            .map_err(|err| err.to_string())?;
+        #[cfg(target_arch = "wasm32")]
+        let data = {
+            let key = chunk_key(path);
+            if let Some(err) = self.unfetched.lock().unwrap().get(&key) {
+                return Err(format!("Failed to read the chunk at {path:?}: {err}"));
+            }
+            let data = self.chunks.lock().unwrap().get(&key).cloned();
+            if data.is_none() && !self.fetch(path) {
+                return Err(format!("The chunk at {path:?} is still being read"));
+            }
+            data
+        };
+        let data = data.ok_or_else(|| format!("No chunk stored at {path:?}"))?;
+        let data = paging::decompress(&data).map_err(|err| err.to_string())?;
+        let node = format::decode(&data)?;
+        let mut tree = self.build(node, &mut path.to_vec());
+        let root = tree.root();
+        tree[root].stored_chunk = true;
+        tree.mark_saved(root);
+        Ok(tree)
+    }
+
+    /// Whether the chunk at `path` can be loaded now, either read already or known to be
+    /// missing. If not, it is fetched in the background, repainting once it arrived.
+    #[cfg(target_arch = "wasm32")]
+    fn fetch(self: &Arc<Self>, path: &[(u8, u8)]) -> bool {
+        let key = chunk_key(path);
+        if self.chunks.lock().unwrap().contains_key(&key)
+            || self.unfetched.lock().unwrap().contains_key(&key)
+        {
+            return true;
+        }
+        if self.fetching.lock().unwrap().insert(key.clone()) {
+            let store = self.clone();
+            indexed_db::queue(async move {
+                let fetched = match indexed_db::open(&store.db).await {
+                    Ok(db) => {
+                        let fetched =
+                            indexed_db::read(&db, indexed_db::CHUNKS, &[key.clone()]).await;
+                        db.close();
+                        fetched
+                    }
+                    Err(err) => Err(err),
+                };
+                // Keys fetched before the chunks moved no longer name the same chunk.
+                if store.fetching.lock().unwrap().remove(&key) {
+                    match fetched.map(|mut values| values.pop().flatten()) {
+                        Ok(Some(data)) => {
+                            store.chunks.lock().unwrap().insert(key, data);
+                        }
+                        Ok(None) => {
+                            let missing = "it is not in the database".to_string();
+                            store.unfetched.lock().unwrap().insert(key, missing);
+                        }
+                        Err(err) => {
+                            store.unfetched.lock().unwrap().insert(key, err);
+                        }
+                    }
+                }
+                store.ctx.request_repaint();
+            });
+        }
+        false
+    }
+
+    /// Drops the chunk at `path` from memory, and with `below` those below it too.
+    #[cfg(target_arch = "wasm32")]
+    fn forget(&self, path: &[(u8, u8)], below: bool) {
+        let key = chunk_key(path);
+        let mut chunks = self.chunks.lock().unwrap();
+        if below {
+            chunks.retain(|stored, _| !stored.starts_with(&key));
+        } else {
+            chunks.remove(&key);
+        }
+    }
+
+    /// Whether every save was written, so that chunks left to the database are there to be
+    /// fetched again.
+    #[cfg(target_arch = "wasm32")]
+    pub fn written(&self) -> bool {
+        self.writing.load(Ordering::Relaxed) == 0 && self.unwritten.lock().unwrap().is_empty()
+    }
+
+    /// Drops the subtree of `id`, a chunk the store holds as it is, from memory, leaving a stub
+    /// that fetches it again once something reaches into it.
+    #[cfg(target_arch = "wasm32")]
+    pub fn page_out(self: &Arc<Self>, tree: &mut CanvasTree, id: NodeId) -> Result<(), String> {
+        if !self.written() {
+            return Err("The last save is still being written".to_string());
+        }
+        if !tree[id].stored_chunk || tree[id].is_unsaved() {
+            return Err("The subtree is not saved to the store as it is".to_string());
+        }
+        let prefix = self
+            .root_path(tree)
+            .ok_or("The tree is not kept in the store")?;
+        let (_, mut path) = tree.get_top_level_and_path(id);
+        path.reverse();
+        let path = path
+            .strip_prefix(&prefix[..])
+            .ok_or("The subtree is outside the stored chunks")?
+            .to_vec();
+        self.forget(&path, true);
+        let store = self.clone();
+        tree.page_out_with(id, |_| Ok(Page::Chunk(ChunkPage { store, path })))
+    }
+
+    /// Turns a chunk's node at `path` into a tree of its own, with stubs for the chunks below.
+    fn build(self: &Arc<Self>, node: ChunkNode, path: &mut NodePath) -> CanvasTree {
+        let (root, children) = self.node(node, path);
+        let mut tree = CanvasTree::with_root(root);
+        let root = tree.root();
+        self.build_children(&mut tree, root, children, path);
+        tree
+    }
+
+    /// Adds the nodes of a chunk below the node `id` at `path`.
+    fn build_children(
+        self: &Arc<Self>,
+        tree: &mut CanvasTree,
+        id: NodeId,
+        children: ChunkChildren,
+        path: &mut NodePath,
+    ) {
+        for (y, row) in children.into_iter().enumerate() {
+            for (x, child) in row.into_iter().enumerate() {
+                let Some(child) = child else {
+                    continue;
+                };
+                path.push((x as u8, y as u8));
+                let (node, grandchildren) = self.node(*child, path);
+                let child = tree.insert_child(id, (x, y), node);
+                self.build_children(tree, child, grandchildren, path);
+                path.pop();
+            }
+        }
+    }
+
+    /// Turns a chunk's node at `path` into a detached node and its children, or into a stub
+    /// for the chunk below.
+    fn node(self: &Arc<Self>, node: ChunkNode, path: &NodePath) -> (DrawNode, ChunkChildren) {
+        let Some(stub) = node.stub else {
+            return (DrawNode::new(node.strokes, node.hash), node.children);
+        };
+        let page = Page::Chunk(ChunkPage {
+            store: self.clone(),
+            path: path.clone(),
+        });
+        let mut node = DrawNode::stub(page, stub.summary, stub.bounds, node.hash);
+        node.stored_chunk = true;
+        (node, Default::default())
+    }
+
+    /// Starts writing the chunks of `tree` that changed since they were last saved, with the
+    /// path to the center cell as `get_or_create_path` takes it. The chunks are copied out of
+    /// the tree here and written on another thread, after waiting for the previous save.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn save(
+        self: &Arc<Self>,
+        tree: &mut CanvasTree,
+        center_path: &[(u8, u8)],
+        format: SaveFormat,
+    ) -> Result<(), String> {
+        self.finish_saving(tree);
+        let top_level = tree.root();
+        tree.update_hashes(top_level);
+        let prefix = self.root_path(tree);
+        // When every chunk moves below new outermost nodes, moving them in place could
+        // overwrite ones yet to be moved, so they are copied to a new tree. A tree the store
+        // never held is written to a new tree too, leaving the old chunks behind.
+        let target = match &prefix {
+            Some(prefix) if prefix.is_empty() => None,
+            _ => Some(self.new_tree()?),
+        };
+        let mut writer = ChunkWriter {
+            tree,
+            fresh: prefix.is_none(),
+            chunks: vec![],
+            roots: vec![],
+        };
+        writer.visit_chunk(tree, top_level, &mut vec![])?;
+        let ChunkWriter { chunks, roots, .. } = writer;
+        let center = ron::to_string(center_path).map_err(|err| err.to_string())?;
+        // Changes from here on are left for the next save.
+        tree.mark_saved(top_level);
+        for &root in &roots {
+            tree[root].stored_chunk = true;
+        }
+        let job = SaveJob {
+            db: self.db.clone(),
+            current: self.chunks.lock().unwrap().clone(),
+            target: target.clone(),
+            copy_below: prefix.clone().filter(|prefix| !prefix.is_empty()),
+            chunks,
+            format,
+            center,
+        };
+        *self.saving.lock().unwrap() = Some(BackgroundSave {
+            thread: std::thread::spawn(move || job.run()),
+            top_level,
+            roots,
+            target,
+            prefix: prefix.unwrap_or_default(),
+        });
+        Ok(())
+    }
+
+    /// Queues the chunks of `tree` that changed since they were last saved, with the path to the
+    /// center cell as `get_or_create_path` takes it, to be written to the database after the
+    /// saves that failed before.
+    #[cfg(target_arch = "wasm32")]
+    pub fn save(
+        self: &Arc<Self>,
+        tree: &mut CanvasTree,
+        center_path: &[(u8, u8)],
+        format: SaveFormat,
+    ) -> Result<(), String> {
+        let top_level = tree.root();
+        tree.update_hashes(top_level);
+        let prefix = self.root_path(tree);
+        let mut writer = ChunkWriter {
+            tree,
+            fresh: prefix.is_none(),
+            chunks: vec![],
+            roots: vec![],
+        };
+        writer.visit_chunk(tree, top_level, &mut vec![])?;
+        let ChunkWriter { chunks, roots, .. } = writer;
+        let mut written = vec![];
+        for (key, chunk) in chunks {
+            let data = format.encode(&chunk)?;
+            written.push((key, paging::compress(&data).map_err(|err| err.to_string())?));
+        }
+        let center = ron::to_string(center_path).map_err(|err| err.to_string())?;
+
+        let mut write = Write {
+            move_below: None,
+            clear: false,
+            puts: written
+                .into_iter()
+                .map(|(key, data)| (indexed_db::CHUNKS, key, data))
+                .collect(),
+        };
+        match &prefix {
+            Some(prefix) if prefix.is_empty() => {}
+            // Every chunk moves below new outermost nodes, those in memory right away.
+            Some(prefix) => {
+                let below = chunk_key(prefix);
+                let mut stored = self.chunks.lock().unwrap();
+                *stored = std::mem::take(&mut *stored)
+                    .into_iter()
+                    .map(|(key, data)| ([&below[..], &key[..]].concat(), data))
+                    .collect();
+                self.fetching.lock().unwrap().clear();
+                self.rebase(tree, top_level, prefix);
+                write.move_below = Some(below);
+            }
+            // The store never held the tree, which leaves the old chunks behind.
+            None => {
+                self.chunks.lock().unwrap().clear();
+                self.fetching.lock().unwrap().clear();
+                write.clear = true;
+            }
+        }
+        // Chunks that were missing may have been written since.
+        self.unfetched.lock().unwrap().clear();
+        write.puts.push((
+            indexed_db::META,
+            CENTER_KEY.as_bytes().to_vec(),
+            center.clone().into_bytes(),
+        ));
+        *self.center.lock().unwrap() = Some(center.into_bytes());
+        *self.root.lock().unwrap() = Some(top_level);
+        tree.mark_saved(top_level);
+        for &root in &roots {
+            tree[root].stored_chunk = true;
+        }
+
+        let retried = std::mem::take(&mut *self.unwritten.lock().unwrap());
+        for write in retried.into_iter().chain([write]) {
+            self.writing.fetch_add(1, Ordering::Relaxed);
+            let store = self.clone();
+            indexed_db::queue(async move {
+                // Saves after one that failed wait for it to be written first.
+                let failed = !store.unwritten.lock().unwrap().is_empty();
+                let result = if failed {
+                    Err(String::new())
+                } else {
+                    write.run(&store.db).await
+                };
+                if let Err(err) = result {
+                    if !failed {
+                        log::error!("Failed to write the canvas to IndexedDB: {err}");
+                    }
+                    store.unwritten.lock().unwrap().push(write);
+                }
+                store.writing.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+        Ok(())
+    }
+
+    /// Waits for the save being written in the background, if any, and takes its outcome into
+    /// `tree`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn finish_saving(self: &Arc<Self>, tree: &mut CanvasTree) {
+        let Some(save) = self.saving.lock().unwrap().take() else {
+            return;
+        };
+        let result = save
+            .thread
+            .join()
+            .unwrap_or_else(|_| Err("The saving thread panicked".to_string()));
+        if let Err(err) = result {
+            log::error!("Failed to save the canvas to its store: {err}");
+            for root in save.roots {
+                tree.mark_unsaved(root);
+            }
+            if let Some(target) = save.target {
+                self.drop_tree(target);
+            }
+            return;
+        }
+        if let Some(target) = save.target {
+            let current = std::mem::replace(&mut *self.chunks.lock().unwrap(), target);
+            if tree.contains(save.top_level) {
+                self.rebase(tree, save.top_level, &save.prefix);
+            }
+            self.drop_tree(current);
+        }
+        *self.root.lock().unwrap() = Some(save.top_level);
+    }
+
+    /// Drops a tree of chunks nothing refers to anymore, on another thread.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn drop_tree(&self, tree: sled::Tree) {
+        let db = self.db.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = db.drop_tree(tree.name()) {
+                log::warn!("Failed to drop old canvas chunks: {err}");
+            }
+        });
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn new_tree(&self) -> Result<sled::Tree, String> {
+        let id = self.db.generate_id().map_err(|err| err.to_string())?;
+        self.db
+            .open_tree(format!("chunks-{id}"))
+            .map_err(|err| err.to_string())
+    }
+
+    /// Path from the outermost node of `tree` to the outermost node when the store was last
+    /// loaded or saved, which is where the stored chunks' paths start. None if that node is
+    /// not in the tree. Trees that did not come from the store have no stored chunks, so an id
+    /// from another tree never matches one of their nodes.
+    fn root_path(&self, tree: &CanvasTree) -> Option<NodePath> {
+        let root = (*self.root.lock().unwrap())?;
+        if !tree.get(root)?.stored_chunk {
+            return None;
+        }
+        let (_, mut path) = tree.get_top_level_and_path(root);
+        path.reverse();
+        Some(path)
+    }
+
+    /// Moves the paths of the pages below `top_level` that still refer to this store under
+    /// `prefix`, after the stored chunks were.
+    fn rebase(self: &Arc<Self>, tree: &mut CanvasTree, top_level: NodeId, prefix: &[(u8, u8)]) {
+        if prefix.is_empty() {
+            return;
+        }
+        let mut stack = vec![top_level];
+        while let Some(node) = stack.pop() {
+            let node = &mut tree[node];
+            if let Some(Page::Chunk(page)) = node.page_mut() {
+                if Arc::ptr_eq(&page.store, self) {
+                    page.path.splice(0..0, prefix.iter().copied());
+                }
+            }
+            stack.extend(node.child_nodes().map(|(_, child)| child));
+        }
+    }
+}
+
+/// Serializes the chunks of a tree being saved.
+struct ChunkWriter<'a> {
+    /// The tree being saved. Paged out subtrees are written from trees of their own.
+    tree: &'a CanvasTree,
+    /// Whether the store holds none of the tree yet, so every chunk is written.
+    fresh: bool,
+    chunks: Vec<(Vec<u8>, ChunkNode)>,
+    /// Roots of the chunks written, among the nodes of the tree being saved.
+    roots: Vec<NodeId>,
+}
+
+impl ChunkWriter<'_> {
+    /// Serializes the chunk rooted at the node `id` of `tree`, at `path`, unless the store
+    /// already holds it as it is, and the chunks below it that changed.
+    fn visit_chunk(
+        &mut self,
+        tree: &CanvasTree,
+        id: NodeId,
+        path: &mut NodePath,
+    ) -> Result<(), String> {
+        let node = &tree[id];
+        if !self.fresh && node.stored_chunk && !node.is_unsaved() {
+            return Ok(());
+        }
+        match node.page() {
+            // Nodes created below a page still being fetched are only merged with its content
+            // once it is loaded.
+            Some(_) if node.child_nodes().next().is_some() || !node.own_strokes().is_empty() => {
+                return Err("Part of the canvas is still being read".to_string());
+            }
+            Some(page) => {
+                let loaded = page.load()?;
+                self.write_chunk(&loaded, loaded.root(), path)?;
+            }
+            None => self.write_chunk(tree, id, path)?,
+        }
+        if std::ptr::eq(tree, self.tree) {
+            self.roots.push(id);
+        }
+        Ok(())
+    }
+
+    fn write_chunk(
+        &mut self,
+        tree: &CanvasTree,
+        id: NodeId,
+        path: &mut NodePath,
+    ) -> Result<(), String> {
+        let chunk = self.chunk_node(tree, id, path, 0)?;
+        self.chunks.push((chunk_key(path), chunk));
+        Ok(())
+    }
+
+    fn chunk_node(
+        &mut self,
+        tree: &CanvasTree,
+        id: NodeId,
+        path: &mut NodePath,
+        depth: usize,
+    ) -> Result<ChunkNode, String> {
+        let node_ref = &tree[id];
+        if depth > 0 && (depth == CHUNK_DEPTH || node_ref.stored_chunk || node_ref.is_paged()) {
+            self.visit_chunk(tree, id, path)?;
+            return Ok(ChunkNode {
+                children: Default::default(),
+                strokes: vec![],
+                hash: node_ref.hash(),
+                stub: Some(ChunkStub {
+                    summary: tree.summary(id).clone(),
+                    bounds: tree.content_bounds(id),
+                }),
+            });
+        }
+        let mut children: ChunkChildren = Default::default();
+        for ((x, y), child) in node_ref.child_nodes() {
+            path.push((x as u8, y as u8));
+            children[y][x] = Some(Box::new(self.chunk_node(tree, child, path, depth + 1)?));
+            path.pop();
+        }
+        Ok(ChunkNode {
+            children,
+            strokes: node_ref.own_strokes().to_vec(),
+            hash: node_ref.hash(),
+            stub: None,
+        })
+    }
+}
@@ -1,6 +1,4 @@
-use std::{cell::RefCell, rc::Rc};
-
-use crate::structure::{Direction, DrawNode};
+use crate::structure::{CanvasTree, Direction, NodeId};
 
 pub struct CircularBuffer2D<T, const N: usize> {
     data: [[Option<T>; N]; N],
@@ -29,23 +27,24 @@ impl<T: Cleanupable, const N: usize> CircularBuffer2D<T, N> {
             .as_ref()
     }
 
-    pub fn set(&mut self, x: i32, y: i32, obj: T) {
-        self.clear(x, y);
+    pub fn set(&mut self, x: i32, y: i32, obj: T, context: &mut T::Context) {
+        self.clear(x, y, context);
         self.data[((x + N as i32 / 2) as usize + self.offset.0) % N]
             [((y + N as i32 / 2) as usize + self.offset.1) % N] = Some(obj);
     }
 
-    pub fn clear(&mut self, x: i32, y: i32) {
+    pub fn clear(&mut self, x: i32, y: i32, context: &mut T::Context) {
         self.deallocate(
             ((x + N as i32 / 2) as usize + self.offset.0) % N,
             ((y + N as i32 / 2) as usize + self.offset.1) % N,
+            context,
         );
     }
 
-    pub fn clear_all(&mut self) {
+    pub fn clear_all(&mut self, context: &mut T::Context) {
         for x in -(N as i32) / 2..=(N as i32) / 2 {
             for y in -(N as i32) / 2..=(N as i32) / 2 {
-                self.clear(x, y);
+                self.clear(x, y, context);
             }
         }
     }
@@ -61,54 +60,59 @@ impl<T: Cleanupable, const N: usize> CircularBuffer2D<T, N> {
         cells
     }
 
-    pub fn shift_pos_x(&mut self) {
+    pub fn shift_pos_x(&mut self, context: &mut T::Context) {
         self.offset.0 = (self.offset.0 + 1) % N;
         for y in 0..N {
-            self.deallocate((N - 1 + self.offset.0) % N, y);
+            self.deallocate((N - 1 + self.offset.0) % N, y, context);
         }
     }
 
-    pub fn shift_neg_x(&mut self) {
+    pub fn shift_neg_x(&mut self, context: &mut T::Context) {
         self.offset.0 = (self.offset.0 + N - 1) % N;
         for y in 0..N {
-            self.deallocate(self.offset.0, y);
+            self.deallocate(self.offset.0, y, context);
         }
     }
 
-    pub fn shift_pos_y(&mut self) {
+    pub fn shift_pos_y(&mut self, context: &mut T::Context) {
         self.offset.1 = (self.offset.1 + 1) % N;
         for x in 0..N {
-            self.deallocate(x, (N - 1 + self.offset.1) % N);
+            self.deallocate(x, (N - 1 + self.offset.1) % N, context);
         }
     }
 
-    pub fn shift_neg_y(&mut self) {
+    pub fn shift_neg_y(&mut self, context: &mut T::Context) {
         self.offset.1 = (self.offset.1 + N - 1) % N;
         for x in 0..N {
-            self.deallocate(x, self.offset.1);
+            self.deallocate(x, self.offset.1, context);
         }
     }
 
-    fn deallocate(&mut self, x: usize, y: usize) {
+    fn deallocate(&mut self, x: usize, y: usize, context: &mut T::Context) {
         if let Some(ref mut data) = self.data[x][y] {
-            data.cleanup();
+            data.cleanup(context);
         }
         self.data[x][y] = None;
     }
 }
 
 pub trait Cleanupable {
-    fn cleanup(&mut self);
+    /// What cleaning up needs besides the value, such as the tree a node id refers into.
+    type Context;
+
+    fn cleanup(&mut self, context: &mut Self::Context);
 }
 
-impl Cleanupable for Rc<RefCell<DrawNode>> {
-    fn cleanup(&mut self) {
-        self.borrow_mut().try_cleanup();
+impl Cleanupable for NodeId {
+    type Context = CanvasTree;
+
+    fn cleanup(&mut self, tree: &mut CanvasTree) {
+        tree.try_cleanup(*self);
     }
 }
 
-impl<const N: usize> CircularBuffer2D<Rc<RefCell<DrawNode>>, N> {
-    pub fn zoom_in(&mut self, corner: (u8, u8)) {
+impl<const N: usize> CircularBuffer2D<NodeId, N> {
+    pub fn zoom_in(&mut self, tree: &mut CanvasTree, corner: (u8, u8)) {
         let mut new_data = [(); N].map(|_| [(); N].map(|_| None));
         for x in -(N as i32) / 2..=(N as i32) / 2 {
             for y in -(N as i32) / 2..=(N as i32) / 2 {
@@ -120,55 +124,49 @@ impl<const N: usize> CircularBuffer2D<Rc<RefCell<DrawNode>>, N> {
                     ((x + 2 * N as i32) as u8 + corner.0) % 2,
                     ((y + 2 * N as i32) as u8 + corner.1) % 2,
                 );
-                let new_node = zoomed_out_node.map(|node| {
-                    node.borrow_mut()
-                        .get_or_create_child_from_corner(corner, node.clone())
-                });
+                let new_node =
+                    zoomed_out_node.map(|node| tree.get_or_create_child_from_corner(*node, corner));
                 new_data[(x + N as i32 / 2) as usize][(y + N as i32 / 2) as usize] = new_node;
             }
         }
-        self.clear_all();
+        self.clear_all(tree);
         self.data = new_data;
         self.offset = (0, 0);
     }
 
-    pub fn zoom_out(&mut self) {
+    pub fn zoom_out(&mut self, tree: &mut CanvasTree) {
         let mut new_data = [(); N].map(|_| [(); N].map(|_| None));
         for x in -(N as i32) / 4..=(N as i32) / 4 {
             for y in -(N as i32) / 4..=(N as i32) / 4 {
-                let Some(node) = self.get(2 * x, 2 * y) else {
+                let Some(&node) = self.get(2 * x, 2 * y) else {
                     continue;
                 };
-                let parent = node.borrow_mut().get_or_create_parent(node.clone());
+                let parent = tree.get_or_create_parent(node);
                 new_data[(x + N as i32 / 2) as usize][(y + N as i32 / 2) as usize] = Some(parent);
             }
         }
-        self.clear_all();
+        self.clear_all(tree);
         self.data = new_data;
         self.offset = (0, 0);
     }
 
-    pub fn load_all(&mut self) {
+    pub fn load_all(&mut self, tree: &mut CanvasTree) {
         for x in -(N as i32) / 2..=(N as i32) / 2 {
             for y in -(N as i32) / 2..=(N as i32) / 2 {
                 if self.get(x, y).is_some() {
                     continue;
                 }
                 if x > -(N as i32) / 2 {
-                    if let Some(left_node) = self.get(x - 1, y).cloned() {
-                        let neighbor = left_node
-                            .borrow_mut()
-                            .get_or_create_neighbor(Direction::PosX, left_node.clone());
-                        self.set(x, y, neighbor);
+                    if let Some(&left_node) = self.get(x - 1, y) {
+                        let neighbor = tree.get_or_create_neighbor(left_node, Direction::PosX);
+                        self.set(x, y, neighbor, tree);
                         continue;
                     }
                 }
                 if y > -(N as i32) / 2 {
-                    if let Some(above_node) = self.get(x, y - 1).cloned() {
-                        let neighbor = above_node
-                            .borrow_mut()
-                            .get_or_create_neighbor(Direction::PosY, above_node.clone());
-                        self.set(x, y, neighbor);
+                    if let Some(&above_node) = self.get(x, y - 1) {
+                        let neighbor = tree.get_or_create_neighbor(above_node, Direction::PosY);
+                        self.set(x, y, neighbor, tree);
                         continue;
                     }
                 }
@@ -182,20 +180,16 @@ impl<const N: usize> CircularBuffer2D<Rc<RefCell<DrawNode>>, N> {
                     continue;
                 }
                 if x < (N as i32) / 2 {
-                    if let Some(right_node) = self.get(x + 1, y).cloned() {
-                        let neighbor = right_node
-                            .borrow_mut()
-                            .get_or_create_neighbor(Direction::NegX, right_node.clone());
-                        self.set(x, y, neighbor);
+                    if let Some(&right_node) = self.get(x + 1, y) {
+                        let neighbor = tree.get_or_create_neighbor(right_node, Direction::NegX);
+                        self.set(x, y, neighbor, tree);
                         continue;
                     }
                 }
                 if y < (N as i32) / 2 {
-                    if let Some(below_node) = self.get(x, y + 1).cloned() {
-                        let neighbor = below_node
-                            .borrow_mut()
-                            .get_or_create_neighbor(Direction::NegY, below_node.clone());
-                        self.set(x, y, neighbor);
+                    if let Some(&below_node) = self.get(x, y + 1) {
+                        let neighbor = tree.get_or_create_neighbor(below_node, Direction::NegY);
+                        self.set(x, y, neighbor, tree);
                         continue;
                     }
                 }
@@ -0,0 +1,186 @@
+#[cfg(target_arch = "wasm32")]
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex};
+
+use egui::{ColorImage, Rect};
+use serde::{Deserialize, Serialize};
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::raster;
+use crate::structure::{CanvasDrawable, GroupId, StrokePriority};
+
+#[cfg(target_arch = "wasm32")]
+thread_local! {
+    /// Images pasted into the page and not yet taken, or None until pastes are listened for.
+    static PASTED_IMAGES: RefCell<Option<Vec<ColorImage>>> = const { RefCell::new(None) };
+}
+
+/// Strokes copied from the canvas, positioned relative to the center of the copied selection.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct CopiedStrokes {
+    /// Depth below the outermost node of the nodes whose coordinates the strokes are stored
+    /// in, so pasting at another depth keeps their size on the canvas.
+    pub depth: u32,
+    /// Bottom to top.
+    pub strokes: Vec<CopiedStroke>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct CopiedStroke {
+    pub drawable: Box<dyn CanvasDrawable>,
+    pub priority: StrokePriority,
+    /// Group within the copied strokes, given a fresh id when pasted.
+    pub group: Option<GroupId>,
+}
+
+impl CopiedStrokes {
+    pub fn to_ron(&self) -> Result<String, ron::Error> {
+        ron::to_string(self)
+    }
+
+    /// Returns None for clipboard contents that are not copied strokes.
+    pub fn from_ron(value: &str) -> Option<Self> {
+        ron::from_str(value).ok()
+    }
+
+    /// Area covered by the strokes, around the copied selection's center at the origin.
+    pub fn bounds(&self) -> Rect {
+        self.strokes
+            .iter()
+            .map(|stroke| stroke.drawable.bounds())
+            .fold(Rect::NOTHING, |bounds, rect| bounds.union(rect))
+    }
+}
+
+/// Text asked of the system clipboard. Natively it is read at once, while browsers answer some
+/// time after the frame that asked, so it is polled until it arrives. Copying text needs none
+/// of this, as egui writes copied text to the browser's clipboard itself.
+#[derive(Clone, Default)]
+pub struct ClipboardText(Arc<Mutex<Option<String>>>);
+
+impl ClipboardText {
+    /// Starts reading the clipboard. What cannot be read, such as anything but text, reads as
+    /// empty.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn read(_ctx: &egui::Context) -> Self {
+        use clipboard_rs::{Clipboard, ClipboardContext};
+        let text = ClipboardContext::new()
+            .ok()
+            .and_then(|clipboard| clipboard.get_text().ok())
+            .unwrap_or_default();
+        Self(Arc::new(Mutex::new(Some(text))))
+    }
+
+    /// Starts reading the clipboard, repainting `ctx` once the browser answers. What cannot be
+    /// read, such as anything but text or anything the user did not allow, reads as empty.
+    #[cfg(target_arch = "wasm32")]
+    pub fn read(ctx: &egui::Context) -> Self {
+        let read = Self::default();
+        let Some(window) = web_sys::window() else {
+            *read.0.lock().unwrap() = Some(String::new());
+            return read;
+        };
+        let promise = window.navigator().clipboard().read_text();
+        let (ctx, answer) = (ctx.clone(), read.clone());
+        wasm_bindgen_futures::spawn_local(async move {
+            let text = match wasm_bindgen_futures::JsFuture::from(promise).await {
+                Ok(text) => text.as_string().unwrap_or_default(),
+                Err(err) => {
+                    log::warn!("Failed to read the clipboard: {err:?}");
+                    String::new()
+                }
+            };
+            *answer.0.lock().unwrap() = Some(text);
+            ctx.request_repaint();
+        });
+        read
+    }
+
+    /// The text once it has been read. It is handed out only once.
+    pub fn take(&self) -> Option<String> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+/// The image on the clipboard if `pasting`, the paste shortcut having just been pressed. Returns
+/// None for anything but an image, and for images that fail to decode.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn pasted_image(_ctx: &egui::Context, pasting: bool) -> Option<ColorImage> {
+    use clipboard_rs::{common::RustImage, Clipboard, ClipboardContext, ContentFormat};
+    if !pasting {
+        return None;
+    }
+    let clipboard = ClipboardContext::new().ok()?;
+    if !clipboard.has(ContentFormat::Image) {
+        return None;
+    }
+    let png = clipboard.get_image().ok()?.to_png().ok()?;
+    raster::decode_png(png.get_bytes())
+        .inspect_err(|err| log::error!("Failed to decode the pasted image: {err}"))
+        .ok()
+}
+
+/// An image pasted into the page since the last call. Browsers hand pasted images only to the
+/// page's paste event, so the first call starts listening for them, repainting `ctx` when one
+/// arrives. PNG is the only format browsers put images on the clipboard in.
+#[cfg(target_arch = "wasm32")]
+pub fn pasted_image(ctx: &egui::Context, _pasting: bool) -> Option<ColorImage> {
+    PASTED_IMAGES.with_borrow_mut(|images| match images {
+        Some(images) => images.pop(),
+        None => {
+            match listen_for_images(ctx) {
+                Ok(()) => *images = Some(vec![]),
+                Err(err) => log::error!("Failed to listen for pasted images: {err}"),
+            }
+            None
+        }
+    })
+}
+
+#[cfg(target_arch = "wasm32")]
+fn listen_for_images(ctx: &egui::Context) -> Result<(), String> {
+    use eframe::wasm_bindgen::{closure::Closure, JsCast as _};
+
+    let document = web_sys::window()
+        .and_then(|window| window.document())
+        .ok_or("No document")?;
+    let ctx = ctx.clone();
+    let on_paste = Closure::<dyn FnMut(web_sys::ClipboardEvent)>::new(
+        move |event: web_sys::ClipboardEvent| {
+            let Some(files) = event.clipboard_data().and_then(|data| data.files()) else {
+                return;
+            };
+            for file in (0..files.length()).filter_map(|index| files.get(index)) {
+                if file.type_() != "image/png" {
+                    continue;
+                }
+                let ctx = ctx.clone();
+                wasm_bindgen_futures::spawn_local(async move {
+                    let data = match wasm_bindgen_futures::JsFuture::from(file.array_buffer()).await
+                    {
+                        Ok(buffer) => js_sys::Uint8Array::new(&buffer).to_vec(),
+                        Err(err) => {
+                            log::error!("Failed to read the pasted image: {err:?}");
+                            return;
+                        }
+                    };
+                    match crate::raster::decode_png(&data) {
+                        Ok(image) => {
+                            PASTED_IMAGES.with_borrow_mut(|images| {
+                                images.get_or_insert_with(Vec::new).push(image)
+                            });
+                            ctx.request_repaint();
+                        }
+                        Err(err) => log::error!("Failed to decode the pasted image: {err}"),
+                    }
+                });
+            }
+        },
+    );
+    document
+        .add_event_listener_with_callback("paste", on_paste.as_ref().unchecked_ref())
+        .map_err(|err| format!("{err:?}"))?;
+    // The listener stays for as long as the page.
+    on_paste.forget();
+    Ok(())
+}
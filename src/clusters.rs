@@ -0,0 +1,169 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use egui::{Rect, Ui};
+
+/// Size in screen points of the grid content is binned into when looking for clusters. Content
+/// separated by less than a cell joins the same cluster.
+const CLUSTER_CELL_SIZE: f32 = 24.0;
+
+/// Screen points left between a cluster and the frame wrapped around it, and the size its name
+/// is written at.
+pub const FRAME_PADDING: f32 = 16.0;
+pub const FRAME_TITLE_SIZE: f32 = 14.0;
+
+/// A group of strokes found close together, offered to be wrapped in a frame.
+pub struct Cluster {
+    pub name: String,
+    pub include: bool,
+    pub orders: BTreeSet<u64>,
+}
+
+/// What the clusters window asks the painting to do.
+#[derive(Clone, Copy)]
+pub enum ClusterAction {
+    Detect,
+    /// Wrap each included cluster in a frame named after it.
+    Frame,
+    /// Center the view on a cluster.
+    Show(usize),
+}
+
+#[derive(Default)]
+pub struct ClusterFraming {
+    pub clusters: Vec<Cluster>,
+    /// Whether clusters have been detected since frames were last wrapped around them.
+    detected: bool,
+}
+
+impl ClusterFraming {
+    /// Replaces the offered clusters with the connected regions of the grid cells over `view`
+    /// that `objects`, given by order and screen bounds, cover.
+    pub fn detect(&mut self, view: Rect, objects: &[(u64, Rect)]) {
+        let columns = (view.width() / CLUSTER_CELL_SIZE).ceil().max(1.0) as usize;
+        let rows = (view.height() / CLUSTER_CELL_SIZE).ceil().max(1.0) as usize;
+        let cell_range = |bounds: Rect| {
+            let bounds = bounds.intersect(view);
+            let to_cell = |value: f32, min: f32, count: usize| {
+                (((value - min) / CLUSTER_CELL_SIZE).floor().max(0.0) as usize).min(count - 1)
+            };
+            let (min_x, max_x) = (
+                to_cell(bounds.min.x, view.min.x, columns),
+                to_cell(bounds.max.x, view.min.x, columns),
+            );
+            let (min_y, max_y) = (
+                to_cell(bounds.min.y, view.min.y, rows),
+                to_cell(bounds.max.y, view.min.y, rows),
+            );
+            (min_y..=max_y).flat_map(move |y| (min_x..=max_x).map(move |x| (x, y)))
+        };
+        let objects = objects
+            .iter()
+            .filter(|(_, bounds)| bounds.intersects(view))
+            .collect::<Vec<_>>();
+        let mut occupied = vec![false; columns * rows];
+        for (_, bounds) in &objects {
+            for (x, y) in cell_range(*bounds) {
+                occupied[y * columns + x] = true;
+            }
+        }
+        // Flood fills each region of occupied cells, labelling its cells with the region.
+        let mut labels = vec![None; columns * rows];
+        let mut regions = 0;
+        for start in 0..occupied.len() {
+            if !occupied[start] || labels[start].is_some() {
+                continue;
+            }
+            labels[start] = Some(regions);
+            let mut pending = vec![start];
+            while let Some(cell) = pending.pop() {
+                let (x, y) = (cell % columns, cell / columns);
+                let neighbors = [
+                    (x > 0).then(|| cell - 1),
+                    (x + 1 < columns).then(|| cell + 1),
+                    (y > 0).then(|| cell - columns),
+                    (y + 1 < rows).then(|| cell + columns),
+                ];
+                for neighbor in neighbors.into_iter().flatten() {
+                    if occupied[neighbor] && labels[neighbor].is_none() {
+                        labels[neighbor] = Some(regions);
+                        pending.push(neighbor);
+                    }
+                }
+            }
+            regions += 1;
+        }
+        let mut clusters = BTreeMap::<usize, BTreeSet<u64>>::new();
+        for (order, bounds) in objects {
+            let region = cell_range(*bounds)
+                .find_map(|(x, y)| labels[y * columns + x])
+                .expect("cells covered by an object are occupied");
+            clusters.entry(region).or_default().insert(*order);
+        }
+        self.clusters = clusters
+            .into_values()
+            .enumerate()
+            .map(|(index, orders)| Cluster {
+                name: format!("Cluster {}", index + 1),
+                include: true,
+                orders,
+            })
+            .collect();
+        self.detected = true;
+    }
+
+    /// Follows strokes given new orders.
+    pub fn renumber(&mut self, renumbered: &BTreeMap<u64, u64>) {
+        for cluster in &mut self.clusters {
+            cluster.orders = cluster
+                .orders
+                .iter()
+                .map(|order| renumbered.get(order).copied().unwrap_or(*order))
+                .collect();
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui) -> Option<ClusterAction> {
+        let mut action = None;
+        if ui
+            .button("Detect in view")
+            .on_hover_text("Find groups of content separated by empty space")
+            .clicked()
+        {
+            action = Some(ClusterAction::Detect);
+        }
+        if !self.detected {
+            return action;
+        }
+        ui.separator();
+        if self.clusters.is_empty() {
+            ui.label("No content in view");
+            return action;
+        }
+        egui::ScrollArea::vertical()
+            .max_height(240.0)
+            .show(ui, |ui| {
+                egui::Grid::new("clusters").num_columns(3).show(ui, |ui| {
+                    for (index, cluster) in self.clusters.iter_mut().enumerate() {
+                        ui.checkbox(&mut cluster.include, "");
+                        ui.text_edit_singleline(&mut cluster.name);
+                        ui.horizontal(|ui| {
+                            ui.weak(format!("{} strokes", cluster.orders.len()));
+                            if ui.small_button("Show").clicked() {
+                                action = Some(ClusterAction::Show(index));
+                            }
+                        });
+                        ui.end_row();
+                    }
+                });
+            });
+        ui.separator();
+        let included = self.clusters.iter().any(|cluster| cluster.include);
+        if ui
+            .add_enabled(included, egui::Button::new("Wrap in frames"))
+            .clicked()
+        {
+            action = Some(ClusterAction::Frame);
+        }
+        action
+    }
+}
@@ -0,0 +1,664 @@
+use std::collections::BTreeMap;
+
+use egui::{ecolor::Hsva, Color32, Pos2, Rect, Ui, Vec2};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    connection::{Connection, Received},
+    merge::{self, new_stroke_id, Conflict, Versions},
+    oplog::{self, Op, OpLog, OpSink},
+    structure::{CanvasTree, GroupId, StrokeEntry},
+};
+
+/// Seconds between presence updates while nothing changes.
+const HEARTBEAT: f64 = 1.0;
+/// Least seconds between presence updates as the cursor or view moves.
+const MIN_INTERVAL: f64 = 0.05;
+/// Seconds after which a participant not heard from is taken to have left.
+const TIMEOUT: f64 = 5.0;
+/// Most changes sent at once, so sharing a large canvas does not make for one huge message.
+const MAX_BATCH: usize = 1000;
+
+/// Where a participant is looking and pointing.
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct Presence {
+    pub participant: u64,
+    pub name: String,
+    /// The node their view is centered on, as `ViewLocation::path` gives it.
+    pub path: Vec<(u8, u8)>,
+    pub pan: Vec2,
+    pub zoom: f32,
+    /// The pointer in the units of the view's cells, as `pan` is, while over the canvas.
+    pub cursor: Option<Pos2>,
+}
+
+/// A region a participant is editing, where changes others make wait until they unlock it.
+/// Each participant holds back sending their changes to regions others locked, so the room's
+/// log only ever takes them once the region is unlocked.
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct Lock {
+    pub participant: u64,
+    /// The name of whoever locked the region.
+    pub name: String,
+    /// The node the region is given in, as `ViewLocation::path` gives it.
+    pub path: Vec<(u8, u8)>,
+    /// The region in the units of the node's cells, as `Presence::pan` is.
+    pub rect: Rect,
+}
+
+impl Lock {
+    /// Whether `ops` change strokes inside the region. Removals are taken to be anywhere in the
+    /// node they were made to.
+    fn covers(&self, ops: &[Op]) -> bool {
+        oplog::touched(ops).into_iter().any(|(path, bounds)| {
+            // Strokes are given in node units, which span two of a cell's.
+            let bounds = bounds.map_or(
+                Rect::from_center_size(Pos2::ZERO, Vec2::splat(1.0)),
+                |bounds| Rect::from_min_max(bounds.min * 0.5, bounds.max * 0.5),
+            );
+            match (
+                relocate(&path, bounds.min, &self.path),
+                relocate(&path, bounds.max, &self.path),
+            ) {
+                (Some(min), Some(max)) => Rect::from_two_pos(min, max).intersects(self.rect),
+                _ => false,
+            }
+        })
+    }
+}
+
+/// Changes to the canvas of a room, numbered as in the room's log.
+#[derive(Deserialize, Serialize)]
+struct Changes {
+    participant: u64,
+    /// Tells the logs of rooms apart, so changes are never made to a canvas other than the one
+    /// they were made to.
+    log: u64,
+    /// How many changes the room's log held when these were made. The relay only takes changes
+    /// made to the canvas as its log leaves it, so that everyone makes them to the same one.
+    index: usize,
+    /// Corner of the outermost node the log starts from, given with its first changes.
+    corner: Option<(u8, u8)>,
+    ops: Vec<Op>,
+}
+
+/// What participants send each other through the relay.
+#[derive(Deserialize, Serialize)]
+enum Message {
+    Presence(Presence),
+    /// Passed on to everyone in the room, the sender included, once the relay has added them
+    /// to the room's log, in the order it added them. Those joining later are sent every change
+    /// the log holds.
+    Changes(Changes),
+    /// Sent by the relay to those joining once it has passed on the changes the room holds.
+    Joined,
+    /// Repeated along with presence while the region stays locked, so that those joining later
+    /// learn of it, and it lapses with its author.
+    Lock(Lock),
+    /// The participant unlocked the region they had locked.
+    Unlock(u64),
+}
+
+/// The log of changes kept by the relay for a room, as far as it arrived.
+struct RoomLog {
+    id: u64,
+    corner: (u8, u8),
+    ops: Vec<Op>,
+}
+
+impl RoomLog {
+    /// The room's canvas once its first `count` changes are made.
+    fn replay(&self, count: usize) -> CanvasTree {
+        oplog::replay(self.corner, &self.ops[..count])
+    }
+}
+
+/// What bringing the canvas in line with the room changed, beyond the strokes themselves.
+pub struct Synced {
+    /// Strokes others added or changed, as they arrived.
+    pub arrived: Vec<StrokeEntry>,
+    /// Whether changes made here that the room lacked were made again over changes from others,
+    /// which may have given the objects they changed new orders.
+    pub rebased: bool,
+    /// Objects changed both here and by others, which were kept as they are here, with paths
+    /// from the outermost node.
+    pub conflicts: Vec<Conflict>,
+    /// The changes others made, as they arrived, each with the name of whoever made them if
+    /// they gave one.
+    pub changes: Vec<(Option<String>, Vec<Op>)>,
+}
+
+/// Where to collaborate, kept with the painting.
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct CollabSettings {
+    /// Address of the relay server, such as `ws://localhost:9000`.
+    pub server: String,
+    /// Participants joining the same room of a server see each other and share the canvas.
+    /// Room names are letters, digits, `-` and `_`.
+    pub room: String,
+    /// Outline what each participant has in view, along with their cursor.
+    pub show_views: bool,
+    /// Show a small live picture of what each participant has in view in a sidebar.
+    pub show_thumbnails: bool,
+}
+
+impl Default for CollabSettings {
+    fn default() -> Self {
+        Self {
+            server: "ws://localhost:9000".to_string(),
+            room: String::new(),
+            show_views: true,
+            show_thumbnails: true,
+        }
+    }
+}
+
+/// Requests from the collaboration window.
+pub enum CollabAction {
+    Join,
+    Leave,
+    /// Lock the region in view, so others' changes there wait until it is unlocked.
+    Lock,
+    Unlock,
+}
+
+impl CollabSettings {
+    pub fn ui(&mut self, ui: &mut Ui, session: Option<&Collaboration>) -> Option<CollabAction> {
+        let mut action = None;
+        egui::Grid::new("collab_settings")
+            .num_columns(2)
+            .show(ui, |ui| {
+                ui.label("Server");
+                ui.add_enabled(
+                    session.is_none(),
+                    egui::TextEdit::singleline(&mut self.server),
+                );
+                ui.end_row();
+                ui.label("Room");
+                ui.add_enabled(
+                    session.is_none(),
+                    egui::TextEdit::singleline(&mut self.room),
+                );
+                ui.end_row();
+            });
+        ui.checkbox(&mut self.show_views, "Show what others have in view");
+        ui.checkbox(&mut self.show_thumbnails, "Show others' views in a sidebar");
+        match session {
+            None => {
+                if ui
+                    .add_enabled(!self.room.is_empty(), egui::Button::new("Join"))
+                    .clicked()
+                {
+                    action = Some(CollabAction::Join);
+                }
+            }
+            Some(session) => {
+                if let Some(err) = &session.error {
+                    ui.colored_label(ui.visuals().error_fg_color, err);
+                }
+                ui.separator();
+                let peers = session.peers().collect::<Vec<_>>();
+                if peers.is_empty() {
+                    ui.weak("Nobody else is here");
+                }
+                for peer in peers {
+                    let color = participant_color(peer.participant);
+                    match session
+                        .locks()
+                        .find(|lock| lock.participant == peer.participant)
+                    {
+                        Some(_) => {
+                            ui.colored_label(color, format!("🔒 {}", display_name(&peer.name)))
+                        }
+                        None => ui.colored_label(color, display_name(&peer.name)),
+                    };
+                }
+                ui.separator();
+                if session.own_lock().is_some() {
+                    ui.label("Others' changes in the region you locked wait until you unlock it.");
+                    if ui.button("Unlock").clicked() {
+                        action = Some(CollabAction::Unlock);
+                    }
+                } else if ui
+                    .button("Lock what is in view")
+                    .on_hover_text("Others' changes in the region wait until you unlock it")
+                    .clicked()
+                {
+                    action = Some(CollabAction::Lock);
+                }
+                if let Some(name) = session.held_by() {
+                    ui.colored_label(
+                        ui.visuals().warn_fg_color,
+                        format!("Your changes wait for {name} to unlock the region they are in."),
+                    );
+                }
+                if ui.button("Leave").clicked() {
+                    action = Some(CollabAction::Leave);
+                }
+            }
+        }
+        action
+    }
+}
+
+/// Taking part in a room of a relay server, which passes each participant's messages on to
+/// the others in the room, and keeps the changes made to the room's canvas in a log.
+///
+/// Changes made here are sent a batch at a time, each made to the canvas as the room's log
+/// leaves it. When others' changes get into the log first, the relay turns the batch away, and
+/// the changes made here are made again over theirs, object by object, and sent anew.
+pub struct Collaboration {
+    connection: Connection,
+    participant: u64,
+    /// The others in the room, with when each was last heard from.
+    peers: BTreeMap<u64, (Presence, f64)>,
+    last_sent: Option<(Presence, f64)>,
+    /// The region locked here, if any.
+    lock: Option<Lock>,
+    /// Regions others locked, with when each lock was last heard of.
+    locks: BTreeMap<u64, (Lock, f64)>,
+    /// The name of whoever locked the region the next changes to send are in, while they wait.
+    held_by: Option<String>,
+    /// Changes from the room that arrived since they were last taken in.
+    incoming: Vec<Changes>,
+    /// Whether the changes the room held on joining have all arrived.
+    joined: bool,
+    /// The room's log, once its first changes arrive or are sent.
+    room: Option<RoomLog>,
+    /// How many of the room's changes the canvas here was built from, the changes in `sent`
+    /// and `unsent` following them. None until the canvas is brought in line with the room.
+    synced: Option<usize>,
+    /// Changes sent to the room and not yet passed back.
+    sent: Vec<Op>,
+    /// Changes made here and not yet sent.
+    unsent: Vec<Op>,
+    /// Changes others made that the canvas here lacks, with the names of whoever made them.
+    heard: Vec<(Option<String>, Vec<Op>)>,
+    /// The id of the painting's operation log, and how many of its changes were taken.
+    taken: Option<(u64, usize)>,
+    /// Why the connection ended, once it has.
+    error: Option<String>,
+}
+
+impl Collaboration {
+    pub fn join(ctx: &egui::Context, settings: &CollabSettings) -> Result<Self, String> {
+        let url = format!(
+            "{}/{}",
+            settings.server.trim_end_matches('/'),
+            settings.room
+        );
+        Ok(Self {
+            connection: Connection::open(&url, ctx)?,
+            participant: new_stroke_id(),
+            peers: BTreeMap::new(),
+            last_sent: None,
+            lock: None,
+            locks: BTreeMap::new(),
+            held_by: None,
+            incoming: vec![],
+            joined: false,
+            room: None,
+            synced: None,
+            sent: vec![],
+            unsent: vec![],
+            heard: vec![],
+            taken: None,
+            error: None,
+        })
+    }
+
+    /// Takes in what arrived since the last frame, and sends where this participant is looking
+    /// and pointing if it changed or is due to be repeated. The participant is filled in.
+    pub fn update(&mut self, ctx: &egui::Context, mut presence: Presence) {
+        let now = ctx.input(|input| input.time);
+        for received in self.connection.receive() {
+            match received {
+                Received::Text(text) => match serde_json::from_str(&text) {
+                    Ok(Message::Presence(peer)) if peer.participant != self.participant => {
+                        self.peers.insert(peer.participant, (peer, now));
+                    }
+                    Ok(Message::Presence(_)) => {}
+                    Ok(Message::Changes(changes)) => self.incoming.push(changes),
+                    Ok(Message::Joined) => self.joined = true,
+                    Ok(Message::Lock(lock)) if lock.participant != self.participant => {
+                        self.locks.insert(lock.participant, (lock, now));
+                    }
+                    Ok(Message::Lock(_)) => {}
+                    Ok(Message::Unlock(participant)) => {
+                        self.locks.remove(&participant);
+                    }
+                    Err(err) => log::warn!("Ignoring a message that failed to parse: {err}"),
+                },
+                Received::Closed(reason) => {
+                    log::warn!("Left the room: {reason}");
+                    self.error = Some(reason);
+                    self.peers.clear();
+                    self.locks.clear();
+                }
+            }
+        }
+        self.peers.retain(|_, (_, heard)| now - *heard < TIMEOUT);
+        self.locks.retain(|_, (_, heard)| now - *heard < TIMEOUT);
+
+        presence.participant = self.participant;
+        let due = match &self.last_sent {
+            None => true,
+            Some((sent, at)) if *sent == presence => now - at >= HEARTBEAT,
+            Some((_, at)) => now - at >= MIN_INTERVAL,
+        };
+        if due {
+            self.send(&Message::Presence(presence.clone()));
+            if let Some(lock) = &self.lock {
+                self.send(&Message::Lock(lock.clone()));
+            }
+            self.last_sent = Some((presence, now));
+        }
+        // Keeps repeating presence and noticing others leave while the pointer is still.
+        ctx.request_repaint_after(std::time::Duration::from_secs_f64(MIN_INTERVAL));
+    }
+
+    /// Takes the changes `op_log` recorded since the last call, to be sent to the room. Changes
+    /// the log no longer holds, or a log that is not the one last taken from, leave the canvas
+    /// to be brought in line with the room anew.
+    pub fn take_changes(&mut self, op_log: &OpLog) {
+        if let (Some(_), Some((id, count))) = (self.synced, self.taken) {
+            match op_log.ops_since(count).filter(|_| id == op_log.id()) {
+                Some(ops) => self.unsent.extend(ops),
+                None => self.synced = None,
+            }
+        }
+        self.taken = Some((op_log.id(), op_log.count()));
+    }
+
+    /// Brings `tree`, whose changes `op_log` records, in line with the room: changes others
+    /// made are made to it, and changes made to it here are sent on. While `busy`, changes
+    /// made here are never made again over others', as that could renumber the object being
+    /// drawn. The other arguments are as `merge::rebase` takes them. Returns what changed, if
+    /// others changed anything.
+    pub fn sync(
+        &mut self,
+        tree: &mut CanvasTree,
+        op_log: &OpLog,
+        versions: &mut Versions,
+        next_order: &mut u64,
+        next_group: &mut GroupId,
+        busy: bool,
+    ) -> Option<Synced> {
+        self.take_changes(op_log);
+        let mut arrived = vec![];
+        // Changes from others made to the canvas as they arrived.
+        let mut heard = vec![];
+        for changes in std::mem::take(&mut self.incoming) {
+            let room = match &mut self.room {
+                Some(room) if room.id == changes.log && room.ops.len() == changes.index => room,
+                // Another participant started the room's log before the changes started here
+                // reached the relay, which turned them away.
+                Some(room) if room.ops.is_empty() && changes.index == 0 => {
+                    self.synced = None;
+                    room.id = changes.log;
+                    room.corner = changes.corner.unwrap_or_default();
+                    room
+                }
+                None if changes.index == 0 => self.room.insert(RoomLog {
+                    id: changes.log,
+                    corner: changes.corner.unwrap_or_default(),
+                    ops: vec![],
+                }),
+                _ => {
+                    log::warn!("Ignoring changes out of sequence with the room's log");
+                    continue;
+                }
+            };
+            room.ops.extend_from_slice(&changes.ops);
+            let up_to_date = self.synced == Some(changes.index);
+            if changes.participant == self.participant {
+                if up_to_date {
+                    self.synced = Some(room.ops.len());
+                    self.sent.clear();
+                }
+            } else {
+                let author = self
+                    .peers
+                    .get(&changes.participant)
+                    .map(|(peer, _)| peer.name.clone())
+                    .filter(|name| !name.is_empty());
+                // Changes to subtrees still being fetched wait for the whole tree to be loaded.
+                if up_to_date
+                    && self.sent.is_empty()
+                    && self.unsent.is_empty()
+                    && tree.page_in_all(tree.root())
+                {
+                    for op in &changes.ops {
+                        oplog::apply(tree, op);
+                    }
+                    arrived.extend(changed_strokes(&changes.ops));
+                    self.synced = Some(room.ops.len());
+                    self.taken = Some((op_log.id(), op_log.count()));
+                    heard.push((author, changes.ops));
+                } else {
+                    self.heard.push((author, changes.ops));
+                }
+            }
+        }
+        if !self.joined {
+            return None;
+        }
+        let behind = match (&self.room, self.synced) {
+            (Some(room), Some(synced)) => synced < room.ops.len(),
+            _ => true,
+        };
+        let synced = if behind && !busy && tree.page_in_all(tree.root()) {
+            if let Some(room) = &self.room {
+                arrived.extend(changed_strokes(&room.ops[self.synced.unwrap_or(0)..]));
+            }
+            let conflicts = self.rebuild(tree, versions, next_order, next_group);
+            self.taken = Some((op_log.id(), op_log.count()));
+            Some(Synced {
+                arrived,
+                rebased: true,
+                conflicts,
+                changes: heard.into_iter().chain(self.heard.drain(..)).collect(),
+            })
+        } else {
+            (!heard.is_empty()).then_some(Synced {
+                arrived,
+                rebased: false,
+                conflicts: vec![],
+                changes: heard,
+            })
+        };
+        self.send_changes();
+        synced
+    }
+
+    /// Makes `tree` the room's canvas, with the changes made here that the room lacks made
+    /// again over it. A room without a canvas yet starts from `tree` as it is. Returns the
+    /// objects changed both here and by others.
+    fn rebuild(
+        &mut self,
+        tree: &mut CanvasTree,
+        versions: &mut Versions,
+        next_order: &mut u64,
+        next_group: &mut GroupId,
+    ) -> Vec<Conflict> {
+        // Changes sent and not passed back were turned away, or are about to be.
+        let sent = std::mem::take(&mut self.sent);
+        let Some(room) = &self.room else {
+            let (corner, ops) = oplog::snapshot(tree);
+            oplog::reconcile(tree, &oplog::replay(corner, &ops));
+            self.room = Some(RoomLog {
+                id: new_stroke_id(),
+                corner,
+                ops: vec![],
+            });
+            self.synced = Some(0);
+            self.unsent = ops;
+            return vec![];
+        };
+        let mut target = room.replay(room.ops.len());
+        let changes = OpSink::default();
+        target.record_ops(&changes);
+        let base = match self.synced {
+            Some(synced) => {
+                let mut base = room.replay(synced);
+                // Trees built from the same log grow outward alike, so each level matches.
+                let level = oplog::grown(&room.ops[..synced])
+                    + oplog::grown(sent.iter().chain(&self.unsent));
+                let target_level = oplog::grown(&room.ops);
+                for (tree, from) in [
+                    (&mut base, oplog::grown(&room.ops[..synced])),
+                    (&mut target, target_level),
+                    (&mut *tree, level),
+                ] {
+                    for _ in from..level.max(target_level) {
+                        tree.get_or_create_parent(tree.root());
+                    }
+                }
+                Some(base)
+            }
+            None => {
+                merge::align_trees(&mut target, tree);
+                None
+            }
+        };
+        let conflicts = merge::rebase(
+            &mut target,
+            base.as_ref(),
+            tree,
+            versions,
+            next_order,
+            next_group,
+        );
+        oplog::reconcile(tree, &target);
+        self.synced = Some(room.ops.len());
+        self.unsent = changes.take();
+        conflicts
+    }
+
+    /// Sends the next batch of changes made here, once the room has every batch sent before
+    /// and the canvas here is in line with it, and none of its changes are in a region another
+    /// participant locked.
+    fn send_changes(&mut self) {
+        if self.unsent.is_empty() {
+            self.held_by = None;
+        }
+        let Some(room) = &self.room else {
+            return;
+        };
+        if self.synced != Some(room.ops.len()) || !self.sent.is_empty() || self.unsent.is_empty() {
+            return;
+        }
+        let count = self.unsent.len().min(MAX_BATCH);
+        self.held_by = self
+            .locks
+            .values()
+            .find(|(lock, _)| lock.covers(&self.unsent[..count]))
+            .map(|(lock, _)| lock.name.clone());
+        if self.held_by.is_some() {
+            return;
+        }
+        self.sent = self.unsent.drain(..count).collect();
+        let changes = Changes {
+            participant: self.participant,
+            log: room.id,
+            index: room.ops.len(),
+            corner: room.ops.is_empty().then_some(room.corner),
+            ops: self.sent.clone(),
+        };
+        self.send(&Message::Changes(changes));
+    }
+
+    fn send(&self, message: &Message) {
+        match serde_json::to_string(message) {
+            Ok(text) => self.connection.send(text),
+            Err(err) => log::error!("Failed to encode a message for the room: {err}"),
+        }
+    }
+
+    /// Where the others in the room are looking and pointing.
+    pub fn peers(&self) -> impl Iterator<Item = &Presence> {
+        self.peers.values().map(|(presence, _)| presence)
+    }
+
+    /// Locks the region `rect` of the node at `path`, as `Lock` gives them, in place of any
+    /// region locked before, under the name `name`.
+    pub fn lock(&mut self, name: String, path: Vec<(u8, u8)>, rect: Rect) {
+        let lock = Lock {
+            participant: self.participant,
+            name,
+            path,
+            rect,
+        };
+        self.send(&Message::Lock(lock.clone()));
+        self.lock = Some(lock);
+    }
+
+    pub fn unlock(&mut self) {
+        if self.lock.take().is_some() {
+            self.send(&Message::Unlock(self.participant));
+        }
+    }
+
+    /// The region locked here, if any.
+    pub fn own_lock(&self) -> Option<&Lock> {
+        self.lock.as_ref()
+    }
+
+    /// The regions others locked.
+    pub fn locks(&self) -> impl Iterator<Item = &Lock> {
+        self.locks.values().map(|(lock, _)| lock)
+    }
+
+    /// The name of whoever locked the region changes made here are waiting on, if they are.
+    pub fn held_by(&self) -> Option<&str> {
+        self.held_by.as_deref()
+    }
+}
+
+/// The strokes `ops` add or change.
+fn changed_strokes(ops: &[Op]) -> impl Iterator<Item = StrokeEntry> + '_ {
+    ops.iter().flat_map(|op| match op {
+        Op::Add { strokes, .. } | Op::Set { strokes, .. } => strokes.clone(),
+        Op::Update { stroke, .. } => vec![stroke.clone()],
+        Op::Remove { .. } | Op::Grow => vec![],
+    })
+}
+
+/// The color a participant is shown in, the same for everyone.
+pub fn participant_color(participant: u64) -> Color32 {
+    let hue = (participant % 360) as f32 / 360.0;
+    Hsva::new(hue, 0.75, 0.85, 1.0).into()
+}
+
+/// The name a participant who gave the name `name` is labelled with.
+pub fn display_name(name: &str) -> &str {
+    if name.is_empty() {
+        "Anonymous"
+    } else {
+        name
+    }
+}
+
+/// The point `point` in the units of the node at `from`, as `ViewLocation::path` gives it, in
+/// the units of the node at `to`. Returns None if the result is too far away to be shown.
+pub fn relocate(from: &[(u8, u8)], point: Pos2, to: &[(u8, u8)]) -> Option<Pos2> {
+    // Paths list corners innermost first, so they share their ends.
+    let shared = from
+        .iter()
+        .rev()
+        .zip(to.iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (mut x, mut y) = (f64::from(point.x), f64::from(point.y));
+    // Each level up halves the units, and moves the child's center to its quarter.
+    for (cx, cy) in &from[..from.len() - shared] {
+        x = x / 2.0 + (f64::from(*cx) - 0.5) / 2.0;
+        y = y / 2.0 + (f64::from(*cy) - 0.5) / 2.0;
+    }
+    for (cx, cy) in to[..to.len() - shared].iter().rev() {
+        x = (x - (f64::from(*cx) - 0.5) / 2.0) * 2.0;
+        y = (y - (f64::from(*cy) - 0.5) / 2.0) * 2.0;
+    }
+    (x.abs() < 1e6 && y.abs() < 1e6).then(|| Pos2::new(x as f32, y as f32))
+}
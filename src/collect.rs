@@ -0,0 +1,543 @@
+use std::{hash::Hasher, time::Duration};
+
+use egui::{
+    emath::RectTransform,
+    epaint::{Mesh, Primitive},
+    pos2, Color32, Context, Rect, Shape, TextureHandle, TextureOptions, Vec2,
+};
+
+use crate::{
+    blend::{self, BlendMode},
+    integrity::ContentHasher,
+    layers::Layers,
+    lod::NodeSummary,
+    quality::RenderQuality,
+    raster,
+    structure::{CanvasTree, DrawNode, NodeId, StrokeEntry, StrokeVisitor, NODE_BOUNDS},
+};
+use itertools::Itertools;
+#[cfg(not(target_arch = "wasm32"))]
+use rayon::prelude::*;
+use web_time::Instant;
+
+/// Levels below a visible cell whose strokes are drawn.
+pub const DRAW_DEPTH: u32 = 14;
+
+/// Most a cached render is scaled by before the node is rendered again.
+const MAX_RENDER_SCALE: f32 = 1.25;
+
+/// Side in pixels of node thumbnails.
+const THUMBNAIL_SIZE: usize = 64;
+
+/// Time a frame may spend collecting strokes before the rest is left to later frames.
+const FRAME_BUDGET: Duration = Duration::from_millis(8);
+
+/// A node placed on screen, with the levels below it whose strokes are collected.
+type QueuedNode = (NodeId, Rect, u32);
+
+/// Everything a cached render depends on besides the node's content.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct RenderKey {
+    layers: u64,
+    quality: RenderQuality,
+}
+
+impl RenderKey {
+    pub fn new(layers: &Layers, quality: RenderQuality) -> Self {
+        let mut hasher = ContentHasher::default();
+        if let Ok(layers) = ron::to_string(layers) {
+            hasher.write(layers.as_bytes());
+        }
+        Self {
+            layers: hasher.finish(),
+            quality,
+        }
+    }
+}
+
+/// A node's content tessellated once and moved along with the node on later frames, so it is
+/// drawn as a few meshes instead of stroke by stroke.
+pub struct NodeRender {
+    key: RenderKey,
+    /// Screen rect the node covered when it was rendered.
+    screen_rect: Rect,
+    meshes: Vec<Mesh>,
+}
+
+impl NodeRender {
+    /// Whether the render can stand in for the node covering `screen_rect`.
+    fn fits(&self, key: RenderKey, screen_rect: Rect) -> bool {
+        let scale = screen_rect.width() / self.screen_rect.width();
+        self.key == key && (1.0 / MAX_RENDER_SCALE..=MAX_RENDER_SCALE).contains(&scale)
+    }
+
+    /// The meshes moved to a node covering `screen_rect`.
+    fn shapes_at(&self, screen_rect: Rect) -> impl Iterator<Item = Shape> + '_ {
+        let to_screen = RectTransform::from_to(self.screen_rect, screen_rect);
+        self.meshes.iter().map(move |mesh| {
+            let mut mesh = mesh.clone();
+            for vertex in &mut mesh.vertices {
+                vertex.pos = to_screen * vertex.pos;
+            }
+            Shape::mesh(mesh)
+        })
+    }
+}
+
+/// A node's content drawn into a small image, standing in for the node while it is too small on
+/// screen for more detail to matter.
+pub struct Thumbnail {
+    key: RenderKey,
+    texture: TextureHandle,
+}
+
+impl Thumbnail {
+    /// The image stretched over a node covering `screen_rect`.
+    fn shape_at(&self, screen_rect: Rect) -> Shape {
+        Shape::image(
+            self.texture.id(),
+            screen_rect,
+            Rect::from_min_max(pos2(0.0, 0.0), pos2(1.0, 1.0)),
+            Color32::WHITE,
+        )
+    }
+}
+
+/// Maps `items` on rayon's threads on desktop, and one after another on the web, keeping their
+/// order.
+fn map_in_parallel<T: Send, R: Send>(items: Vec<T>, map: impl Fn(T) -> R + Sync + Send) -> Vec<R> {
+    #[cfg(not(target_arch = "wasm32"))]
+    return items.into_par_iter().map(map).collect();
+    #[cfg(target_arch = "wasm32")]
+    return items.into_iter().map(map).collect();
+}
+
+/// Whether a stroke in a node covering `screen_rect` reaches into the screen rect `view` and
+/// spans at least `min_stroke_size` points.
+pub fn is_drawn(stroke: &StrokeEntry, screen_rect: Rect, view: Rect, min_stroke_size: f32) -> bool {
+    let bounds =
+        RectTransform::from_to(NODE_BOUNDS, screen_rect).transform_rect(stroke.drawable.bounds());
+    bounds.intersects(view) && bounds.size().max_elem() >= min_stroke_size
+}
+
+/// What a frame collected, kept so later frames showing the same content from the same view
+/// can draw it again without walking the tree.
+pub struct CollectedFrame {
+    view: Rect,
+    pan: Vec2,
+    zoom: f32,
+    center: NodeId,
+    key: RenderKey,
+    pub loaded: usize,
+    pub strokes: Vec<(StrokeEntry, Rect)>,
+    summaries: Vec<(NodeSummary, Rect)>,
+    pub cached: Vec<Shape>,
+    pub cache_hits: usize,
+    pub cache_misses: usize,
+    /// Nodes left for later frames once the budget ran out, each with the summary drawn in
+    /// its place meanwhile if one was already computed.
+    pending: Vec<(QueuedNode, Option<NodeSummary>)>,
+}
+
+impl CollectedFrame {
+    /// Whether the frame was collected for the same view, assuming the tree is unchanged.
+    pub fn shows(&self, view: Rect, pan: Vec2, zoom: f32, center: NodeId, key: RenderKey) -> bool {
+        self.view == view
+            && self.pan == pan
+            && self.zoom == zoom
+            && self.center == center
+            && self.key == key
+    }
+
+    /// Whether every node in view was collected, rather than some left for later frames.
+    pub fn is_complete(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Summaries drawn in place of nodes too small to draw and of nodes still pending.
+    pub fn summaries(&self) -> impl Iterator<Item = (&NodeSummary, Rect)> {
+        let pending = self
+            .pending
+            .iter()
+            .filter_map(|((_, screen_rect, _), summary)| Some((summary.as_ref()?, *screen_rect)));
+        self.summaries
+            .iter()
+            .map(|(summary, screen_rect)| (summary, *screen_rect))
+            .chain(pending)
+    }
+}
+
+/// Gathers what to draw in a frame: the strokes of nodes large enough to draw stroke by
+/// stroke, summaries of nodes too small for that, and cached renders of nodes in between.
+pub struct StrokeCollector<'a> {
+    ctx: &'a Context,
+    layers: &'a Layers,
+    key: RenderKey,
+    view: Rect,
+    min_stroke_size: f32,
+    lod_node_size: f32,
+    thumbnail_node_size: f32,
+    cache_node_size: f32,
+    /// Whether strokes outside the view or too small to draw are collected too, for actions
+    /// that can reach them.
+    keep_culled: bool,
+    /// Whether paged out nodes are loaded to be collected, rather than drawn from their
+    /// summaries.
+    pages_in: bool,
+    /// When collection stops and the remaining nodes are left pending, unless everything must
+    /// be collected.
+    deadline: Option<Instant>,
+    pending: Vec<(QueuedNode, Option<NodeSummary>)>,
+    /// Strokes on visible layers that were visited.
+    pub loaded: usize,
+    pub strokes: Vec<(StrokeEntry, Rect)>,
+    pub summaries: Vec<(NodeSummary, Rect)>,
+    pub cached: Vec<Shape>,
+    pub cache_hits: usize,
+    pub cache_misses: usize,
+}
+
+impl<'a> StrokeCollector<'a> {
+    pub fn new(
+        ctx: &'a Context,
+        layers: &'a Layers,
+        quality: RenderQuality,
+        key: RenderKey,
+        view: Rect,
+        keep_culled: bool,
+    ) -> Self {
+        let (lod_node_size, thumbnail_node_size, cache_node_size) = if keep_culled {
+            (0.0, 0.0, 0.0)
+        } else {
+            (
+                quality.lod_node_size(),
+                quality.thumbnail_node_size(),
+                quality.cache_node_size(),
+            )
+        };
+        Self {
+            ctx,
+            layers,
+            key,
+            view,
+            min_stroke_size: quality.min_stroke_size(),
+            lod_node_size,
+            thumbnail_node_size,
+            cache_node_size,
+            keep_culled,
+            pages_in: true,
+            deadline: (!keep_culled).then(|| Instant::now() + FRAME_BUDGET),
+            pending: vec![],
+            loaded: 0,
+            strokes: vec![],
+            summaries: vec![],
+            cached: vec![],
+            cache_hits: 0,
+            cache_misses: 0,
+        }
+    }
+
+    /// Collects everything within the frame and draws nodes stroke by stroke rather than from
+    /// cached renders, for views beside the main one. They keep no frame to resume, and at
+    /// their own scale they would replace the renders cached for the main view every frame.
+    pub fn for_secondary_view(self) -> Self {
+        Self {
+            cache_node_size: self.thumbnail_node_size,
+            deadline: None,
+            ..self
+        }
+    }
+
+    /// A collector with the same settings that has collected nothing yet.
+    fn fork(&self) -> Self {
+        StrokeCollector {
+            pending: vec![],
+            loaded: 0,
+            strokes: vec![],
+            summaries: vec![],
+            cached: vec![],
+            cache_hits: 0,
+            cache_misses: 0,
+            ..*self
+        }
+    }
+
+    /// Adds what a collector from `fork` collected after what this one did.
+    fn merge(&mut self, other: StrokeCollector<'_>) {
+        self.pending.extend(other.pending);
+        self.loaded += other.loaded;
+        self.strokes.extend(other.strokes);
+        self.summaries.extend(other.summaries);
+        self.cached.extend(other.cached);
+        self.cache_hits += other.cache_hits;
+        self.cache_misses += other.cache_misses;
+    }
+
+    /// Sorts the strokes bottom to top, by layer and then by their own sort key.
+    pub fn sort(&mut self) {
+        let layers = self.layers;
+        let key =
+            |(stroke, _): &(StrokeEntry, Rect)| (layers.rank(stroke.layer), stroke.sort_key());
+        #[cfg(not(target_arch = "wasm32"))]
+        self.strokes.par_sort_by_key(key);
+        #[cfg(target_arch = "wasm32")]
+        self.strokes.sort_by_key(key);
+    }
+
+    fn out_of_time(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Collects the strokes of `nodes`, each placed on screen with the levels below it to
+    /// collect. Nodes are visited level by level, so once the frame budget runs out the coarser
+    /// levels are drawn and only finer ones are left pending. The nodes of a level are visited
+    /// in parallel on desktop, and what they collected is kept in the order they were queued.
+    pub fn collect(&mut self, tree: &mut CanvasTree, nodes: impl IntoIterator<Item = QueuedNode>) {
+        let mut level = nodes.into_iter().collect_vec();
+        while !level.is_empty() {
+            if !self.out_of_time() {
+                for (node, _, _) in &level {
+                    tree.page_in(*node);
+                }
+            }
+            let tree = &*tree;
+            let visited = map_in_parallel(level, |queued| {
+                let mut collector = self.fork();
+                let children = collector.visit(tree, queued);
+                (collector, children)
+            });
+            level = vec![];
+            for (collector, children) in visited {
+                self.merge(collector);
+                level.extend(children);
+            }
+        }
+    }
+
+    /// Collects the strokes of a queued node, which must be loaded, returning the children to
+    /// queue for the next level.
+    fn visit(
+        &mut self,
+        tree: &CanvasTree,
+        (node, screen_rect, depth): QueuedNode,
+    ) -> Vec<QueuedNode> {
+        // Pages still being fetched are drawn from their summaries until they arrive.
+        let fetching = tree[node].page().is_some_and(|page| !page.fetch());
+        if self.out_of_time() || fetching {
+            let summary = tree[node].cached_summary().cloned();
+            self.pending.push(((node, screen_rect, depth), summary));
+            return vec![];
+        }
+        tree.for_each_stroke(node, screen_rect, 0, self);
+        if depth == 0 {
+            return vec![];
+        }
+        tree[node]
+            .child_nodes()
+            .filter_map(|((x, y), child)| {
+                let child_rect = DrawNode::child_rect(screen_rect, x, y);
+                (tree.content_bounds(child).is_some() && self.enter(tree, child, child_rect))
+                    .then_some((child, child_rect, depth - 1))
+            })
+            .collect()
+    }
+
+    /// Collects the nodes `frame` left pending, within a new frame budget.
+    pub fn resume(
+        ctx: &Context,
+        layers: &Layers,
+        quality: RenderQuality,
+        tree: &mut CanvasTree,
+        frame: CollectedFrame,
+    ) -> CollectedFrame {
+        let mut collector = StrokeCollector {
+            loaded: frame.loaded,
+            strokes: frame.strokes,
+            summaries: frame.summaries,
+            cached: frame.cached,
+            ..StrokeCollector::new(ctx, layers, quality, frame.key, frame.view, false)
+        };
+        let mut nodes = vec![];
+        for ((node, screen_rect, depth), _) in frame.pending {
+            if tree.contains(node) && collector.enter(tree, node, screen_rect) {
+                nodes.push((node, screen_rect, depth));
+            }
+        }
+        collector.collect(tree, nodes);
+        collector.sort();
+        collector.into_frame(frame.pan, frame.zoom, frame.center)
+    }
+
+    /// Keeps what was collected for a view panned and zoomed by `pan` and `zoom` around the
+    /// cell `center`.
+    pub fn into_frame(self, pan: Vec2, zoom: f32, center: NodeId) -> CollectedFrame {
+        CollectedFrame {
+            view: self.view,
+            pan,
+            zoom,
+            center,
+            key: self.key,
+            loaded: self.loaded,
+            strokes: self.strokes,
+            summaries: self.summaries,
+            cached: self.cached,
+            cache_hits: self.cache_hits,
+            cache_misses: self.cache_misses,
+            pending: self.pending,
+        }
+    }
+
+    /// Tessellates everything in the node `id`, which covers `screen_rect`.
+    fn render(&self, tree: &CanvasTree, id: NodeId, screen_rect: Rect) -> NodeRender {
+        NodeRender {
+            key: self.key,
+            screen_rect,
+            meshes: self.tessellate(tree, id, screen_rect, self.ctx.pixels_per_point()),
+        }
+    }
+
+    /// Draws everything in the node `id` into an image.
+    fn thumbnail(&self, tree: &CanvasTree, id: NodeId) -> Thumbnail {
+        let size = THUMBNAIL_SIZE as f32;
+        let screen_rect = Rect::from_min_max(pos2(0.0, 0.0), pos2(size, size));
+        let image = raster::rasterize(
+            &self.tessellate(tree, id, screen_rect, 1.0),
+            [THUMBNAIL_SIZE, THUMBNAIL_SIZE],
+        );
+        Thumbnail {
+            key: self.key,
+            texture: self
+                .ctx
+                .load_texture("node_thumbnail", image, TextureOptions::LINEAR),
+        }
+    }
+
+    /// The meshes of everything in the node `id`, which covers `screen_rect`.
+    fn tessellate(
+        &self,
+        tree: &CanvasTree,
+        id: NodeId,
+        screen_rect: Rect,
+        pixels_per_point: f32,
+    ) -> Vec<Mesh> {
+        let mut inner = StrokeCollector {
+            view: Rect::EVERYTHING,
+            thumbnail_node_size: 0.0,
+            cache_node_size: 0.0,
+            pages_in: false,
+            deadline: None,
+            ..self.fork()
+        };
+        tree.for_each_stroke(id, screen_rect, DRAW_DEPTH, &mut inner);
+        inner.sort();
+        let to_screen = RectTransform::from_to(NODE_BOUNDS, screen_rect);
+        let clip_rect = tree
+            .content_bounds(id)
+            .map_or(screen_rect, |bounds| to_screen.transform_rect(bounds))
+            .expand(1.0);
+        let shapes = raster::capture_shapes(self.ctx, clip_rect, |painter| {
+            for (summary, screen_rect) in &inner.summaries {
+                summary.paint(painter, self.layers, *screen_rect);
+            }
+            blend::paint_strokes(painter, self.layers, &inner.strokes);
+        });
+        self.ctx
+            .tessellate(shapes, pixels_per_point)
+            .into_iter()
+            .filter_map(|primitive| match primitive.primitive {
+                Primitive::Mesh(mesh) => Some(mesh),
+                Primitive::Callback(_) => None,
+            })
+            .collect()
+    }
+}
+
+impl StrokeVisitor for StrokeCollector<'_> {
+    fn stroke(&mut self, stroke: &StrokeEntry, screen_rect: Rect) {
+        if !self.layers.is_visible(stroke.layer) {
+            return;
+        }
+        self.loaded += 1;
+        if self.keep_culled || is_drawn(stroke, screen_rect, self.view, self.min_stroke_size) {
+            self.strokes.push((stroke.clone(), screen_rect));
+        }
+    }
+
+    fn enter(&mut self, tree: &CanvasTree, id: NodeId, screen_rect: Rect) -> bool {
+        if self.keep_culled {
+            return true;
+        }
+        let node = &tree[id];
+        let to_screen = RectTransform::from_to(NODE_BOUNDS, screen_rect);
+        if !tree
+            .content_bounds(id)
+            .is_some_and(|bounds| to_screen.transform_rect(bounds).intersects(self.view))
+        {
+            return false;
+        }
+        if screen_rect.width() < self.lod_node_size {
+            self.summaries.push((tree.summary(id).clone(), screen_rect));
+            return false;
+        }
+        if node.is_paged() {
+            // Loaded once dequeued, where the node itself is at hand.
+            if self.pages_in {
+                return true;
+            }
+            self.summaries.push((tree.summary(id).clone(), screen_rect));
+            return false;
+        }
+        let thumbnail = screen_rect.width() < self.thumbnail_node_size;
+        if !thumbnail && screen_rect.width() >= self.cache_node_size {
+            return true;
+        }
+        // Multiply layers are drawn by a paint callback, which cannot be kept in a mesh.
+        if tree
+            .summary(id)
+            .layers()
+            .any(|layer| self.layers.blend(layer) == BlendMode::Multiply)
+        {
+            return true;
+        }
+        if thumbnail {
+            let mut thumbnail = node.thumbnail().lock().unwrap();
+            if thumbnail
+                .as_ref()
+                .is_some_and(|thumbnail| thumbnail.key == self.key)
+            {
+                self.cache_hits += 1;
+            } else if self.out_of_time() {
+                return true;
+            } else {
+                self.cache_misses += 1;
+                *thumbnail = Some(self.thumbnail(tree, id));
+            }
+            if let Some(thumbnail) = thumbnail.as_ref() {
+                self.cached.push(thumbnail.shape_at(screen_rect));
+            }
+            return false;
+        }
+        let mut render = node.render().lock().unwrap();
+        if render
+            .as_ref()
+            .is_some_and(|render| render.fits(self.key, screen_rect))
+        {
+            self.cache_hits += 1;
+        } else if self.out_of_time() {
+            // Rendered once the node's turn comes in a later frame.
+            return true;
+        } else {
+            self.cache_misses += 1;
+            *render = Some(self.render(tree, id, screen_rect));
+        }
+        if let Some(render) = render.as_ref() {
+            self.cached.extend(render.shapes_at(screen_rect));
+        }
+        false
+    }
+}
@@ -0,0 +1,186 @@
+#[cfg(target_arch = "wasm32")]
+use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+#[cfg(not(target_arch = "wasm32"))]
+use std::{
+    sync::mpsc::{self, Receiver, Sender, TryRecvError},
+    time::Duration,
+};
+
+#[cfg(target_arch = "wasm32")]
+use eframe::wasm_bindgen::{closure::Closure, JsCast as _};
+
+/// How long the connection's thread waits for a message before sending those queued.
+#[cfg(not(target_arch = "wasm32"))]
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// What arrived on a connection since it was last polled.
+pub enum Received {
+    Text(String),
+    /// The connection ended, with the reason.
+    Closed(String),
+}
+
+/// A WebSocket connection exchanging text messages. Natively it is served by a thread of its
+/// own, which only supports unencrypted `ws://` addresses, and in the browser by the browser's
+/// own socket.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct Connection {
+    outgoing: Sender<String>,
+    incoming: Receiver<Received>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Connection {
+    /// Starts connecting to `url`, repainting `ctx` whenever something arrives.
+    pub fn open(url: &str, ctx: &egui::Context) -> Result<Self, String> {
+        let (outgoing, to_send) = mpsc::channel();
+        let (received, incoming) = mpsc::channel();
+        let (url, ctx) = (url.to_owned(), ctx.clone());
+        std::thread::Builder::new()
+            .name("connection".to_string())
+            .spawn(move || {
+                let reason = match serve(&url, &to_send, &received, &ctx) {
+                    Ok(()) => "Disconnected".to_string(),
+                    Err(err) => err,
+                };
+                let _ = received.send(Received::Closed(reason));
+                ctx.request_repaint();
+            })
+            .map_err(|err| err.to_string())?;
+        Ok(Self { outgoing, incoming })
+    }
+
+    /// Queues `text` to be sent once connected. It is dropped if the connection has ended.
+    pub fn send(&self, text: String) {
+        let _ = self.outgoing.send(text);
+    }
+
+    /// Everything that arrived since the last call, oldest first.
+    pub fn receive(&self) -> Vec<Received> {
+        self.incoming.try_iter().collect()
+    }
+}
+
+/// Exchanges messages with `url` until either side closes the connection. The connection is
+/// closed once the `Connection` sending to `to_send` is dropped.
+#[cfg(not(target_arch = "wasm32"))]
+fn serve(
+    url: &str,
+    to_send: &Receiver<String>,
+    received: &Sender<Received>,
+    ctx: &egui::Context,
+) -> Result<(), String> {
+    use tungstenite::{stream::MaybeTlsStream, Message};
+
+    let (mut socket, _) = tungstenite::connect(url).map_err(|err| err.to_string())?;
+    match socket.get_ref() {
+        MaybeTlsStream::Plain(stream) => stream
+            .set_read_timeout(Some(POLL_INTERVAL))
+            .map_err(|err| err.to_string())?,
+        _ => return Err("Only ws:// addresses are supported".to_string()),
+    }
+    loop {
+        loop {
+            match to_send.try_recv() {
+                Ok(text) => socket
+                    .send(Message::text(text))
+                    .map_err(|err| err.to_string())?,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    let _ = socket.close(None);
+                    let _ = socket.flush();
+                    return Ok(());
+                }
+            }
+        }
+        match socket.read() {
+            Ok(Message::Text(text)) => {
+                if received
+                    .send(Received::Text(text.as_str().to_owned()))
+                    .is_err()
+                {
+                    return Ok(());
+                }
+                ctx.request_repaint();
+            }
+            Ok(Message::Close(_)) => return Ok(()),
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(err))
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) => {}
+            Err(err) => return Err(err.to_string()),
+        }
+    }
+}
+
+/// A WebSocket connection exchanging text messages. Natively it is served by a thread of its
+/// own, which only supports unencrypted `ws://` addresses, and in the browser by the browser's
+/// own socket.
+#[cfg(target_arch = "wasm32")]
+pub struct Connection {
+    socket: web_sys::WebSocket,
+    incoming: Rc<RefCell<VecDeque<Received>>>,
+    /// Kept alive for as long as the socket may call them.
+    _on_message: Closure<dyn FnMut(web_sys::MessageEvent)>,
+    _on_close: Closure<dyn FnMut(web_sys::CloseEvent)>,
+}
+
+#[cfg(target_arch = "wasm32")]
+impl Connection {
+    /// Starts connecting to `url`, repainting `ctx` whenever something arrives.
+    pub fn open(url: &str, ctx: &egui::Context) -> Result<Self, String> {
+        let socket = web_sys::WebSocket::new(url).map_err(|err| format!("{err:?}"))?;
+        let incoming = Rc::new(RefCell::new(VecDeque::new()));
+        let (arrived, repaint) = (incoming.clone(), ctx.clone());
+        let on_message = Closure::new(move |event: web_sys::MessageEvent| {
+            if let Some(text) = event.data().as_string() {
+                arrived.borrow_mut().push_back(Received::Text(text));
+                repaint.request_repaint();
+            }
+        });
+        let (arrived, repaint) = (incoming.clone(), ctx.clone());
+        let on_close = Closure::new(move |event: web_sys::CloseEvent| {
+            let reason = match event.reason() {
+                reason if reason.is_empty() => "Disconnected".to_string(),
+                reason => reason,
+            };
+            arrived.borrow_mut().push_back(Received::Closed(reason));
+            repaint.request_repaint();
+        });
+        socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+        Ok(Self {
+            socket,
+            incoming,
+            _on_message: on_message,
+            _on_close: on_close,
+        })
+    }
+
+    /// Sends `text` if connected. Browsers refuse to queue messages while connecting, so it is
+    /// dropped until then.
+    pub fn send(&self, text: String) {
+        if self.socket.ready_state() != web_sys::WebSocket::OPEN {
+            return;
+        }
+        if let Err(err) = self.socket.send_with_str(&text) {
+            log::warn!("Failed to send a message: {err:?}");
+        }
+    }
+
+    /// Everything that arrived since the last call, oldest first.
+    pub fn receive(&self) -> Vec<Received> {
+        self.incoming.borrow_mut().drain(..).collect()
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.socket.set_onmessage(None);
+        self.socket.set_onclose(None);
+        let _ = self.socket.close();
+    }
+}
@@ -0,0 +1,94 @@
+use crate::{painting::Painting, sessions::ViewLocation};
+
+/// Seconds the address is left alone after it changes, since browsers throttle pages that
+/// replace their history entry too often.
+const UPDATE_INTERVAL: f64 = 0.25;
+
+/// Keeps the page's URL fragment in step with the view, such as `#view=0312/0.1,-0.25/1.5`
+/// for the node reached from the outermost one through the corners numbered 0, 3, 1 and 2,
+/// panned and zoomed as given. Opening such a link, or editing it in the address bar, goes
+/// there.
+#[derive(Default)]
+pub struct DeepLink {
+    /// The fragment last written to or read from the address.
+    fragment: String,
+    /// When `fragment` was last written.
+    written_at: f64,
+}
+
+impl DeepLink {
+    /// Jumps to the fragment of the address if it changed since the last call, then writes the
+    /// view of `painting` into it.
+    pub fn sync(&mut self, ctx: &egui::Context, painting: &mut Painting) {
+        let Some(window) = web_sys::window() else {
+            return;
+        };
+        let hash = window.location().hash().unwrap_or_default();
+        let linked = hash.strip_prefix('#').unwrap_or(&hash);
+        if linked != self.fragment {
+            if let Some(location) = from_fragment(linked) {
+                painting.jump_to(&location);
+            }
+            self.fragment = linked.to_owned();
+        }
+
+        let fragment = to_fragment(&painting.current_location());
+        if fragment == self.fragment {
+            return;
+        }
+        let now = ctx.input(|input| input.time);
+        let wait = self.written_at + UPDATE_INTERVAL - now;
+        if wait > 0.0 {
+            ctx.request_repaint_after(std::time::Duration::from_secs_f64(wait));
+            return;
+        }
+        let replaced = window.history().and_then(|history| {
+            history.replace_state_with_url(
+                &eframe::wasm_bindgen::JsValue::NULL,
+                "",
+                Some(&format!("#{fragment}")),
+            )
+        });
+        if let Err(err) = replaced {
+            log::error!("Failed to update the address: {err:?}");
+        }
+        self.fragment = fragment;
+        self.written_at = now;
+    }
+}
+
+/// The fragment for `location`: its path from the outermost node, one digit for each corner
+/// gone through counting across then down, then its pan and zoom.
+fn to_fragment(location: &ViewLocation) -> String {
+    let path: String = location
+        .path()
+        .iter()
+        .rev()
+        .map(|(x, y)| char::from(b'0' + x + 2 * y))
+        .collect();
+    format!(
+        "view={path}/{},{}/{}",
+        location.pan.x, location.pan.y, location.zoom
+    )
+}
+
+/// The location written by `to_fragment`, or None if `fragment` is not one.
+fn from_fragment(fragment: &str) -> Option<ViewLocation> {
+    let mut parts = fragment.strip_prefix("view=")?.split('/');
+    let path = parts
+        .next()?
+        .bytes()
+        .rev()
+        .map(|digit| match digit {
+            b'0'..=b'3' => Some(((digit - b'0') % 2, (digit - b'0') / 2)),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let (x, y) = parts.next()?.split_once(',')?;
+    let pan = egui::vec2(x.parse().ok()?, y.parse().ok()?);
+    let zoom: f32 = parts.next()?.parse().ok()?;
+    if parts.next().is_some() || !pan.is_finite() || !(zoom.is_finite() && zoom > 0.0) {
+        return None;
+    }
+    Some(ViewLocation::from_path(path, pan, zoom))
+}
@@ -0,0 +1,706 @@
+use std::sync::OnceLock;
+
+use egui::{
+    emath::RectTransform,
+    epaint::{Mesh, TextShape, Vertex, WHITE_UV},
+    pos2, vec2, Color32, ColorImage, FontId, Painter, Pos2, Rect, Shape, Stroke, TextureHandle,
+    TextureOptions, Vec2,
+};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    geometry::{self, Affine2},
+    raster,
+    structure::{CanvasDrawable, CanvasDrawableGenerator, Property, SegmentStyle, VectorShape},
+};
+
+/// Smallest and largest on-screen font size text is laid out at. Text is skipped
+/// below the minimum and stops growing past the maximum to keep the font atlas bounded.
+const MIN_FONT_SIZE: f32 = 3.0;
+const MAX_FONT_SIZE: f32 = 256.0;
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct StickyNote {
+    min_x: f32,
+    min_y: f32,
+    max_x: f32,
+    max_y: f32,
+    color: Color32,
+    pub text: String,
+}
+
+impl StickyNote {
+    pub fn new(p1: Pos2, p2: Pos2, color: Color32, text: String) -> Self {
+        let rect = Rect::from_two_pos(p1, p2);
+        Self {
+            min_x: rect.min.x,
+            min_y: rect.min.y,
+            max_x: rect.max.x,
+            max_y: rect.max.y,
+            color,
+            text,
+        }
+    }
+
+    pub fn text_color(background: Color32) -> Color32 {
+        let [r, g, b, _] = background.to_array();
+        if 0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32 > 128.0 {
+            Color32::from_gray(30)
+        } else {
+            Color32::from_gray(230)
+        }
+    }
+
+    /// Draws a note occupying `screen_rect`, shared with the placement preview.
+    pub fn paint(painter: &Painter, screen_rect: Rect, color: Color32, text: &str) {
+        let rounding = 0.05 * screen_rect.size().min_elem();
+        painter.rect_filled(screen_rect, rounding, color);
+        painter.rect_stroke(
+            screen_rect,
+            rounding,
+            Stroke::new(1.0, color.gamma_multiply(0.7)),
+        );
+        let font_size = screen_rect.height() / 8.0;
+        if font_size < MIN_FONT_SIZE || text.is_empty() {
+            return;
+        }
+        let font_size = font_size.min(MAX_FONT_SIZE);
+        let padding = font_size / 2.0;
+        let galley = painter.layout(
+            text.to_string(),
+            FontId::proportional(font_size),
+            Self::text_color(color),
+            (screen_rect.width() - 2.0 * padding).max(0.0),
+        );
+        painter
+            .with_clip_rect(screen_rect.intersect(painter.clip_rect()))
+            .galley(
+                screen_rect.min + egui::vec2(padding, padding),
+                galley,
+                Color32::PLACEHOLDER,
+            );
+    }
+}
+
+#[typetag::serde]
+impl CanvasDrawable for StickyNote {
+    fn draw(&self, painter: &Painter, to_screen: RectTransform) {
+        let screen_rect = Rect::from_min_max(
+            to_screen * pos2(self.min_x, self.min_y),
+            to_screen * pos2(self.max_x, self.max_y),
+        );
+        if !painter.clip_rect().intersects(screen_rect) {
+            return;
+        }
+        Self::paint(painter, screen_rect, self.color, &self.text);
+    }
+
+    fn bounds(&self) -> Rect {
+        Rect::from_min_max(pos2(self.min_x, self.min_y), pos2(self.max_x, self.max_y))
+    }
+
+    /// Notes stay upright, so rotations only move them.
+    fn transform(&mut self, transform: &Affine2) {
+        let bounds = self.bounds();
+        let center = transform.transform_point(bounds.center());
+        let size = vec2(
+            transform.x_axis.length() * bounds.width(),
+            transform.y_axis.length() * bounds.height(),
+        );
+        let rect = Rect::from_center_size(center, size);
+        (self.min_x, self.min_y) = (rect.min.x, rect.min.y);
+        (self.max_x, self.max_y) = (rect.max.x, rect.max.y);
+    }
+
+    fn properties(&mut self) -> Vec<Property<'_>> {
+        vec![
+            Property::Color(&mut self.color),
+            Property::Text(&mut self.text),
+        ]
+    }
+
+    /// The note as a square-cornered rectangle with its text unwrapped, one line of text to
+    /// each line break.
+    fn vector_shapes(&self) -> Vec<VectorShape> {
+        let rect = self.bounds();
+        let mut shapes = vec![VectorShape::Polygon {
+            points: vec![
+                rect.left_top(),
+                rect.right_top(),
+                rect.right_bottom(),
+                rect.left_bottom(),
+            ],
+            color: self.color,
+        }];
+        let size = rect.height() / 8.0;
+        let padding = size / 2.0;
+        shapes.extend(self.text.lines().enumerate().map(|(line, text)| {
+            VectorShape::Text {
+                // Rows are about 1.25 times the font size, with the baseline three quarters down.
+                origin: rect.min + vec2(padding, padding + size * (1.25 * line as f32 + 0.95)),
+                angle: 0.0,
+                text: text.to_string(),
+                size,
+                color: Self::text_color(self.color),
+            }
+        }));
+        shapes
+    }
+
+    fn accessible_label(&self) -> Option<String> {
+        Some(if self.text.is_empty() {
+            "Empty sticky note".to_string()
+        } else {
+            format!("Sticky note: {}", self.text)
+        })
+    }
+
+    fn box_clone(&self) -> Box<dyn CanvasDrawable> {
+        Box::new((*self).clone())
+    }
+}
+
+/// A filled ribbon through points that each carry their own width.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct TaperedStroke {
+    /// `(x, y, width)` triples.
+    points: Vec<(f32, f32, f32)>,
+    color: Color32,
+}
+
+impl TaperedStroke {
+    /// A stroke of even `width` through `points`.
+    pub fn new(points: &[Pos2], width: f32, color: Color32) -> Self {
+        Self {
+            points: points
+                .iter()
+                .map(|point| (point.x, point.y, width))
+                .collect(),
+            color,
+        }
+    }
+}
+
+#[typetag::serde]
+impl CanvasDrawable for TaperedStroke {
+    fn draw(&self, painter: &Painter, to_screen: RectTransform) {
+        let scale_factor = to_screen.scale().max_elem();
+        let points = self
+            .points
+            .iter()
+            .map(|(x, y, width)| (to_screen * pos2(*x, *y), width * scale_factor / 2.0))
+            .collect_vec();
+        for ((a, a_radius), (b, b_radius)) in points.iter().tuple_windows() {
+            let direction = *b - *a;
+            if direction.length_sq() == 0.0 {
+                continue;
+            }
+            let normal = direction.normalized().rot90();
+            painter.add(Shape::convex_polygon(
+                vec![
+                    *a + normal * *a_radius,
+                    *b + normal * *b_radius,
+                    *b - normal * *b_radius,
+                    *a - normal * *a_radius,
+                ],
+                self.color,
+                Stroke::NONE,
+            ));
+        }
+        for (point, radius) in points {
+            painter.circle_filled(point, radius, self.color);
+        }
+    }
+
+    fn bounds(&self) -> Rect {
+        self.points
+            .iter()
+            .map(|(x, y, width)| Rect::from_center_size(pos2(*x, *y), Vec2::splat(*width)))
+            .fold(Rect::NOTHING, |bounds, point| bounds.union(point))
+    }
+
+    fn hit_test(&self, point: Pos2, tolerance: f32) -> bool {
+        match self.points.as_slice() {
+            [(x, y, width)] => point.distance(pos2(*x, *y)) <= width / 2.0 + tolerance,
+            points => points
+                .iter()
+                .tuple_windows()
+                .any(|((ax, ay, aw), (bx, by, bw))| {
+                    geometry::segment_distance(point, pos2(*ax, *ay), pos2(*bx, *by))
+                        <= aw.max(*bw) / 2.0 + tolerance
+                }),
+        }
+    }
+
+    fn transform(&mut self, transform: &Affine2) {
+        let scale = transform.scale_factor();
+        for (x, y, width) in &mut self.points {
+            let point = transform.transform_point(pos2(*x, *y));
+            (*x, *y) = (point.x, point.y);
+            *width *= scale;
+        }
+    }
+
+    fn properties(&mut self) -> Vec<Property<'_>> {
+        vec![Property::Color(&mut self.color)]
+    }
+
+    /// The taper is evened out to the average width.
+    fn vector_shapes(&self) -> Vec<VectorShape> {
+        let width = self.points.iter().map(|(_, _, width)| width).sum::<f32>()
+            / self.points.len().max(1) as f32;
+        vec![VectorShape::Polyline {
+            points: self.points.iter().map(|(x, y, _)| pos2(*x, *y)).collect(),
+            width,
+            color: self.color,
+            closed: false,
+        }]
+    }
+
+    fn box_clone(&self) -> Box<dyn CanvasDrawable> {
+        Box::new((*self).clone())
+    }
+}
+
+impl CanvasDrawableGenerator for TaperedStroke {
+    fn from_points(p1: Pos2, p2: Pos2, scale: f32, style: &SegmentStyle) -> Box<Self> {
+        let width = style.stroke.width * scale;
+        Box::new(TaperedStroke {
+            points: vec![
+                (p1.x, p1.y, width * style.widths[0]),
+                (p2.x, p2.y, width * style.widths[1]),
+            ],
+            color: style.stroke.color,
+        })
+    }
+}
+
+/// A solid polygon, stored with its triangulation so it can be drawn without recomputing it.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct FilledPolygon {
+    points: Vec<(f32, f32)>,
+    triangles: Vec<u32>,
+    color: Color32,
+}
+
+impl FilledPolygon {
+    pub fn new(points: &[Pos2], color: Color32) -> Self {
+        Self {
+            points: points.iter().map(|point| (point.x, point.y)).collect(),
+            triangles: geometry::triangulate(points),
+            color,
+        }
+    }
+}
+
+#[typetag::serde]
+impl CanvasDrawable for FilledPolygon {
+    fn draw(&self, painter: &Painter, to_screen: RectTransform) {
+        painter.add(Mesh {
+            indices: self.triangles.clone(),
+            vertices: self
+                .points
+                .iter()
+                .map(|(x, y)| Vertex {
+                    pos: to_screen * pos2(*x, *y),
+                    uv: WHITE_UV,
+                    color: self.color,
+                })
+                .collect(),
+            ..Default::default()
+        });
+    }
+
+    fn bounds(&self) -> Rect {
+        Rect::from_points(&self.points.iter().map(|(x, y)| pos2(*x, *y)).collect_vec())
+    }
+
+    fn hit_test(&self, point: Pos2, tolerance: f32) -> bool {
+        let points = self.points.iter().map(|(x, y)| pos2(*x, *y)).collect_vec();
+        geometry::polygon_contains(&points, point)
+            || geometry::polyline_distance(point, &points) <= tolerance
+            || points
+                .first()
+                .zip(points.last())
+                .is_some_and(|(first, last)| {
+                    geometry::segment_distance(point, *last, *first) <= tolerance
+                })
+    }
+
+    fn transform(&mut self, transform: &Affine2) {
+        for (x, y) in &mut self.points {
+            let point = transform.transform_point(pos2(*x, *y));
+            (*x, *y) = (point.x, point.y);
+        }
+    }
+
+    fn properties(&mut self) -> Vec<Property<'_>> {
+        vec![Property::Color(&mut self.color)]
+    }
+
+    fn vector_shapes(&self) -> Vec<VectorShape> {
+        vec![VectorShape::Polygon {
+            points: self.points.iter().map(|(x, y)| pos2(*x, *y)).collect(),
+            color: self.color,
+        }]
+    }
+
+    fn box_clone(&self) -> Box<dyn CanvasDrawable> {
+        Box::new((*self).clone())
+    }
+}
+
+/// Text laid out one glyph at a time along a polyline, sitting on top of it.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct PathText {
+    points: Vec<(f32, f32)>,
+    text: String,
+    /// Font size in node coordinates.
+    size: f32,
+    color: Color32,
+}
+
+impl PathText {
+    pub fn new(points: &[Pos2], text: String, size: f32, color: Color32) -> Self {
+        Self {
+            points: points.iter().map(|point| (point.x, point.y)).collect(),
+            text,
+            size,
+            color,
+        }
+    }
+}
+
+/// Position and direction at `distance` along `points`, where `lengths` holds the distance to
+/// each point. `None` past the end of the path.
+fn point_along(points: &[Pos2], lengths: &[f32], distance: f32) -> Option<(Pos2, Vec2)> {
+    let segment = lengths.partition_point(|length| *length <= distance);
+    if segment == 0 || segment >= points.len() {
+        return None;
+    }
+    let (a, b) = (points[segment - 1], points[segment]);
+    let t = (distance - lengths[segment - 1]) / (lengths[segment] - lengths[segment - 1]);
+    Some((a.lerp(b, t), (b - a).normalized()))
+}
+
+#[typetag::serde]
+impl CanvasDrawable for PathText {
+    fn draw(&self, painter: &Painter, to_screen: RectTransform) {
+        if !painter
+            .clip_rect()
+            .intersects(to_screen.transform_rect(self.bounds()))
+        {
+            return;
+        }
+        let font_size = self.size * to_screen.scale().max_elem();
+        if font_size < MIN_FONT_SIZE {
+            return;
+        }
+        let font = FontId::proportional(font_size.min(MAX_FONT_SIZE));
+        let points = self
+            .points
+            .iter()
+            .map(|(x, y)| to_screen * pos2(*x, *y))
+            .dedup()
+            .collect_vec();
+        let lengths = std::iter::once(0.0)
+            .chain(points.iter().tuple_windows().scan(0.0, |length, (a, b)| {
+                *length += (*b - *a).length();
+                Some(*length)
+            }))
+            .collect_vec();
+        let mut distance = 0.0;
+        for glyph in self.text.chars() {
+            let galley = painter.layout_no_wrap(glyph.to_string(), font.clone(), self.color);
+            let advance = galley.size().x;
+            // Glyphs are oriented by the direction at their middle so they follow bends evenly.
+            let Some((middle, direction)) =
+                point_along(&points, &lengths, distance + advance / 2.0)
+            else {
+                break;
+            };
+            // Puts the baseline, roughly three quarters down the row, on the path.
+            let pos =
+                middle - direction * advance / 2.0 - direction.rot90() * galley.size().y * 0.75;
+            painter.add(TextShape::new(pos, galley, self.color).with_angle(direction.angle()));
+            distance += advance;
+        }
+    }
+
+    fn bounds(&self) -> Rect {
+        Rect::from_points(&self.points.iter().map(|(x, y)| pos2(*x, *y)).collect_vec())
+            .expand(self.size)
+    }
+
+    /// Tests against the band the text is laid out in along its path.
+    fn hit_test(&self, point: Pos2, tolerance: f32) -> bool {
+        let points = self.points.iter().map(|(x, y)| pos2(*x, *y)).collect_vec();
+        geometry::polyline_distance(point, &points) <= self.size + tolerance
+    }
+
+    fn transform(&mut self, transform: &Affine2) {
+        for (x, y) in &mut self.points {
+            let point = transform.transform_point(pos2(*x, *y));
+            (*x, *y) = (point.x, point.y);
+        }
+        self.size *= transform.scale_factor();
+    }
+
+    fn properties(&mut self) -> Vec<Property<'_>> {
+        vec![
+            Property::Color(&mut self.color),
+            Property::Text(&mut self.text),
+        ]
+    }
+
+    /// The text as a straight line along the direction the path starts in.
+    fn vector_shapes(&self) -> Vec<VectorShape> {
+        let points = self
+            .points
+            .iter()
+            .map(|(x, y)| pos2(*x, *y))
+            .dedup()
+            .collect_vec();
+        let Some(origin) = points.first() else {
+            return vec![];
+        };
+        let angle = points.get(1).map_or(0.0, |next| (*next - *origin).angle());
+        vec![VectorShape::Text {
+            origin: *origin,
+            angle,
+            text: self.text.clone(),
+            size: self.size,
+            color: self.color,
+        }]
+    }
+
+    fn accessible_label(&self) -> Option<String> {
+        Some(format!("Text: {}", self.text))
+    }
+
+    fn box_clone(&self) -> Box<dyn CanvasDrawable> {
+        Box::new((*self).clone())
+    }
+}
+
+/// A named rectangle marking out a region of the canvas, with its name above the top left
+/// corner.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Frame {
+    min_x: f32,
+    min_y: f32,
+    max_x: f32,
+    max_y: f32,
+    name: String,
+    /// Font size of the name in node coordinates.
+    title_size: f32,
+    color: Color32,
+}
+
+impl Frame {
+    pub fn new(p1: Pos2, p2: Pos2, name: String, title_size: f32, color: Color32) -> Self {
+        let rect = Rect::from_two_pos(p1, p2);
+        Self {
+            min_x: rect.min.x,
+            min_y: rect.min.y,
+            max_x: rect.max.x,
+            max_y: rect.max.y,
+            name,
+            title_size,
+            color,
+        }
+    }
+
+    fn rect(&self) -> Rect {
+        Rect::from_min_max(pos2(self.min_x, self.min_y), pos2(self.max_x, self.max_y))
+    }
+
+    /// Band above the frame its name is drawn in.
+    fn title_rect(&self) -> Rect {
+        let rect = self.rect();
+        Rect::from_min_max(
+            rect.min - vec2(0.0, 1.5 * self.title_size),
+            pos2(rect.max.x, rect.min.y),
+        )
+    }
+}
+
+#[typetag::serde]
+impl CanvasDrawable for Frame {
+    fn draw(&self, painter: &Painter, to_screen: RectTransform) {
+        if !painter
+            .clip_rect()
+            .intersects(to_screen.transform_rect(self.bounds()))
+        {
+            return;
+        }
+        let screen_rect = to_screen.transform_rect(self.rect());
+        painter.rect_stroke(screen_rect, 0.0, Stroke::new(1.5, self.color));
+        let font_size = self.title_size * to_screen.scale().max_elem();
+        if font_size < MIN_FONT_SIZE {
+            return;
+        }
+        painter.text(
+            screen_rect.min - vec2(0.0, font_size / 4.0),
+            egui::Align2::LEFT_BOTTOM,
+            &self.name,
+            FontId::proportional(font_size.min(MAX_FONT_SIZE)),
+            self.color,
+        );
+    }
+
+    fn bounds(&self) -> Rect {
+        self.rect().union(self.title_rect())
+    }
+
+    /// Only the outline and name hit, so clicks inside reach the content being framed.
+    fn hit_test(&self, point: Pos2, tolerance: f32) -> bool {
+        let rect = self.rect();
+        let on_outline =
+            rect.expand(tolerance).contains(point) && !rect.shrink(tolerance).contains(point);
+        on_outline || self.title_rect().expand(tolerance).contains(point)
+    }
+
+    /// Frames stay upright, so rotations only move them.
+    fn transform(&mut self, transform: &Affine2) {
+        let rect = self.rect();
+        let center = transform.transform_point(rect.center());
+        let size = vec2(
+            transform.x_axis.length() * rect.width(),
+            transform.y_axis.length() * rect.height(),
+        );
+        let rect = Rect::from_center_size(center, size);
+        (self.min_x, self.min_y) = (rect.min.x, rect.min.y);
+        (self.max_x, self.max_y) = (rect.max.x, rect.max.y);
+        self.title_size *= transform.scale_factor();
+    }
+
+    fn properties(&mut self) -> Vec<Property<'_>> {
+        vec![
+            Property::Color(&mut self.color),
+            Property::Text(&mut self.name),
+        ]
+    }
+
+    fn accessible_label(&self) -> Option<String> {
+        Some(format!("Frame: {}", self.name))
+    }
+
+    fn frame_name(&self) -> Option<&str> {
+        Some(&self.name)
+    }
+
+    /// The outline as a hairline, since it is drawn the same width at any zoom.
+    fn vector_shapes(&self) -> Vec<VectorShape> {
+        let rect = self.rect();
+        vec![
+            VectorShape::Polyline {
+                points: vec![
+                    rect.left_top(),
+                    rect.right_top(),
+                    rect.right_bottom(),
+                    rect.left_bottom(),
+                ],
+                width: 0.0,
+                color: self.color,
+                closed: true,
+            },
+            VectorShape::Text {
+                origin: rect.min - vec2(0.0, self.title_size / 4.0),
+                angle: 0.0,
+                text: self.name.clone(),
+                size: self.title_size,
+                color: self.color,
+            },
+        ]
+    }
+
+    fn box_clone(&self) -> Box<dyn CanvasDrawable> {
+        Box::new((*self).clone())
+    }
+}
+
+/// A bitmap stretched over a rectangle, such as a page of an imported document.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct RasterImage {
+    min_x: f32,
+    min_y: f32,
+    max_x: f32,
+    max_y: f32,
+    /// The bitmap, PNG encoded.
+    png: Vec<u8>,
+    /// The bitmap uploaded when first drawn, or None if it could not be decoded.
+    #[serde(skip)]
+    texture: OnceLock<Option<TextureHandle>>,
+}
+
+impl RasterImage {
+    pub fn new(rect: Rect, image: &ColorImage) -> Result<Self, png::EncodingError> {
+        Ok(Self {
+            min_x: rect.min.x,
+            min_y: rect.min.y,
+            max_x: rect.max.x,
+            max_y: rect.max.y,
+            png: raster::encode_png(image)?,
+            texture: OnceLock::new(),
+        })
+    }
+}
+
+#[typetag::serde]
+impl CanvasDrawable for RasterImage {
+    fn draw(&self, painter: &Painter, to_screen: RectTransform) {
+        let screen_rect = to_screen.transform_rect(self.bounds());
+        if !painter.clip_rect().intersects(screen_rect) {
+            return;
+        }
+        let texture = self
+            .texture
+            .get_or_init(|| match raster::decode_png(&self.png) {
+                Ok(image) => Some(painter.ctx().load_texture(
+                    "raster_image",
+                    image,
+                    TextureOptions::LINEAR,
+                )),
+                Err(err) => {
+                    log::error!("Failed to decode an image: {err}");
+                    None
+                }
+            });
+        match texture {
+            Some(texture) => {
+                let uv = Rect::from_min_max(Pos2::ZERO, pos2(1.0, 1.0));
+                painter.image(texture.id(), screen_rect, uv, Color32::WHITE);
+            }
+            None => {
+                painter.rect_filled(screen_rect, 0.0, Color32::GRAY);
+            }
+        }
+    }
+
+    fn bounds(&self) -> Rect {
+        Rect::from_min_max(pos2(self.min_x, self.min_y), pos2(self.max_x, self.max_y))
+    }
+
+    /// Images stay upright, so rotations only move them.
+    fn transform(&mut self, transform: &Affine2) {
+        let bounds = self.bounds();
+        let center = transform.transform_point(bounds.center());
+        let size = vec2(
+            transform.x_axis.length() * bounds.width(),
+            transform.y_axis.length() * bounds.height(),
+        );
+        let rect = Rect::from_center_size(center, size);
+        (self.min_x, self.min_y) = (rect.min.x, rect.min.y);
+        (self.max_x, self.max_y) = (rect.max.x, rect.max.y);
+    }
+
+    fn accessible_label(&self) -> Option<String> {
+        Some("Image".to_string())
+    }
+
+    fn box_clone(&self) -> Box<dyn CanvasDrawable> {
+        Box::new((*self).clone())
+    }
+}
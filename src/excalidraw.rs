@@ -0,0 +1,407 @@
+use egui::{pos2, vec2, Color32, Pos2, Rect, Vec2};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clipboard::{CopiedStroke, CopiedStrokes},
+    drawables::{FilledPolygon, PathText, TaperedStroke},
+    geometry::Affine2,
+    structure::{CanvasDrawable, StrokePriority, VectorShape},
+};
+
+/// Excalidraw's line height for text, in multiples of the font size.
+const LINE_HEIGHT: f32 = 1.25;
+
+/// An `.excalidraw` file, or the JSON Excalidraw puts on the clipboard.
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Scene {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    version: u32,
+    #[serde(default)]
+    source: String,
+    elements: Vec<Element>,
+    #[serde(default)]
+    app_state: serde_json::Value,
+    #[serde(default)]
+    files: serde_json::Value,
+}
+
+/// The fields of an Excalidraw element that are converted, along with those Excalidraw
+/// expects to find when loading one. Excalidraw fills in any others.
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", default)]
+struct Element {
+    id: String,
+    #[serde(rename = "type")]
+    kind: String,
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+    /// Clockwise rotation in radians about the element's center.
+    angle: f32,
+    stroke_color: String,
+    background_color: String,
+    fill_style: String,
+    stroke_width: f32,
+    stroke_style: String,
+    roughness: u32,
+    /// From 0 to 100.
+    opacity: f32,
+    group_ids: Vec<String>,
+    seed: u32,
+    version: u32,
+    version_nonce: u32,
+    is_deleted: bool,
+    locked: bool,
+    /// Points of lines, arrows and freedraw elements relative to `x` and `y`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    points: Vec<[f32; 2]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    original_text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    font_size: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    font_family: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line_height: Option<f32>,
+}
+
+impl Default for Element {
+    fn default() -> Self {
+        Self {
+            id: String::new(),
+            kind: String::new(),
+            x: 0.0,
+            y: 0.0,
+            width: 0.0,
+            height: 0.0,
+            angle: 0.0,
+            stroke_color: "#1e1e1e".to_string(),
+            background_color: "transparent".to_string(),
+            fill_style: "solid".to_string(),
+            stroke_width: 1.0,
+            stroke_style: "solid".to_string(),
+            roughness: 0,
+            opacity: 100.0,
+            group_ids: vec![],
+            seed: 1,
+            version: 1,
+            version_nonce: 0,
+            is_deleted: false,
+            locked: false,
+            points: vec![],
+            text: None,
+            original_text: None,
+            font_size: None,
+            font_family: None,
+            line_height: None,
+        }
+    }
+}
+
+impl Element {
+    /// Maps points relative to the element to absolute ones, rotated about its center.
+    fn placement(&self) -> Affine2 {
+        let center = pos2(self.x + self.width / 2.0, self.y + self.height / 2.0);
+        Affine2::from_translation(vec2(self.x, self.y))
+            .then(&Affine2::from_angle(self.angle).about(center))
+    }
+
+    fn color(&self, color: &str) -> Option<Color32> {
+        let [r, g, b, a] = parse_color(color)?.to_srgba_unmultiplied();
+        let alpha = a as f32 * (self.opacity / 100.0).clamp(0.0, 1.0);
+        Some(Color32::from_rgba_unmultiplied(
+            r,
+            g,
+            b,
+            alpha.round() as u8,
+        ))
+    }
+}
+
+/// Whether `text` looks like an Excalidraw scene rather than other pasted text.
+pub fn is_excalidraw(text: &str) -> bool {
+    text.trim_start().starts_with('{') && text.contains("\"excalidraw")
+}
+
+/// Converts the lines, arrows, freehand drawings, rectangles and text of the Excalidraw scene
+/// `data` into strokes in the coordinates of nodes `depth` levels deep, with `scale` node units
+/// per Excalidraw pixel, centered on the origin and grouped together. Arrowheads, rounded
+/// corners and the sketchy look are left out, and fills come out solid.
+pub fn import(data: &[u8], depth: u32, scale: f32) -> Result<CopiedStrokes, String> {
+    let scene: Scene = serde_json::from_slice(data).map_err(|err| err.to_string())?;
+    if !scene.kind.starts_with("excalidraw") {
+        return Err(format!("Not an Excalidraw scene but {:?}", scene.kind));
+    }
+    let mut drawables: Vec<Box<dyn CanvasDrawable>> = vec![];
+    for element in scene.elements.iter().filter(|element| !element.is_deleted) {
+        let placement = element.placement();
+        let stroke = element.color(&element.stroke_color);
+        let fill = element.color(&element.background_color);
+        let outline = match element.kind.as_str() {
+            "line" | "arrow" | "freedraw" => element
+                .points
+                .iter()
+                .map(|[x, y]| placement.transform_point(pos2(*x, *y)))
+                .collect_vec(),
+            "rectangle" => {
+                let rect = Rect::from_min_size(Pos2::ZERO, vec2(element.width, element.height));
+                [
+                    rect.left_top(),
+                    rect.right_top(),
+                    rect.right_bottom(),
+                    rect.left_bottom(),
+                    rect.left_top(),
+                ]
+                .map(|corner| placement.transform_point(corner))
+                .to_vec()
+            }
+            "text" => {
+                let (Some(text), Some(color)) = (&element.text, stroke) else {
+                    continue;
+                };
+                let size = element.font_size.unwrap_or(20.0);
+                let line_height = element.line_height.unwrap_or(LINE_HEIGHT);
+                for (line, text) in text.lines().enumerate() {
+                    // The baseline sits about four fifths down each row, and the path runs a
+                    // generous em per character since glyphs stop where it ends.
+                    let baseline = size * (line_height * line as f32 + 0.8);
+                    let length = element.width.max(size * text.chars().count() as f32);
+                    let points = [pos2(0.0, baseline), pos2(length, baseline)]
+                        .map(|point| placement.transform_point(point) * scale);
+                    drawables.push(Box::new(PathText::new(
+                        &points,
+                        text.to_string(),
+                        size * scale,
+                        color,
+                    )));
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        let outline = outline.into_iter().map(|point| point * scale).collect_vec();
+        let closed = outline.len() > 2 && outline.first() == outline.last();
+        if let Some(fill) = fill.filter(|_| closed) {
+            drawables.push(Box::new(FilledPolygon::new(
+                &outline[..outline.len() - 1],
+                fill,
+            )));
+        }
+        if let Some(stroke) = stroke.filter(|_| !outline.is_empty()) {
+            drawables.push(Box::new(TaperedStroke::new(
+                &outline,
+                element.stroke_width * scale,
+                stroke,
+            )));
+        }
+    }
+    if drawables.is_empty() {
+        return Err("The scene has nothing that can be imported".to_string());
+    }
+    let bounds = drawables.iter().fold(Rect::NOTHING, |bounds, drawable| {
+        bounds.union(drawable.bounds())
+    });
+    let recenter = Affine2::from_translation(-bounds.center().to_vec2());
+    let strokes = drawables
+        .into_iter()
+        .map(|mut drawable| {
+            drawable.transform(&recenter);
+            CopiedStroke {
+                drawable,
+                priority: StrokePriority::Ink,
+                group: Some(0),
+            }
+        })
+        .collect();
+    Ok(CopiedStrokes { depth, strokes })
+}
+
+/// Writes `shapes`, bottom to top in Excalidraw's pixels, as an `.excalidraw` file on a
+/// `background`. Polylines continuing where the one before them ended in the same color and
+/// width are joined into one line, so pen strokes made of many segments stay whole.
+pub fn export(
+    shapes: impl IntoIterator<Item = VectorShape>,
+    background: Color32,
+) -> Result<String, serde_json::Error> {
+    let mut elements: Vec<Element> = vec![];
+    let mut open_line: Option<(Vec<Pos2>, f32, Color32)> = None;
+    let finish_line = |elements: &mut Vec<Element>, line: Option<(Vec<Pos2>, f32, Color32)>| {
+        if let Some((points, width, color)) = line {
+            elements.push(line_element(&points, width, color, None));
+        }
+    };
+    for shape in shapes {
+        match shape {
+            VectorShape::Polyline {
+                points,
+                width,
+                color,
+                closed: false,
+            } => {
+                if let Some((line, line_width, line_color)) = &mut open_line {
+                    let continues = *line_width == width
+                        && *line_color == color
+                        && line.last().zip(points.first()).is_some_and(|(end, start)| {
+                            end.distance(*start) <= 1e-3 * width.max(1.0)
+                        });
+                    if continues {
+                        line.extend(points.into_iter().skip(1));
+                        continue;
+                    }
+                }
+                finish_line(&mut elements, open_line.replace((points, width, color)));
+            }
+            shape => {
+                finish_line(&mut elements, open_line.take());
+                elements.extend(shape_element(shape));
+            }
+        }
+    }
+    finish_line(&mut elements, open_line);
+    for (index, element) in elements.iter_mut().enumerate() {
+        element.id = format!("true-infinite-canvas-{index}");
+        element.seed = index as u32 + 1;
+    }
+    serde_json::to_string_pretty(&Scene {
+        kind: "excalidraw".to_string(),
+        version: 2,
+        source: "https://github.com/Devon7925/TrueInfiniteCanvas".to_string(),
+        elements,
+        app_state: serde_json::json!({ "viewBackgroundColor": hex_color(background) }),
+        files: serde_json::json!({}),
+    })
+}
+
+fn shape_element(shape: VectorShape) -> Option<Element> {
+    match shape {
+        VectorShape::Polyline {
+            mut points,
+            width,
+            color,
+            closed,
+        } => {
+            if closed {
+                points.extend(points.first().copied());
+            }
+            Some(line_element(&points, width, color, None))
+        }
+        VectorShape::Polygon { mut points, color } => {
+            points.extend(points.first().copied());
+            Some(line_element(
+                &points,
+                0.0,
+                Color32::TRANSPARENT,
+                Some(color),
+            ))
+        }
+        VectorShape::Text {
+            origin,
+            angle,
+            text,
+            size,
+            color,
+        } => {
+            let (rgb, opacity) = split_alpha(color);
+            // Rough extent of the text, which Excalidraw measures again when it loads.
+            let size_vec = vec2(0.6 * size * text.chars().count() as f32, LINE_HEIGHT * size);
+            let top_left = origin - vec2(0.0, 0.8 * size);
+            // Excalidraw rotates about the center rather than the origin.
+            let center = Affine2::from_angle(angle)
+                .about(origin)
+                .transform_point(top_left + size_vec / 2.0);
+            let min = center - size_vec / 2.0;
+            Some(Element {
+                kind: "text".to_string(),
+                x: min.x,
+                y: min.y,
+                width: size_vec.x,
+                height: size_vec.y,
+                angle,
+                stroke_color: rgb,
+                opacity,
+                original_text: Some(text.clone()),
+                text: Some(text),
+                font_size: Some(size),
+                font_family: Some(1),
+                line_height: Some(LINE_HEIGHT),
+                ..Default::default()
+            })
+        }
+    }
+}
+
+/// A line element through `points`, filled with `fill` if given.
+fn line_element(points: &[Pos2], width: f32, color: Color32, fill: Option<Color32>) -> Element {
+    let bounds = Rect::from_points(points);
+    let origin = points.first().copied().unwrap_or(Pos2::ZERO);
+    let (stroke_color, stroke_opacity) = split_alpha(color);
+    let (background_color, fill_opacity) =
+        fill.map_or(("transparent".to_string(), 0.0), split_alpha);
+    Element {
+        kind: "line".to_string(),
+        x: origin.x,
+        y: origin.y,
+        width: bounds.width(),
+        height: bounds.height(),
+        stroke_color,
+        background_color,
+        stroke_width: width.max(0.5),
+        // Excalidraw has one opacity for the stroke and fill.
+        opacity: stroke_opacity.max(fill_opacity),
+        points: points
+            .iter()
+            .map(|point| {
+                let offset: Vec2 = *point - origin;
+                [offset.x, offset.y]
+            })
+            .collect(),
+        ..Default::default()
+    }
+}
+
+/// `color` as an opaque `#rrggbb` color and an opacity from 0 to 100, or transparent.
+fn split_alpha(color: Color32) -> (String, f32) {
+    if color.a() == 0 {
+        return ("transparent".to_string(), 0.0);
+    }
+    let [r, g, b, a] = color.to_srgba_unmultiplied();
+    (
+        hex_color(Color32::from_rgb(r, g, b)),
+        (a as f32 / 2.55).round(),
+    )
+}
+
+fn hex_color(color: Color32) -> String {
+    let [r, g, b, _] = color.to_srgba_unmultiplied();
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+/// Reads the `#rgb`, `#rrggbb` and `#rrggbbaa` colors Excalidraw writes, with `transparent` as
+/// None.
+fn parse_color(color: &str) -> Option<Color32> {
+    let hex = color.strip_prefix('#')?;
+    let digit = |index: usize| u8::from_str_radix(hex.get(index..index + 1)?, 16).ok();
+    let byte = |index: usize| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok();
+    match hex.len() {
+        3 => Some(Color32::from_rgb(
+            digit(0)? * 17,
+            digit(1)? * 17,
+            digit(2)? * 17,
+        )),
+        6 => Some(Color32::from_rgb(byte(0)?, byte(2)?, byte(4)?)),
+        8 => Some(Color32::from_rgba_unmultiplied(
+            byte(0)?,
+            byte(2)?,
+            byte(4)?,
+            byte(6)?,
+        )),
+        _ => None,
+    }
+}
@@ -0,0 +1,317 @@
+use egui::Vec2;
+use serde::{Deserialize, Serialize};
+
+/// Longest side an image is rendered at before it is downsampled. Supersampling is reduced to
+/// stay within it.
+const MAX_RENDER_SIDE: usize = 16384;
+
+/// Which part of the canvas an image is exported from.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExportRegion {
+    #[default]
+    View,
+    /// The bounds of the selected objects, as far as they are in view.
+    Selection,
+    /// A rectangle dragged over the canvas after choosing to export.
+    Area,
+}
+
+impl ExportRegion {
+    pub const ALL: [ExportRegion; 3] = [
+        ExportRegion::View,
+        ExportRegion::Selection,
+        ExportRegion::Area,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExportRegion::View => "View",
+            ExportRegion::Selection => "Selection",
+            ExportRegion::Area => "Dragged area",
+        }
+    }
+}
+
+/// The format images of the canvas are exported in.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ImageFormat {
+    #[default]
+    Png,
+    /// A one page vector PDF.
+    Pdf,
+}
+
+impl ImageFormat {
+    pub const ALL: [ImageFormat; 2] = [ImageFormat::Png, ImageFormat::Pdf];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "PNG",
+            ImageFormat::Pdf => "PDF",
+        }
+    }
+}
+
+/// How images of the canvas are exported.
+#[derive(Deserialize, Serialize, Clone, Copy)]
+#[serde(default)]
+pub struct ImageExport {
+    pub format: ImageFormat,
+    pub region: ExportRegion,
+    /// Image pixels per pixel on screen, or for PDFs page points per point on screen.
+    pub scale: f32,
+    /// Each image pixel is rendered as this many pixels on a side and averaged, smoothing edges
+    /// beyond what tessellation feathers.
+    pub supersample: usize,
+    /// Longest side in pixels of the tiles an image is split into when exported as tiles.
+    pub tile_size: usize,
+}
+
+impl Default for ImageExport {
+    fn default() -> Self {
+        Self {
+            format: ImageFormat::Png,
+            region: ExportRegion::View,
+            scale: 1.0,
+            supersample: 1,
+            tile_size: 2048,
+        }
+    }
+}
+
+impl ImageExport {
+    /// Size in pixels of the image of a region `points` in size on a screen with
+    /// `pixels_per_point`.
+    pub fn image_size(&self, points: Vec2, pixels_per_point: f32) -> [usize; 2] {
+        let size = (points * pixels_per_point * self.scale)
+            .round()
+            .max(Vec2::splat(1.0));
+        [size.x as usize, size.y as usize]
+    }
+
+    /// How many times larger on a side an image of `size` is rendered before it is downsampled.
+    pub fn supersample_for(&self, size: [usize; 2]) -> usize {
+        let longest = size[0].max(size[1]).max(1);
+        self.supersample.min(MAX_RENDER_SIDE / longest).max(1)
+    }
+
+    /// Splits an image of `size` into tiles of at most `tile_size` pixels on a side, row by
+    /// row.
+    pub fn tiles(&self, size: [usize; 2]) -> TileManifest {
+        TileManifest::new(size, self.tile_size, |column, row| {
+            format!("tile_{column}_{row}.png")
+        })
+    }
+}
+
+/// Whether an image of `size` can be rendered in one piece rather than as tiles.
+pub fn fits_one_image(size: [usize; 2]) -> bool {
+    size[0].max(size[1]) <= MAX_RENDER_SIDE
+}
+
+/// Describes how an image exported as tiles fits back together. Written beside the tiles as
+/// `manifest.json`.
+#[derive(Serialize)]
+pub struct TileManifest {
+    /// Size in pixels of the whole image.
+    pub width: usize,
+    pub height: usize,
+    pub tile_size: usize,
+    pub columns: usize,
+    pub rows: usize,
+    pub tiles: Vec<Tile>,
+}
+
+impl TileManifest {
+    /// Splits an image of `size` into tiles of at most `tile_size` pixels on a side, row by
+    /// row, naming the file of each with `file` from its column and row.
+    fn new(size: [usize; 2], tile_size: usize, file: impl Fn(usize, usize) -> String) -> Self {
+        let tile_size = tile_size.clamp(1, MAX_RENDER_SIDE);
+        let [width, height] = size;
+        let columns = width.div_ceil(tile_size);
+        let rows = height.div_ceil(tile_size);
+        let tiles = (0..rows)
+            .flat_map(|row| (0..columns).map(move |column| (column, row)))
+            .map(|(column, row)| {
+                let (x, y) = (column * tile_size, row * tile_size);
+                Tile {
+                    file: file(column, row),
+                    column,
+                    row,
+                    x,
+                    y,
+                    width: tile_size.min(width - x),
+                    height: tile_size.min(height - y),
+                }
+            })
+            .collect();
+        Self {
+            width,
+            height,
+            tile_size,
+            columns,
+            rows,
+            tiles,
+        }
+    }
+}
+
+/// One tile of a `TileManifest`, placed at `x`, `y` pixels in the whole image.
+#[derive(Serialize)]
+pub struct Tile {
+    pub file: String,
+    pub column: usize,
+    pub row: usize,
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// The levels of a Deep Zoom image, as OpenSeadragon and other zoomable viewers read them.
+/// Level 0 is a single pixel and each level is twice the size of the one before, up to the
+/// full image at the last level.
+pub struct DeepZoom {
+    pub size: [usize; 2],
+    pub tile_size: usize,
+}
+
+impl DeepZoom {
+    pub fn new(size: [usize; 2], tile_size: usize) -> Self {
+        Self {
+            size,
+            tile_size: tile_size.clamp(1, MAX_RENDER_SIDE),
+        }
+    }
+
+    /// The level at full size.
+    pub fn max_level(&self) -> u32 {
+        self.size[0]
+            .max(self.size[1])
+            .max(1)
+            .next_power_of_two()
+            .trailing_zeros()
+    }
+
+    pub fn level_size(&self, level: u32) -> [usize; 2] {
+        let shift = self.max_level() - level;
+        self.size.map(|side| side.div_ceil(1 << shift).max(1))
+    }
+
+    /// The tiles of `level`, in the `canvas_files` folder the descriptor points viewers to.
+    pub fn tiles(&self, level: u32) -> Vec<Tile> {
+        TileManifest::new(self.level_size(level), self.tile_size, |column, row| {
+            format!("canvas_files/{level}/{column}_{row}.png")
+        })
+        .tiles
+    }
+
+    /// The `.dzi` file describing the image.
+    pub fn descriptor(&self) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<Image xmlns="http://schemas.microsoft.com/deepzoom/2008" Format="png" Overlap="0" TileSize="{}">
+  <Size Width="{}" Height="{}"/>
+</Image>
+"#,
+            self.tile_size, self.size[0], self.size[1]
+        )
+    }
+}
+
+/// Where the files of an export made of several files go: natively a new folder inside one
+/// the user picks, and on the web separate downloads sharing a prefix.
+pub struct FileSink {
+    #[cfg(not(target_arch = "wasm32"))]
+    dir: std::path::PathBuf,
+    #[cfg(target_arch = "wasm32")]
+    prefix: String,
+}
+
+impl FileSink {
+    /// Asks where to put the files of an export called `name`. None if the user cancelled.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn pick(name: &str) -> Result<Option<Self>, String> {
+        let Some(parent) = rfd::FileDialog::new()
+            .set_title(format!("Choose where to export {name}"))
+            .pick_folder()
+        else {
+            return Ok(None);
+        };
+        let dir = parent.join(name);
+        std::fs::create_dir_all(&dir).map_err(|err| err.to_string())?;
+        Ok(Some(Self { dir }))
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn pick(name: &str) -> Result<Option<Self>, String> {
+        Ok(Some(Self {
+            prefix: name.to_string(),
+        }))
+    }
+
+    /// Writes `data` as the file `name` of the export, which may be in folders separated by
+    /// `/`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn write(&self, name: &str, data: &[u8]) -> Result<(), String> {
+        let path = self.dir.join(name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|err| err.to_string())?;
+        }
+        std::fs::write(path, data).map_err(|err| err.to_string())
+    }
+
+    /// Downloads have no folders, so they are flattened into the name.
+    #[cfg(target_arch = "wasm32")]
+    pub fn write(&self, name: &str, data: &[u8]) -> Result<(), String> {
+        save(&format!("{}_{}", self.prefix, name.replace('/', "_")), data)
+    }
+
+    /// Logs where the export went once every file is written.
+    pub fn finish(self) {
+        #[cfg(not(target_arch = "wasm32"))]
+        log::info!("Exported {}", self.dir.display());
+        #[cfg(target_arch = "wasm32")]
+        log::info!("Exported {}", self.prefix);
+    }
+}
+
+/// Hands `data` to the user as a file named `name`: natively through a save dialog starting
+/// with that name, and on the web as a download. Cancelling the dialog is not an error.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn save(name: &str, data: &[u8]) -> Result<(), String> {
+    let mut dialog = rfd::FileDialog::new().set_file_name(name);
+    if let Some(extension) = std::path::Path::new(name)
+        .extension()
+        .and_then(|extension| extension.to_str())
+    {
+        dialog = dialog.add_filter(extension.to_uppercase(), &[extension]);
+    }
+    let Some(path) = dialog.save_file() else {
+        return Ok(());
+    };
+    std::fs::write(&path, data).map_err(|err| err.to_string())?;
+    log::info!("Exported {}", path.display());
+    Ok(())
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn save(name: &str, data: &[u8]) -> Result<(), String> {
+    use eframe::wasm_bindgen::JsCast as _;
+
+    let js_error = |err: eframe::wasm_bindgen::JsValue| format!("{err:?}");
+    let parts = js_sys::Array::of1(&js_sys::Uint8Array::from(data));
+    let blob = web_sys::Blob::new_with_u8_array_sequence(&parts).map_err(js_error)?;
+    let url = web_sys::Url::create_object_url_with_blob(&blob).map_err(js_error)?;
+    let anchor = web_sys::window()
+        .and_then(|window| window.document())
+        .ok_or("No document")?
+        .create_element("a")
+        .map_err(js_error)?
+        .dyn_into::<web_sys::HtmlAnchorElement>()
+        .map_err(|_| "Not an anchor element")?;
+    anchor.set_href(&url);
+    anchor.set_download(name);
+    anchor.click();
+    web_sys::Url::revoke_object_url(&url).map_err(js_error)
+}
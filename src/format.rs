@@ -0,0 +1,80 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::structure::DrawNode;
+
+/// Starts data in the binary format. RON text never starts with it, so either format can be
+/// read without knowing which one was written.
+const BINARY_MAGIC: &[u8] = b"TICB\x01";
+
+/// How paintings and their trees are written out.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum SaveFormat {
+    /// Readable text, but large and slow to parse for deep trees.
+    #[default]
+    Ron,
+    /// MessagePack, with structs written as maps so fields can still be added and defaulted.
+    Binary,
+}
+
+impl SaveFormat {
+    pub const ALL: [SaveFormat; 2] = [SaveFormat::Ron, SaveFormat::Binary];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SaveFormat::Ron => "RON",
+            SaveFormat::Binary => "Binary",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            SaveFormat::Ron => "ron",
+            SaveFormat::Binary => "ticb",
+        }
+    }
+
+    /// The format whose files end in `extension`, ignoring case.
+    pub fn from_extension(extension: &str) -> Option<SaveFormat> {
+        SaveFormat::ALL
+            .into_iter()
+            .find(|format| format.extension().eq_ignore_ascii_case(extension))
+    }
+
+    pub fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            SaveFormat::Ron => {
+                let mut out = Vec::new();
+                let mut serializer = ron::ser::Serializer::with_options(
+                    &mut out,
+                    None,
+                    ron::Options::default().without_recursion_limit(),
+                )
+                .map_err(|err| err.to_string())?;
+                value
+                    .serialize(serde_stacker::Serializer::new(&mut serializer))
+                    .map_err(|err| err.to_string())?;
+                Ok(out)
+            }
+            SaveFormat::Binary => {
+                let mut out = BINARY_MAGIC.to_vec();
+                let mut serializer = rmp_serde::Serializer::new(&mut out).with_struct_map();
+                value
+                    .serialize(serde_stacker::Serializer::new(&mut serializer))
+                    .map_err(|err| err.to_string())?;
+                Ok(out)
+            }
+        }
+    }
+}
+
+/// Reads data written by `SaveFormat::encode` in either format.
+pub fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T, String> {
+    let Some(data) = data.strip_prefix(BINARY_MAGIC) else {
+        let data = std::str::from_utf8(data).map_err(|err| err.to_string())?;
+        return DrawNode::read_ron(data).map_err(|err| err.to_string());
+    };
+    let mut deserializer = rmp_serde::Deserializer::from_read_ref(data);
+    deserializer.set_max_depth(usize::MAX);
+    T::deserialize(serde_stacker::Deserializer::new(&mut deserializer))
+        .map_err(|err| err.to_string())
+}
@@ -0,0 +1,246 @@
+use egui::{pos2, vec2, Pos2, Vec2};
+use itertools::Itertools;
+
+/// An affine map of the plane, taking `p` to `p.x * x_axis + p.y * y_axis + translation`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Affine2 {
+    pub x_axis: Vec2,
+    pub y_axis: Vec2,
+    pub translation: Vec2,
+}
+
+impl Affine2 {
+    pub const IDENTITY: Affine2 = Affine2 {
+        x_axis: Vec2::X,
+        y_axis: Vec2::Y,
+        translation: Vec2::ZERO,
+    };
+
+    pub fn from_translation(translation: Vec2) -> Self {
+        Self {
+            translation,
+            ..Self::IDENTITY
+        }
+    }
+
+    pub fn from_scale(scale: Vec2) -> Self {
+        Self {
+            x_axis: vec2(scale.x, 0.0),
+            y_axis: vec2(0.0, scale.y),
+            translation: Vec2::ZERO,
+        }
+    }
+
+    /// Rotation by `angle` radians, clockwise on screen since y points down.
+    pub fn from_angle(angle: f32) -> Self {
+        let (sin, cos) = angle.sin_cos();
+        Self {
+            x_axis: vec2(cos, sin),
+            y_axis: vec2(-sin, cos),
+            translation: Vec2::ZERO,
+        }
+    }
+
+    /// The same map applied around `pivot` instead of the origin.
+    pub fn about(&self, pivot: Pos2) -> Self {
+        Self::from_translation(-pivot.to_vec2())
+            .then(self)
+            .then(&Self::from_translation(pivot.to_vec2()))
+    }
+
+    /// Applies `self` and then `next`.
+    pub fn then(&self, next: &Affine2) -> Self {
+        Self {
+            x_axis: next.transform_vector(self.x_axis),
+            y_axis: next.transform_vector(self.y_axis),
+            translation: next.transform_point(self.translation.to_pos2()).to_vec2(),
+        }
+    }
+
+    /// The map undoing this one. Degenerate maps have no inverse and give non-finite values.
+    pub fn inverse(&self) -> Self {
+        let det = self.determinant();
+        let x_axis = vec2(self.y_axis.y, -self.x_axis.y) / det;
+        let y_axis = vec2(-self.y_axis.x, self.x_axis.x) / det;
+        let inverse = Self {
+            x_axis,
+            y_axis,
+            translation: Vec2::ZERO,
+        };
+        Self {
+            translation: -inverse.transform_vector(self.translation),
+            ..inverse
+        }
+    }
+
+    pub fn transform_point(&self, point: Pos2) -> Pos2 {
+        pos2(0.0, 0.0) + self.transform_vector(point.to_vec2()) + self.translation
+    }
+
+    pub fn transform_vector(&self, vector: Vec2) -> Vec2 {
+        vector.x * self.x_axis + vector.y * self.y_axis
+    }
+
+    fn determinant(&self) -> f32 {
+        self.x_axis.x * self.y_axis.y - self.x_axis.y * self.y_axis.x
+    }
+
+    /// How much the map scales lengths on average, for widths and text sizes.
+    pub fn scale_factor(&self) -> f32 {
+        self.determinant().abs().sqrt()
+    }
+
+    /// Whether the map only moves points, without scaling or rotating them.
+    pub fn is_translation(&self) -> bool {
+        self.x_axis == Vec2::X && self.y_axis == Vec2::Y
+    }
+}
+
+/// Ramer–Douglas–Peucker simplification of a closed polygon, dropping points that lie within
+/// `tolerance` of the simplified outline.
+pub fn simplify_polygon(points: &[Pos2], tolerance: f32) -> Vec<Pos2> {
+    if points.len() <= 4 {
+        return points.to_vec();
+    }
+    // Split the loop at the point farthest from the first one so both halves are open chains.
+    let far = (1..points.len())
+        .max_by(|a, b| {
+            points[0]
+                .distance_sq(points[*a])
+                .total_cmp(&points[0].distance_sq(points[*b]))
+        })
+        .unwrap();
+    let mut result = simplify_polyline(&points[..=far], tolerance);
+    result.pop();
+    let mut second_half = points[far..].to_vec();
+    second_half.push(points[0]);
+    result.extend(simplify_polyline(&second_half, tolerance));
+    result.pop();
+    result
+}
+
+/// Ramer–Douglas–Peucker simplification of an open polyline. The end points are always kept.
+pub fn simplify_polyline(points: &[Pos2], tolerance: f32) -> Vec<Pos2> {
+    simplified_indices(points, tolerance)
+        .into_iter()
+        .map(|i| points[i])
+        .collect()
+}
+
+/// Indices, in order, of the points `simplify_polyline` keeps.
+pub fn simplified_indices(points: &[Pos2], tolerance: f32) -> Vec<usize> {
+    if points.len() <= 2 {
+        return (0..points.len()).collect();
+    }
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+    let mut ranges = vec![(0, points.len() - 1)];
+    while let Some((start, end)) = ranges.pop() {
+        let (farthest, distance) = (start + 1..end)
+            .map(|i| (i, segment_distance(points[i], points[start], points[end])))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap_or((start, 0.0));
+        if distance > tolerance {
+            keep[farthest] = true;
+            ranges.push((start, farthest));
+            ranges.push((farthest, end));
+        }
+    }
+    (0..points.len()).filter(|i| keep[*i]).collect()
+}
+
+/// Distance from `p` to the closest point of the segment from `a` to `b`.
+pub fn segment_distance(p: Pos2, a: Pos2, b: Pos2) -> f32 {
+    let ab = b - a;
+    let length_sq = ab.length_sq();
+    if length_sq == 0.0 {
+        return p.distance(a);
+    }
+    let t = ((p - a).dot(ab) / length_sq).clamp(0.0, 1.0);
+    p.distance(a + t * ab)
+}
+
+/// Distance from `p` to the closest point of the polyline through `points`.
+pub fn polyline_distance(p: Pos2, points: &[Pos2]) -> f32 {
+    match points {
+        [] => f32::INFINITY,
+        [point] => p.distance(*point),
+        _ => points
+            .iter()
+            .tuple_windows()
+            .map(|(a, b)| segment_distance(p, *a, *b))
+            .fold(f32::INFINITY, f32::min),
+    }
+}
+
+/// Whether `p` lies inside a polygon, by the even-odd rule.
+pub fn polygon_contains(points: &[Pos2], p: Pos2) -> bool {
+    let mut inside = false;
+    for (a, b) in points.iter().circular_tuple_windows() {
+        if (a.y > p.y) != (b.y > p.y) && p.x < a.x + (p.y - a.y) / (b.y - a.y) * (b.x - a.x) {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+/// Twice the signed area of a polygon, positive when its points wind clockwise on screen.
+pub fn signed_area(points: &[Pos2]) -> f32 {
+    let n = points.len();
+    (0..n)
+        .map(|i| {
+            let (a, b) = (points[i], points[(i + 1) % n]);
+            a.x * b.y - b.x * a.y
+        })
+        .sum()
+}
+
+/// Ear clipping triangulation of a simple polygon, returning indices into `points`.
+pub fn triangulate(points: &[Pos2]) -> Vec<u32> {
+    let mut remaining: Vec<u32> = (0..points.len() as u32).collect();
+    if signed_area(points) < 0.0 {
+        remaining.reverse();
+    }
+    let mut indices = Vec::with_capacity(3 * points.len().saturating_sub(2));
+    let mut misses = 0;
+    let mut i = 0;
+    while remaining.len() > 3 {
+        let n = remaining.len();
+        let (a, b, c) = (
+            remaining[(i + n - 1) % n],
+            remaining[i % n],
+            remaining[(i + 1) % n],
+        );
+        let (pa, pb, pc) = (points[a as usize], points[b as usize], points[c as usize]);
+        let is_ear = cross(pa, pb, pc) > 0.0
+            && !remaining.iter().any(|&other| {
+                other != a
+                    && other != b
+                    && other != c
+                    && inside_triangle(points[other as usize], pa, pb, pc)
+            });
+        // A polygon that has stopped yielding ears is degenerate, so clip anyway rather than
+        // looping forever.
+        if is_ear || misses > n {
+            indices.extend([a, b, c]);
+            remaining.remove(i % n);
+            misses = 0;
+        } else {
+            i += 1;
+            misses += 1;
+        }
+    }
+    if remaining.len() == 3 {
+        indices.extend(remaining);
+    }
+    indices
+}
+
+fn cross(a: Pos2, b: Pos2, c: Pos2) -> f32 {
+    (b - a).x * (c - b).y - (b - a).y * (c - b).x
+}
+
+fn inside_triangle(p: Pos2, a: Pos2, b: Pos2, c: Pos2) -> bool {
+    cross(a, b, p) >= 0.0 && cross(b, c, p) >= 0.0 && cross(c, a, p) >= 0.0
+}
@@ -0,0 +1,275 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use chrono::{DateTime, Local, Utc};
+use egui::Ui;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::{oplog::Op, structure::StrokeId};
+
+/// Repeats of a change by the same author within this many seconds are kept as one event, so
+/// dragging a selection is not recorded once per frame.
+const COALESCE_SECONDS: i64 = 5;
+
+/// Most events listed for a selection.
+const MAX_LISTED_EVENTS: usize = 50;
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectChange {
+    Created,
+    Moved,
+    /// Scaled or rotated.
+    Transformed,
+    Recolored,
+    /// Given a new width, or the pen's style by the restyle tool.
+    Restyled,
+    TextEdited,
+    /// Brought to the front or sent to the back.
+    Reordered,
+    /// Changed in a way not told apart from the others, such as by erasing part of it.
+    Edited,
+    Deleted,
+}
+
+impl ObjectChange {
+    fn name(&self) -> &'static str {
+        match self {
+            ObjectChange::Created => "Created",
+            ObjectChange::Moved => "Moved",
+            ObjectChange::Transformed => "Scaled or rotated",
+            ObjectChange::Recolored => "Recolored",
+            ObjectChange::Restyled => "Restyled",
+            ObjectChange::TextEdited => "Text edited",
+            ObjectChange::Reordered => "Reordered",
+            ObjectChange::Edited => "Edited",
+            ObjectChange::Deleted => "Deleted",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ObjectEvent {
+    pub change: ObjectChange,
+    /// Unix timestamp in seconds.
+    pub time: i64,
+    pub author: String,
+}
+
+/// Who changed each object and when, keyed by object id, as found in the changes recorded in
+/// the operation log. Strokes drawn before objects had ids have no history.
+#[derive(Deserialize, Serialize, Default)]
+#[serde(default)]
+pub struct ObjectHistory {
+    /// Name recorded with the changes made here.
+    pub author: String,
+    objects: BTreeMap<StrokeId, Vec<ObjectEvent>>,
+}
+
+impl ObjectHistory {
+    /// Records what `ops` did to each object, as made by `author`, or here if None. Objects
+    /// they add are recorded as created by whoever drew them, once, as strokes drawn a segment
+    /// at a time are added once per segment. Objects they remove and do not add back are
+    /// recorded as deleted, and those they otherwise change as `change`.
+    pub fn follow(&mut self, ops: &[Op], change: ObjectChange, author: Option<&str>) {
+        let mut added = BTreeMap::new();
+        let mut updated = BTreeSet::new();
+        let mut removed = BTreeSet::new();
+        for op in ops {
+            match op {
+                Op::Add { strokes, .. } | Op::Set { strokes, .. } => {
+                    for stroke in strokes {
+                        added.entry(stroke.id).or_insert(stroke.author.as_deref());
+                    }
+                }
+                Op::Update { stroke, .. } => {
+                    updated.insert(stroke.id);
+                }
+                Op::Remove { ids, .. } => removed.extend(ids.iter().copied()),
+                Op::Grow => {}
+            }
+        }
+        let author = author.unwrap_or(&self.author).to_owned();
+        let now = Utc::now().timestamp();
+        let ids: BTreeSet<StrokeId> = added
+            .keys()
+            .chain(&updated)
+            .chain(&removed)
+            .copied()
+            .collect();
+        for id in ids {
+            if id == 0 {
+                continue;
+            }
+            let (event, by) = match added.get(&id) {
+                None if !updated.contains(&id) => (ObjectChange::Deleted, author.as_str()),
+                Some(drawn_by) if !updated.contains(&id) && !removed.contains(&id) => {
+                    (ObjectChange::Created, drawn_by.unwrap_or(&author))
+                }
+                _ => (change, author.as_str()),
+            };
+            self.record(id, event, by, now);
+        }
+    }
+
+    fn record(&mut self, id: StrokeId, change: ObjectChange, author: &str, now: i64) {
+        let events = self.objects.entry(id).or_default();
+        if change == ObjectChange::Created && !events.is_empty() {
+            return;
+        }
+        match events.last_mut() {
+            Some(last)
+                if last.change == change
+                    && last.author == author
+                    && now - last.time < COALESCE_SECONDS =>
+            {
+                last.time = now;
+            }
+            _ => events.push(ObjectEvent {
+                change,
+                time: now,
+                author: author.to_owned(),
+            }),
+        }
+    }
+
+    /// The last `count` changes to any object, oldest first, one per line. Authors are left out.
+    pub fn recent(&self, count: usize) -> String {
+        let events = self
+            .objects
+            .iter()
+            .flat_map(|(id, events)| {
+                events
+                    .iter()
+                    .map(move |event| (event.time, *id, event.change))
+            })
+            .sorted_by_key(|(time, id, _)| (*time, *id))
+            .collect_vec();
+        events[events.len().saturating_sub(count)..]
+            .iter()
+            .map(|(time, id, change)| format!("{time}\t{id:016x}\t{}\n", change.name()))
+            .collect()
+    }
+
+    /// Lists the changes to the objects in `ids`, newest first. Each change to a group is
+    /// listed once, however many objects it touched.
+    pub fn ui(&mut self, ui: &mut Ui, ids: impl IntoIterator<Item = StrokeId>) {
+        ui.horizontal(|ui| {
+            ui.label("Your name");
+            ui.text_edit_singleline(&mut self.author)
+                .on_hover_text("Recorded with the changes you make");
+        });
+        let events = ids
+            .into_iter()
+            .filter_map(|id| self.objects.get(&id))
+            .flatten()
+            .map(|event| (event.time, event.change.name(), event.author.as_str()))
+            .sorted_by(|a, b| b.cmp(a))
+            .dedup()
+            .take(MAX_LISTED_EVENTS)
+            .collect_vec();
+        if events.is_empty() {
+            ui.weak("No recorded changes");
+            return;
+        }
+        egui::Grid::new("object_history")
+            .num_columns(3)
+            .striped(true)
+            .show(ui, |ui| {
+                for (time, change, author) in events {
+                    let time = DateTime::<Utc>::from_timestamp(time, 0)
+                        .map(|time| {
+                            time.with_timezone(&Local)
+                                .format("%Y-%m-%d %H:%M:%S")
+                                .to_string()
+                        })
+                        .unwrap_or_default();
+                    ui.label(time);
+                    ui.label(change);
+                    if author.is_empty() {
+                        ui.weak("Unknown");
+                    } else {
+                        ui.label(author);
+                    }
+                    ui.end_row();
+                }
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use egui::{pos2, Color32};
+
+    use super::*;
+    use crate::{
+        drawables::FilledPolygon,
+        structure::{StrokeEntry, StrokeMeta, StrokePriority},
+    };
+
+    fn stroke(id: StrokeId, author: &str) -> StrokeEntry {
+        let points = [pos2(0.0, 0.0), pos2(0.1, 0.0), pos2(0.0, 0.1)];
+        StrokeMeta {
+            order: id,
+            priority: StrokePriority::Ink,
+            layer: 0,
+            created: 0,
+            author: Some(author.to_owned()),
+            id,
+        }
+        .entry(Box::new(FilledPolygon::new(&points, Color32::RED)))
+    }
+
+    fn add(ids: &[StrokeId], author: &str) -> Op {
+        Op::Add {
+            path: vec![],
+            strokes: ids.iter().map(|id| stroke(*id, author)).collect(),
+        }
+    }
+
+    fn remove(ids: &[StrokeId]) -> Op {
+        Op::Remove {
+            path: vec![],
+            indices: (0..ids.len()).collect(),
+            ids: ids.to_vec(),
+        }
+    }
+
+    fn changes(history: &ObjectHistory, id: StrokeId) -> Vec<(&str, &str)> {
+        history.objects[&id]
+            .iter()
+            .map(|event| (event.change.name(), event.author.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn follows_objects_through_the_changes_made_to_them() {
+        let mut history = ObjectHistory {
+            author: "me".to_owned(),
+            ..Default::default()
+        };
+        // Drawn a segment at a time.
+        history.follow(&[add(&[1, 2], "me")], ObjectChange::Edited, None);
+        history.follow(&[add(&[1], "me")], ObjectChange::Edited, None);
+        // Moved by removing and adding again.
+        history.follow(&[remove(&[1]), add(&[1], "me")], ObjectChange::Moved, None);
+        history.follow(&[remove(&[2])], ObjectChange::Edited, None);
+        history.follow(
+            &[Op::Update {
+                path: vec![],
+                index: 0,
+                stroke: stroke(1, "me"),
+            }],
+            ObjectChange::Edited,
+            Some("them"),
+        );
+        // Arrived from someone else, who drew it.
+        history.follow(&[add(&[3], "them")], ObjectChange::Edited, Some(""));
+
+        assert_eq!(
+            changes(&history, 1),
+            [("Created", "me"), ("Moved", "me"), ("Edited", "them"),]
+        );
+        assert_eq!(changes(&history, 2), [("Created", "me"), ("Deleted", "me")]);
+        assert_eq!(changes(&history, 3), [("Created", "them")]);
+    }
+}
@@ -0,0 +1,66 @@
+use std::collections::VecDeque;
+
+use egui::{vec2, Align2, Color32, FontId, Painter, Rect};
+
+/// How many recent frames the frame rate is averaged over.
+const ROLLING_FRAMES: usize = 60;
+
+/// What the canvas drew in one frame.
+pub struct FrameStats {
+    pub drawn: usize,
+    /// Strokes visited in the loaded nodes that were skipped for lying outside the view or being
+    /// too small to draw at the current quality. Strokes in nodes wholly outside the view are
+    /// not visited, so not counted.
+    pub culled: usize,
+    /// Depth of the center cell below the outermost node.
+    pub depth: usize,
+    /// Small nodes drawn from a cached render or thumbnail, and those that had to be rendered
+    /// again.
+    pub cache_hits: usize,
+    pub cache_misses: usize,
+    /// Nodes in view whose strokes are left for later frames, to keep within the frame budget.
+    pub pending: usize,
+}
+
+/// A small overlay of rendering statistics, averaged over recent frames where that makes sense.
+#[derive(Default)]
+pub struct PerfHud {
+    frame_times: VecDeque<f64>,
+}
+
+impl PerfHud {
+    pub fn record_frame(&mut self, time: f64) {
+        self.frame_times.push_back(time);
+        while self.frame_times.len() > ROLLING_FRAMES {
+            self.frame_times.pop_front();
+        }
+    }
+
+    fn fps(&self) -> Option<f64> {
+        let (first, last) = (self.frame_times.front()?, self.frame_times.back()?);
+        (last > first).then(|| (self.frame_times.len() - 1) as f64 / (last - first))
+    }
+
+    /// Draws the overlay in the top right corner of `rect`.
+    pub fn paint(&self, painter: &Painter, rect: Rect, stats: &FrameStats) {
+        let fps = self
+            .fps()
+            .map_or("-".to_string(), |fps| format!("{fps:.0}"));
+        let cached = stats.cache_hits + stats.cache_misses;
+        let hit_rate = if cached == 0 {
+            "-".to_string()
+        } else {
+            format!("{:.0}%", 100.0 * stats.cache_hits as f32 / cached as f32)
+        };
+        let text = format!(
+            "FPS {fps}\nDrawn {}\nCulled {}\nDepth {}\nCache {hit_rate} of {cached}\nPending {}",
+            stats.drawn, stats.culled, stats.depth, stats.pending
+        );
+        let galley = painter.layout_no_wrap(text, FontId::monospace(12.0), Color32::WHITE);
+        let padding = vec2(6.0, 4.0);
+        let background = Align2::RIGHT_TOP
+            .align_size_within_rect(galley.size() + 2.0 * padding, rect.shrink(8.0));
+        painter.rect_filled(background, 4.0, Color32::from_black_alpha(160));
+        painter.galley(background.min + padding, galley, Color32::WHITE);
+    }
+}
@@ -0,0 +1,185 @@
+use std::{cell::RefCell, collections::VecDeque, future::Future, pin::Pin};
+
+use eframe::wasm_bindgen::{closure::Closure, JsCast as _, JsValue};
+use js_sys::{Array, Promise, Uint8Array};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{IdbDatabase, IdbRequest, IdbTransaction, IdbTransactionMode};
+
+/// Object store of the chunks of a canvas, by key.
+pub const CHUNKS: &str = "chunks";
+/// Object store of everything else about a canvas, by name.
+pub const META: &str = "meta";
+
+/// Version of the databases' layout, raised whenever object stores are added.
+const VERSION: u32 = 1;
+
+/// Work done against a database in the background.
+type Work = Pin<Box<dyn Future<Output = ()>>>;
+
+thread_local! {
+    /// Work waiting its turn, and whether the work before it is still running.
+    static QUEUE: RefCell<(VecDeque<Work>, bool)> = RefCell::default();
+}
+
+/// Runs `work` once the work queued before it is done, so reads see the writes queued before
+/// them and writes land in the order they were queued.
+pub fn queue(work: impl Future<Output = ()> + 'static) {
+    let idle = QUEUE.with_borrow_mut(|(queued, running)| {
+        queued.push_back(Box::pin(work));
+        !std::mem::replace(running, true)
+    });
+    if !idle {
+        return;
+    }
+    wasm_bindgen_futures::spawn_local(async {
+        while let Some(work) = QUEUE.with_borrow_mut(|(queued, running)| {
+            let work = queued.pop_front();
+            *running = work.is_some();
+            work
+        }) {
+            work.await;
+        }
+    });
+}
+
+fn js_error(err: JsValue) -> String {
+    format!("{err:?}")
+}
+
+/// Opens the database `name` of the page's origin, creating it if there is none.
+pub async fn open(name: &str) -> Result<IdbDatabase, String> {
+    let factory = web_sys::window()
+        .and_then(|window| window.indexed_db().ok().flatten())
+        .ok_or("IndexedDB is not available")?;
+    let request = factory.open_with_u32(name, VERSION).map_err(js_error)?;
+    let upgrading = request.clone();
+    // Only called when the database is created, as there is one version so far.
+    let on_upgrade = Closure::once_into_js(move || {
+        let created = upgrading
+            .result()
+            .map(|db| db.unchecked_into::<IdbDatabase>())
+            .and_then(|db| {
+                db.create_object_store(CHUNKS)?;
+                db.create_object_store(META)
+            });
+        if let Err(err) = created {
+            log::error!("Failed to set up the canvas database: {err:?}");
+        }
+    });
+    request.set_onupgradeneeded(Some(on_upgrade.unchecked_ref()));
+    let db = finished(&request).await?;
+    Ok(db.unchecked_into())
+}
+
+/// Every key and value of `store` in `db`, in key order.
+pub async fn read_all(db: &IdbDatabase, store: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>, String> {
+    let transaction = db.transaction_with_str(store).map_err(js_error)?;
+    let object_store = transaction.object_store(store).map_err(js_error)?;
+    // Both are asked for at once, as the transaction ends when none are left outstanding.
+    let keys = finished(&object_store.get_all_keys().map_err(js_error)?);
+    let values = finished(&object_store.get_all().map_err(js_error)?);
+    let keys = Array::from(&keys.await?);
+    let values = Array::from(&values.await?);
+    Ok(keys
+        .iter()
+        .zip(values.iter())
+        .map(|(key, value)| {
+            (
+                Uint8Array::new(&key).to_vec(),
+                Uint8Array::new(&value).to_vec(),
+            )
+        })
+        .collect())
+}
+
+/// The value under each of `keys` in `store` of `db`, if there is one.
+pub async fn read(
+    db: &IdbDatabase,
+    store: &str,
+    keys: &[Vec<u8>],
+) -> Result<Vec<Option<Vec<u8>>>, String> {
+    let transaction = db.transaction_with_str(store).map_err(js_error)?;
+    let object_store = transaction.object_store(store).map_err(js_error)?;
+    // All are asked for at once, as the transaction ends when none are left outstanding.
+    let requests = keys
+        .iter()
+        .map(|key| {
+            let request = object_store
+                .get(&Uint8Array::from(&key[..]))
+                .map_err(js_error)?;
+            Ok(finished(&request))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let mut values = vec![];
+    for request in requests {
+        let value = request.await?;
+        values.push((!value.is_undefined()).then(|| Uint8Array::new(&value).to_vec()));
+    }
+    Ok(values)
+}
+
+/// Empties the object stores `clear` of the database `name`, then puts each value in `puts`
+/// under its key in the store named with it, all in one transaction.
+pub async fn write(
+    name: &str,
+    clear: &[&str],
+    puts: Vec<(&str, Vec<u8>, Vec<u8>)>,
+) -> Result<(), String> {
+    let db = open(name).await?;
+    let stores = Array::of2(&JsValue::from_str(CHUNKS), &JsValue::from_str(META));
+    let transaction = db
+        .transaction_with_str_sequence_and_mode(&stores, IdbTransactionMode::Readwrite)
+        .map_err(js_error)?;
+    for store in clear {
+        transaction
+            .object_store(store)
+            .and_then(|store| store.clear())
+            .map_err(js_error)?;
+    }
+    for (store, key, value) in puts {
+        transaction
+            .object_store(store)
+            .and_then(|store| {
+                store.put_with_key(&Uint8Array::from(&value[..]), &Uint8Array::from(&key[..]))
+            })
+            .map_err(js_error)?;
+    }
+    let result = committed(&transaction).await;
+    db.close();
+    result
+}
+
+/// The result of `request` once it succeeds. It is listened for from the call on, so other
+/// requests can be awaited first.
+fn finished(request: &IdbRequest) -> impl Future<Output = Result<JsValue, String>> {
+    let promise = Promise::new(&mut |resolve, reject| {
+        request.set_onsuccess(Some(&resolve));
+        request.set_onerror(Some(&reject));
+    });
+    let request = request.clone();
+    async move {
+        if JsFuture::from(promise).await.is_err() {
+            return Err(match request.error() {
+                Ok(Some(err)) => err.message(),
+                _ => "The request failed".to_string(),
+            });
+        }
+        request.result().map_err(js_error)
+    }
+}
+
+/// Waits for `transaction` to be written.
+async fn committed(transaction: &IdbTransaction) -> Result<(), String> {
+    let promise = Promise::new(&mut |resolve, reject| {
+        transaction.set_oncomplete(Some(&resolve));
+        transaction.set_onerror(Some(&reject));
+        transaction.set_onabort(Some(&reject));
+    });
+    if JsFuture::from(promise).await.is_err() {
+        return Err(match transaction.error() {
+            Some(err) => err.message(),
+            None => "The transaction was aborted".to_string(),
+        });
+    }
+    Ok(())
+}
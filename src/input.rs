@@ -0,0 +1,88 @@
+use egui::{PointerButton, Ui};
+use serde::{Deserialize, Serialize};
+
+/// What a mouse button other than the primary one does on the canvas.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MouseAction {
+    None,
+    /// Draws with the secondary color.
+    DrawSecondary,
+    Pan,
+    /// Picks up the color under the cursor as the active tool's color.
+    Eyedropper,
+}
+
+impl MouseAction {
+    const ALL: [MouseAction; 4] = [
+        MouseAction::None,
+        MouseAction::DrawSecondary,
+        MouseAction::Pan,
+        MouseAction::Eyedropper,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MouseAction::None => "Nothing",
+            MouseAction::DrawSecondary => "Draw with secondary color",
+            MouseAction::Pan => "Pan",
+            MouseAction::Eyedropper => "Eyedropper",
+        }
+    }
+}
+
+/// Actions bound to each mouse button. The primary button always uses the active tool.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Debug)]
+#[serde(default)]
+pub struct MouseMappings {
+    pub secondary: MouseAction,
+    pub middle: MouseAction,
+    pub extra1: MouseAction,
+    pub extra2: MouseAction,
+}
+
+impl Default for MouseMappings {
+    fn default() -> Self {
+        Self {
+            secondary: MouseAction::DrawSecondary,
+            middle: MouseAction::Pan,
+            extra1: MouseAction::None,
+            extra2: MouseAction::None,
+        }
+    }
+}
+
+impl MouseMappings {
+    /// Buttons bound to `action`.
+    pub fn buttons(&self, action: MouseAction) -> impl Iterator<Item = PointerButton> + '_ {
+        [
+            (PointerButton::Secondary, self.secondary),
+            (PointerButton::Middle, self.middle),
+            (PointerButton::Extra1, self.extra1),
+            (PointerButton::Extra2, self.extra2),
+        ]
+        .into_iter()
+        .filter(move |(_, bound)| *bound == action)
+        .map(|(button, _)| button)
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui) {
+        for (label, action) in [
+            ("Right button", &mut self.secondary),
+            ("Middle button", &mut self.middle),
+            ("Back button", &mut self.extra1),
+            ("Forward button", &mut self.extra2),
+        ] {
+            ui.menu_button(format!("{label}: {}", action.name()), |ui| {
+                for option in MouseAction::ALL {
+                    if ui.radio_value(action, option, option.name()).clicked() {
+                        ui.close_menu();
+                    }
+                }
+            });
+        }
+        ui.separator();
+        if ui.button("Reset to defaults").clicked() {
+            *self = Self::default();
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use std::fmt::Write;
+
+use chrono::Local;
+use egui::{pos2, vec2, Color32, Pos2, Rect, Ui, Vec2};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clipboard::{CopiedStroke, CopiedStrokes},
+    drawables::PathText,
+    structure::{GroupId, StrokePriority},
+};
+
+/// Space left between pages and above a page for its date, as a fraction of the page size.
+const PAGE_GAP: f32 = 0.05;
+
+/// Which side of the last page the next one is stamped on.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PageDirection {
+    #[default]
+    Right,
+    Down,
+    Left,
+    Up,
+}
+
+impl PageDirection {
+    const ALL: [PageDirection; 4] = [
+        PageDirection::Right,
+        PageDirection::Down,
+        PageDirection::Left,
+        PageDirection::Up,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            PageDirection::Right => "Right",
+            PageDirection::Down => "Down",
+            PageDirection::Left => "Left",
+            PageDirection::Up => "Up",
+        }
+    }
+
+    fn vector(&self) -> Vec2 {
+        match self {
+            PageDirection::Right => vec2(1.0, 0.0),
+            PageDirection::Down => vec2(0.0, 1.0),
+            PageDirection::Left => vec2(-1.0, 0.0),
+            PageDirection::Up => vec2(0.0, -1.0),
+        }
+    }
+}
+
+/// What the journal window asks the painting to do.
+#[derive(Clone, Copy)]
+pub enum JournalAction {
+    /// Use the selected strokes as the page template.
+    SetTemplate,
+    NewPage,
+}
+
+/// Pages stamped from a template, each labelled with the date it was added.
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct Journal {
+    template: Option<CopiedStrokes>,
+    direction: PageDirection,
+    /// chrono format string for the date above each page.
+    date_format: String,
+    date_color: Color32,
+    /// Group holding the most recently stamped page.
+    pub last_page: Option<GroupId>,
+}
+
+impl Default for Journal {
+    fn default() -> Self {
+        Self {
+            template: None,
+            direction: PageDirection::Right,
+            date_format: "%A %-d %B %Y".to_string(),
+            date_color: Color32::from_gray(40),
+            last_page: None,
+        }
+    }
+}
+
+impl Journal {
+    pub fn set_template(&mut self, template: CopiedStrokes) {
+        self.template = Some(template);
+    }
+
+    /// A fresh page dated today, in the template's coordinates. None until a template has
+    /// been set.
+    pub fn page(&self) -> Option<CopiedStrokes> {
+        let mut page = self.template.clone()?;
+        let bounds = page.bounds();
+        let now = Local::now();
+        let mut date = String::new();
+        if write!(date, "{}", now.format(&self.date_format)).is_err() {
+            // chrono reports invalid format strings when formatting.
+            date = now.format("%Y-%m-%d").to_string();
+        }
+        let baseline = bounds.min.y - PAGE_GAP / 2.0 * bounds.height();
+        page.strokes.push(CopiedStroke {
+            drawable: Box::new(PathText::new(
+                &[pos2(bounds.min.x, baseline), pos2(bounds.max.x, baseline)],
+                date,
+                PAGE_GAP * bounds.height(),
+                self.date_color,
+            )),
+            priority: StrokePriority::Ink,
+            group: None,
+        });
+        Some(page)
+    }
+
+    /// Where to center a page of `size` so it sits beside the page covering `last`.
+    pub fn next_center(&self, last: Rect, size: Vec2) -> Pos2 {
+        let direction = self.direction.vector();
+        let gap = PAGE_GAP * last.size().max(size);
+        last.center() + direction * (last.size() + size + 2.0 * gap) / 2.0
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui, has_selection: bool) -> Option<JournalAction> {
+        let mut action = None;
+        egui::Grid::new("journal").show(ui, |ui| {
+            ui.label("Template");
+            ui.horizontal(|ui| {
+                ui.label(match &self.template {
+                    Some(template) => format!("{} strokes", template.strokes.len()),
+                    None => "None".to_string(),
+                });
+                if ui
+                    .add_enabled(has_selection, egui::Button::new("Use selection"))
+                    .on_disabled_hover_text("Select the strokes making up a page first")
+                    .clicked()
+                {
+                    action = Some(JournalAction::SetTemplate);
+                }
+            });
+            ui.end_row();
+            ui.label("Next page");
+            egui::ComboBox::from_id_salt("page_direction")
+                .selected_text(self.direction.name())
+                .show_ui(ui, |ui| {
+                    for direction in PageDirection::ALL {
+                        ui.selectable_value(&mut self.direction, direction, direction.name());
+                    }
+                });
+            ui.end_row();
+            ui.label("Date format");
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(&mut self.date_format)
+                    .on_hover_text("chrono format, e.g. %Y-%m-%d");
+                ui.color_edit_button_srgba(&mut self.date_color);
+            });
+            ui.end_row();
+        });
+        ui.separator();
+        if ui
+            .add_enabled(self.template.is_some(), egui::Button::new("New page"))
+            .on_hover_text(
+                "Stamp a dated copy of the template beside the last page, or in the middle of \
+                 the view if the last page is not loaded",
+            )
+            .clicked()
+        {
+            action = Some(JournalAction::NewPage);
+        }
+        action
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::blend::BlendMode;
+
+/// Identifies a layer. Ids are never reused within a painting.
+pub type LayerId = u32;
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct Layer {
+    pub id: LayerId,
+    pub name: String,
+    /// Hidden layers are left out when collecting strokes to draw.
+    pub visible: bool,
+    /// Tools refuse to add to or change locked layers.
+    pub locked: bool,
+    pub opacity: f32,
+    pub blend: BlendMode,
+}
+
+impl Default for Layer {
+    fn default() -> Self {
+        Self {
+            id: 0,
+            name: String::new(),
+            visible: true,
+            locked: false,
+            opacity: 1.0,
+            blend: BlendMode::Normal,
+        }
+    }
+}
+
+/// The layers of a painting, ordered bottom to top.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct Layers {
+    layers: Vec<Layer>,
+    active: LayerId,
+    next_id: LayerId,
+    /// Layers ticked for the next export.
+    #[serde(skip)]
+    export: BTreeSet<LayerId>,
+}
+
+/// Changes to the painting requested from the layer list.
+pub enum LayerAction {
+    /// The layer has already been removed from the list and its strokes should be deleted.
+    Delete(LayerId),
+    /// Save an image of each of these layers.
+    Export(BTreeSet<LayerId>),
+}
+
+impl Default for Layers {
+    fn default() -> Self {
+        Self {
+            layers: vec![Layer {
+                id: 0,
+                name: "Layer 1".to_string(),
+                ..Default::default()
+            }],
+            active: 0,
+            next_id: 1,
+            export: BTreeSet::new(),
+        }
+    }
+}
+
+impl Layers {
+    pub fn active(&self) -> LayerId {
+        self.active
+    }
+
+    fn get(&self, id: LayerId) -> Option<&Layer> {
+        self.layers.iter().find(|layer| layer.id == id)
+    }
+
+    /// Strokes on unknown layers are treated as visible, unlocked and opaque.
+    pub fn is_visible(&self, id: LayerId) -> bool {
+        !matches!(self.get(id), Some(layer) if !layer.visible)
+    }
+
+    pub fn is_locked(&self, id: LayerId) -> bool {
+        self.get(id).is_some_and(|layer| layer.locked)
+    }
+
+    pub fn opacity(&self, id: LayerId) -> f32 {
+        self.get(id).map_or(1.0, |layer| layer.opacity)
+    }
+
+    pub fn blend(&self, id: LayerId) -> BlendMode {
+        self.get(id).map_or(BlendMode::Normal, |layer| layer.blend)
+    }
+
+    /// Position of a layer from the bottom, used as the most significant part of the draw order.
+    /// Strokes on unknown layers draw at the bottom.
+    pub fn rank(&self, id: LayerId) -> usize {
+        self.layers
+            .iter()
+            .position(|layer| layer.id == id)
+            .unwrap_or(0)
+    }
+
+    /// Adds the layers of `other` missing here on top, as those of a copy of the same painting.
+    pub fn add_missing(&mut self, other: &Layers) {
+        for layer in &other.layers {
+            if self.get(layer.id).is_none() {
+                self.layers.push(layer.clone());
+            }
+        }
+        self.next_id = self.next_id.max(other.next_id);
+    }
+
+    /// Adds a layer above the active one and makes it active.
+    pub fn add(&mut self) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let index = (self.rank(self.active) + 1).min(self.layers.len());
+        self.layers.insert(
+            index,
+            Layer {
+                id,
+                name: format!("Layer {}", id + 1),
+                ..Default::default()
+            },
+        );
+        self.active = id;
+    }
+
+    /// Removes a layer from the list. The caller is responsible for deleting its strokes.
+    fn remove(&mut self, id: LayerId) {
+        if self.layers.len() <= 1 {
+            return;
+        }
+        let index = self.rank(id);
+        self.layers.remove(index);
+        if self.active == id {
+            self.active = self.layers[index.saturating_sub(1)].id;
+        }
+    }
+
+    /// The name of a layer, or a made up one for unknown layers.
+    pub fn name(&self, id: LayerId) -> String {
+        self.get(id)
+            .map_or_else(|| format!("Layer {}", id + 1), |layer| layer.name.clone())
+    }
+
+    /// Shows the layer list top to bottom.
+    pub fn ui(&mut self, ui: &mut egui::Ui) -> Option<LayerAction> {
+        let mut action = None;
+        let mut delete = None;
+        let mut swap = None;
+        let count = self.layers.len();
+        if ui.button("New layer").clicked() {
+            self.add();
+        }
+        ui.separator();
+        egui::Grid::new("layers").striped(true).show(ui, |ui| {
+            for index in (0..count).rev() {
+                let layer = &mut self.layers[index];
+                ui.radio_value(&mut self.active, layer.id, "")
+                    .on_hover_text("Draw on this layer");
+                ui.toggle_value(&mut layer.visible, "👁")
+                    .on_hover_text("Show layer");
+                ui.toggle_value(&mut layer.locked, "🔒")
+                    .on_hover_text("Lock layer");
+                ui.add(egui::TextEdit::singleline(&mut layer.name).desired_width(120.0));
+                ui.add(
+                    egui::DragValue::new(&mut layer.opacity)
+                        .range(0.0..=1.0)
+                        .speed(0.01)
+                        .max_decimals(2),
+                )
+                .on_hover_text("Opacity");
+                egui::ComboBox::from_id_salt(("blend", layer.id))
+                    .width(80.0)
+                    .selected_text(layer.blend.name())
+                    .show_ui(ui, |ui| {
+                        for blend in BlendMode::ALL {
+                            ui.selectable_value(&mut layer.blend, blend, blend.name());
+                        }
+                    });
+                let mut export = self.export.contains(&layer.id);
+                if ui
+                    .checkbox(&mut export, "")
+                    .on_hover_text("Include in export")
+                    .changed()
+                {
+                    if export {
+                        self.export.insert(layer.id);
+                    } else {
+                        self.export.remove(&layer.id);
+                    }
+                }
+                if ui
+                    .add_enabled(index + 1 < count, egui::Button::new("⬆").small())
+                    .on_hover_text("Move up")
+                    .clicked()
+                {
+                    swap = Some((index, index + 1));
+                }
+                if ui
+                    .add_enabled(index > 0, egui::Button::new("⬇").small())
+                    .on_hover_text("Move down")
+                    .clicked()
+                {
+                    swap = Some((index, index - 1));
+                }
+                if ui
+                    .add_enabled(count > 1 && !layer.locked, egui::Button::new("🗑").small())
+                    .on_hover_text("Delete layer and its strokes")
+                    .clicked()
+                {
+                    delete = Some(layer.id);
+                }
+                ui.end_row();
+            }
+        });
+        if let Some((a, b)) = swap {
+            self.layers.swap(a, b);
+        }
+        if ui
+            .add_enabled(
+                !self.export.is_empty(),
+                egui::Button::new("Export ticked layers"),
+            )
+            .on_hover_text(
+                "Save an image of each ticked layer, as the image export settings ask for",
+            )
+            .clicked()
+        {
+            action = Some(LayerAction::Export(self.export.clone()));
+        }
+        if let Some(id) = delete {
+            self.remove(id);
+            self.export.remove(&id);
+            action = Some(LayerAction::Delete(id));
+        }
+        action
+    }
+}
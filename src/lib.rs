@@ -1,7 +1,57 @@
 #![warn(clippy::all, rust_2018_idioms)]
 
 mod app;
+mod blend;
+mod bug_report;
+mod canvas_view;
+mod chunks;
 mod circular_buffer;
+mod clipboard;
+mod clusters;
+mod collab;
+mod collect;
+mod connection;
+#[cfg(target_arch = "wasm32")]
+mod deep_link;
+mod drawables;
+mod excalidraw;
+mod export;
+mod format;
+mod geometry;
+mod history;
+mod hud;
+#[cfg(target_arch = "wasm32")]
+mod indexed_db;
+mod input;
+mod integrity;
+mod journal;
+mod layers;
+mod lod;
+mod merge;
+mod oplog;
+mod ordering;
+mod paging;
 mod painting;
+mod palette;
+mod pdf;
+mod picking;
+mod presets;
+mod quality;
+mod raster;
+mod recolor;
+#[cfg(not(target_arch = "wasm32"))]
+mod recovery;
+mod selection;
+mod sessions;
+mod settings;
+mod stress;
 mod structure;
+mod svg;
+mod version_history;
+mod viewport;
+#[cfg(target_arch = "wasm32")]
+mod web_file;
+mod world;
 pub use app::TemplateApp;
+pub use canvas_view::CanvasView;
+pub use painting::Painting;
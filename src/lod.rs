@@ -0,0 +1,148 @@
+use std::collections::BTreeMap;
+
+use egui::{emath::RectTransform, vec2, Color32, Painter, Rect, Rgba};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    layers::{LayerId, Layers},
+    structure::{Property, StrokeEntry, NODE_BOUNDS},
+};
+
+/// Strokes of one layer in a subtree, reduced to where they are and their average color.
+#[derive(Clone, Copy, Deserialize, Serialize)]
+struct LayerSummary {
+    /// Union of the strokes' bounds in the node's coordinates.
+    bounds: Rect,
+    color: Rgba,
+    count: usize,
+}
+
+impl LayerSummary {
+    fn merge(&mut self, other: &LayerSummary) {
+        let total = (self.count + other.count) as f32;
+        self.color =
+            self.color * (self.count as f32 / total) + other.color * (other.count as f32 / total);
+        self.bounds = self.bounds.union(other.bounds);
+        self.count += other.count;
+    }
+}
+
+/// What a node and its descendants hold, drawn in their place once the node is too small on
+/// screen for its strokes to be told apart.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct NodeSummary {
+    layers: BTreeMap<LayerId, LayerSummary>,
+    /// Nodes in the subtree, the node itself included.
+    #[serde(default)]
+    nodes: usize,
+    /// Shallowest and deepest levels below the node holding strokes, the node itself being
+    /// level 0.
+    #[serde(default)]
+    depths: Option<(u32, u32)>,
+}
+
+impl NodeSummary {
+    /// Summarizes `strokes` stored in a node whose children are summarized by `children`, each
+    /// given with its corner.
+    pub fn new<'a>(
+        strokes: &[StrokeEntry],
+        children: impl IntoIterator<Item = ((usize, usize), &'a NodeSummary)>,
+    ) -> Self {
+        let mut summary = NodeSummary {
+            nodes: 1,
+            depths: (!strokes.is_empty()).then_some((0, 0)),
+            ..Default::default()
+        };
+        for stroke in strokes {
+            summary.add(
+                stroke.layer,
+                LayerSummary {
+                    bounds: stroke.drawable.bounds(),
+                    color: stroke_color(stroke).into(),
+                    count: 1,
+                },
+            );
+        }
+        for ((x, y), child) in children {
+            summary.nodes += child.nodes;
+            if let Some((min, max)) = child.depths {
+                summary.depths = Some(summary.depths.map_or((min + 1, max + 1), |(low, high)| {
+                    (low.min(min + 1), high.max(max + 1))
+                }));
+            }
+            let offset = vec2(x as f32 - 0.5, y as f32 - 0.5);
+            for (layer, child) in &child.layers {
+                let bounds = Rect::from_min_max(
+                    child.bounds.min / 2.0 + offset,
+                    child.bounds.max / 2.0 + offset,
+                );
+                summary.add(*layer, LayerSummary { bounds, ..*child });
+            }
+        }
+        summary
+    }
+
+    fn add(&mut self, layer: LayerId, summary: LayerSummary) {
+        match self.layers.get_mut(&layer) {
+            Some(existing) => existing.merge(&summary),
+            None => {
+                self.layers.insert(layer, summary);
+            }
+        }
+    }
+
+    /// Strokes in the subtree.
+    pub fn stroke_count(&self) -> usize {
+        self.layers.values().map(|layer| layer.count).sum()
+    }
+
+    /// Nodes in the subtree, the node itself included, as of when it was summarized. Empty
+    /// nodes come and go without the summary changing, so this is only kept exact for paged
+    /// out subtrees.
+    pub fn node_count(&self) -> usize {
+        self.nodes
+    }
+
+    pub fn set_node_count(&mut self, nodes: usize) {
+        self.nodes = nodes;
+    }
+
+    /// Shallowest and deepest levels below the node holding strokes, the node itself being
+    /// level 0. `None` for an empty subtree.
+    pub fn depth_range(&self) -> Option<(u32, u32)> {
+        self.depths
+    }
+
+    /// Layers with strokes in the subtree.
+    pub fn layers(&self) -> impl Iterator<Item = LayerId> + '_ {
+        self.layers.keys().copied()
+    }
+
+    /// Fills the bounds of each visible layer's strokes with their average color, for a node
+    /// covering `screen_rect`.
+    pub fn paint(&self, painter: &Painter, layers: &Layers, screen_rect: Rect) {
+        let to_screen = RectTransform::from_to(NODE_BOUNDS, screen_rect);
+        for (layer, summary) in self
+            .layers
+            .iter()
+            .filter(|(layer, _)| layers.is_visible(**layer))
+        {
+            let color = Color32::from(summary.color).gamma_multiply(layers.opacity(*layer));
+            painter.rect_filled(to_screen.transform_rect(summary.bounds), 0.0, color);
+        }
+    }
+}
+
+/// The first color among a stroke's properties, or gray for drawables without one.
+fn stroke_color(stroke: &StrokeEntry) -> Color32 {
+    // Properties hand out mutable references, so they are read from a copy.
+    let mut drawable = stroke.drawable.clone();
+    drawable
+        .properties()
+        .into_iter()
+        .find_map(|property| match property {
+            Property::Color(color) => Some(*color),
+            _ => None,
+        })
+        .unwrap_or(Color32::GRAY)
+}
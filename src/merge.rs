@@ -0,0 +1,818 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet},
+    hash::{BuildHasher, Hash, Hasher},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use chrono::Utc;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ordering,
+    structure::{CanvasTree, GroupId, NodeId, StrokeEntry, StrokeId},
+};
+
+/// Ids handed out so far, mixed into new ones so those made within the same instant differ.
+static ISSUED: AtomicU64 = AtomicU64::new(0);
+
+/// A new random id for an object, never 0.
+pub fn new_stroke_id() -> StrokeId {
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_i64(Utc::now().timestamp_nanos_opt().unwrap_or_default());
+    hasher.write_u64(ISSUED.fetch_add(1, Ordering::Relaxed));
+    // The standard library has no randomness to seed its hashers with in the browser.
+    #[cfg(target_arch = "wasm32")]
+    hasher.write_u64(js_sys::Math::random().to_bits());
+    hasher.finish().max(1)
+}
+
+/// The id of each object and when each last changed, so copies of a painting edited apart can
+/// be merged object by object, keeping the latest change to each.
+#[derive(Deserialize, Serialize, Default, Clone)]
+#[serde(default)]
+pub struct Versions {
+    /// Keyed by stroke order.
+    ids: BTreeMap<u64, StrokeId>,
+    /// Unix timestamp in milliseconds of the last change to each object. Deleted objects are
+    /// kept, so a deletion wins over older changes made to another copy.
+    changed: BTreeMap<StrokeId, i64>,
+}
+
+impl Versions {
+    /// The id of the object drawn with `order`, which is given one the first time, noting that
+    /// it changed.
+    pub fn created(&mut self, order: u64) -> StrokeId {
+        let id = *self.ids.entry(order).or_insert_with(new_stroke_id);
+        self.changed.insert(id, Utc::now().timestamp_millis());
+        id
+    }
+
+    /// The id of the object drawn with `order`, if it has one.
+    pub fn id(&self, order: u64) -> Option<StrokeId> {
+        self.ids.get(&order).copied()
+    }
+
+    /// Notes that the objects in `orders` were changed or deleted just now.
+    pub fn touch(&mut self, orders: impl IntoIterator<Item = u64>) {
+        let now = Utc::now().timestamp_millis();
+        for order in orders {
+            if let Some(id) = self.ids.get(&order) {
+                self.changed.insert(*id, now);
+            }
+        }
+    }
+
+    /// Notes that the object `stroke` belongs to arrived just now, as changed elsewhere.
+    pub fn arrived(&mut self, stroke: &StrokeEntry) {
+        if stroke.id != 0 {
+            self.ids.insert(stroke.order, stroke.id);
+            self.changed
+                .insert(stroke.id, Utc::now().timestamp_millis());
+        }
+    }
+
+    /// Follows objects given new orders, as returned by `ordering::move_orders`.
+    pub fn renumber(&mut self, renumbered: &BTreeMap<u64, u64>) {
+        // Removed first, since an object's new order can be another's old one.
+        let moved = renumbered
+            .iter()
+            .filter_map(|(old, new)| Some((*new, self.ids.remove(old)?)))
+            .collect_vec();
+        self.ids.extend(moved);
+    }
+
+    /// Follows every object given new orders, as returned by `ordering::normalize`, forgetting
+    /// the orders of those missing from `renumbered`.
+    pub fn renumber_all(&mut self, renumbered: &BTreeMap<u64, u64>) {
+        self.ids = renumbered
+            .iter()
+            .filter_map(|(old, new)| Some((*new, self.ids.remove(old)?)))
+            .collect();
+    }
+
+    fn changed(&self, id: StrokeId) -> i64 {
+        self.changed.get(&id).copied().unwrap_or(0)
+    }
+}
+
+/// The strokes of an object, each with the path to its node in the order
+/// `CanvasTree::get_or_create_path` takes it.
+pub type Placed = Vec<(Vec<(u8, u8)>, StrokeEntry)>;
+
+/// How many objects a merge took from the other copy, and how many of this one's it removed.
+pub struct Merged {
+    pub added: usize,
+    pub removed: usize,
+    /// Objects kept here or deleted here over an older deletion or version in the other copy.
+    /// The other copy lacks these changes.
+    pub kept: usize,
+    /// Objects both copies hold differently, left as they are here until resolved, with paths
+    /// from the outermost node.
+    pub conflicts: Vec<Conflict>,
+}
+
+/// An object two copies of a painting hold differently.
+#[derive(Clone)]
+pub struct Conflict {
+    pub id: StrokeId,
+    /// The object as this copy holds it.
+    pub mine: Placed,
+    /// The object as the other copy holds it, or nothing if it deleted it.
+    pub theirs: Placed,
+}
+
+/// Conflicts not yet resolved, as found by merges and collaboration.
+#[derive(Default)]
+pub struct Conflicts {
+    pub objects: Vec<Conflict>,
+    /// The outermost node of the tree the paths start from.
+    root: NodeId,
+}
+
+impl Conflicts {
+    /// Adds `found`, with paths from the current outermost node of `tree`, in place of any
+    /// earlier conflicts over the same objects.
+    pub fn add(&mut self, tree: &CanvasTree, found: Vec<Conflict>) {
+        if self.objects.is_empty() {
+            self.root = tree.root();
+        }
+        self.rebase(tree);
+        let ids = found
+            .iter()
+            .map(|conflict| conflict.id)
+            .collect::<BTreeSet<_>>();
+        self.objects.retain(|conflict| !ids.contains(&conflict.id));
+        self.objects.extend(found);
+    }
+
+    /// Extends the paths to the current outermost node of `tree`, after it grew outward.
+    pub fn rebase(&mut self, tree: &CanvasTree) {
+        if self.root == tree.root() || !tree.contains(self.root) {
+            return;
+        }
+        let (root, above) = tree.get_top_level_and_path(self.root);
+        for conflict in &mut self.objects {
+            for (path, _) in conflict.mine.iter_mut().chain(&mut conflict.theirs) {
+                path.extend(&above);
+            }
+        }
+        self.root = root;
+    }
+}
+
+/// How another copy of a painting differs from this one, object by object.
+pub struct Diff {
+    /// The strokes of objects the other copy has and this one lacks or holds differently, with
+    /// paths from `root`.
+    pub added: Placed,
+    /// Objects this copy has and the other lacks or holds differently.
+    pub removed: BTreeSet<StrokeId>,
+    /// How many objects are in both copies but differ, and so are in both `added` and
+    /// `removed`.
+    pub changed: usize,
+    /// The outermost node of this copy's tree the paths start from.
+    root: NodeId,
+}
+
+impl Diff {
+    /// How many objects the other copy adds, changes and lacks.
+    pub fn counts(&self) -> (usize, usize, usize) {
+        let added = self
+            .added
+            .iter()
+            .map(|(_, stroke)| object_id(stroke))
+            .collect::<BTreeSet<_>>();
+        (
+            added.len() - self.changed,
+            self.changed,
+            self.removed.len() - self.changed,
+        )
+    }
+
+    /// Extends the paths of the added strokes to the current outermost node of `tree`, after
+    /// it grew outward.
+    pub fn rebase(&mut self, tree: &CanvasTree) {
+        if self.root == tree.root() || !tree.contains(self.root) {
+            return;
+        }
+        let (root, above) = tree.get_top_level_and_path(self.root);
+        for (path, _) in &mut self.added {
+            path.extend(&above);
+        }
+        self.root = root;
+    }
+}
+
+/// Merges `their_tree`, the tree of another copy of the painting whose tree is `tree`, into it.
+/// Objects only one copy holds end up as in the copy that changed them last, so a deletion wins
+/// over older changes, and either copy merged into the other gives the same objects. Objects
+/// both hold differently are left as they are here and returned as conflicts. Objects new to
+/// this copy are given orders from `next_order` and groups from `next_group`, keeping their
+/// stacking. Both trees must be paged in.
+///
+/// Strokes drawn before objects had ids are told apart by their content, so one edited in
+/// either copy is kept in both versions.
+pub fn merge(
+    tree: &mut CanvasTree,
+    versions: &mut Versions,
+    next_order: &mut u64,
+    next_group: &mut GroupId,
+    mut their_tree: CanvasTree,
+    their_versions: &Versions,
+) -> Merged {
+    let mut ours = objects(tree, tree.root());
+    let mut theirs = objects(&their_tree, their_tree.root());
+    let root = tree.root();
+    align(tree, &ours, &mut their_tree, &mut theirs);
+    if tree.root() != root {
+        ours = objects(tree, tree.root());
+    }
+
+    let mut removed = BTreeSet::new();
+    let mut taken = vec![];
+    let mut kept = 0;
+    let mut conflicts = vec![];
+    for id in ours
+        .keys()
+        .chain(theirs.keys())
+        .copied()
+        .collect::<BTreeSet<_>>()
+    {
+        let (our_time, their_time) = (versions.changed(id), their_versions.changed(id));
+        match (ours.get(&id), theirs.remove(&id)) {
+            (Some(_), None) => {
+                if their_time > our_time {
+                    removed.insert(id);
+                } else {
+                    kept += 1;
+                }
+            }
+            (None, Some(placed)) => {
+                if their_time >= our_time {
+                    taken.push((id, placed));
+                } else {
+                    kept += 1;
+                }
+            }
+            (Some(mine), Some(placed)) => {
+                if digest(&placed) != digest(mine) {
+                    conflicts.push(Conflict {
+                        id,
+                        mine: mine.clone(),
+                        theirs: placed,
+                    });
+                }
+            }
+            (None, None) => {}
+        }
+    }
+
+    let merged = Merged {
+        added: taken.len(),
+        removed: removed.len(),
+        kept,
+        conflicts,
+    };
+    replace(
+        tree, &ours, &removed, taken, versions, next_order, next_group,
+    );
+
+    for (id, time) in &their_versions.changed {
+        let changed = versions.changed.entry(*id).or_default();
+        *changed = (*changed).max(*time);
+    }
+    merged
+}
+
+/// Makes the changes that turned `base` into `tree` to `target` as well, object by object,
+/// where `target` is `base` as changed elsewhere since. Objects changed both here and there end
+/// up as they are in `tree`. Without a `base`, the objects `target` lacks are added to it, and
+/// those it has are left as they are there. Objects new to `target` are given orders from
+/// `next_order` and groups from `next_group`, beyond any `target` uses. The trees must have
+/// grown outward as far, and be paged in. Returns the objects changed in both, with paths from
+/// the outermost node.
+pub fn rebase(
+    target: &mut CanvasTree,
+    base: Option<&CanvasTree>,
+    tree: &CanvasTree,
+    versions: &mut Versions,
+    next_order: &mut u64,
+    next_group: &mut GroupId,
+) -> Vec<Conflict> {
+    let theirs = objects(target, target.root());
+    let mut ours = objects(tree, tree.root());
+    for (_, stroke) in theirs.values().flatten() {
+        *next_order = (*next_order).max(stroke.order + 1);
+        if let Some(group) = stroke.group {
+            *next_group = (*next_group).max(group + 1);
+        }
+    }
+
+    let mut removed = BTreeSet::new();
+    let mut taken = vec![];
+    let mut conflicts = vec![];
+    match base.map(|base| objects(base, base.root())) {
+        None => taken.extend(ours.into_iter().filter(|(id, _)| !theirs.contains_key(id))),
+        Some(before) => {
+            for id in before
+                .keys()
+                .chain(ours.keys())
+                .copied()
+                .collect::<BTreeSet<_>>()
+            {
+                let old = before.get(&id).map(digest);
+                let Some(mine) = ours.remove(&id) else {
+                    removed.insert(id);
+                    continue;
+                };
+                if old == Some(digest(&mine)) {
+                    continue;
+                }
+                if old.is_some() && theirs.get(&id).map(digest) != old {
+                    conflicts.push(Conflict {
+                        id,
+                        mine: mine.clone(),
+                        theirs: theirs.get(&id).cloned().unwrap_or_default(),
+                    });
+                }
+                removed.insert(id);
+                taken.push((id, mine));
+            }
+        }
+    }
+    replace(
+        target, &theirs, &removed, taken, versions, next_order, next_group,
+    );
+    conflicts
+}
+
+/// Settles `conflict` over an object of `tree`, leaving it as the other copy holds it if
+/// `theirs`, or else as it is here, and noting that it changed just now. The tree must be paged
+/// in, and the paths of the conflict start from its outermost node.
+pub fn resolve(
+    tree: &mut CanvasTree,
+    conflict: Conflict,
+    theirs: bool,
+    versions: &mut Versions,
+    next_order: &mut u64,
+    next_group: &mut GroupId,
+) {
+    versions
+        .changed
+        .insert(conflict.id, Utc::now().timestamp_millis());
+    if !theirs {
+        return;
+    }
+    let ours = objects(tree, tree.root());
+    let removed = BTreeSet::from([conflict.id]);
+    let taken = if conflict.theirs.is_empty() {
+        vec![]
+    } else {
+        vec![(conflict.id, conflict.theirs)]
+    };
+    replace(
+        tree, &ours, &removed, taken, versions, next_order, next_group,
+    );
+}
+
+/// Removes the objects in `removed` from `tree`, whose objects are `ours`, and adds those in
+/// `taken`. Objects `tree` had keep their place in the stacking and their group, and new ones
+/// are given orders from `next_order`, keeping their stacking, and groups from `next_group`.
+fn replace(
+    tree: &mut CanvasTree,
+    ours: &BTreeMap<StrokeId, Placed>,
+    removed: &BTreeSet<StrokeId>,
+    mut taken: Vec<(StrokeId, Placed)>,
+    versions: &mut Versions,
+    next_order: &mut u64,
+    next_group: &mut GroupId,
+) {
+    let root = tree.root();
+    tree.retain_strokes(root, &|stroke| !removed.contains(&object_id(stroke)));
+    taken.sort_by_key(|(_, placed)| placed[0].1.order);
+    let mut groups = BTreeMap::new();
+    let mut placing: BTreeMap<Vec<(u8, u8)>, Vec<StrokeEntry>> = BTreeMap::new();
+    for (id, placed) in taken {
+        // Replaced objects keep their place in the stacking and their group.
+        let (order, group) = match ours.get(&id) {
+            Some(mine) => (mine[0].1.order, mine[0].1.group),
+            None => {
+                let order = *next_order;
+                *next_order += ordering::ORDER_SPACING;
+                let group = placed[0].1.group.map(|group| {
+                    *groups.entry(group).or_insert_with(|| {
+                        *next_group += 1;
+                        *next_group - 1
+                    })
+                });
+                (order, group)
+            }
+        };
+        if placed[0].1.id != 0 {
+            versions.ids.insert(order, id);
+        }
+        for (path, mut stroke) in placed {
+            stroke.order = order;
+            stroke.group = group;
+            placing.entry(path).or_default().push(stroke);
+        }
+    }
+    for (mut path, strokes) in placing {
+        let node = tree.get_or_create_path(&mut path, root);
+        tree.insert_strokes(node, strokes);
+    }
+}
+
+/// Compares `their_tree`, the tree of another copy of the painting whose tree is `tree`, with
+/// it, matching objects by id. `tree` is grown if the other copy grew further outward. Both
+/// trees must be paged in.
+pub fn diff(tree: &mut CanvasTree, mut their_tree: CanvasTree) -> Diff {
+    let ours = objects(tree, tree.root());
+    let mut theirs = objects(&their_tree, their_tree.root());
+    align(tree, &ours, &mut their_tree, &mut theirs);
+
+    let mut diff = Diff {
+        added: vec![],
+        removed: BTreeSet::new(),
+        changed: 0,
+        root: tree.root(),
+    };
+    for (id, mine) in &ours {
+        match theirs.remove(id) {
+            None => {
+                diff.removed.insert(*id);
+            }
+            Some(placed) if digest(&placed) != digest(mine) => {
+                diff.removed.insert(*id);
+                diff.added.extend(placed);
+                diff.changed += 1;
+            }
+            Some(_) => {}
+        }
+    }
+    diff.added.extend(theirs.into_values().flatten());
+    diff
+}
+
+/// Grows the shallower of `tree` and `their_tree`, the trees of two copies of a painting, to
+/// match the other, as the paths to the objects both hold agree. Both trees must be paged in.
+pub fn align_trees(tree: &mut CanvasTree, their_tree: &mut CanvasTree) {
+    let ours = objects(tree, tree.root());
+    let mut theirs = objects(their_tree, their_tree.root());
+    align(tree, &ours, their_tree, &mut theirs);
+}
+
+/// Grows the shallower of `tree` and `their_tree` to match the other, as either copy may have
+/// grown outward since they parted, extending the paths in `theirs` to match. `ours` and
+/// `theirs` are the objects of each.
+fn align(
+    tree: &mut CanvasTree,
+    ours: &BTreeMap<StrokeId, Placed>,
+    their_tree: &mut CanvasTree,
+    theirs: &mut BTreeMap<StrokeId, Placed>,
+) {
+    let offset = depth_offset(ours, theirs);
+    if offset < 0 {
+        for _ in 0..-offset {
+            tree.get_or_create_parent(tree.root());
+        }
+    } else if offset > 0 {
+        let old_root = their_tree.root();
+        for _ in 0..offset {
+            their_tree.get_or_create_parent(their_tree.root());
+        }
+        let (_, above) = their_tree.get_top_level_and_path(old_root);
+        for (path, _) in theirs.values_mut().flatten() {
+            path.extend(&above);
+        }
+    }
+}
+
+/// Every object of the tree below `root`, by id.
+fn objects(tree: &CanvasTree, root: NodeId) -> BTreeMap<StrokeId, Placed> {
+    let mut objects: BTreeMap<_, Placed> = BTreeMap::new();
+    let mut pending = vec![(root, vec![])];
+    while let Some((node, path)) = pending.pop() {
+        for stroke in tree[node].own_strokes() {
+            objects
+                .entry(object_id(stroke))
+                .or_default()
+                .push((path.clone(), stroke.clone()));
+        }
+        for ((x, y), child) in tree[node].child_nodes() {
+            let mut child_path = vec![(x as u8, y as u8)];
+            child_path.extend(&path);
+            pending.push((child, child_path));
+        }
+    }
+    objects
+}
+
+/// The id of the object `stroke` belongs to. Strokes drawn before objects had ids are each
+/// their own object, identified by their content.
+pub fn object_id(stroke: &StrokeEntry) -> StrokeId {
+    if stroke.id != 0 {
+        return stroke.id;
+    }
+    let mut hasher = DefaultHasher::new();
+    ron::to_string(&(&stroke.drawable, stroke.created, stroke.layer))
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    hasher.finish().max(1)
+}
+
+/// Orders the versions of an object when both copies changed it at the same moment.
+fn digest(placed: &Placed) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for (path, stroke) in placed {
+        path.hash(&mut hasher);
+        ron::to_string(&(
+            &stroke.drawable,
+            stroke.priority,
+            stroke.layer,
+            stroke.group,
+        ))
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// How many levels deeper `ours` is than `theirs`, as the paths to the objects both hold agree
+/// on most often, or 0 if they hold none in common.
+fn depth_offset(ours: &BTreeMap<StrokeId, Placed>, theirs: &BTreeMap<StrokeId, Placed>) -> i32 {
+    let mut votes: BTreeMap<i32, usize> = BTreeMap::new();
+    for (id, placed) in ours {
+        let Some(other) = theirs.get(id) else {
+            continue;
+        };
+        let (ours, theirs) = (&placed[0].0, &other[0].0);
+        // Levels added above a tree go at the end of the paths.
+        let offset = if ours.starts_with(theirs) {
+            ours.len() as i32 - theirs.len() as i32
+        } else if theirs.starts_with(ours) {
+            -(theirs.len() as i32 - ours.len() as i32)
+        } else {
+            continue;
+        };
+        *votes.entry(offset).or_default() += 1;
+    }
+    votes
+        .into_iter()
+        .max_by_key(|(offset, count)| (*count, -offset.abs()))
+        .map_or(0, |(offset, _)| offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use egui::{pos2, vec2, Color32, Pos2};
+
+    use super::*;
+    use crate::{
+        drawables::FilledPolygon,
+        geometry::Affine2,
+        oplog,
+        structure::{StrokeMeta, StrokePriority},
+    };
+
+    /// One copy of a painting, as far as merging goes.
+    struct Replica {
+        tree: CanvasTree,
+        versions: Versions,
+        next_order: u64,
+        next_group: GroupId,
+    }
+
+    impl Replica {
+        fn new() -> Self {
+            Self {
+                tree: CanvasTree::default(),
+                versions: Versions::default(),
+                next_order: ordering::ORDER_SPACING,
+                next_group: 0,
+            }
+        }
+
+        /// Draws a new object at `at`, changed at `time`, returning its id.
+        fn draw(&mut self, at: Pos2, time: i64) -> StrokeId {
+            let order = self.next_order;
+            self.next_order += ordering::ORDER_SPACING;
+            let id = self.versions.created(order);
+            self.versions.changed.insert(id, time);
+            let points = [at, at + vec2(0.1, 0.0), at + vec2(0.0, 0.1)];
+            let stroke = StrokeMeta {
+                order,
+                priority: StrokePriority::Ink,
+                layer: 0,
+                created: 0,
+                author: None,
+                id,
+            }
+            .entry(Box::new(FilledPolygon::new(&points, Color32::RED)));
+            let root = self.tree.root();
+            self.tree.insert_strokes(root, vec![stroke]);
+            id
+        }
+
+        /// Moves the object `id` by `offset`, changed at `time`.
+        fn nudge(&mut self, id: StrokeId, offset: f32, time: i64) {
+            let nudge = Affine2::from_translation(vec2(offset, 0.0));
+            let root = self.tree.root();
+            self.tree.update_strokes(root, 0, &mut |stroke, _| {
+                if stroke.id != id {
+                    return false;
+                }
+                stroke.drawable.transform(&nudge);
+                true
+            });
+            self.versions.changed.insert(id, time);
+        }
+
+        fn delete(&mut self, id: StrokeId, time: i64) {
+            let root = self.tree.root();
+            self.tree.retain_strokes(root, &|stroke| stroke.id != id);
+            self.versions.changed.insert(id, time);
+        }
+
+        /// Another copy holding the same objects.
+        fn fork(&self) -> Self {
+            let (corner, ops) = oplog::snapshot(&self.tree);
+            Self {
+                tree: oplog::replay(corner, &ops),
+                versions: self.versions.clone(),
+                next_order: self.next_order,
+                next_group: self.next_group,
+            }
+        }
+
+        fn merge(&mut self, other: &Replica) -> Merged {
+            merge(
+                &mut self.tree,
+                &mut self.versions,
+                &mut self.next_order,
+                &mut self.next_group,
+                other.fork().tree,
+                &other.versions,
+            )
+        }
+
+        fn ids(&self) -> BTreeSet<StrokeId> {
+            objects(&self.tree, self.tree.root()).into_keys().collect()
+        }
+
+        fn object(&self, id: StrokeId) -> Option<Placed> {
+            objects(&self.tree, self.tree.root()).remove(&id)
+        }
+
+        fn hash(&self) -> Option<u64> {
+            self.tree.update_hashes(self.tree.root())
+        }
+    }
+
+    /// A copy with two objects, and another copy of it.
+    fn forked() -> (Replica, Replica, [StrokeId; 2]) {
+        let mut mine = Replica::new();
+        let first = mine.draw(pos2(-0.5, -0.5), 1);
+        let second = mine.draw(pos2(0.5, 0.5), 1);
+        let theirs = mine.fork();
+        (mine, theirs, [first, second])
+    }
+
+    #[test]
+    fn merging_a_copy_with_itself_changes_nothing() {
+        let (mut mine, theirs, _) = forked();
+        let hash = mine.hash();
+        let merged = mine.merge(&theirs);
+        assert_eq!((merged.added, merged.removed, merged.kept), (0, 0, 0));
+        assert!(merged.conflicts.is_empty());
+        assert_eq!(mine.hash(), hash);
+    }
+
+    #[test]
+    fn objects_added_in_either_copy_end_up_in_both() {
+        let (mut mine, mut theirs, _) = forked();
+        let ours = mine.draw(pos2(0.2, -0.6), 2);
+        let added = theirs.draw(pos2(-0.6, 0.2), 3);
+
+        let merged = mine.merge(&theirs);
+        assert_eq!((merged.added, merged.removed, merged.kept), (1, 0, 1));
+        assert!(merged.conflicts.is_empty());
+        // Taken in as it is there, with an order of this copy's.
+        assert_eq!(
+            mine.object(added).map(|placed| digest(&placed)),
+            theirs.object(added).map(|placed| digest(&placed))
+        );
+
+        let merged = theirs.merge(&mine);
+        assert_eq!((merged.added, merged.removed), (1, 0));
+        assert_eq!(mine.ids(), theirs.ids());
+        assert!(mine.ids().contains(&ours));
+    }
+
+    #[test]
+    fn deletions_win_over_older_changes() {
+        let (mut mine, mut theirs, [first, second]) = forked();
+        mine.nudge(first, 0.1, 2);
+        theirs.delete(first, 3);
+        // Changed here after the other copy deleted it, so it stays.
+        theirs.delete(second, 2);
+        mine.nudge(second, 0.1, 3);
+
+        let merged = mine.merge(&theirs);
+        assert_eq!((merged.added, merged.removed, merged.kept), (0, 1, 1));
+        assert!(merged.conflicts.is_empty());
+        assert_eq!(mine.ids(), BTreeSet::from([second]));
+    }
+
+    #[test]
+    fn objects_both_copies_changed_are_conflicts() {
+        let (mut mine, mut theirs, [first, second]) = forked();
+        mine.nudge(first, 0.1, 2);
+        theirs.nudge(first, -0.1, 3);
+        let before = mine.object(first).unwrap();
+
+        let mut merged = mine.merge(&theirs);
+        assert_eq!(merged.conflicts.len(), 1);
+        let conflict = merged.conflicts.pop().unwrap();
+        assert_eq!(conflict.id, first);
+        assert_eq!(digest(&conflict.mine), digest(&before));
+        assert_eq!(
+            digest(&conflict.theirs),
+            digest(&theirs.object(first).unwrap())
+        );
+        // Left as it is here until resolved.
+        assert_eq!(digest(&mine.object(first).unwrap()), digest(&before));
+        assert!(mine.object(second).is_some());
+
+        resolve(
+            &mut mine.tree,
+            conflict,
+            true,
+            &mut mine.versions,
+            &mut mine.next_order,
+            &mut mine.next_group,
+        );
+        let resolved = mine.object(first).unwrap();
+        assert_eq!(digest(&resolved), digest(&theirs.object(first).unwrap()));
+        // Keeps its place in the stacking.
+        assert_eq!(resolved[0].1.order, before[0].1.order);
+    }
+
+    #[test]
+    fn objects_added_with_the_same_order_keep_apart() {
+        let (mut mine, mut theirs, _) = forked();
+        let ours = mine.draw(pos2(0.2, -0.6), 2);
+        let added = theirs.draw(pos2(-0.6, 0.2), 2);
+        let order = |copy: &Replica, id| copy.object(id).unwrap()[0].1.order;
+        assert_eq!(order(&mine, ours), order(&theirs, added));
+
+        mine.merge(&theirs);
+        assert_ne!(order(&mine, ours), order(&mine, added));
+        let orders = objects(&mine.tree, mine.tree.root())
+            .values()
+            .map(|placed| placed[0].1.order)
+            .collect::<BTreeSet<_>>();
+        assert_eq!(orders.len(), 4);
+        assert!(orders.iter().all(|order| *order < mine.next_order));
+    }
+
+    #[test]
+    fn diff_counts_added_changed_and_removed_objects() {
+        let (mut mine, mut theirs, [first, second]) = forked();
+        theirs.nudge(first, 0.1, 2);
+        theirs.delete(second, 2);
+        theirs.draw(pos2(0.0, 0.0), 2);
+        let diff = diff(&mut mine.tree, theirs.fork().tree);
+        assert_eq!(diff.counts(), (1, 1, 1));
+        assert_eq!(diff.removed, BTreeSet::from([first, second]));
+    }
+
+    #[test]
+    fn rebasing_makes_changes_over_others_and_reports_overlaps() {
+        let (base, mut target, [first, second]) = forked();
+        let mut mine = base.fork();
+        mine.nudge(first, 0.1, 2);
+        mine.delete(second, 2);
+        let added = mine.draw(pos2(0.0, 0.0), 2);
+        target.nudge(first, -0.1, 3);
+        let elsewhere = target.draw(pos2(0.3, 0.3), 3);
+
+        let conflicts = rebase(
+            &mut target.tree,
+            Some(&base.tree),
+            &mine.tree,
+            &mut mine.versions,
+            &mut mine.next_order,
+            &mut mine.next_group,
+        );
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].id, first);
+        // Changes made here win over the ones made elsewhere.
+        assert_eq!(
+            digest(&target.object(first).unwrap()),
+            digest(&mine.object(first).unwrap())
+        );
+        assert_eq!(target.ids(), BTreeSet::from([first, added, elsewhere]));
+    }
+}
@@ -0,0 +1,573 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Range,
+    sync::{Arc, Mutex},
+};
+
+use egui::Rect;
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::{
+    merge::new_stroke_id,
+    structure::{CanvasTree, NodeId, StrokeEntry, StrokeId},
+};
+
+/// A change to the strokes of a `CanvasTree`, recorded as it is made. Paths lead from the
+/// outermost node at the time, in the order `CanvasTree::get_or_create_path` takes them.
+#[derive(Deserialize, Serialize, Clone)]
+pub enum Op {
+    /// Strokes added to a node as they are, after which it is subdivided if it holds too many.
+    Add {
+        path: Vec<(u8, u8)>,
+        strokes: Vec<StrokeEntry>,
+    },
+    /// The strokes at `indices`, in ascending order, removed from those stored directly in a
+    /// node. Erasing removes strokes, and moving one removes it before adding it again.
+    Remove {
+        path: Vec<(u8, u8)>,
+        indices: Vec<usize>,
+        /// Ids of the strokes removed, for the history of each object. Missing from changes
+        /// recorded before removals noted them.
+        #[serde(default)]
+        ids: Vec<StrokeId>,
+    },
+    /// The stroke at `index` of those stored directly in a node replaced with `stroke`.
+    Update {
+        path: Vec<(u8, u8)>,
+        index: usize,
+        stroke: StrokeEntry,
+    },
+    /// The strokes stored directly in a node replaced with `strokes`, which stay as they are,
+    /// bringing a copy of the tree in line with another.
+    Set {
+        path: Vec<(u8, u8)>,
+        strokes: Vec<StrokeEntry>,
+    },
+    /// A new outermost node added around the old one.
+    Grow,
+}
+
+/// Where a tree records its changes, shared with the `OpLog` keeping them.
+#[derive(Clone, Default)]
+pub struct OpSink(Arc<Mutex<Vec<Op>>>);
+
+impl OpSink {
+    pub fn push(&self, op: Op) {
+        self.0.lock().unwrap().push(op);
+    }
+
+    /// The changes recorded since the last call.
+    pub fn take(&self) -> Vec<Op> {
+        std::mem::take(&mut self.0.lock().unwrap())
+    }
+}
+
+/// Every change made to the strokes of a painting, in order. Once the tree is saved the changes
+/// it holds are forgotten, keeping only their number, so that a journal or collaborator can
+/// tell which changes it still lacks. Only the log's identity is saved with the painting; the
+/// changes since the last save are in the change journal.
+#[derive(Deserialize, Default)]
+#[serde(from = "SerializedOpLog")]
+pub struct OpLog {
+    /// Tells logs apart, so changes recorded in one are never applied after another's. 0 until
+    /// the log starts.
+    id: u64,
+    /// How many changes were forgotten since the log started.
+    compacted: usize,
+    /// Levels the tree had grown outward before the changes held, counted from where the first
+    /// log of the painting started.
+    grown: u32,
+    /// Corner of the outermost node when the log started, if the tree was empty then and no
+    /// change has been forgotten since, so that it can be rebuilt from the changes alone.
+    start: Option<(u8, u8)>,
+    ops: OpSink,
+}
+
+/// What is saved of an `OpLog`: every change it recorded counts as forgotten once loaded, as the
+/// saved tree holds them.
+#[derive(Deserialize, Serialize, Default)]
+#[serde(default)]
+struct SerializedOpLog {
+    id: u64,
+    /// Missing from paintings saved with their changes, which are counted instead.
+    count: Option<usize>,
+    grown: u32,
+    #[serde(skip_serializing)]
+    ops: Vec<Op>,
+}
+
+impl From<SerializedOpLog> for OpLog {
+    fn from(value: SerializedOpLog) -> Self {
+        Self {
+            id: value.id,
+            compacted: value.count.unwrap_or(value.ops.len()),
+            grown: value.grown + grown(&value.ops),
+            start: None,
+            ops: OpSink::default(),
+        }
+    }
+}
+
+impl Serialize for OpLog {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SerializedOpLog {
+            id: self.id,
+            count: Some(self.count()),
+            grown: self.root_level(),
+            ops: vec![],
+        }
+        .serialize(serializer)
+    }
+}
+
+impl OpLog {
+    /// Records the changes made to `tree` from now on, starting the log if it has not started.
+    /// Called every frame, as the tree may have been replaced since.
+    pub fn record(&mut self, tree: &mut CanvasTree) {
+        if self.id == 0 {
+            self.id = new_stroke_id();
+            let root = tree.root();
+            self.start = (!tree.has_content(root)).then_some(tree[root].corner);
+        }
+        tree.record_ops(&self.ops);
+    }
+
+    /// Forgets the changes recorded so far, which the tree as saved holds.
+    pub fn compact(&mut self) {
+        let mut ops = self.ops.0.lock().unwrap();
+        self.grown += grown(ops.iter());
+        self.compacted += ops.len();
+        ops.clear();
+        self.start = None;
+    }
+
+    /// How many changes were recorded since the log started, forgotten ones included.
+    pub fn count(&self) -> usize {
+        self.compacted + self.ops.0.lock().unwrap().len()
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// How many of the changes recorded are held, rather than forgotten.
+    pub fn held(&self) -> usize {
+        self.ops.0.lock().unwrap().len()
+    }
+
+    /// The changes recorded from the one at `index` on, or None if some were forgotten.
+    pub fn ops_since(&self, index: usize) -> Option<Vec<Op>> {
+        self.ops_in(index..self.count())
+    }
+
+    /// The changes recorded at `range`, or None if some were forgotten.
+    pub fn ops_in(&self, range: Range<usize>) -> Option<Vec<Op>> {
+        let start = range.start.checked_sub(self.compacted)?;
+        let end = range.end.checked_sub(self.compacted)?;
+        self.ops
+            .0
+            .lock()
+            .unwrap()
+            .get(start..end)
+            .map(|ops| ops.to_vec())
+    }
+
+    /// Levels the outermost node is above the one the painting's first log started with.
+    pub fn root_level(&self) -> u32 {
+        self.grown + grown(self.ops.0.lock().unwrap().iter())
+    }
+
+    /// Rebuilds the tree from the log and describes how it differs from `tree`, which must be
+    /// paged in. Only a log holding every change since the canvas was empty can be rebuilt
+    /// from.
+    pub fn check(&self, tree: &CanvasTree) -> String {
+        let Some(corner) = self.start else {
+            return "The log does not reach back to an empty canvas".to_string();
+        };
+        let rebuilt = replay(corner, self.ops.0.lock().unwrap().iter());
+        let (ours, replayed) = (
+            node_strokes(tree, tree.root()),
+            node_strokes(&rebuilt, rebuilt.root()),
+        );
+        let differing = ours
+            .iter()
+            .filter(|(path, (_, own))| replayed.get(*path).map(|(_, own)| own) != Some(own))
+            .count()
+            + replayed
+                .keys()
+                .filter(|path| !ours.contains_key(*path))
+                .count();
+        if differing == 0 {
+            format!("Rebuilding from {} changes matches", self.held())
+        } else {
+            format!("{differing} nodes differ after rebuilding from the changes")
+        }
+    }
+}
+
+/// Changes that build the strokes `tree` holds, node by node, starting from a tree whose only
+/// node is at the returned corner. `tree` must be paged in.
+pub fn snapshot(tree: &CanvasTree) -> ((u8, u8), Vec<Op>) {
+    let root = tree.root();
+    let mut ops = vec![];
+    let mut pending = vec![(root, vec![])];
+    while let Some((node, path)) = pending.pop() {
+        let strokes = tree[node].own_strokes();
+        if !strokes.is_empty() {
+            ops.push(Op::Add {
+                path: path.clone(),
+                strokes: strokes.to_vec(),
+            });
+        }
+        for ((x, y), child) in tree[node].child_nodes() {
+            let mut child_path = vec![(x as u8, y as u8)];
+            child_path.extend(&path);
+            pending.push((child, child_path));
+        }
+    }
+    (tree[root].corner, ops)
+}
+
+/// How many levels `ops` grow a tree outward.
+pub fn grown<'a>(ops: impl IntoIterator<Item = &'a Op>) -> u32 {
+    ops.into_iter().filter(|op| matches!(op, Op::Grow)).count() as u32
+}
+
+/// The path to a node whose strokes changed, with the bounds of the strokes it gained, or None
+/// where it only lost some.
+pub type Touched = (Vec<(u8, u8)>, Option<Rect>);
+
+/// Where `ops` changed strokes. Changes before the tree last grew are left out, as their paths
+/// start from an older outermost node.
+pub fn touched<'a>(ops: impl IntoIterator<Item = &'a Op>) -> Vec<Touched> {
+    let mut touched = vec![];
+    for op in ops {
+        let (path, strokes) = match op {
+            Op::Add { path, strokes } | Op::Set { path, strokes } => (path, strokes.iter()),
+            Op::Update { path, stroke, .. } => (path, std::slice::from_ref(stroke).iter()),
+            Op::Remove { path, .. } => (path, [].iter()),
+            Op::Grow => {
+                touched.clear();
+                continue;
+            }
+        };
+        let bounds = strokes
+            .map(|stroke| stroke.drawable.bounds())
+            .reduce(Rect::union);
+        touched.push((path.clone(), bounds));
+    }
+    touched
+}
+
+/// A tree built by making `ops` to one whose only node is at `corner`.
+pub fn replay<'a>(corner: (u8, u8), ops: impl IntoIterator<Item = &'a Op>) -> CanvasTree {
+    let mut tree = CanvasTree::default();
+    let root = tree.root();
+    tree[root].corner = corner;
+    for op in ops {
+        apply(&mut tree, op);
+    }
+    tree
+}
+
+/// Makes the change `op` to `tree`, which is recorded again if the tree records its changes.
+pub fn apply(tree: &mut CanvasTree, op: &Op) {
+    match op {
+        Op::Add { path, strokes } => {
+            let node = node_at(tree, path);
+            tree.insert_strokes(node, strokes.clone());
+        }
+        Op::Remove { path, indices, .. } => {
+            let node = node_at(tree, path);
+            tree.take_strokes_at(node, indices);
+            tree.ancestors_changed(node);
+        }
+        Op::Update {
+            path,
+            index,
+            stroke,
+        } => {
+            let node = node_at(tree, path);
+            let mut current = 0;
+            tree.update_own_strokes(node, &mut |entry| {
+                current += 1;
+                if current - 1 != *index {
+                    return false;
+                }
+                *entry = stroke.clone();
+                true
+            });
+            tree.ancestors_changed(node);
+        }
+        Op::Set { path, strokes } => {
+            let node = node_at(tree, path);
+            tree.replace_own_strokes(node, strokes.clone());
+        }
+        Op::Grow => {
+            let root = tree.root();
+            tree.get_or_create_parent(root);
+        }
+    }
+}
+
+/// Makes the strokes stored directly in each node of `tree` those stored at the same path in
+/// `target`, recording the changes, so that changes made to `target` can be made to `tree` as
+/// well. Both trees must have grown outward as far, and `target` must be paged in.
+pub fn reconcile(tree: &mut CanvasTree, target: &CanvasTree) {
+    let root = tree.root();
+    tree[root].corner = target[target.root()].corner;
+    tree.page_in_all(root);
+    let (ours, theirs) = (
+        node_strokes(tree, root),
+        node_strokes(target, target.root()),
+    );
+    let differing = ours
+        .keys()
+        .chain(theirs.keys())
+        .filter(|path| ours.get(*path).map(|(_, own)| own) != theirs.get(*path).map(|(_, own)| own))
+        .cloned()
+        .collect::<BTreeSet<_>>();
+    for path in differing {
+        let strokes = theirs
+            .get(&path)
+            .map(|(node, _)| target[*node].own_strokes().to_vec())
+            .unwrap_or_default();
+        let node = node_at(tree, &path);
+        tree.replace_own_strokes(node, strokes);
+    }
+}
+
+/// The node at `path` below the outermost node of `tree`, created if missing. Nodes along the
+/// way are paged in, so the change reaches the strokes stored there.
+fn node_at(tree: &mut CanvasTree, path: &[(u8, u8)]) -> NodeId {
+    let mut node = tree.root();
+    tree.page_in(node);
+    for corner in path.iter().rev() {
+        node = tree.get_or_create_child_from_corner(node, *corner);
+        tree.page_in(node);
+    }
+    node
+}
+
+/// Each node of the tree below `root` storing strokes directly, with those strokes written out
+/// for comparison, by path.
+fn node_strokes(tree: &CanvasTree, root: NodeId) -> BTreeMap<Vec<(u8, u8)>, (NodeId, String)> {
+    let mut strokes = BTreeMap::new();
+    let mut pending = vec![(root, vec![])];
+    while let Some((node, path)) = pending.pop() {
+        let own = tree[node].own_strokes();
+        if !own.is_empty() {
+            strokes.insert(
+                path.clone(),
+                (node, ron::to_string(own).unwrap_or_default()),
+            );
+        }
+        for ((x, y), child) in tree[node].child_nodes() {
+            let mut child_path = vec![(x as u8, y as u8)];
+            child_path.extend(&path);
+            pending.push((child, child_path));
+        }
+    }
+    strokes
+}
+
+#[cfg(test)]
+mod tests {
+    use egui::{pos2, vec2, Color32, Pos2};
+
+    use super::*;
+    use crate::{
+        drawables::FilledPolygon,
+        geometry::Affine2,
+        ordering::ORDER_SPACING,
+        structure::{StrokeMeta, StrokePriority},
+    };
+
+    /// SplitMix64, so each test draws the same strokes every run.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next_f32(&mut self) -> f32 {
+            self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+            let mut z = self.0;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            ((z ^ (z >> 31)) >> 40) as f32 / (1u64 << 24) as f32
+        }
+    }
+
+    /// Draws `count` strokes numbered from `first` into the outermost node of `tree`. They are
+    /// large enough to be stored in the node they are sent to while fitting within one of its
+    /// quarters, so the node fills up and is subdivided. Every four hundredth reaches outside
+    /// the tree, which grows to hold it.
+    fn draw(tree: &mut CanvasTree, rng: &mut Rng, first: u64, count: u64) {
+        for id in first..first + count {
+            let p1 = if id % 400 == 0 {
+                pos2(1.2, 0.0)
+            } else {
+                pos2(-0.9 + 1.25 * rng.next_f32(), -0.9 + 1.75 * rng.next_f32())
+            };
+            let p2 = p1 + vec2(0.5 + 0.05 * rng.next_f32(), 0.05);
+            let meta = StrokeMeta {
+                order: id * ORDER_SPACING,
+                priority: StrokePriority::Ink,
+                layer: 0,
+                created: 0,
+                author: None,
+                id,
+            };
+            let root = tree.root();
+            tree.send_drawable(p1, p2, 1.0, root, |p1, p2, _| {
+                let points = [p1, p2, p1 + (p2 - p1).rot90() * 0.1];
+                meta.entry(Box::new(FilledPolygon::new(&points, Color32::RED)))
+            });
+        }
+    }
+
+    /// The deepest level below the outermost node holding strokes.
+    fn deepest(tree: &CanvasTree) -> u32 {
+        let mut deepest = 0;
+        tree.for_each_node(tree.root(), 0, &mut |node, depth| {
+            if !node.own_strokes().is_empty() {
+                deepest = deepest.max(depth);
+            }
+        });
+        deepest
+    }
+
+    fn assert_same(tree: &CanvasTree, other: &CanvasTree) {
+        let hash = tree.update_hashes(tree.root());
+        assert!(hash.is_some());
+        assert_eq!(hash, other.update_hashes(other.root()));
+        assert_eq!(tree[tree.root()].corner, other[other.root()].corner);
+    }
+
+    #[test]
+    fn replay_rebuilds_drawing_past_the_node_limit() {
+        let mut tree = CanvasTree::default();
+        let mut log = OpLog::default();
+        log.record(&mut tree);
+        draw(&mut tree, &mut Rng(1), 1, 1000);
+        let ops = log.ops_since(0).unwrap();
+        assert!(grown(&ops) > 0);
+        // Strokes went to the outermost node at the time, now as deep as the tree grew since,
+        // and subdividing moved some deeper.
+        assert!(deepest(&tree) > grown(&ops));
+
+        assert_same(&tree, &replay(log.start.unwrap(), &ops));
+        assert_eq!(
+            log.check(&tree),
+            format!("Rebuilding from {} changes matches", ops.len())
+        );
+    }
+
+    #[test]
+    fn replay_rebuilds_deletes_and_updates_after_subdividing() {
+        let mut tree = CanvasTree::default();
+        let mut log = OpLog::default();
+        log.record(&mut tree);
+        let mut rng = Rng(2);
+        draw(&mut tree, &mut rng, 1, 800);
+        let root = tree.root();
+        // Indices of the strokes removed and updated count those subdividing left behind.
+        let removed = tree.retain_strokes(root, &|stroke| stroke.id % 3 != 0);
+        assert!(!removed.is_empty());
+        let nudge = Affine2::from_translation(vec2(0.01, -0.01));
+        tree.update_strokes(root, 0, &mut |stroke, _| {
+            if stroke.id % 5 != 0 {
+                return false;
+            }
+            stroke.drawable.transform(&nudge);
+            stroke.layer = 1;
+            true
+        });
+        let erased = tree.delete_strokes_in(
+            root,
+            Rect::from_center_size(Pos2::ZERO, vec2(1.0, 1.0)),
+            &|_| false,
+        );
+        assert!(!erased.is_empty());
+        // Filling the emptied nodes up again subdivides them anew.
+        draw(&mut tree, &mut rng, 801, 800);
+        let ops = log.ops_since(0).unwrap();
+        for kind in ["Remove", "Update", "Add"] {
+            assert!(ops
+                .iter()
+                .any(|op| ron::to_string(op).unwrap().starts_with(kind)));
+        }
+
+        assert_same(&tree, &replay(log.start.unwrap(), &ops));
+    }
+
+    #[test]
+    fn replaying_part_of_the_log_and_then_the_rest_matches() {
+        let mut tree = CanvasTree::default();
+        let mut log = OpLog::default();
+        log.record(&mut tree);
+        let mut rng = Rng(3);
+        draw(&mut tree, &mut rng, 1, 400);
+        let halfway = log.count();
+        let root = tree.root();
+        tree.retain_strokes(root, &|stroke| stroke.id % 2 == 0);
+        draw(&mut tree, &mut rng, 401, 400);
+
+        let corner = log.start.unwrap();
+        let mut rebuilt = replay(corner, &log.ops_in(0..halfway).unwrap());
+        for op in log.ops_since(halfway).unwrap() {
+            apply(&mut rebuilt, &op);
+        }
+        assert_same(&tree, &rebuilt);
+    }
+
+    #[test]
+    fn snapshot_rebuilds_the_tree() {
+        let mut tree = CanvasTree::default();
+        draw(&mut tree, &mut Rng(4), 1, 1000);
+        let (corner, ops) = snapshot(&tree);
+        assert_same(&tree, &replay(corner, &ops));
+    }
+
+    #[test]
+    fn reconcile_records_changes_that_bring_a_copy_in_line() {
+        let mut tree = CanvasTree::default();
+        let mut log = OpLog::default();
+        log.record(&mut tree);
+        let mut rng = Rng(5);
+        draw(&mut tree, &mut rng, 1, 600);
+        let (corner, before) = (log.start.unwrap(), log.count());
+
+        // A copy changed elsewhere, grown outward as far.
+        let mut target = replay(corner, &log.ops_since(0).unwrap());
+        let target_root = target.root();
+        target.retain_strokes(target_root, &|stroke| stroke.id % 4 != 1);
+        // None of these reaches outside the tree.
+        draw(&mut target, &mut Rng(6), 601, 20);
+
+        reconcile(&mut tree, &target);
+        assert_same(&tree, &target);
+        // Making the recorded changes to another copy brings it in line too.
+        let mut copy = replay(corner, &log.ops_in(0..before).unwrap());
+        for op in log.ops_since(before).unwrap() {
+            apply(&mut copy, &op);
+        }
+        assert_same(&copy, &target);
+    }
+
+    #[test]
+    fn compacting_forgets_the_changes() {
+        let mut tree = CanvasTree::default();
+        let mut log = OpLog::default();
+        log.record(&mut tree);
+        draw(&mut tree, &mut Rng(7), 1, 10);
+        let count = log.count();
+        log.compact();
+        assert_eq!(log.count(), count);
+        assert_eq!(log.held(), 0);
+        assert!(log.ops_since(0).is_none());
+        assert_eq!(log.ops_since(count).unwrap().len(), 0);
+        assert_eq!(
+            log.check(&tree),
+            "The log does not reach back to an empty canvas"
+        );
+    }
+}
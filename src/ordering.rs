@@ -0,0 +1,337 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::structure::{CanvasTree, NodeId};
+
+/// Gap left between the orders of consecutive strokes, both as they are drawn and when
+/// rebalancing, so strokes can be moved between two others, halving the gap each time, about
+/// 32 times before the tree has to be renumbered.
+pub const ORDER_SPACING: u64 = 1 << 32;
+
+/// Orders past this are close enough to overflowing that the tree is renumbered before more
+/// strokes are added. Half the range is left above it, so a frame can add any number of
+/// strokes.
+pub const ORDER_LIMIT: u64 = 1 << 63;
+
+/// Every order used by a stroke in the node `top_level` of `tree` or its descendants.
+fn used_orders(tree: &CanvasTree, top_level: NodeId) -> BTreeSet<u64> {
+    let mut orders = BTreeSet::new();
+    tree.for_each_node(top_level, 0, &mut |node, _| {
+        orders.extend(node.own_strokes().iter().map(|stroke| stroke.order));
+    });
+    orders
+}
+
+/// Changes the order of every stroke whose order is a key of `renumber`.
+fn apply(tree: &mut CanvasTree, top_level: NodeId, renumber: &BTreeMap<u64, u64>) {
+    tree.update_strokes(top_level, 0, &mut |stroke, _| {
+        if let Some(order) = renumber.get(&stroke.order) {
+            stroke.order = *order;
+        }
+        false
+    });
+}
+
+/// Spreads the orders of all strokes out to multiples of `spacing`, starting at `spacing` so
+/// there is room below the bottom stroke too, keeping their relative order. Returns the
+/// renumbering applied and the first order past the last stroke.
+fn rebalance(tree: &mut CanvasTree, top_level: NodeId, spacing: u64) -> (BTreeMap<u64, u64>, u64) {
+    let renumber: BTreeMap<u64, u64> = used_orders(tree, top_level)
+        .into_iter()
+        .zip(1..)
+        .map(|(order, index)| (order, index * spacing))
+        .collect();
+    apply(tree, top_level, &renumber);
+    let next = (renumber.len() as u64 + 1) * spacing;
+    (renumber, next)
+}
+
+/// Whether `next_order` fails to lie past every stroke in `top_level`, as in canvases saved by
+/// older versions or pieced together by hand, or is close to overflowing.
+pub fn needs_normalizing(tree: &CanvasTree, top_level: NodeId, next_order: u64) -> bool {
+    next_order >= ORDER_LIMIT
+        || used_orders(tree, top_level)
+            .last()
+            .is_some_and(|order| *order >= next_order)
+}
+
+/// Spreads the orders of all strokes out evenly, keeping their relative order, and moves
+/// `next_order` past them. Reclaims the orders left by deleted strokes, using tighter spacing
+/// when there are too many strokes to stay under `ORDER_LIMIT` otherwise. Returns the new order
+/// of every stroke.
+pub fn normalize(
+    tree: &mut CanvasTree,
+    top_level: NodeId,
+    next_order: &mut u64,
+) -> BTreeMap<u64, u64> {
+    let count = used_orders(tree, top_level).len() as u64;
+    let spacing = ORDER_SPACING.min(ORDER_LIMIT / (count + 2)).max(1);
+    let (renumber, next) = rebalance(tree, top_level, spacing);
+    *next_order = next;
+    renumber
+}
+
+/// Gives the strokes with orders in `moved` new orders directly above `target` if `above`,
+/// otherwise directly below it, keeping their order among themselves. Rebalances the tree first
+/// if the orders around `target` are too tightly packed, which spaced orders leave for when
+/// strokes were moved between the same two many times over. `next_order` is the order the next
+/// new stroke will get and is kept past every stroke. Returns the new order of every stroke
+/// whose order changed.
+pub fn move_orders(
+    tree: &mut CanvasTree,
+    top_level: NodeId,
+    moved: &BTreeSet<u64>,
+    target: u64,
+    above: bool,
+    next_order: &mut u64,
+) -> BTreeMap<u64, u64> {
+    let used = used_orders(tree, top_level);
+    if !used.contains(&target) {
+        return BTreeMap::new();
+    }
+    let mut moved = moved.intersection(&used).copied().collect::<BTreeSet<_>>();
+    let mut target = target;
+    let mut renumbered = BTreeMap::new();
+    loop {
+        let others = used_orders(tree, top_level)
+            .into_iter()
+            .filter(|order| !moved.contains(order))
+            .collect::<BTreeSet<_>>();
+        let count = moved.len() as i128;
+        // Exclusive bounds of the range the moved strokes must land in.
+        let (low, high) = if above {
+            let high = match others.range(target + 1..).next() {
+                Some(order) => *order as i128,
+                None => {
+                    (*next_order as i128).max(target as i128 + (count + 1) * ORDER_SPACING as i128)
+                }
+            };
+            (target as i128, high)
+        } else {
+            let low = others
+                .range(..target)
+                .next_back()
+                .map_or(-1, |order| *order as i128);
+            (low, target as i128)
+        };
+        if high - low > count {
+            let step = (high - low) / (count + 1);
+            let placed: BTreeMap<u64, u64> = moved
+                .iter()
+                .zip(1..)
+                .map(|(order, index)| (*order, (low + step * index) as u64))
+                .collect();
+            apply(tree, top_level, &placed);
+            let top = placed.values().max().map_or(0, |order| order + 1);
+            *next_order = (*next_order).max(top);
+            // Compose with any rebalancing so callers can follow the strokes from their old
+            // orders.
+            if renumbered.is_empty() {
+                return placed;
+            }
+            for order in renumbered.values_mut() {
+                if let Some(placed) = placed.get(order) {
+                    *order = *placed;
+                }
+            }
+            return renumbered;
+        }
+        let (renumber, next) = rebalance(tree, top_level, ORDER_SPACING);
+        *next_order = next;
+        moved = moved.iter().map(|order| renumber[order]).collect();
+        target = renumber[&target];
+        renumbered = if renumbered.is_empty() {
+            renumber
+        } else {
+            renumbered
+                .into_iter()
+                .map(|(old, order)| (old, renumber[&order]))
+                .collect()
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use egui::{pos2, Color32};
+
+    use super::*;
+    use crate::{
+        drawables::FilledPolygon,
+        structure::{StrokeEntry, StrokeMeta, StrokePriority},
+    };
+
+    fn stroke(order: u64) -> StrokeEntry {
+        let points = [pos2(0.0, 0.0), pos2(0.1, 0.0), pos2(0.0, 0.1)];
+        StrokeMeta {
+            order,
+            priority: StrokePriority::Ink,
+            layer: 0,
+            created: 0,
+            author: None,
+            id: order,
+        }
+        .entry(Box::new(FilledPolygon::new(&points, Color32::RED)))
+    }
+
+    /// A tree of one node holding a stroke with each of `orders`, which its strokes keep as
+    /// their ids.
+    fn tree(orders: &[u64]) -> (CanvasTree, NodeId) {
+        let mut tree = CanvasTree::default();
+        let root = tree.root();
+        tree.insert_strokes(root, orders.iter().map(|order| stroke(*order)).collect());
+        (tree, root)
+    }
+
+    /// The order of each stroke by its id, in the order they are stored.
+    fn orders(tree: &CanvasTree, root: NodeId) -> Vec<(u64, u64)> {
+        tree[root]
+            .own_strokes()
+            .iter()
+            .map(|stroke| (stroke.id, stroke.order))
+            .collect()
+    }
+
+    #[test]
+    fn normalize_spreads_orders_keeping_their_stacking() {
+        let (mut tree, root) = tree(&[7, 3, 12]);
+        let mut next_order = 13;
+        let renumbered = normalize(&mut tree, root, &mut next_order);
+        let spacing = ORDER_SPACING;
+        assert_eq!(
+            orders(&tree, root),
+            [(7, 2 * spacing), (3, spacing), (12, 3 * spacing)]
+        );
+        assert_eq!(next_order, 4 * spacing);
+        assert_eq!(
+            renumbered,
+            BTreeMap::from([(3, spacing), (7, 2 * spacing), (12, 3 * spacing)])
+        );
+    }
+
+    #[test]
+    fn normalize_keeps_colliding_orders_together() {
+        // Pieced together from two canvases, so some strokes share orders and the next order
+        // falls behind them.
+        let (mut tree, root) = tree(&[5, 5, 2, 9, 2]);
+        let mut next_order = 3;
+        assert!(needs_normalizing(&tree, root, next_order));
+        normalize(&mut tree, root, &mut next_order);
+        let orders = orders(&tree, root)
+            .into_iter()
+            .map(|(_, order)| order / ORDER_SPACING)
+            .collect::<Vec<_>>();
+        assert_eq!(orders, [2, 2, 1, 3, 1]);
+        assert_eq!(next_order, 4 * ORDER_SPACING);
+        assert!(!needs_normalizing(&tree, root, next_order));
+    }
+
+    #[test]
+    fn normalize_pulls_orders_back_from_the_limit() {
+        let (mut tree, root) = tree(&[1, ORDER_LIMIT - 1]);
+        let mut next_order = ORDER_LIMIT;
+        assert!(needs_normalizing(&tree, root, next_order));
+        normalize(&mut tree, root, &mut next_order);
+        assert!(next_order < ORDER_LIMIT);
+        let spacing = ORDER_SPACING;
+        assert_eq!(
+            orders(&tree, root),
+            [(1, spacing), (ORDER_LIMIT - 1, 2 * spacing)]
+        );
+    }
+
+    #[test]
+    fn moving_between_strokes_leaves_the_others() {
+        let spacing = ORDER_SPACING;
+        let (mut tree, root) = tree(&[spacing, 2 * spacing, 3 * spacing]);
+        let mut next_order = 4 * spacing;
+        let moved = BTreeSet::from([spacing]);
+        let renumbered = move_orders(&mut tree, root, &moved, 2 * spacing, true, &mut next_order);
+        let placed = 2 * spacing + spacing / 2;
+        assert_eq!(renumbered, BTreeMap::from([(spacing, placed)]));
+        assert_eq!(
+            orders(&tree, root),
+            [
+                (spacing, placed),
+                (2 * spacing, 2 * spacing),
+                (3 * spacing, 3 * spacing)
+            ]
+        );
+        assert_eq!(next_order, 4 * spacing);
+    }
+
+    #[test]
+    fn moving_past_either_end() {
+        let spacing = ORDER_SPACING;
+        let (mut tree, root) = tree(&[spacing, 2 * spacing]);
+        let mut next_order = 3 * spacing;
+        let to_top = BTreeSet::from([spacing]);
+        move_orders(&mut tree, root, &to_top, 2 * spacing, true, &mut next_order);
+        let top = orders(&tree, root)[0].1;
+        assert!(top > 2 * spacing);
+        assert!(next_order > top);
+
+        let to_bottom = BTreeSet::from([top]);
+        move_orders(
+            &mut tree,
+            root,
+            &to_bottom,
+            2 * spacing,
+            false,
+            &mut next_order,
+        );
+        let bottom = orders(&tree, root)[0].1;
+        assert!(bottom < 2 * spacing);
+        assert_eq!(orders(&tree, root)[1], (2 * spacing, 2 * spacing));
+    }
+
+    #[test]
+    fn moving_keeps_colliding_orders_together() {
+        let spacing = ORDER_SPACING;
+        let (mut tree, root) = tree(&[spacing, spacing, 2 * spacing, 3 * spacing]);
+        let mut next_order = 4 * spacing;
+        let moved = BTreeSet::from([spacing]);
+        move_orders(&mut tree, root, &moved, 3 * spacing, true, &mut next_order);
+        let orders = orders(&tree, root);
+        assert_eq!(orders[0].1, orders[1].1);
+        assert!(orders[0].1 > 3 * spacing);
+    }
+
+    #[test]
+    fn moving_into_a_used_up_gap_rebalances() {
+        // Neighboring orders, as in canvases saved before orders were spaced out.
+        let (mut tree, root) = tree(&[1, 2, 3]);
+        let mut next_order = 4;
+        let moved = BTreeSet::from([3]);
+        let renumbered = move_orders(&mut tree, root, &moved, 1, true, &mut next_order);
+        let orders = orders(&tree, root);
+        let (first, second, third) = (orders[0].1, orders[1].1, orders[2].1);
+        assert!(first < third && third < second);
+        assert!(next_order > second);
+        // Every stroke can be followed from its old order.
+        assert_eq!(renumbered.len(), 3);
+        assert_eq!(renumbered[&1], first);
+        assert_eq!(renumbered[&2], second);
+        assert_eq!(renumbered[&3], third);
+    }
+
+    #[test]
+    fn moving_relative_to_a_missing_stroke_does_nothing() {
+        let (mut tree, root) = tree(&[1, 2]);
+        let mut next_order = 3;
+        let moved = BTreeSet::from([1]);
+        assert!(move_orders(&mut tree, root, &moved, 5, true, &mut next_order).is_empty());
+        assert_eq!(orders(&tree, root), [(1, 1), (2, 2)]);
+    }
+
+    #[test]
+    fn orders_saved_as_u32_still_load() {
+        let drawable = stroke(0).drawable;
+        let ron = format!("({},7)", ron::to_string(&drawable).unwrap());
+        assert_eq!(ron::from_str::<StrokeEntry>(&ron).unwrap().order, 7);
+        let binary = rmp_serde::to_vec(&(&drawable, 7u32)).unwrap();
+        assert_eq!(
+            rmp_serde::from_slice::<StrokeEntry>(&binary).unwrap().order,
+            7
+        );
+    }
+}
@@ -0,0 +1,308 @@
+use std::io::{Read, Write};
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(target_arch = "wasm32")]
+use std::sync::Arc;
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+
+#[cfg(target_arch = "wasm32")]
+use crate::chunks::ChunkStore;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::format;
+use crate::{
+    chunks::ChunkPage,
+    structure::{CanvasTree, DrawNode, NodeId},
+};
+
+/// Seconds between checks of the painting's memory use against the budget.
+const CHECK_INTERVAL: f64 = 5.0;
+
+/// Numbers the pages written by this process, so their files never collide.
+#[cfg(not(target_arch = "wasm32"))]
+static NEXT_PAGE: AtomicU64 = AtomicU64::new(0);
+
+/// Where the content of a paged out subtree is.
+pub enum Page {
+    /// Written out by the `Pager`.
+    #[cfg(not(target_arch = "wasm32"))]
+    Temporary(TemporaryPage),
+    /// Left in the chunk store the painting is kept in, not loaded yet.
+    Chunk(ChunkPage),
+}
+
+impl Page {
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn write(data: &[u8]) -> std::io::Result<Page> {
+        Ok(Page::Temporary(TemporaryPage::write(data)?))
+    }
+
+    /// Reads the subtree back, detached from the tree.
+    pub fn load(&self) -> Result<CanvasTree, String> {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            Page::Temporary(page) => {
+                let data = page.read().map_err(|err| err.to_string())?;
+                format::decode(&data)
+            }
+            Page::Chunk(page) => page.load(),
+        }
+    }
+
+    /// Whether the subtree can be read back now. Pages that can only be read asynchronously
+    /// are fetched in the background if not, and can be on a later frame.
+    pub fn fetch(&self) -> bool {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            Page::Temporary(_) => true,
+            Page::Chunk(page) => page.fetch(),
+        }
+    }
+
+    /// Drops the page once its subtree was loaded back into the tree, along with what was kept
+    /// of it.
+    pub fn loaded(self) {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            Page::Temporary(_) => {}
+            Page::Chunk(page) => page.loaded(),
+        }
+    }
+}
+
+pub fn compress(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+pub fn decompress(compressed: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    DeflateDecoder::new(compressed).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// The serialized content of a paged out subtree, compressed and written to a file in the
+/// temporary directory, removed once the page is dropped. Browsers have no synchronous storage
+/// large enough to page out to, so there only chunks saved to the chunk store are paged out,
+/// left in the store as they are.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct TemporaryPage {
+    path: std::path::PathBuf,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl TemporaryPage {
+    fn write(data: &[u8]) -> std::io::Result<TemporaryPage> {
+        let dir = std::env::temp_dir().join("true_infinite_canvas_pages");
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(format!(
+            "{}-{}.ticb.deflate",
+            std::process::id(),
+            NEXT_PAGE.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&path, compress(data)?)?;
+        Ok(TemporaryPage { path })
+    }
+
+    fn read(&self) -> std::io::Result<Vec<u8>> {
+        decompress(&std::fs::read(&self.path)?)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Drop for TemporaryPage {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            log::warn!("Failed to remove page {}: {err}", self.path.display());
+        }
+    }
+}
+
+/// Keeps a painting under a memory budget by paging out the subtrees viewed least recently,
+/// leaving stub nodes that load their content again once something reaches into them. On the
+/// web only saved chunks of the store the painting is kept in are paged out, so the budget can
+/// be exceeded by changes not saved yet, and by paintings kept in no store.
+#[derive(Default)]
+pub struct Pager {
+    /// Frames shown so far, stamped on the cells in view.
+    frame: u64,
+    /// Time of the last check against the budget.
+    last_check: f64,
+    paged: Vec<NodeId>,
+    /// The store the painting is kept in, which chunks are paged out to.
+    #[cfg(target_arch = "wasm32")]
+    store: Option<Arc<ChunkStore>>,
+}
+
+impl Pager {
+    /// Pages out to `store` from now on, which the painting is kept in.
+    #[cfg(target_arch = "wasm32")]
+    pub fn page_to(&mut self, store: &Arc<ChunkStore>) {
+        self.store = Some(store.clone());
+    }
+
+    /// Starts a frame showing `cells`.
+    pub fn view(&mut self, tree: &mut CanvasTree, cells: impl IntoIterator<Item = NodeId>) {
+        self.frame += 1;
+        for cell in cells {
+            tree[cell].last_viewed = self.frame;
+        }
+    }
+
+    /// Nodes currently paged out.
+    pub fn paged_count(&self, tree: &CanvasTree) -> usize {
+        self.paged
+            .iter()
+            .filter_map(|node| tree.get(*node))
+            .filter(|node| node.is_paged())
+            .count()
+    }
+
+    /// Pages out subtrees of `top_level`, least recently viewed first, until the tree takes at
+    /// most `budget` bytes, at most once every few seconds going by the time `now`. Subtrees
+    /// holding cells in view or nodes already paged out stay as they are.
+    pub fn enforce(&mut self, tree: &mut CanvasTree, top_level: NodeId, budget: usize, now: f64) {
+        if now - self.last_check < CHECK_INTERVAL {
+            return;
+        }
+        self.last_check = now;
+        // Chunks are fetched from the database again once paged out, which only holds them as
+        // saved once every save was written.
+        #[cfg(target_arch = "wasm32")]
+        let Some(store) = self.store.clone().filter(|store| store.written()) else {
+            return;
+        };
+        let memory = tree.stats(top_level).memory;
+        if memory <= budget {
+            return;
+        }
+        let (_, _, mut candidates) = self.gather(tree, top_level, false);
+        candidates.retain(|(_, node)| *node != top_level);
+        candidates.sort_by_key(|(recent, _)| *recent);
+        let mut excess = memory - budget;
+        let mut paged = 0;
+        for (_, node) in candidates {
+            if excess == 0 {
+                break;
+            }
+            let size = tree.stats(node).memory;
+            #[cfg(not(target_arch = "wasm32"))]
+            let result = tree.page_out(node);
+            #[cfg(target_arch = "wasm32")]
+            let result = store.page_out(tree, node);
+            match result {
+                Ok(()) => {
+                    excess = excess.saturating_sub(size);
+                    paged += 1;
+                    self.paged.push(node);
+                }
+                Err(err) => log::error!("Failed to page out a subtree: {err}"),
+            }
+        }
+        log::info!("Paged out {paged} subtrees to stay within the memory budget");
+    }
+
+    /// Finds the largest subtrees below `node` that can be paged out, each with the last frame
+    /// any of its nodes was in view. Returns whether nothing in the subtree of
+    /// `node` keeps it from being paged out, the last frame it was in view, and the subtrees
+    /// found, left to the caller unless it pages out the whole of `node`.
+    fn gather(
+        &self,
+        tree: &CanvasTree,
+        node: NodeId,
+        in_view: bool,
+    ) -> (bool, u64, Vec<(u64, NodeId)>) {
+        let node_ref = &tree[node];
+        if node_ref.is_paged() {
+            // Writing it into a larger page would load it first, whereas a chunk page is
+            // already in the store the chunk above it goes to.
+            return (cfg!(target_arch = "wasm32"), node_ref.last_viewed, vec![]);
+        }
+        let in_view = in_view || node_ref.last_viewed == self.frame;
+        let mut movable = !in_view;
+        let mut recent = node_ref.last_viewed;
+        let mut found = vec![];
+        for (_, child) in node_ref.child_nodes() {
+            let (child_movable, child_recent, child_found) = self.gather(tree, child, in_view);
+            movable &= child_movable;
+            recent = recent.max(child_recent);
+            found.extend(child_found);
+        }
+        if movable && pageable(node_ref) {
+            found = vec![(recent, node)];
+        }
+        (movable, recent, found)
+    }
+}
+
+/// Whether a subtree out of view can be paged out whole from `node`, its root.
+#[cfg(not(target_arch = "wasm32"))]
+fn pageable(_: &DrawNode) -> bool {
+    true
+}
+
+/// Whether a subtree out of view can be paged out whole from `node`, its root, which on the web
+/// only the roots of chunks saved as they are can, as they are left in the store.
+#[cfg(target_arch = "wasm32")]
+fn pageable(node: &DrawNode) -> bool {
+    node.stored_chunk && !node.is_unsaved()
+}
+
+#[cfg(test)]
+mod tests {
+    use egui::{pos2, vec2, Color32};
+
+    use super::*;
+    use crate::{
+        drawables::FilledPolygon,
+        ordering::ORDER_SPACING,
+        structure::{StrokeMeta, StrokePriority},
+    };
+
+    /// A tree of small strokes spread over a grid, enough for the outermost node to be
+    /// subdivided.
+    fn grid() -> CanvasTree {
+        let mut tree = CanvasTree::default();
+        for id in 0..1024u64 {
+            let at = pos2((id % 32) as f32, (id / 32) as f32) / 16.0 - vec2(0.99, 0.99);
+            let points = [at, at + vec2(0.01, 0.0), at + vec2(0.0, 0.01)];
+            let stroke = StrokeMeta {
+                order: (id + 1) * ORDER_SPACING,
+                priority: StrokePriority::Ink,
+                layer: 0,
+                created: 0,
+                author: None,
+                id,
+            }
+            .entry(Box::new(FilledPolygon::new(&points, Color32::RED)));
+            let root = tree.root();
+            tree.insert_strokes(root, vec![stroke]);
+        }
+        tree
+    }
+
+    #[test]
+    fn pages_out_what_is_out_of_view_and_loads_it_back() {
+        let mut tree = grid();
+        let root = tree.root();
+        let hash = tree.update_hashes(root);
+        let strokes = tree.stats(root).strokes;
+        let (_, in_view) = tree[root].child_nodes().next().unwrap();
+
+        let mut pager = Pager::default();
+        pager.view(&mut tree, [in_view]);
+        pager.enforce(&mut tree, root, 0, CHECK_INTERVAL);
+        assert!(pager.paged_count(&tree) > 0);
+        assert!(!tree[root].is_paged());
+        let mut paged_in_view = false;
+        tree.for_each_node(in_view, 0, &mut |node, _| paged_in_view |= node.is_paged());
+        assert!(!paged_in_view);
+        assert!(tree.stats(root).strokes < strokes);
+
+        assert!(tree.page_in_all(root));
+        assert_eq!(pager.paged_count(&tree), 0);
+        assert_eq!(tree.stats(root).strokes, strokes);
+        assert_eq!(tree.update_hashes(root), hash);
+    }
+}
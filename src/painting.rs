@@ -1,320 +1,5291 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Deref,
+    sync::Arc,
+};
 
-use egui::{emath, pos2, vec2, Color32, Pos2, Rect, Sense, Stroke, Ui, Vec2};
+use chrono::Utc;
+use egui::{emath, pos2, vec2, Color32, ColorImage, Pos2, Rect, Sense, Stroke, Ui, Vec2};
 use itertools::Itertools;
-use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::{
+    de::{
+        value::MapAccessDeserializer, DeserializeSeed, IgnoredAny, IntoDeserializer, MapAccess,
+        Visitor,
+    },
+    ser::{Error as _, SerializeStruct},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 
+#[cfg(not(target_arch = "wasm32"))]
+use crate::oplog::Op;
 use crate::{
+    blend,
+    canvas_view::CanvasView,
+    chunks::ChunkStore,
     circular_buffer::CircularBuffer2D,
-    structure::{DrawNode, DrawNodeRef, Line},
+    clipboard::{self, ClipboardText, CopiedStroke, CopiedStrokes},
+    clusters::{ClusterAction, ClusterFraming, FRAME_PADDING, FRAME_TITLE_SIZE},
+    collab::{
+        display_name, participant_color, relocate, CollabAction, CollabSettings, Collaboration,
+        Presence,
+    },
+    collect::{is_drawn, CollectedFrame, RenderKey, StrokeCollector, DRAW_DEPTH},
+    drawables::{FilledPolygon, Frame, PathText, RasterImage, StickyNote, TaperedStroke},
+    excalidraw,
+    export::{self, DeepZoom, ExportRegion, ImageExport, ImageFormat},
+    format::{self, SaveFormat},
+    geometry::{self, Affine2},
+    history::{ObjectChange, ObjectHistory},
+    hud::{FrameStats, PerfHud},
+    input::{MouseAction, MouseMappings},
+    journal::{Journal, JournalAction},
+    layers::{LayerAction, LayerId, Layers},
+    merge::{self, Conflict, Conflicts, Diff, Versions},
+    oplog::{self, OpLog},
+    ordering,
+    paging::Pager,
+    palette::Palette,
+    pdf,
+    presets::BrushPreset,
+    quality::RenderQuality,
+    raster::{self, Mask},
+    recolor::{ColorReplace, ReplaceAction, ReplaceScope},
+    selection::Selection,
+    sessions::{SessionLog, ViewLocation},
+    settings::SettingsProfile,
+    stress::{self, StressConfig},
+    structure::{
+        CanvasDrawable, CanvasTree, Direction, DrawNode, GroupId, Line, LineStyle, NodeId,
+        Property, SegmentStyle, StrokeEntry, StrokeMeta, StrokePriority, TreeStats, VectorShape,
+    },
+    svg,
+    version_history::{VersionAction, VersionHistory},
+    viewport::{paint_location, SecondaryView},
+    world::{WorldAction, WorldBounds, MIN_WORLD_FRACTION},
 };
 
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum Tool {
+    Select,
+    Pen,
+    Highlighter,
+    StickyNote,
+    Fill,
+    PathText,
+    /// Applies the pen's color and width to a clicked stroke.
+    Restyle,
+    /// Deletes every stroke touching a dragged rectangle.
+    EraseRegion,
+}
+
+/// Size in screen points of the grid cells used to find the region a fill covers.
+const FILL_CELL_SIZE: f32 = 1.0;
+
+/// Screen points a simplified pen stroke may stray from the pointer samples it was drawn with.
+const STROKE_TOLERANCE: f32 = 0.5;
+
+/// Factor the `[` and `]` keys shrink and grow the brush by, and the range they stay within.
+const BRUSH_RESIZE_STEP: f32 = 1.25;
+const MIN_BRUSH_WIDTH: f32 = 0.1;
+const MAX_BRUSH_WIDTH: f32 = 100.0;
+
+/// Outline and name color of frames wrapped around content clusters.
+const FRAME_COLOR: Color32 = Color32::from_gray(110);
+
+/// Largest fraction of the view a paste may cover before it is shrunk to fit.
+const PASTE_FIT: f32 = 0.8;
+
+/// Reads shapes from another format's file into strokes, as `svg::import` does.
+type ShapeImport = fn(&[u8], u32, f32) -> Result<CopiedStrokes, String>;
+
+/// Size of the selection's transform handles, and how far above it the rotation handle sits.
+const HANDLE_SIZE: f32 = 8.0;
+const ROTATE_HANDLE_OFFSET: f32 = 24.0;
+/// Rotations snap to multiples of this many degrees while shift is held.
+const ROTATE_SNAP_DEGREES: f32 = 15.0;
+
+/// Most text objects exposed to screen readers at once, nearest the top left first.
+const MAX_ACCESSIBLE_OBJECTS: usize = 200;
+
+/// How long the toast offering to restore an automatic snapshot stays up.
+const SNAPSHOT_TOAST_SECONDS: f64 = 30.0;
+
+/// Colors of the objects a compared copy adds and lacks.
+const DIFF_ADDED_COLOR: Color32 = Color32::from_rgb(40, 180, 70);
+const DIFF_REMOVED_COLOR: Color32 = Color32::from_rgb(220, 50, 50);
+/// Size of each version of a conflicting object shown to choose between them.
+const CONFLICT_PREVIEW_SIZE: Vec2 = vec2(160.0, 160.0);
+
+/// A copy of the painting taken automatically before an operation that replaces or removes a lot
+/// of content.
+struct Snapshot {
+    /// Describes the operation that followed the snapshot.
+    label: String,
+    data: String,
+    shown_at: Option<f64>,
+}
+
+/// Commands moving the selection in the draw order.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Arrange {
+    BringToFront,
+    SendToBack,
+}
+
+/// Commands lining up the selected objects, where a group counts as one object.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Align {
+    Left,
+    HorizontalCenter,
+    Right,
+    Top,
+    VerticalCenter,
+    Bottom,
+    /// Spaces objects with equal gaps between the leftmost and rightmost.
+    DistributeHorizontally,
+    DistributeVertically,
+}
+
+impl Align {
+    const ALIGN: [Align; 6] = [
+        Align::Left,
+        Align::HorizontalCenter,
+        Align::Right,
+        Align::Top,
+        Align::VerticalCenter,
+        Align::Bottom,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Align::Left => "Align left",
+            Align::HorizontalCenter => "Align centers horizontally",
+            Align::Right => "Align right",
+            Align::Top => "Align top",
+            Align::VerticalCenter => "Align centers vertically",
+            Align::Bottom => "Align bottom",
+            Align::DistributeHorizontally => "Distribute horizontally",
+            Align::DistributeVertically => "Distribute vertically",
+        }
+    }
+
+    /// Screen offsets moving each of `objects` into place.
+    fn offsets(&self, objects: &[Rect]) -> Vec<Vec2> {
+        let Some(bounds) = objects.iter().copied().reduce(Rect::union) else {
+            return vec![];
+        };
+        let distribute = |axis: usize| {
+            let order = (0..objects.len())
+                .sorted_by(|a, b| objects[*a].min[axis].total_cmp(&objects[*b].min[axis]))
+                .collect_vec();
+            let total: f32 = objects.iter().map(|object| object.size()[axis]).sum();
+            let gap = (bounds.size()[axis] - total) / (objects.len() as f32 - 1.0).max(1.0);
+            let mut offsets = vec![Vec2::ZERO; objects.len()];
+            let mut next = bounds.min[axis];
+            for index in order {
+                offsets[index][axis] = next - objects[index].min[axis];
+                next += objects[index].size()[axis] + gap;
+            }
+            offsets
+        };
+        match self {
+            Align::DistributeHorizontally => return distribute(0),
+            Align::DistributeVertically => return distribute(1),
+            _ => {}
+        }
+        objects
+            .iter()
+            .map(|object| match self {
+                Align::Left => vec2(bounds.min.x - object.min.x, 0.0),
+                Align::HorizontalCenter => vec2(bounds.center().x - object.center().x, 0.0),
+                Align::Right => vec2(bounds.max.x - object.max.x, 0.0),
+                Align::Top => vec2(0.0, bounds.min.y - object.min.y),
+                Align::VerticalCenter => vec2(0.0, bounds.center().y - object.center().y),
+                Align::Bottom => vec2(0.0, bounds.max.y - object.max.y),
+                Align::DistributeHorizontally | Align::DistributeVertically => Vec2::ZERO,
+            })
+            .collect()
+    }
+}
+
+/// A handle around the selection that scales or rotates it when dragged.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum TransformHandle {
+    /// Scales about the opposite corner, numbered like `Rect::min` and `Rect::max` components
+    /// with 0 for min and 1 for max.
+    Corner(u8, u8),
+    /// Rotates about the center.
+    Rotate,
+}
+
+impl TransformHandle {
+    const ALL: [TransformHandle; 5] = [
+        TransformHandle::Corner(0, 0),
+        TransformHandle::Corner(1, 0),
+        TransformHandle::Corner(0, 1),
+        TransformHandle::Corner(1, 1),
+        TransformHandle::Rotate,
+    ];
+
+    fn position(&self, bounds: Rect) -> Pos2 {
+        match self {
+            TransformHandle::Corner(x, y) => corner_of(bounds, *x, *y),
+            TransformHandle::Rotate => bounds.center_top() - vec2(0.0, ROTATE_HANDLE_OFFSET),
+        }
+    }
+
+    /// The screen transform for dragging this handle on `bounds` to `pointer`. Shift keeps the
+    /// aspect ratio when scaling and snaps the angle when rotating.
+    fn transform(&self, bounds: Rect, pointer: Pos2, shift: bool) -> Affine2 {
+        match self {
+            TransformHandle::Corner(x, y) => {
+                let anchor = corner_of(bounds, 1 - x, 1 - y);
+                let from = corner_of(bounds, *x, *y) - anchor;
+                let to = pointer - anchor;
+                let scale = if shift {
+                    Vec2::splat(to.dot(from) / from.length_sq())
+                } else {
+                    vec2(to.x / from.x, to.y / from.y)
+                };
+                Affine2::from_scale(scale).about(anchor)
+            }
+            TransformHandle::Rotate => {
+                let center = bounds.center();
+                let mut angle =
+                    (pointer - center).angle() - (self.position(bounds) - center).angle();
+                if shift {
+                    let snap = ROTATE_SNAP_DEGREES.to_radians();
+                    angle = (angle / snap).round() * snap;
+                }
+                Affine2::from_angle(angle).about(center)
+            }
+        }
+    }
+}
+
+fn corner_of(rect: Rect, x: u8, y: u8) -> Pos2 {
+    pos2(
+        if x == 0 { rect.min.x } else { rect.max.x },
+        if y == 0 { rect.min.y } else { rect.max.y },
+    )
+}
+
+/// A change to existing strokes, applied to every stroke it fits.
+enum Edit {
+    Color(Color32),
+    /// Multiplies outline widths, which are stored relative to each stroke's node.
+    ScaleWidth(f32),
+    /// Sets outline widths, in screen points.
+    Width(f32),
+    Text(String),
+    /// Offset in screen points.
+    Move(Vec2),
+}
+
+/// A node together with the screen rect it covers.
+type PlacedNode = (NodeId, Rect);
+
+/// A sticky note whose area has been chosen but whose text is still being edited.
+struct PendingNote {
+    node: NodeId,
+    p1: Pos2,
+    p2: Pos2,
+    text: String,
+}
+
+/// Screen points mapped into the coordinates of the parent of the cell under their bounding box.
+struct LocatedShape {
+    node: NodeId,
+    /// Corners of the bounding box.
+    p1: Pos2,
+    p2: Pos2,
+    points: Vec<Pos2>,
+    /// Length of one screen point in the node's coordinates.
+    point_size: f32,
+}
+
+/// A paste that was shrunk to fit the view, kept so it can be redone at its original size.
+struct FittedPaste {
+    copied: CopiedStrokes,
+    /// Screen position the paste was centered on.
+    target: Pos2,
+    orders: BTreeSet<u64>,
+    scale: f32,
+}
+
+/// Text on a path whose curve has been drawn but whose text is still being edited.
+struct PendingPathText {
+    shape: LocatedShape,
+    /// The curve as drawn, for previewing the text.
+    screen_points: Vec<Pos2>,
+    text: String,
+}
+
+/// What text being read from the clipboard is for.
+#[derive(Clone, Copy)]
+enum ClipboardUse {
+    /// Replacing the painting with one exported to the clipboard.
+    Import,
+    /// Counting the nodes that differ from a painting exported to the clipboard.
+    Compare,
+    /// Merging in a copy of the painting, or the file of one, from the clipboard.
+    Merge,
+    /// Showing how a copy of the painting, or the file of one, from the clipboard differs.
+    Diff,
+}
+
+/// Saved in sections by the `Serialize` impl below, this derive writing the `state` section.
 #[derive(Deserialize, Serialize)]
+#[serde(remote = "Self")]
+#[serde(default)]
 pub struct Painting {
-    #[serde(serialize_with = "structure_serializer")]
+    /// Saved in the `canvas` and `view` sections, or at the top of the painting in files saved
+    /// before those.
+    #[serde(skip_serializing)]
     #[serde(deserialize_with = "structure_deserializer")]
-    draw_boxes: CircularBuffer2D<Rc<RefCell<DrawNode>>, 5>,
+    draw_boxes: Cells,
     last_cursor_pos: Option<Pos2>,
     zoom: f32,
     pan: Vec2,
     stroke: Stroke,
-    next_stroke_order: u32,
+    highlighter: Stroke,
+    line_style: LineStyle,
+    taper: bool,
+    /// How strongly pen input is smoothed, from 0 (raw input) towards 1.
+    smoothing: f32,
+    /// Draw strokes in progress straight from pointer samples and only add them to the tree
+    /// once the pointer is released.
+    low_latency: bool,
+    quality: RenderQuality,
+    /// Memory in MiB the tree may take before subtrees out of view are paged out, or 0 for no
+    /// limit. The web only pages out saved chunks, back to the chunk store.
+    memory_budget: usize,
+    #[serde(skip)]
+    pager: Pager,
+    /// Format the tree is stored and the painting exported in.
+    save_format: SaveFormat,
+    world: WorldBounds,
+    /// Pointer samples of the stroke being drawn in low latency mode, in the units of the
+    /// cells of `live_stroke_origin` as `pan` is, so they stay put as the view moves.
+    #[serde(skip)]
+    live_stroke: Vec<(Pos2, f32)>,
+    /// Where the view was when the stroke being drawn in low latency mode started.
+    #[serde(skip)]
+    live_stroke_origin: Option<ViewLocation>,
+    /// Pointer samples of the stroke being drawn outside low latency mode, whose segments are
+    /// added as it goes, and the pan and zoom they were taken at.
+    #[serde(skip)]
+    drawn_stroke: Vec<(Pos2, f32)>,
+    #[serde(skip)]
+    drawn_stroke_view: (Vec2, f32),
+    #[serde(skip)]
+    dash_phase: f32,
+    #[serde(skip)]
+    last_width: f32,
+    next_stroke_order: u64,
+    next_group: GroupId,
     debug_render: bool,
+    show_hud: bool,
+    #[serde(skip)]
+    hud: PerfHud,
+    tool: Tool,
+    note_color: Color32,
+    fill_color: Color32,
+    text_color: Color32,
+    /// Font size of text on a path, in screen points at the zoom it is placed at.
+    text_size: f32,
+    palette: Palette,
+    /// Color used instead of the tool's own color when drawing with the secondary mouse button.
+    secondary_color: Color32,
+    #[serde(skip)]
+    use_secondary: bool,
+    mouse_mappings: MouseMappings,
+    layers: Layers,
+    #[serde(skip)]
+    show_layers: bool,
+    #[serde(skip)]
+    show_replace_color: bool,
+    journal: Journal,
+    #[serde(skip)]
+    show_journal: bool,
+    history: ObjectHistory,
+    versions: Versions,
+    /// Saved as its identity alone, the changes since the last save being in the change
+    /// journal.
+    op_log: OpLog,
+    version_history: VersionHistory,
+    #[serde(skip)]
+    show_versions: bool,
+    /// A copy of the painting at a past version, to be opened in a tab of its own.
+    #[serde(skip)]
+    branch: Option<Box<Painting>>,
+    /// Applied on the next frame, once the loaded strokes are known.
+    #[serde(skip)]
+    journal_action: Option<JournalAction>,
+    #[serde(skip)]
+    show_clusters: bool,
+    collab: CollabSettings,
+    #[serde(skip)]
+    show_collab: bool,
+    #[serde(skip)]
+    collaboration: Option<Collaboration>,
+    #[serde(skip)]
+    cluster_framing: ClusterFraming,
+    /// Applied on the next frame, once the loaded strokes are known.
+    #[serde(skip)]
+    cluster_action: Option<ClusterAction>,
+    #[serde(skip)]
+    color_replace: ColorReplace,
+    #[serde(skip)]
+    selection: Selection,
+    /// Applied on the next frame, once the strokes around the selection are known.
+    #[serde(skip)]
+    arrange: Option<Arrange>,
+    /// Applied on the next frame, once the bounds of the selected objects are known.
+    #[serde(skip)]
+    align: Option<Align>,
+    /// The handle being dragged and the selection's screen bounds when the drag started.
+    #[serde(skip)]
+    transform_drag: Option<(TransformHandle, Rect)>,
+    /// Where the canvas was last shown, for converting inspector edits from screen points.
+    #[serde(skip)]
+    canvas_rect: Rect,
+    /// Screen bounds of the selected objects in view as of the last frame.
+    #[serde(skip)]
+    selection_rect: Option<Rect>,
+    image_export: ImageExport,
+    /// Whether the next drag over the canvas picks the area to export rather than using the
+    /// tool.
+    #[serde(skip)]
+    picking_export_area: bool,
+    #[serde(skip)]
+    export_drag: Option<(Pos2, Pos2)>,
+    #[serde(skip)]
+    note_drag: Option<(Pos2, Pos2)>,
+    #[serde(skip)]
+    erase_drag: Option<(Pos2, Pos2)>,
+    #[serde(skip)]
+    marquee_drag: Option<(Pos2, Pos2)>,
+    #[serde(skip)]
+    pending_note: Option<PendingNote>,
+    #[serde(skip)]
+    path_drag: Vec<Pos2>,
+    #[serde(skip)]
+    pending_path_text: Option<PendingPathText>,
+    #[serde(skip)]
+    fitted_paste: Option<FittedPaste>,
+    sessions: SessionLog,
+    /// How many changes of the operation log the edits noted in the session log cover.
+    #[serde(skip)]
+    edits_noted: Option<usize>,
+    /// How many of the operation log's changes were recorded in the history of objects.
+    #[serde(skip)]
+    history_noted: Option<usize>,
+    #[serde(skip)]
+    show_sessions: bool,
+    /// Views of the painting in windows of their own.
+    views: Vec<SecondaryView>,
+    #[serde(skip)]
+    stress_config: StressConfig,
+    #[serde(skip)]
+    snapshot: Option<Snapshot>,
+    /// Whether the painting was edited since it was last opened or saved to a file.
+    #[serde(skip)]
+    modified: bool,
+    /// Whether the hashes saved with the tree have been checked since it was loaded.
+    #[serde(skip)]
+    integrity_checked: bool,
+    /// Paths to nodes whose content did not match their saved hash, not yet shown.
+    #[serde(skip)]
+    damaged_nodes: Vec<Vec<(u8, u8)>>,
+    /// Result of the last comparison against a painting on the clipboard or the operation log.
+    #[serde(skip)]
+    comparison: Option<String>,
+    /// How a copy of the painting differs from it, shown over the canvas until dismissed.
+    #[serde(skip)]
+    diff: Option<Diff>,
+    /// Objects this copy and another changed differently, kept as they are here until resolved.
+    #[serde(skip)]
+    conflicts: Conflicts,
+    /// Text being read from the clipboard, and what it is for once it arrives.
+    #[serde(skip)]
+    clipboard_read: Option<(ClipboardUse, ClipboardText)>,
+    /// Why the last import failed, until the dialog saying so is closed.
+    #[serde(skip)]
+    import_error: Option<String>,
+    /// Why the last action could not be done, until the dialog saying so is closed.
+    #[serde(skip)]
+    action_error: Option<String>,
+    /// Statistics of the tree from the last time they were measured in the debug menu.
+    #[serde(skip)]
+    tree_stats: Option<TreeStats>,
+    /// What the last frame drew, reused while neither the view nor the content changes.
+    #[serde(skip)]
+    last_frame: Option<CollectedFrame>,
+}
+
+/// The tree is read back as a `CanvasTree` and written from a reference to one.
+#[derive(Deserialize, Serialize)]
+struct CircularBufferSerialization<T = CanvasTree> {
+    center_path: Vec<(u8, u8)>,
+    top_level_parent: T,
+}
+
+/// Paintings are saved as the strokes alone under `canvas`, where they sit in the tree and what
+/// was in view under `view`, and everything else under `state`. Painting the same strokes so
+/// always saves the same canvas section, which starts the file, however the view moved and the
+/// tree grew around them. A painting whose tree is saved on its own has only `state`.
+impl Serialize for Painting {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let detached = is_detached(&self.draw_boxes);
+        let mut saved = serializer.serialize_struct("Painting", if detached { 1 } else { 3 })?;
+        if !detached {
+            let tree = &self.draw_boxes.tree;
+            let root = tree.root();
+            tree.update_hashes(root);
+            let (canvas, above) = tree.trimmed(root).map_err(S::Error::custom)?;
+            let center = *self.draw_boxes.get(0, 0).unwrap();
+            saved.serialize_field("canvas", &canvas)?;
+            saved.serialize_field(
+                "view",
+                &CanvasPlacement {
+                    above,
+                    corner: tree[root].corner,
+                    center: tree.get_top_level_and_path(center).1,
+                },
+            )?;
+        }
+        saved.serialize_field("state", &PaintingState(self))?;
+        saved.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Painting {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_struct("Painting", &["canvas", "view", "state"], SavedPainting)
+    }
+}
+
+/// Where the saved canvas sits in the tree, and which part of it was in view.
+#[derive(Deserialize, Serialize, Default)]
+#[serde(default)]
+struct CanvasPlacement {
+    /// Corners from the outermost node of the saved canvas to the outermost node of the tree,
+    /// innermost first.
+    above: Vec<(u8, u8)>,
+    /// Corner of the outermost node of the tree, which decides which way it grows next.
+    corner: (u8, u8),
+    /// Path from the outermost node of the tree to the center cell, in the order
+    /// `get_or_create_path` takes it.
+    center: Vec<(u8, u8)>,
+}
+
+impl CanvasPlacement {
+    /// The cells in view over `canvas`, grown back into the tree it was saved from.
+    fn cells(mut self, mut canvas: CanvasTree) -> Cells {
+        for corner in self.above {
+            let root = canvas.root();
+            canvas[root].corner = corner;
+            canvas.get_or_create_parent(root);
+        }
+        let root = canvas.root();
+        canvas[root].corner = self.corner;
+        let center = canvas.get_or_create_path(&mut self.center, root);
+        cells_around(canvas, center)
+    }
+}
+
+/// Everything saved of a painting but its tree.
+struct PaintingState<'a>(&'a Painting);
+
+impl Serialize for PaintingState<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Painting::serialize(self.0, serializer)
+    }
+}
+
+/// Reads the `state` section.
+struct StateSeed;
+
+impl<'de> DeserializeSeed<'de> for StateSeed {
+    type Value = Painting;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Painting, D::Error> {
+        Painting::deserialize(deserializer)
+    }
+}
+
+struct SavedPainting;
+
+impl<'de> Visitor<'de> for SavedPainting {
+    type Value = Painting;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        formatter.write_str("a painting")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Painting, A::Error> {
+        let Some(FieldName(first)) = map.next_key()? else {
+            return Ok(Painting::default());
+        };
+        if !["canvas", "view", "state"].contains(&first.as_str()) {
+            // Saved with every field of the painting at the top, the tree among them.
+            return Painting::deserialize(MapAccessDeserializer::new(Replayed {
+                key: Some(first),
+                map,
+            }));
+        }
+        let (mut canvas, mut placement, mut painting) = (None, CanvasPlacement::default(), None);
+        let mut key = Some(first);
+        while let Some(name) = key {
+            match name.as_str() {
+                "canvas" => canvas = Some(map.next_value::<CanvasTree>()?),
+                "view" => placement = map.next_value()?,
+                "state" => painting = Some(map.next_value_seed(StateSeed)?),
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+            key = map.next_key::<FieldName>()?.map(|FieldName(name)| name);
+        }
+        let mut painting = painting.unwrap_or_default();
+        if let Some(canvas) = canvas {
+            painting.draw_boxes = placement.cells(canvas);
+        }
+        Ok(painting)
+    }
+}
+
+/// The name of a field, read as an identifier so that every format gives it.
+struct FieldName(String);
+
+impl<'de> Deserialize<'de> for FieldName {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct NameVisitor;
+
+        impl Visitor<'_> for NameVisitor {
+            type Value = FieldName;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                formatter.write_str("a field name")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<FieldName, E> {
+                Ok(FieldName(value.to_string()))
+            }
+        }
+
+        deserializer.deserialize_identifier(NameVisitor)
+    }
+}
+
+/// The fields of a map whose first key was already read, giving that key back first.
+struct Replayed<A> {
+    key: Option<String>,
+    map: A,
+}
+
+impl<'de, A: MapAccess<'de>> MapAccess<'de> for Replayed<A> {
+    type Error = A::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, A::Error> {
+        match self.key.take() {
+            Some(key) => seed.deserialize(key.into_deserializer()).map(Some),
+            None => self.map.next_key_seed(seed),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, A::Error> {
+        self.map.next_value_seed(seed)
+    }
+}
+
+fn structure_serializer<S>(structure: &Cells, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let Some(&center_cell) = structure.get(0, 0) else {
+        panic!("No center cell to serialize from");
+    };
+    let (top_level, path) = structure.tree.get_top_level_and_path(center_cell);
+    structure.tree.update_hashes(top_level);
+    CircularBufferSerialization {
+        center_path: path,
+        top_level_parent: &structure.tree,
+    }
+    .serialize(serializer)
+}
+
+fn structure_deserializer<'de, D>(deserializer: D) -> Result<Cells, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let serialization = CircularBufferSerialization::<CanvasTree>::deserialize(deserializer)?;
+    let mut center_path = serialization.center_path;
+    let mut tree = serialization.top_level_parent;
+    // The center cell is left out of the tree if it had no strokes.
+    let center = tree.get_or_create_path(&mut center_path, tree.root());
+    Ok(cells_around(tree, center))
+}
+
+/// The cells around the view, along with the tree they are part of.
+#[derive(Default)]
+struct Cells {
+    buffer: CircularBuffer2D<NodeId, 5>,
+    tree: CanvasTree,
+}
+
+impl Deref for Cells {
+    type Target = CircularBuffer2D<NodeId, 5>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.buffer
+    }
+}
+
+impl Cells {
+    fn set(&mut self, x: i32, y: i32, node: NodeId) {
+        self.buffer.set(x, y, node, &mut self.tree);
+    }
+
+    fn clear_all(&mut self) {
+        self.buffer.clear_all(&mut self.tree);
+    }
+
+    fn shift_pos_x(&mut self) {
+        self.buffer.shift_pos_x(&mut self.tree);
+    }
+
+    fn shift_neg_x(&mut self) {
+        self.buffer.shift_neg_x(&mut self.tree);
+    }
+
+    fn shift_pos_y(&mut self) {
+        self.buffer.shift_pos_y(&mut self.tree);
+    }
+
+    fn shift_neg_y(&mut self) {
+        self.buffer.shift_neg_y(&mut self.tree);
+    }
+
+    fn zoom_in(&mut self, corner: (u8, u8)) {
+        self.buffer.zoom_in(&mut self.tree, corner);
+    }
+
+    fn zoom_out(&mut self) {
+        self.buffer.zoom_out(&mut self.tree);
+    }
+
+    fn load_all(&mut self) {
+        self.buffer.load_all(&mut self.tree);
+    }
+}
+
+/// The cells around `center`, a node of `tree`, which they take ownership of.
+fn cells_around(tree: CanvasTree, center: NodeId) -> Cells {
+    let mut cells = Cells {
+        buffer: CircularBuffer2D::default(),
+        tree,
+    };
+    cells.set(0, 0, center);
+    cells.load_all();
+    cells
+}
+
+/// Whether the cells were taken out by `Painting::store_tree`, leaving the tree out of the
+/// saved painting.
+fn is_detached(structure: &Cells) -> bool {
+    structure.get(0, 0).is_none()
+}
+
+/// The cells of a painting whose tree is saved on its own, kept aside while the rest of the
+/// painting is saved.
+pub struct DetachedTree(Cells);
+
+impl Default for Painting {
+    fn default() -> Self {
+        let tree = CanvasTree::default();
+        let root = tree.root();
+        let draw_boxes = cells_around(tree, root);
+        Self {
+            draw_boxes,
+            last_cursor_pos: None,
+            zoom: 1.0,
+            pan: vec2(0.0, 0.0),
+            stroke: Stroke::new(1.0, Color32::from_rgb(25, 200, 100)),
+            highlighter: Stroke::new(8.0, Color32::from_rgba_unmultiplied(255, 230, 0, 90)),
+            line_style: LineStyle::Solid,
+            taper: false,
+            smoothing: 0.0,
+            low_latency: false,
+            quality: RenderQuality::default(),
+            memory_budget: 1024,
+            pager: Pager::default(),
+            save_format: SaveFormat::default(),
+            world: WorldBounds::default(),
+            live_stroke: vec![],
+            live_stroke_origin: None,
+            drawn_stroke: vec![],
+            drawn_stroke_view: (Vec2::ZERO, 1.0),
+            dash_phase: 0.0,
+            last_width: 1.0,
+            next_stroke_order: ordering::ORDER_SPACING,
+            next_group: 0,
+            debug_render: false,
+            show_hud: false,
+            hud: PerfHud::default(),
+            tool: Tool::Pen,
+            note_color: Color32::from_rgb(255, 235, 130),
+            fill_color: Color32::from_rgb(120, 170, 230),
+            text_color: Color32::from_gray(40),
+            text_size: 16.0,
+            palette: Palette::default(),
+            secondary_color: Color32::from_rgb(230, 80, 60),
+            use_secondary: false,
+            mouse_mappings: MouseMappings::default(),
+            layers: Layers::default(),
+            show_layers: false,
+            show_replace_color: false,
+            journal: Journal::default(),
+            show_journal: false,
+            history: ObjectHistory::default(),
+            versions: Versions::default(),
+            op_log: OpLog::default(),
+            version_history: VersionHistory::default(),
+            show_versions: false,
+            branch: None,
+            journal_action: None,
+            show_clusters: false,
+            collab: CollabSettings::default(),
+            show_collab: false,
+            collaboration: None,
+            cluster_framing: ClusterFraming::default(),
+            cluster_action: None,
+            color_replace: ColorReplace::default(),
+            selection: Selection::default(),
+            arrange: None,
+            align: None,
+            transform_drag: None,
+            canvas_rect: Rect::NOTHING,
+            selection_rect: None,
+            image_export: ImageExport::default(),
+            picking_export_area: false,
+            export_drag: None,
+            note_drag: None,
+            erase_drag: None,
+            marquee_drag: None,
+            pending_note: None,
+            path_drag: vec![],
+            pending_path_text: None,
+            fitted_paste: None,
+            sessions: SessionLog::default(),
+            edits_noted: None,
+            history_noted: None,
+            show_sessions: false,
+            views: vec![],
+            stress_config: StressConfig::default(),
+            snapshot: None,
+            modified: false,
+            integrity_checked: false,
+            damaged_nodes: vec![],
+            comparison: None,
+            diff: None,
+            conflicts: Conflicts::default(),
+            clipboard_read: None,
+            import_error: None,
+            action_error: None,
+            tree_stats: None,
+            last_frame: None,
+        }
+    }
 }
 
-#[derive(Deserialize, Serialize)]
-struct CircularBufferSerialization {
-    center_path: Vec<(u8, u8)>,
-    top_level_parent: DrawNodeRef,
-}
+const STANDARD_COORD_BOUNDS: Rect = Rect::from_min_max(pos2(-1.0, -1.0), pos2(1.0, 1.0));
+
+impl Painting {
+    pub fn write_settings(&self, profile: &mut SettingsProfile) {
+        profile.insert("brush", &self.stroke);
+        profile.insert("highlighter", &self.highlighter);
+        profile.insert("secondary_color", &self.secondary_color);
+        profile.insert("palette", &self.palette);
+        profile.insert("line_style", &self.line_style);
+        profile.insert("taper", &self.taper);
+        profile.insert("smoothing", &self.smoothing);
+        profile.insert("low_latency", &self.low_latency);
+        profile.insert("quality", &self.quality);
+        profile.insert("debug_render", &self.debug_render);
+        profile.insert("perf_hud", &self.show_hud);
+        profile.insert("mouse", &self.mouse_mappings);
+        profile.insert("author", &self.history.author);
+    }
+
+    pub fn read_settings(&mut self, profile: &SettingsProfile) {
+        if let Some(stroke) = profile.get("brush") {
+            self.stroke = stroke;
+        }
+        if let Some(highlighter) = profile.get("highlighter") {
+            self.highlighter = highlighter;
+        }
+        if let Some(secondary_color) = profile.get("secondary_color") {
+            self.secondary_color = secondary_color;
+        }
+        if let Some(palette) = profile.get("palette") {
+            self.palette = palette;
+        }
+        if let Some(line_style) = profile.get("line_style") {
+            self.line_style = line_style;
+        }
+        if let Some(taper) = profile.get("taper") {
+            self.taper = taper;
+        }
+        if let Some(author) = profile.get("author") {
+            self.history.author = author;
+        }
+        if let Some(smoothing) = profile.get("smoothing") {
+            self.smoothing = smoothing;
+        }
+        if let Some(low_latency) = profile.get("low_latency") {
+            self.low_latency = low_latency;
+        }
+        if let Some(quality) = profile.get("quality") {
+            self.quality = quality;
+        }
+        if let Some(debug_render) = profile.get("debug_render") {
+            self.debug_render = debug_render;
+        }
+        if let Some(show_hud) = profile.get("perf_hud") {
+            self.show_hud = show_hud;
+        }
+        if let Some(mouse_mappings) = profile.get("mouse") {
+            self.mouse_mappings = mouse_mappings;
+        }
+    }
+
+    pub fn ui_control(&mut self, ui: &mut egui::Ui) -> egui::Response {
+        ui.horizontal(|ui| {
+            ui.selectable_value(&mut self.tool, Tool::Select, "Select");
+            ui.selectable_value(&mut self.tool, Tool::Pen, "Pen");
+            ui.selectable_value(&mut self.tool, Tool::Highlighter, "Highlighter");
+            ui.selectable_value(&mut self.tool, Tool::StickyNote, "Sticky note");
+            ui.selectable_value(&mut self.tool, Tool::Fill, "Fill");
+            ui.selectable_value(&mut self.tool, Tool::PathText, "Text path")
+                .on_hover_text("Draw a curve, then type text to follow it");
+            ui.selectable_value(&mut self.tool, Tool::Restyle, "Restyle")
+                .on_hover_text("Click a stroke to give it the pen's color and width");
+            ui.selectable_value(&mut self.tool, Tool::EraseRegion, "Erase region")
+                .on_hover_text("Drag a rectangle to delete every stroke touching it");
+            ui.separator();
+            match self.tool {
+                Tool::Select => {
+                    ui.label(format!("{} selected", self.selection.len()));
+                    ui.add_enabled_ui(!self.selection.is_empty(), |ui| {
+                        if ui.button("Bring to front").clicked() {
+                            self.arrange = Some(Arrange::BringToFront);
+                        }
+                        if ui.button("Send to back").clicked() {
+                            self.arrange = Some(Arrange::SendToBack);
+                        }
+                        if ui
+                            .add_enabled(self.selection.len() > 1, egui::Button::new("Group"))
+                            .on_hover_text("Ctrl+G")
+                            .clicked()
+                        {
+                            self.group_selection();
+                        }
+                        if ui.button("Ungroup").on_hover_text("Ctrl+Shift+G").clicked() {
+                            self.ungroup_selection();
+                        }
+                        ui.add_enabled_ui(self.selection.len() > 1, |ui| {
+                            ui.menu_button("Align", |ui| {
+                                for align in Align::ALIGN {
+                                    if ui.button(align.name()).clicked() {
+                                        self.align = Some(align);
+                                        ui.close_menu();
+                                    }
+                                }
+                                ui.separator();
+                                for align in
+                                    [Align::DistributeHorizontally, Align::DistributeVertically]
+                                {
+                                    if ui.button(align.name()).clicked() {
+                                        self.align = Some(align);
+                                        ui.close_menu();
+                                    }
+                                }
+                            });
+                        });
+                    });
+                }
+                Tool::Pen => {
+                    ui.label("Stroke:");
+                    ui.add(&mut self.stroke);
+                    ui.checkbox(&mut self.taper, "Taper");
+                    ui.add(
+                        egui::Slider::new(&mut self.smoothing, 0.0..=0.9)
+                            .text("Smoothing")
+                            .max_decimals(2),
+                    );
+                }
+                Tool::Highlighter => {
+                    ui.label("Highlighter:");
+                    ui.add(&mut self.highlighter);
+                }
+                Tool::StickyNote => {
+                    ui.label("Note color:");
+                    ui.color_edit_button_srgba(&mut self.note_color);
+                }
+                Tool::Fill => {
+                    ui.label("Fill color:");
+                    ui.color_edit_button_srgba(&mut self.fill_color);
+                }
+                Tool::PathText => {
+                    ui.label("Text:");
+                    ui.color_edit_button_srgba(&mut self.text_color);
+                    ui.add(
+                        egui::DragValue::new(&mut self.text_size)
+                            .range(MIN_BRUSH_WIDTH..=MAX_BRUSH_WIDTH)
+                            .speed(0.1)
+                            .suffix(" pt"),
+                    );
+                }
+                Tool::Restyle => {
+                    ui.label("Stroke:");
+                    ui.add(&mut self.stroke);
+                }
+                Tool::EraseRegion => {
+                    ui.label("Strokes on hidden or locked layers are kept");
+                }
+            }
+            if matches!(self.tool, Tool::Pen | Tool::Highlighter | Tool::Fill) {
+                ui.label("Secondary:");
+                ui.color_edit_button_srgba(&mut self.secondary_color)
+                    .on_hover_text("Used when drawing with the right mouse button");
+                if ui
+                    .small_button("⇄")
+                    .on_hover_text("Swap colors (X)")
+                    .clicked()
+                {
+                    self.swap_colors();
+                }
+            }
+            if !matches!(self.tool, Tool::Select | Tool::EraseRegion) {
+                let current = *self.tool_color_mut();
+                if let Some(color) = self.palette.ui(ui, current) {
+                    *self.tool_color_mut() = color;
+                }
+            }
+            if self.tool == Tool::Highlighter || self.tool == Tool::Pen && !self.taper {
+                egui::ComboBox::from_id_salt("line_style")
+                    .selected_text(self.line_style.name())
+                    .show_ui(ui, |ui| {
+                        for style in [LineStyle::Solid, LineStyle::Dashed, LineStyle::Dotted] {
+                            ui.selectable_value(&mut self.line_style, style, style.name());
+                        }
+                    });
+            }
+            ui.separator();
+            if ui.button("Clear Painting").clicked() {
+                self.replace(Self::default(), "Cleared painting");
+            }
+            ui.checkbox(&mut self.debug_render, "Debug render");
+            ui.checkbox(&mut self.show_hud, "Perf HUD")
+                .on_hover_text("Show frame rate and drawing statistics over the canvas");
+            ui.checkbox(&mut self.low_latency, "Low latency")
+                .on_hover_text("Preview strokes directly and add them to the canvas on release");
+            egui::ComboBox::from_id_salt("render_quality")
+                .selected_text(format!("Quality: {}", self.quality.name()))
+                .show_ui(ui, |ui| {
+                    for quality in RenderQuality::ALL {
+                        ui.selectable_value(&mut self.quality, quality, quality.name());
+                    }
+                })
+                .response
+                .on_hover_text("Lower quality skips tiny details and smoothing to draw faster");
+            ui.menu_button("Debug", |ui| self.debug_menu(ui));
+            ui.menu_button("Bounds", |ui| match self.world.ui(ui) {
+                Some(WorldAction::Bound { levels }) => {
+                    let mut node = *self.draw_boxes.get(0, 0).unwrap();
+                    let tree = &mut self.draw_boxes.tree;
+                    for _ in 0..levels {
+                        node = tree.get_or_create_parent(node);
+                    }
+                    self.world.bound(tree, node);
+                    ui.close_menu();
+                }
+                Some(WorldAction::Unbound) => {
+                    self.world.unbound();
+                    ui.close_menu();
+                }
+                None => {}
+            });
+            ui.toggle_value(&mut self.show_sessions, "Sessions");
+            ui.toggle_value(&mut self.show_layers, "Layers");
+            ui.toggle_value(&mut self.show_replace_color, "Replace color");
+            ui.toggle_value(&mut self.show_journal, "Journal");
+            ui.toggle_value(&mut self.show_versions, "History")
+                .on_hover_text("Keep named versions of the canvas to restore or branch from");
+            ui.toggle_value(&mut self.show_clusters, "Clusters")
+                .on_hover_text("Find groups of content and wrap them in named frames");
+            ui.toggle_value(&mut self.show_collab, "Collaborate")
+                .on_hover_text("Draw together with others in the same room of a relay server");
+            if ui.button("Export").clicked() {
+                match self.save_format {
+                    SaveFormat::Ron => match self.to_ron() {
+                        Ok(export) => ui.output_mut(|output| output.copied_text = export),
+                        Err(err) => {
+                            self.action_error =
+                                Some(format!("Could not export the painting:\n{err}"));
+                        }
+                    },
+                    // Binary data cannot go through the clipboard, so it is written to a file
+                    // whose path is copied instead.
+                    SaveFormat::Binary => match self.export_file() {
+                        Ok(path) => {
+                            log::info!("Exported the painting to {}", path.display());
+                            ui.output_mut(|output| output.copied_text = path.display().to_string());
+                        }
+                        Err(err) => {
+                            self.action_error =
+                                Some(format!("Could not export the painting:\n{err}"));
+                        }
+                    },
+                }
+            }
+            ui.menu_button("Export image", |ui| self.export_image_menu(ui))
+                .response
+                .on_hover_text("Save part of the canvas as a PNG image or a PDF");
+            if ui
+                .button("Import")
+                .on_hover_text("Import a painting, or the file of one, from the clipboard")
+                .clicked()
+            {
+                self.clipboard_read = Some((ClipboardUse::Import, ClipboardText::read(ui.ctx())));
+            }
+            if ui
+                .button("Merge")
+                .on_hover_text(
+                    "Merge in a copy of this painting, or the file of one, from the clipboard, \
+                    keeping the latest changes made to each object in either",
+                )
+                .clicked()
+            {
+                self.clipboard_read = Some((ClipboardUse::Merge, ClipboardText::read(ui.ctx())));
+            }
+            if ui
+                .button("Diff")
+                .on_hover_text(
+                    "Show how a copy of this painting, or the file of one, from the clipboard \
+                    differs from it: objects it adds in green, and those it lacks in red",
+                )
+                .clicked()
+            {
+                self.clipboard_read = Some((ClipboardUse::Diff, ClipboardText::read(ui.ctx())));
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            if ui
+                .button("Import file…")
+                .on_hover_text(
+                    "Add the shapes of an SVG or Excalidraw file, or the pages of a PDF to write \
+                    on, to the middle of the view",
+                )
+                .clicked()
+            {
+                ui.close_menu();
+                if let Some(path) = rfd::FileDialog::new()
+                    .add_filter("SVG", &["svg"])
+                    .add_filter("Excalidraw", &["excalidraw"])
+                    .add_filter("PDF", &["pdf"])
+                    .pick_file()
+                {
+                    let extension = path.extension().and_then(|extension| extension.to_str());
+                    let import: ShapeImport = match extension {
+                        Some("excalidraw") => excalidraw::import,
+                        Some("pdf") => pdf::import_pages,
+                        _ => svg::import,
+                    };
+                    let result = std::fs::read(&path)
+                        .map_err(|err| err.to_string())
+                        .and_then(|data| self.import_shapes(&data, import));
+                    if let Err(err) = result {
+                        log::error!("Failed to import {}: {err}", path.display());
+                    }
+                }
+            }
+            if ui
+                .button("Export Excalidraw")
+                .on_hover_text("Save what is in view as an Excalidraw file")
+                .clicked()
+            {
+                ui.close_menu();
+                if let Err(err) = self.export_excalidraw(ui.ctx()) {
+                    log::error!("Failed to export to Excalidraw: {err}");
+                }
+            }
+        })
+        .response
+    }
+
+    pub fn brush_preset(&self, name: String) -> BrushPreset {
+        BrushPreset {
+            name,
+            stroke: self.stroke,
+            line_style: self.line_style,
+            taper: self.taper,
+            smoothing: self.smoothing,
+        }
+    }
+
+    pub fn apply_brush_preset(&mut self, preset: &BrushPreset) {
+        self.tool = Tool::Pen;
+        self.stroke = preset.stroke;
+        self.line_style = preset.line_style;
+        self.taper = preset.taper;
+        self.smoothing = preset.smoothing;
+    }
+
+    /// Exchanges the active tool's color with the secondary color.
+    fn swap_colors(&mut self) {
+        let secondary = self.secondary_color;
+        self.secondary_color = std::mem::replace(self.tool_color_mut(), secondary);
+    }
+
+    /// The stroke width of the active tool, if it has one.
+    fn tool_width_mut(&mut self) -> Option<&mut f32> {
+        match self.tool {
+            Tool::Pen | Tool::Restyle => Some(&mut self.stroke.width),
+            Tool::Highlighter => Some(&mut self.highlighter.width),
+            Tool::PathText => Some(&mut self.text_size),
+            Tool::Select | Tool::StickyNote | Tool::Fill | Tool::EraseRegion => None,
+        }
+    }
+
+    /// Metadata for the next stroke drawn on the active layer.
+    fn stroke_meta(&mut self, priority: StrokePriority) -> StrokeMeta {
+        StrokeMeta {
+            order: self.next_stroke_order,
+            priority,
+            layer: self.layers.active(),
+            created: Utc::now().timestamp(),
+            author: (!self.history.author.is_empty()).then(|| self.history.author.clone()),
+            id: self.versions.created(self.next_stroke_order),
+        }
+    }
+
+    /// Sorts strokes bottom to top, by layer and then by their own sort key.
+    fn sort_strokes(&self, strokes: &mut [(StrokeEntry, Rect)]) {
+        strokes.sort_by_key(|(stroke, _)| (self.layers.rank(stroke.layer), stroke.sort_key()));
+    }
+
+    fn layers_window(&mut self, ctx: &egui::Context) {
+        let mut action = None;
+        egui::Window::new("Layers")
+            .open(&mut self.show_layers)
+            .show(ctx, |ui| action = self.layers.ui(ui));
+        match action {
+            Some(LayerAction::Delete(layer)) => {
+                let Some(top_level) = self.top_level() else {
+                    return;
+                };
+                let snapshot = self.take_snapshot("Deleted layer");
+                let deleted = self
+                    .draw_boxes
+                    .tree
+                    .retain_strokes(top_level, &|stroke| stroke.layer != layer);
+                self.versions.touch(deleted);
+                self.snapshot = snapshot;
+                self.modified = true;
+            }
+            Some(LayerAction::Export(layers)) => {
+                if let Err(err) = self.export_layers(ctx, &layers) {
+                    log::error!("Failed to export layers: {err}");
+                }
+            }
+            None => {}
+        }
+    }
+
+    fn replace_color_window(&mut self, ctx: &egui::Context, rect: Rect) {
+        let mut action = None;
+        egui::Window::new("Replace color")
+            .open(&mut self.show_replace_color)
+            .show(ctx, |ui| action = self.color_replace.ui(ui));
+        match action {
+            Some(ReplaceAction::Start) => {
+                let nodes = match self.color_replace.scope {
+                    ReplaceScope::Canvas => {
+                        let Some(top_level) = self.top_level() else {
+                            return;
+                        };
+                        vec![(top_level, rect, true)]
+                    }
+                    ReplaceScope::View => {
+                        let (cells, ancestors) = self.visible_nodes(rect);
+                        cells
+                            .into_iter()
+                            .map(|(node, screen_rect)| (node, screen_rect, true))
+                            .chain(
+                                ancestors
+                                    .into_iter()
+                                    .map(|(node, screen_rect)| (node, screen_rect, false)),
+                            )
+                            .collect()
+                    }
+                };
+                let snapshot = self.take_snapshot("Replaced color");
+                self.color_replace.start(nodes, rect);
+                self.snapshot = snapshot;
+                self.modified = true;
+            }
+            Some(ReplaceAction::Cancel) => self.color_replace.cancel(),
+            None => {}
+        }
+        if self.color_replace.is_running() {
+            let recolored = self
+                .color_replace
+                .step(&self.layers, &mut self.draw_boxes.tree);
+            if !recolored.is_empty() {
+                self.versions.touch(recolored);
+                self.note_changes(ObjectChange::Recolored);
+                self.record_edit();
+            }
+            ctx.request_repaint();
+        }
+    }
+
+    fn journal_window(&mut self, ctx: &egui::Context) {
+        let has_selection = !self.selection.is_empty();
+        egui::Window::new("Journal")
+            .open(&mut self.show_journal)
+            .show(ctx, |ui| {
+                if let Some(action) = self.journal.ui(ui, has_selection) {
+                    self.journal_action = Some(action);
+                }
+            });
+    }
+
+    fn clusters_window(&mut self, ctx: &egui::Context) {
+        egui::Window::new("Content clusters")
+            .open(&mut self.show_clusters)
+            .show(ctx, |ui| {
+                if let Some(action) = self.cluster_framing.ui(ui) {
+                    self.cluster_action = Some(action);
+                }
+            });
+    }
+
+    fn collab_window(&mut self, ctx: &egui::Context) {
+        let mut action = None;
+        egui::Window::new("Collaborate")
+            .open(&mut self.show_collab)
+            .show(ctx, |ui| {
+                action = self.collab.ui(ui, self.collaboration.as_ref());
+            });
+        match action {
+            Some(CollabAction::Join) => match Collaboration::join(ctx, &self.collab) {
+                Ok(collaboration) => self.collaboration = Some(collaboration),
+                Err(err) => log::error!("Failed to join the room: {err}"),
+            },
+            Some(CollabAction::Leave) => self.collaboration = None,
+            Some(CollabAction::Lock) => {
+                let path = self.current_location().path();
+                let rect = Rect::from_center_size(self.pan.to_pos2(), Vec2::splat(1.0 / self.zoom));
+                if let Some(collaboration) = &mut self.collaboration {
+                    collaboration.lock(self.history.author.clone(), path, rect);
+                }
+            }
+            Some(CollabAction::Unlock) => {
+                if let Some(collaboration) = &mut self.collaboration {
+                    collaboration.unlock();
+                }
+            }
+            None => {}
+        }
+    }
+
+    fn versions_window(&mut self, ctx: &egui::Context) {
+        let mut action = None;
+        egui::Window::new("History")
+            .open(&mut self.show_versions)
+            .show(ctx, |ui| {
+                action = self.version_history.ui(ui);
+            });
+        match action {
+            Some(VersionAction::Take(name)) => {
+                if self.top_level().is_some() {
+                    self.version_history
+                        .take(name, &self.op_log, &self.draw_boxes.tree);
+                    self.modified = true;
+                }
+            }
+            Some(VersionAction::Restore(index)) => {
+                let snapshot = self.take_snapshot("Restored version");
+                self.restore_version(index);
+                self.snapshot = snapshot;
+            }
+            Some(VersionAction::Branch(index)) => {
+                match self.to_ron().map(|ron| Self::from_ron(&ron)) {
+                    Ok(Ok(mut branch)) => {
+                        branch.restore_version(index);
+                        branch.show_versions = false;
+                        branch.views.clear();
+                        self.branch = Some(Box::new(branch));
+                    }
+                    Ok(Err(err)) | Err(err) => log::error!("Failed to copy painting: {err}"),
+                }
+            }
+            Some(VersionAction::Delete(index)) => {
+                self.version_history.delete(index);
+                self.modified = true;
+            }
+            None => {}
+        }
+    }
+
+    /// Replaces the strokes of the canvas with those of the version at `index`, keeping the view.
+    fn restore_version(&mut self, index: usize) {
+        let Some(level) = self.version_history.get(index).map(|version| version.level) else {
+            return;
+        };
+        if self.top_level().is_none() {
+            return;
+        }
+        let strokes = self.version_history.strokes(index);
+        self.op_log.record(&mut self.draw_boxes.tree);
+        let tree = &mut self.draw_boxes.tree;
+        for _ in self.op_log.root_level()..level {
+            tree.get_or_create_parent(tree.root());
+        }
+        // The version's outermost node is where the canvas's was when it was taken, which the
+        // tree has grown around since, each old outermost node sitting opposite its parent's
+        // corner.
+        let mut node = tree.root();
+        for _ in level..self.op_log.root_level() {
+            let corner = tree[node].corner;
+            node = tree.get_or_create_child_from_corner(node, (1 - corner.0, 1 - corner.1));
+        }
+        let root = tree.root();
+        let mut changed = tree.retain_strokes(root, &|_| false);
+        for (mut path, strokes) in strokes {
+            changed.extend(strokes.iter().map(|stroke| stroke.order));
+            let target = tree.get_or_create_path(&mut path, node);
+            tree.insert_strokes(target, strokes);
+        }
+        self.versions.touch(changed);
+        self.selection.clear();
+        self.fitted_paste = None;
+        self.rebase_locations();
+        self.record_edit();
+    }
+
+    /// A copy of the painting at a past version, once one was asked to be branched off.
+    pub fn take_branch(&mut self) -> Option<Painting> {
+        self.branch.take().map(|branch| *branch)
+    }
+
+    /// Screen bounds of the loaded strokes among `strokes` whose orders are in `orders`.
+    fn orders_bounds(strokes: &[(StrokeEntry, Rect)], orders: &BTreeSet<u64>) -> Option<Rect> {
+        strokes
+            .iter()
+            .filter(|(stroke, _)| orders.contains(&stroke.order))
+            .map(|(stroke, screen_rect)| stroke_screen_bounds(stroke, *screen_rect))
+            .reduce(Rect::union)
+    }
+
+    /// Returns true if frames were added.
+    fn apply_cluster_action(
+        &mut self,
+        rect: Rect,
+        strokes: &[(StrokeEntry, Rect)],
+        action: ClusterAction,
+    ) -> bool {
+        match action {
+            ClusterAction::Detect => {
+                // Existing frames would join everything inside them into one cluster.
+                let objects = strokes
+                    .iter()
+                    .filter(|(stroke, _)| stroke.drawable.frame_name().is_none())
+                    .map(|(stroke, screen_rect)| {
+                        (stroke.order, stroke_screen_bounds(stroke, *screen_rect))
+                    })
+                    .collect_vec();
+                self.cluster_framing.detect(rect, &objects);
+                false
+            }
+            ClusterAction::Show(index) => {
+                let bounds = self
+                    .cluster_framing
+                    .clusters
+                    .get(index)
+                    .and_then(|cluster| Self::orders_bounds(strokes, &cluster.orders));
+                if let Some(bounds) = bounds {
+                    self.pan += (bounds.center() - rect.center()) / self.zoom / rect.size();
+                }
+                false
+            }
+            ClusterAction::Frame => {
+                let clusters = std::mem::take(&mut self.cluster_framing).clusters;
+                let mut framed = false;
+                for cluster in clusters.into_iter().filter(|cluster| cluster.include) {
+                    let Some(bounds) = Self::orders_bounds(strokes, &cluster.orders) else {
+                        continue;
+                    };
+                    let bounds = bounds.expand(FRAME_PADDING);
+                    let Some(shape) = self.locate_shape(rect, &[bounds.min, bounds.max], 0.0)
+                    else {
+                        continue;
+                    };
+                    let meta = self.stroke_meta(StrokePriority::Underlay);
+                    self.send_shape(shape, meta, |points, point_size| {
+                        Box::new(Frame::new(
+                            points[0],
+                            points[1],
+                            cluster.name,
+                            FRAME_TITLE_SIZE * point_size,
+                            FRAME_COLOR,
+                        ))
+                    });
+                    self.next_stroke_order += ordering::ORDER_SPACING;
+                    framed = true;
+                }
+                if framed {
+                    self.record_edit();
+                }
+                framed
+            }
+        }
+    }
+
+    /// Returns true if a page was added.
+    fn apply_journal_action(
+        &mut self,
+        rect: Rect,
+        strokes: &[(StrokeEntry, Rect)],
+        action: JournalAction,
+    ) -> bool {
+        match action {
+            JournalAction::SetTemplate => {
+                if let Some(template) = self.copy_selection(strokes) {
+                    self.journal.set_template(template);
+                }
+                false
+            }
+            JournalAction::NewPage => {
+                let Some(page) = self.journal.page() else {
+                    return false;
+                };
+                let unit = self.node_scale(page.depth);
+                let bounds = page.bounds();
+                let last = self.journal.last_page.and_then(|group| {
+                    strokes
+                        .iter()
+                        .filter(|(stroke, _)| stroke.group == Some(group))
+                        .map(|(stroke, screen_rect)| stroke_screen_bounds(stroke, *screen_rect))
+                        .reduce(Rect::union)
+                });
+                let center = match last {
+                    Some(last) => self.journal.next_center(last, bounds.size() * unit),
+                    None => rect.center(),
+                };
+                // Pastes are centered on the middle of the template, not of the dated page.
+                if !self.paste(rect, page, center - bounds.center().to_vec2() * unit, true) {
+                    return false;
+                }
+                self.journal.last_page = self.group_selection();
+                self.pan += (center - rect.center()) / self.zoom / rect.size();
+                true
+            }
+        }
+    }
+
+    /// Saves a PNG of each of `layers` on its own, as the image export settings ask for, over
+    /// the canvas background with the layer's opacity and blend mode.
+    fn export_layers(
+        &mut self,
+        ctx: &egui::Context,
+        layers: &BTreeSet<LayerId>,
+    ) -> Result<(), String> {
+        let rect = self
+            .export_rect(self.image_export.region)
+            .unwrap_or(self.canvas_rect);
+        let size = self
+            .image_export
+            .image_size(rect.size(), ctx.pixels_per_point());
+        if !export::fits_one_image(size) {
+            return Err(format!(
+                "{} × {} pixels is too large to export a layer at",
+                size[0], size[1]
+            ));
+        }
+        let factor = self.image_export.supersample_for(size);
+        for layer in layers {
+            let image = self.render_region(ctx, rect, size.map(|side| side * factor), Some(*layer));
+            let image = raster::downsample(&image, factor);
+            let png = raster::encode_png(&image).map_err(|err| err.to_string())?;
+            export::save(&format!("{}.png", self.layers.name(*layer)), &png)?;
+        }
+        Ok(())
+    }
+
+    /// The color the active tool draws with. The select and erase region tools share the pen's.
+    fn tool_color_mut(&mut self) -> &mut Color32 {
+        match self.tool {
+            Tool::Select | Tool::Pen | Tool::Restyle | Tool::EraseRegion => &mut self.stroke.color,
+            Tool::Highlighter => &mut self.highlighter.color,
+            Tool::StickyNote => &mut self.note_color,
+            Tool::Fill => &mut self.fill_color,
+            Tool::PathText => &mut self.text_color,
+        }
+    }
+
+    /// Number of strokes in the whole canvas. Paged out subtrees are counted from their
+    /// summaries, so none is loaded.
+    pub fn stroke_count(&self) -> usize {
+        self.draw_boxes
+            .tree
+            .summary(self.outermost())
+            .stroke_count()
+    }
+
+    /// Number of quadtree nodes in the whole canvas, including empty ones.
+    pub fn node_count(&self) -> usize {
+        let mut count = 0;
+        self.draw_boxes
+            .tree
+            .for_each_node(self.outermost(), 0, &mut |node, _| {
+                count += match node.cached_summary().filter(|_| node.is_paged()) {
+                    Some(summary) => summary.node_count(),
+                    None => 1,
+                }
+            });
+        count
+    }
+
+    /// Shallowest and deepest levels holding strokes, counted from the outermost node, which is
+    /// level 0. `None` for an empty canvas.
+    pub fn depth_range(&self) -> Option<(u32, u32)> {
+        self.draw_boxes.tree.summary(self.outermost()).depth_range()
+    }
+
+    /// Replaces the tree with the one kept in `store`, if it holds one. The rest of the tree is
+    /// loaded as the view reaches into it.
+    pub fn load_tree(&mut self, store: &Arc<ChunkStore>) -> Result<(), String> {
+        let Some((mut tree, mut center_path)) = store.load()? else {
+            return Ok(());
+        };
+        let root = tree.root();
+        let center = tree.get_or_create_path(&mut center_path, root);
+        self.draw_boxes = cells_around(tree, center);
+        self.last_frame = None;
+        #[cfg(target_arch = "wasm32")]
+        self.pager.page_to(store);
+        Ok(())
+    }
+
+    /// Writes the chunks of the tree that changed since the last save to `store`.
+    pub fn store_tree(&mut self, store: &Arc<ChunkStore>) -> Result<(), String> {
+        #[cfg(target_arch = "wasm32")]
+        self.pager.page_to(store);
+        let center = *self.draw_boxes.get(0, 0).unwrap();
+        let (_, center_path) = self.draw_boxes.tree.get_top_level_and_path(center);
+        store.save(&mut self.draw_boxes.tree, &center_path, self.save_format)
+    }
+
+    /// Waits for the last save to `store` to be written, and takes in its outcome.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn finish_storing(&mut self, store: &Arc<ChunkStore>) {
+        store.finish_saving(&mut self.draw_boxes.tree);
+    }
+
+    /// Whether the tree changed since it was last saved.
+    pub fn tree_unsaved(&self) -> bool {
+        self.draw_boxes.tree[self.outermost()].is_unsaved()
+    }
+
+    /// The tree alone, to be saved apart from the rest of the painting. `mark_tree_saved`
+    /// should follow once it is.
+    pub fn tree_to_ron(&self) -> Result<String, ron::Error> {
+        let mut out = Vec::new();
+        let mut serializer = ron::ser::Serializer::with_options(
+            &mut out,
+            None,
+            ron::Options::default().without_recursion_limit(),
+        )?;
+        structure_serializer(
+            &self.draw_boxes,
+            serde_stacker::Serializer::new(&mut serializer),
+        )?;
+        Ok(String::from_utf8(out).expect("Ron should be utf-8"))
+    }
+
+    pub fn mark_tree_saved(&mut self) {
+        let outermost = self.outermost();
+        self.draw_boxes.tree.mark_saved(outermost);
+    }
+
+    /// Replaces the tree with one saved by `tree_to_ron`.
+    pub fn load_tree_ron(&mut self, value: &str) -> Result<(), ron::Error> {
+        let mut deserializer = ron::de::Deserializer::from_str_with_options(
+            value,
+            ron::Options::default().without_recursion_limit(),
+        )
+        .map_err(|err| err.code)?;
+        self.draw_boxes =
+            structure_deserializer(serde_stacker::Deserializer::new(&mut deserializer))?;
+        self.mark_tree_saved();
+        self.last_frame = None;
+        Ok(())
+    }
+
+    /// A read-only view of the canvas, showing what was in view.
+    pub(crate) fn into_canvas_view(self) -> CanvasView {
+        let center = *self.draw_boxes.get(0, 0).unwrap();
+        CanvasView::new(
+            self.draw_boxes.tree,
+            center,
+            self.pan,
+            self.zoom,
+            self.layers,
+        )
+    }
+
+    /// Takes the tree out of the painting, so the rest can be saved without it. `attach_tree`
+    /// puts it back.
+    pub fn detach_tree(&mut self) -> DetachedTree {
+        DetachedTree(std::mem::take(&mut self.draw_boxes))
+    }
+
+    pub fn attach_tree(&mut self, tree: DetachedTree) {
+        self.draw_boxes = tree.0;
+    }
+
+    /// Area covered by all strokes, in the coordinates of the outermost node, which spans
+    /// -1 to 1 on each axis. `None` for an empty canvas.
+    pub fn content_bounds(&self) -> Option<Rect> {
+        self.draw_boxes.tree.content_bounds(self.outermost())
+    }
+
+    /// Describes the shape of the canvas without any of its content, for problem reports.
+    pub fn document_report(&mut self) -> String {
+        let mut per_depth = BTreeMap::<u32, (usize, usize)>::new();
+        let mut largest_node = 0;
+        let mut groups = BTreeSet::new();
+        let Some(top_level) = self.top_level() else {
+            return "Part of the canvas is still being read from storage".to_string();
+        };
+        self.draw_boxes
+            .tree
+            .for_each_node(top_level, 0, &mut |node, depth| {
+                let (nodes, strokes) = per_depth.entry(depth).or_default();
+                *nodes += 1;
+                *strokes += node.own_stroke_count();
+                largest_node = largest_node.max(node.own_stroke_count());
+                groups.extend(node.own_strokes().iter().filter_map(|stroke| stroke.group));
+            });
+        let mut report = format!(
+            "nodes: {}\nstrokes: {}\ngroups: {}\nlargest node: {} strokes\ndamaged nodes: {}\n\
+             bounded: {}\nquality: {}\n\ndepth\tnodes\tstrokes\n",
+            per_depth.values().map(|(nodes, _)| nodes).sum::<usize>(),
+            per_depth
+                .values()
+                .map(|(_, strokes)| strokes)
+                .sum::<usize>(),
+            groups.len(),
+            largest_node,
+            self.damaged_nodes.len(),
+            self.world.is_bounded(),
+            self.quality.name(),
+        );
+        for (depth, (nodes, strokes)) in per_depth {
+            report += &format!("{depth}\t{nodes}\t{strokes}\n");
+        }
+        report
+    }
+
+    /// The most recent recorded changes, without who made them, for problem reports.
+    pub fn recent_changes(&self, count: usize) -> String {
+        self.history.recent(count)
+    }
+
+    /// Every change made to the strokes, from which the tree can be rebuilt.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn op_log(&self) -> &OpLog {
+        &self.op_log
+    }
+
+    /// Forgets the changes the operation log holds, once the painting has been saved with them.
+    pub fn compact_op_log(&mut self) {
+        if let Some(collaboration) = &mut self.collaboration {
+            collaboration.take_changes(&self.op_log);
+        }
+        self.note_changes(ObjectChange::Edited);
+        self.op_log.compact();
+    }
+
+    /// Makes `ops`, changes that followed the last in the operation log but were not saved, to
+    /// the tree, recording them in the log.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn recover_changes(&mut self, ops: &[Op]) {
+        // Strokes added to a node still paged out would land before those paged in later.
+        if self.top_level().is_none() {
+            log::error!("Failed to load the canvas to recover changes into");
+            return;
+        }
+        self.op_log.record(&mut self.draw_boxes.tree);
+        for op in ops {
+            oplog::apply(&mut self.draw_boxes.tree, op);
+        }
+        self.rebase_locations();
+        self.modified = true;
+    }
+
+    pub fn to_ron(&self) -> Result<String, ron::Error> {
+        let mut out = Vec::new();
+        let mut serializer = ron::ser::Serializer::with_options(
+            &mut out,
+            None,
+            ron::Options::default().without_recursion_limit(),
+        )?;
+        let serializer = serde_stacker::Serializer::new(&mut serializer);
+        Serialize::serialize(self, serializer)?;
+        Ok(String::from_utf8(out).expect("Ron should be utf-8"))
+    }
+
+    pub fn from_ron(value: &str) -> Result<Self, ron::Error> {
+        let mut deserializer = ron::de::Deserializer::from_str_with_options(
+            value,
+            ron::Options::default().without_recursion_limit(),
+        )
+        .map_err(|err| err.code)?;
+        let deserializer = serde_stacker::Deserializer::new(&mut deserializer);
+        <Painting as Deserialize>::deserialize(deserializer)
+    }
+
+    /// Writes the painting in its save format to the working directory, returning the path
+    /// written.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn export_file(&self) -> Result<std::path::PathBuf, String> {
+        let name = format!(
+            "canvas-{}.{}",
+            chrono::Local::now().format("%Y%m%d-%H%M%S"),
+            self.save_format.extension()
+        );
+        let path = std::env::current_dir()
+            .map_err(|err| err.to_string())?
+            .join(name);
+        self.write_file(&path)?;
+        Ok(path)
+    }
+
+    /// Writes the painting to `path`, in the format its extension names or otherwise in its
+    /// save format.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn write_file(&self, path: &std::path::Path) -> Result<(), String> {
+        let format = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(SaveFormat::from_extension)
+            .unwrap_or(self.save_format);
+        std::fs::write(path, format.encode(self)?).map_err(|err| err.to_string())
+    }
+
+    /// Reads a painting from a file written in either format.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn read_file(path: &std::path::Path) -> Result<Self, String> {
+        let data = std::fs::read(path).map_err(|err| err.to_string())?;
+        format::decode(&data)
+    }
+
+    /// The format the painting is exported and saved in unless told otherwise.
+    pub fn save_format(&self) -> SaveFormat {
+        self.save_format
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn export_file(&self) -> Result<std::path::PathBuf, String> {
+        Err("Exporting files is not supported in the browser yet".to_string())
+    }
+
+    /// Reads a painting exported in either format, or natively the file at the path `value`
+    /// names.
+    pub fn import_from_str(value: &str) -> Result<Self, String> {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let path = std::path::Path::new(value.trim());
+            if path.is_file() {
+                return Painting::read_file(path);
+            }
+        }
+        format::decode(value.as_bytes())
+    }
+
+    /// The screen rect `region` covers, if there is anything there to export.
+    fn export_rect(&self, region: ExportRegion) -> Option<Rect> {
+        let rect = match region {
+            ExportRegion::View => self.canvas_rect,
+            ExportRegion::Selection => self.selection_rect?.intersect(self.canvas_rect),
+            ExportRegion::Area => return None,
+        };
+        rect.is_positive().then_some(rect)
+    }
+
+    fn export_image_menu(&mut self, ui: &mut Ui) {
+        let settings = &mut self.image_export;
+        egui::ComboBox::from_label("Format")
+            .selected_text(settings.format.name())
+            .show_ui(ui, |ui| {
+                for format in ImageFormat::ALL {
+                    ui.selectable_value(&mut settings.format, format, format.name());
+                }
+            });
+        let pdf = settings.format == ImageFormat::Pdf;
+        egui::ComboBox::from_label("Region")
+            .selected_text(settings.region.name())
+            .show_ui(ui, |ui| {
+                for region in ExportRegion::ALL {
+                    ui.selectable_value(&mut settings.region, region, region.name());
+                }
+            });
+        let pixels_per_point = ui.ctx().pixels_per_point();
+        let rect = self.export_rect(self.image_export.region);
+        let settings = &mut self.image_export;
+        ui.horizontal(|ui| {
+            ui.label("Scale");
+            ui.add(
+                egui::DragValue::new(&mut settings.scale)
+                    .range(0.25..=64.0)
+                    .speed(0.05)
+                    .suffix("×"),
+            );
+            for scale in [1.0, 2.0, 4.0] {
+                if ui.small_button(format!("{scale}×")).clicked() {
+                    settings.scale = scale;
+                }
+            }
+            if let Some(rect) = rect.filter(|_| !pdf) {
+                // 8K UHD is 7680 pixels across.
+                if ui.small_button("8K").clicked() {
+                    settings.scale = 7680.0 / (rect.width() * pixels_per_point);
+                }
+            }
+        });
+        if !pdf {
+            egui::ComboBox::from_label("Supersampling")
+                .selected_text(format!("{}×", settings.supersample))
+                .show_ui(ui, |ui| {
+                    for factor in [1, 2, 3, 4] {
+                        ui.selectable_value(
+                            &mut settings.supersample,
+                            factor,
+                            format!("{factor}×"),
+                        );
+                    }
+                });
+        }
+        if settings.region == ExportRegion::Area {
+            if ui
+                .button("Drag an area…")
+                .on_hover_text("Drag a rectangle over the canvas to export what it covers")
+                .clicked()
+            {
+                ui.close_menu();
+                self.picking_export_area = true;
+            }
+            return;
+        }
+        let Some(rect) = rect else {
+            ui.label("Nothing to export");
+            return;
+        };
+        if pdf {
+            let size = rect.size() * settings.scale;
+            ui.label(format!("{:.0} × {:.0} points", size.x, size.y));
+            if ui.button("Export…").clicked() {
+                ui.close_menu();
+                if let Err(err) = self.export_pdf(ui.ctx(), rect) {
+                    log::error!("Failed to export a PDF: {err}");
+                }
+            }
+            return;
+        }
+        let size = settings.image_size(rect.size(), pixels_per_point);
+        ui.label(format!("{} × {} pixels", size[0], size[1]));
+        egui::ComboBox::from_label("Tile size")
+            .selected_text(settings.tile_size.to_string())
+            .show_ui(ui, |ui| {
+                for tile_size in [256, 512, 1024, 2048, 4096] {
+                    ui.selectable_value(&mut settings.tile_size, tile_size, tile_size.to_string());
+                }
+            });
+        let fits = export::fits_one_image(size);
+        let single = ui
+            .add_enabled(fits, egui::Button::new("Export…"))
+            .on_disabled_hover_text("Too large for one image, export it as tiles");
+        let tiled = ui
+            .button("Export as tiles…")
+            .on_hover_text("Write a folder of tiles with a manifest of where each one goes");
+        let deep_zoom = ui.button("Export Deep Zoom…").on_hover_text(
+            "Write a pyramid of tiles for zoomable web viewers such as OpenSeadragon",
+        );
+        if single.clicked() || tiled.clicked() || deep_zoom.clicked() {
+            ui.close_menu();
+            let result = if single.clicked() {
+                self.export_png(ui.ctx(), rect)
+            } else if tiled.clicked() {
+                self.export_tiles(ui.ctx(), rect)
+            } else {
+                self.export_deep_zoom(ui.ctx(), rect)
+            };
+            if let Err(err) = result {
+                log::error!("Failed to export an image: {err}");
+            }
+        }
+    }
+
+    /// Exports the screen rect `rect` in the chosen format, as one image if it is small enough
+    /// to render at once and as tiles otherwise.
+    fn export_image(&mut self, ctx: &egui::Context, rect: Rect) -> Result<(), String> {
+        let size = self
+            .image_export
+            .image_size(rect.size(), ctx.pixels_per_point());
+        if self.image_export.format == ImageFormat::Pdf {
+            self.export_pdf(ctx, rect)
+        } else if export::fits_one_image(size) {
+            self.export_png(ctx, rect)
+        } else {
+            self.export_tiles(ctx, rect)
+        }
+    }
+
+    /// Saves what the canvas shows in the screen rect `rect` as a PNG, at the resolution and
+    /// supersampling the export settings ask for.
+    fn export_png(&mut self, ctx: &egui::Context, rect: Rect) -> Result<(), String> {
+        let size = self
+            .image_export
+            .image_size(rect.size(), ctx.pixels_per_point());
+        let factor = self.image_export.supersample_for(size);
+        let image = self.render_region(ctx, rect, size.map(|side| side * factor), None);
+        let image = raster::downsample(&image, factor);
+        let png = raster::encode_png(&image).map_err(|err| err.to_string())?;
+        export::save("canvas.png", &png)
+    }
+
+    /// Saves what the canvas shows in the screen rect `rect` as a one page vector PDF, a point
+    /// on screen to as many points on the page as the export scale asks for.
+    fn export_pdf(&mut self, ctx: &egui::Context, rect: Rect) -> Result<(), String> {
+        let size = rect.size() * self.image_export.scale;
+        let to_page = emath::RectTransform::from_to(
+            Rect::from_min_size(Pos2::ZERO, rect.size()),
+            Rect::from_min_size(Pos2::ZERO, size),
+        );
+        let shapes = self
+            .vector_shapes_in(rect)
+            .into_iter()
+            .map(|shape| shape.transformed(to_page))
+            .collect_vec();
+        let background = ctx.style().visuals.panel_fill;
+        export::save("canvas.pdf", &pdf::export(&shapes, size, background))
+    }
+
+    /// Saves what the canvas shows in the screen rect `rect` as a grid of PNG tiles and a
+    /// manifest of where they go, so images too large to hold in memory at once can be
+    /// exported. Each tile is rendered, written and dropped before the next.
+    fn export_tiles(&mut self, ctx: &egui::Context, rect: Rect) -> Result<(), String> {
+        let size = self
+            .image_export
+            .image_size(rect.size(), ctx.pixels_per_point());
+        let manifest = self.image_export.tiles(size);
+        let Some(sink) = export::FileSink::pick("canvas_tiles")? else {
+            return Ok(());
+        };
+        let factor = self.image_export.supersample_for([manifest.tile_size; 2]);
+        let to_screen = emath::RectTransform::from_to(
+            Rect::from_min_size(Pos2::ZERO, vec2(size[0] as f32, size[1] as f32)),
+            rect,
+        );
+        for tile in &manifest.tiles {
+            let pixels = Rect::from_min_size(
+                pos2(tile.x as f32, tile.y as f32),
+                vec2(tile.width as f32, tile.height as f32),
+            );
+            let tile_size = [tile.width * factor, tile.height * factor];
+            let image = self.render_region(ctx, to_screen.transform_rect(pixels), tile_size, None);
+            let image = raster::downsample(&image, factor);
+            let png = raster::encode_png(&image).map_err(|err| err.to_string())?;
+            sink.write(&tile.file, &png)?;
+        }
+        let manifest = serde_json::to_vec_pretty(&manifest).map_err(|err| err.to_string())?;
+        sink.write("manifest.json", &manifest)?;
+        sink.finish();
+        Ok(())
+    }
+
+    /// Saves what the canvas shows in the screen rect `rect` as a Deep Zoom image: a pyramid of
+    /// tiled levels from a single pixel up to the export resolution, for zoomable web viewers
+    /// such as OpenSeadragon. Coarser levels draw fewer levels of nodes, as `render_region`
+    /// does.
+    fn export_deep_zoom(&mut self, ctx: &egui::Context, rect: Rect) -> Result<(), String> {
+        let size = self
+            .image_export
+            .image_size(rect.size(), ctx.pixels_per_point());
+        let pyramid = DeepZoom::new(size, self.image_export.tile_size);
+        let Some(sink) = export::FileSink::pick("canvas_deep_zoom")? else {
+            return Ok(());
+        };
+        sink.write("canvas.dzi", pyramid.descriptor().as_bytes())?;
+        let factor = self.image_export.supersample_for([pyramid.tile_size; 2]);
+        for level in 0..=pyramid.max_level() {
+            let level_size = pyramid.level_size(level);
+            let to_screen = emath::RectTransform::from_to(
+                Rect::from_min_size(Pos2::ZERO, vec2(level_size[0] as f32, level_size[1] as f32)),
+                rect,
+            );
+            for tile in pyramid.tiles(level) {
+                let pixels = Rect::from_min_size(
+                    pos2(tile.x as f32, tile.y as f32),
+                    vec2(tile.width as f32, tile.height as f32),
+                );
+                let tile_size = [tile.width * factor, tile.height * factor];
+                let image =
+                    self.render_region(ctx, to_screen.transform_rect(pixels), tile_size, None);
+                let image = raster::downsample(&image, factor);
+                let png = raster::encode_png(&image).map_err(|err| err.to_string())?;
+                sink.write(&tile.file, &png)?;
+            }
+        }
+        sink.finish();
+        Ok(())
+    }
+
+    /// Draws what the canvas shows in the screen rect `region` over its background, scaled to
+    /// an image of `size` pixels. Images coarser than the screen skip a level of nodes below
+    /// the cells in view for each halving, as each level is half the size of the one above.
+    /// Given a `layer`, only its strokes are drawn, hidden or not.
+    fn render_region(
+        &mut self,
+        ctx: &egui::Context,
+        region: Rect,
+        size: [usize; 2],
+        layer: Option<LayerId>,
+    ) -> ColorImage {
+        let pixels_per_point = size[0] as f32 / region.width();
+        let halvings = (ctx.pixels_per_point() / pixels_per_point)
+            .log2()
+            .floor()
+            .max(0.0);
+        let depth = DRAW_DEPTH.saturating_sub(halvings as u32);
+        let strokes = self.strokes_in(region, depth, layer);
+        let image_rect = Rect::from_min_size(Pos2::ZERO, vec2(size[0] as f32, size[1] as f32));
+        let to_image = emath::RectTransform::from_to(region, image_rect);
+        let min_size = self.quality.min_stroke_size() / to_image.scale().x;
+        let strokes = strokes
+            .into_iter()
+            .filter(|(stroke, screen_rect)| is_drawn(stroke, *screen_rect, region, min_size))
+            .map(|(stroke, screen_rect)| (stroke, to_image.transform_rect(screen_rect)))
+            .collect_vec();
+        let mut image = ColorImage::new(size, ctx.style().visuals.panel_fill);
+        blend::rasterize_strokes(ctx, &mut image, &self.layers, &strokes);
+        image
+    }
+
+    /// The vector shapes of the visible strokes drawn in the screen rect `region`, bottom to
+    /// top, in points from its top left corner.
+    fn vector_shapes_in(&mut self, region: Rect) -> Vec<VectorShape> {
+        let min_size = self.quality.min_stroke_size();
+        self.strokes_in(region, DRAW_DEPTH, None)
+            .into_iter()
+            .filter(|(stroke, screen_rect)| is_drawn(stroke, *screen_rect, region, min_size))
+            .flat_map(|(stroke, screen_rect)| {
+                let to_region = emath::RectTransform::from_to(
+                    STANDARD_COORD_BOUNDS,
+                    screen_rect.translate(-region.min.to_vec2()),
+                );
+                stroke
+                    .drawable
+                    .vector_shapes()
+                    .into_iter()
+                    .map(move |shape| shape.transformed(to_region))
+            })
+            .collect()
+    }
+
+    /// Saves what is in view as an Excalidraw file, a point on screen to an Excalidraw pixel.
+    fn export_excalidraw(&mut self, ctx: &egui::Context) -> Result<(), String> {
+        let shapes = self.vector_shapes_in(self.canvas_rect);
+        let background = ctx.style().visuals.panel_fill;
+        let scene = excalidraw::export(shapes, background).map_err(|err| err.to_string())?;
+        export::save("canvas.excalidraw", scene.as_bytes())
+    }
+
+    /// The visible strokes, or those on `layer` if given, intersecting the screen rect `region`,
+    /// in drawing order and each
+    /// with the screen rect of its node, from nodes down to `depth` levels below the cells in
+    /// view. Only the nodes overlapping the region are searched, and paged in if they were
+    /// paged out. Everything is taken as strokes, since thumbnails and cached renders are made
+    /// at the screen's resolution rather than an image's.
+    fn strokes_in(
+        &mut self,
+        region: Rect,
+        depth: u32,
+        layer: Option<LayerId>,
+    ) -> Vec<(StrokeEntry, Rect)> {
+        let (cells, ancestors) = self.visible_nodes(self.canvas_rect);
+        let nodes = cells
+            .into_iter()
+            .map(|node| (node, depth))
+            .chain(ancestors.into_iter().map(|node| (node, 0)));
+        let mut strokes = vec![];
+        for ((node, screen_rect), depth) in nodes {
+            let to_screen = emath::RectTransform::from_to(STANDARD_COORD_BOUNDS, screen_rect);
+            let query = to_screen.inverse().transform_rect(region);
+            self.draw_boxes.tree.page_in_rect(node, query, depth);
+            for stroke_ref in self.draw_boxes.tree.query_rect(node, query, depth) {
+                let node_rect = to_screen
+                    .transform_rect(stroke_ref.to_query.transform_rect(STANDARD_COORD_BOUNDS));
+                strokes.extend(
+                    stroke_ref
+                        .strokes(&self.draw_boxes.tree)
+                        .into_iter()
+                        .filter(|stroke| match layer {
+                            Some(layer) => stroke.layer == layer,
+                            None => self.layers.is_visible(stroke.layer),
+                        })
+                        .map(|stroke| (stroke, node_rect)),
+                );
+            }
+        }
+        self.sort_strokes(&mut strokes);
+        strokes
+    }
+
+    /// Replaces the painting with `value`, offering to restore it as it was before the operation
+    /// described by `label`.
+    pub fn replace(&mut self, value: Painting, label: &str) {
+        let snapshot = self.take_snapshot(label);
+        let sessions = std::mem::take(&mut self.sessions);
+        *self = value;
+        self.keep_sessions(sessions);
+        self.snapshot = snapshot;
+        self.modified = true;
+    }
+
+    /// Keeps `sessions`, the session log from before the painting was replaced, as the log
+    /// goes with whoever edits the painting rather than with its content.
+    fn keep_sessions(&mut self, mut sessions: SessionLog) {
+        sessions.detach();
+        self.sessions = sessions;
+    }
+
+    /// Merges `other`, a copy of the painting edited apart from this one, keeping the latest
+    /// change to each object only one holds, and noting those both hold differently as
+    /// conflicts.
+    pub fn merge(&mut self, mut other: Painting) {
+        if self.top_level().is_none() || other.top_level().is_none() {
+            return;
+        }
+        let snapshot = self.take_snapshot("Merged painting");
+        let merged = merge::merge(
+            &mut self.draw_boxes.tree,
+            &mut self.versions,
+            &mut self.next_stroke_order,
+            &mut self.next_group,
+            std::mem::take(&mut other.draw_boxes.tree),
+            &other.versions,
+        );
+        self.layers.add_missing(&other.layers);
+        self.rebase_locations();
+        if !merged.conflicts.is_empty() {
+            log::info!(
+                "Kept {} objects the merged copy holds differently until resolved",
+                merged.conflicts.len()
+            );
+            self.conflicts.add(&self.draw_boxes.tree, merged.conflicts);
+        }
+        if merged.added + merged.removed == 0 {
+            return;
+        }
+        log::info!(
+            "Merged a copy, adding {} objects and removing {}, while it lacks changes to {}",
+            merged.added,
+            merged.removed,
+            merged.kept
+        );
+        self.selection.clear();
+        self.fitted_paste = None;
+        self.snapshot = snapshot;
+        self.record_edit();
+    }
+
+    /// Compares `other`, a copy of the painting, with this one, to show how it differs over the
+    /// canvas.
+    fn show_diff(&mut self, mut other: Painting) {
+        if self.top_level().is_none() || other.top_level().is_none() {
+            return;
+        }
+        let diff = merge::diff(
+            &mut self.draw_boxes.tree,
+            std::mem::take(&mut other.draw_boxes.tree),
+        );
+        self.rebase_locations();
+        let (added, changed, removed) = diff.counts();
+        log::info!(
+            "Compared a copy, which adds {added} objects, changes {changed} and lacks {removed}"
+        );
+        self.diff = Some(diff);
+    }
+
+    /// Summarizes the differences shown over the canvas, which closing stops showing.
+    fn diff_window(&mut self, ctx: &egui::Context) {
+        let Some(diff) = &self.diff else {
+            return;
+        };
+        let mut open = true;
+        egui::Window::new("Differences")
+            .open(&mut open)
+            .resizable(false)
+            .show(ctx, |ui| {
+                let (added, changed, removed) = diff.counts();
+                ui.colored_label(DIFF_ADDED_COLOR, format!("{added} objects added"));
+                ui.label(format!("{changed} objects changed"));
+                ui.colored_label(DIFF_REMOVED_COLOR, format!("{removed} objects removed"));
+            });
+        if !open {
+            self.diff = None;
+        }
+    }
+
+    /// Shows both versions of each object this copy and another changed differently, side by
+    /// side, to choose which to keep.
+    fn conflicts_window(&mut self, ctx: &egui::Context) {
+        let Some(conflict) = self.conflicts.objects.first() else {
+            return;
+        };
+        let mut chosen = None;
+        let mut all = None;
+        egui::Window::new("Conflicts")
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.label(format!(
+                    "{} objects were changed both here and in another copy. Which version of \
+                     this one should stay?",
+                    self.conflicts.objects.len()
+                ));
+                chosen = conflict_previews(ui, conflict);
+                ui.separator();
+                ui.horizontal(|ui| {
+                    if ui.button("Keep all here").clicked() {
+                        all = Some(false);
+                    }
+                    if ui.button("Take all from the other copy").clicked() {
+                        all = Some(true);
+                    }
+                });
+            });
+        if let Some(theirs) = all {
+            self.resolve_conflicts(self.conflicts.objects.len(), theirs);
+        } else if let Some(theirs) = chosen {
+            self.resolve_conflicts(1, theirs);
+        }
+    }
+
+    /// Settles the first `count` conflicts, leaving their objects as the other copy holds them
+    /// if `theirs`, or else as they are here.
+    fn resolve_conflicts(&mut self, count: usize, theirs: bool) {
+        if self.top_level().is_none() {
+            return;
+        }
+        let snapshot = theirs.then(|| self.take_snapshot("Resolved conflicts"));
+        for conflict in self.conflicts.objects.drain(..count).collect_vec() {
+            merge::resolve(
+                &mut self.draw_boxes.tree,
+                conflict,
+                theirs,
+                &mut self.versions,
+                &mut self.next_stroke_order,
+                &mut self.next_group,
+            );
+        }
+        if let Some(snapshot) = snapshot {
+            self.selection.clear();
+            self.fitted_paste = None;
+            self.snapshot = snapshot;
+            self.record_edit();
+        }
+        self.modified = true;
+    }
+
+    /// Draws the objects a compared copy lacks in red, and those it adds in green, over the
+    /// canvas shown in `rect`, outlining each. Objects it changed appear in both colors.
+    fn paint_diff(&self, painter: &egui::Painter, rect: Rect, strokes: &[(StrokeEntry, Rect)]) {
+        let Some(diff) = &self.diff else {
+            return;
+        };
+        let paint = |stroke: &StrokeEntry, screen_rect: Rect, color: Color32| {
+            let mut drawable = stroke.drawable.clone();
+            for property in drawable.properties() {
+                if let Property::Color(tint) = property {
+                    *tint = color;
+                }
+            }
+            drawable.draw(
+                painter,
+                emath::RectTransform::from_to(STANDARD_COORD_BOUNDS, screen_rect),
+            );
+            painter.rect_stroke(
+                stroke_screen_bounds(stroke, screen_rect).expand(2.0),
+                0.0,
+                Stroke::new(1.5, color),
+            );
+        };
+        for (stroke, screen_rect) in strokes {
+            if diff.removed.contains(&merge::object_id(stroke)) {
+                paint(stroke, *screen_rect, DIFF_REMOVED_COLOR);
+            }
+        }
+        let here = self.current_location().path();
+        let to_screen =
+            |point: Pos2| rect.center() + (point.to_vec2() - self.pan) * self.zoom * rect.size();
+        for (path, stroke) in &diff.added {
+            let (Some(min), Some(max)) = (
+                relocate(path, pos2(-0.5, -0.5), &here),
+                relocate(path, pos2(0.5, 0.5), &here),
+            ) else {
+                continue;
+            };
+            let screen_rect = Rect::from_two_pos(to_screen(min), to_screen(max));
+            if stroke_screen_bounds(stroke, screen_rect).intersects(rect) {
+                paint(stroke, screen_rect, DIFF_ADDED_COLOR);
+            }
+        }
+    }
+
+    /// Whether the painting was edited since it was last opened or saved to a file.
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+
+    /// Notes that the painting matches the file it was just opened from or saved to.
+    pub fn mark_file_saved(&mut self) {
+        self.modified = false;
+    }
+
+    /// Copies the painting so it can be restored after the operation described by `label`.
+    /// Callers replacing `self` should move the result into the replacement.
+    fn take_snapshot(&self, label: &str) -> Option<Snapshot> {
+        match self.to_ron() {
+            Ok(data) => Some(Snapshot {
+                label: label.to_string(),
+                data,
+                shown_at: None,
+            }),
+            Err(err) => {
+                log::error!("Failed to snapshot painting: {err}");
+                None
+            }
+        }
+    }
+
+    fn snapshot_toast(&mut self, ctx: &egui::Context) {
+        let Some(snapshot) = self.snapshot.as_mut() else {
+            return;
+        };
+        let now = ctx.input(|input| input.time);
+        let elapsed = now - *snapshot.shown_at.get_or_insert(now);
+        if elapsed > SNAPSHOT_TOAST_SECONDS {
+            self.snapshot = None;
+            return;
+        }
+        ctx.request_repaint_after(std::time::Duration::from_secs_f64(
+            SNAPSHOT_TOAST_SECONDS - elapsed,
+        ));
+        let mut restore = false;
+        let mut dismiss = false;
+        egui::Area::new(egui::Id::new("snapshot_toast"))
+            .anchor(egui::Align2::RIGHT_BOTTOM, vec2(-16.0, -16.0))
+            .show(ctx, |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label(&snapshot.label);
+                        restore = ui.button("Restore").clicked();
+                        dismiss = ui.small_button("✕").clicked();
+                    });
+                });
+            });
+        if restore {
+            match Self::from_ron(&snapshot.data) {
+                Ok(restored) => {
+                    let sessions = std::mem::take(&mut self.sessions);
+                    *self = restored;
+                    self.keep_sessions(sessions);
+                    self.modified = true;
+                }
+                Err(err) => log::error!("Failed to restore snapshot: {err}"),
+            }
+        } else if dismiss {
+            self.snapshot = None;
+        }
+    }
+
+    pub fn mouse_mappings_ui(&mut self, ui: &mut Ui) {
+        self.mouse_mappings.ui(ui);
+    }
+
+    /// Creates a painting filled with procedurally generated strokes for performance testing.
+    pub fn generate_stress(config: &StressConfig) -> Self {
+        let mut painting = Self::default();
+        let center = *painting.draw_boxes.get(0, 0).unwrap();
+        painting.next_stroke_order =
+            stress::populate(&mut painting.draw_boxes.tree, center, config, 0);
+        painting
+    }
+
+    fn debug_menu(&mut self, ui: &mut Ui) {
+        ui.label("Stress canvas");
+        let config = &mut self.stress_config;
+        egui::Grid::new("stress_config").show(ui, |ui| {
+            ui.label("Seed");
+            ui.add(egui::DragValue::new(&mut config.seed));
+            ui.end_row();
+            ui.label("Strokes");
+            ui.add(egui::DragValue::new(&mut config.stroke_count).speed(100));
+            ui.end_row();
+            ui.label("Max depth");
+            ui.add(egui::DragValue::new(&mut config.max_depth).range(0..=60));
+            ui.end_row();
+            ui.label("Depth falloff");
+            ui.add(
+                egui::DragValue::new(&mut config.depth_falloff)
+                    .speed(0.01)
+                    .range(0.0..=4.0),
+            );
+            ui.end_row();
+            ui.label("Clusters");
+            ui.add(egui::DragValue::new(&mut config.clusters));
+            ui.end_row();
+            ui.label("Cluster depth");
+            ui.add(egui::DragValue::new(&mut config.cluster_depth).range(0..=60));
+            ui.end_row();
+        });
+        if ui.button("Generate").clicked() {
+            let config = self.stress_config.clone();
+            self.replace(Self::generate_stress(&config), "Generated stress canvas");
+            self.stress_config = config;
+            ui.close_menu();
+        }
+        ui.separator();
+        if ui
+            .button("Normalize stroke orders")
+            .on_hover_text("Spread stroke orders out evenly, leaving room to reorder strokes")
+            .clicked()
+        {
+            self.normalize_orders();
+            ui.close_menu();
+        }
+        if ui
+            .button("Compare with clipboard")
+            .on_hover_text("Count the nodes that differ from a painting exported to the clipboard")
+            .clicked()
+        {
+            self.clipboard_read = Some((ClipboardUse::Compare, ClipboardText::read(ui.ctx())));
+        }
+        if ui
+            .button("Check operation log")
+            .on_hover_text("Rebuild the painting from its recorded changes and compare the result")
+            .clicked()
+            && self.top_level().is_some()
+        {
+            self.comparison = Some(self.op_log.check(&self.draw_boxes.tree));
+        }
+        if ui
+            .button(format!("Compact operation log ({})", self.op_log.held()))
+            .on_hover_text(
+                "Forget the recorded changes, as happens once the painting is saved. Changes not \
+                 saved yet stay in the change journal",
+            )
+            .clicked()
+        {
+            self.compact_op_log();
+            ui.close_menu();
+        }
+        if let Some(comparison) = &self.comparison {
+            ui.label(comparison);
+        }
+        ui.separator();
+        if ui
+            .button("Measure tree")
+            .on_hover_text("Count the nodes and strokes of the whole painting")
+            .clicked()
+        {
+            if let Some(top_level) = self.top_level() {
+                self.tree_stats = Some(self.draw_boxes.tree.stats(top_level));
+            }
+        }
+        if let Some(stats) = self.tree_stats {
+            egui::Grid::new("tree_stats").show(ui, |ui| {
+                ui.label("Nodes");
+                ui.label(stats.nodes.to_string());
+                ui.end_row();
+                ui.label("Depth");
+                ui.label(stats.max_depth.to_string());
+                ui.end_row();
+                ui.label("Strokes");
+                ui.label(stats.strokes.to_string());
+                ui.end_row();
+                ui.label("Memory");
+                ui.label(format!(
+                    "~{:.1} MiB",
+                    stats.memory as f64 / (1024.0 * 1024.0)
+                ));
+                ui.end_row();
+            });
+        }
+        ui.horizontal(|ui| {
+            ui.label("Memory budget");
+            ui.add(
+                egui::DragValue::new(&mut self.memory_budget)
+                    .speed(16)
+                    .suffix(" MiB"),
+            )
+            .on_hover_text("Page out subtrees out of view past this size, or never at 0");
+        });
+        ui.label(format!(
+            "{} subtrees paged out",
+            self.pager.paged_count(&self.draw_boxes.tree)
+        ));
+        egui::ComboBox::from_id_salt("save_format")
+            .selected_text(format!("Save format: {}", self.save_format.name()))
+            .show_ui(ui, |ui| {
+                for format in SaveFormat::ALL {
+                    ui.selectable_value(&mut self.save_format, format, format.name());
+                }
+            })
+            .response
+            .on_hover_text("Binary is smaller and faster to load, RON can be read and edited");
+    }
+
+    pub fn ui_content(&mut self, ui: &mut Ui) -> egui::Response {
+        let (mut response, painter) =
+            ui.allocate_painter(ui.available_size_before_wrap(), Sense::click_and_drag());
+        // All segments of one pen stroke share an order, which advances once the stroke ends.
+        let was_drawing = self.last_cursor_pos.is_some();
+        self.canvas_rect = response.rect;
+        // Tessellation options are shared by the whole context, so this covers the UI too.
+        let anti_alias = self.quality.anti_alias();
+        ui.ctx()
+            .tessellation_options_mut(|options| options.feathering = anti_alias);
+
+        self.rebase_locations();
+        self.op_log.record(&mut self.draw_boxes.tree);
+        self.edits_noted.get_or_insert(self.op_log.count());
+        // Changes no edit described, such as deleting a layer.
+        self.note_changes(ObjectChange::Edited);
+        if !self.integrity_checked {
+            self.integrity_checked = true;
+            // Subtrees that are not loaded yet are left unchecked.
+            self.damaged_nodes = self.draw_boxes.tree.damaged_nodes(self.outermost());
+            if !self.damaged_nodes.is_empty() {
+                log::warn!(
+                    "{} nodes do not match their saved hash",
+                    self.damaged_nodes.len()
+                );
+            }
+            if ordering::needs_normalizing(
+                &self.draw_boxes.tree,
+                self.outermost(),
+                self.next_stroke_order,
+            ) {
+                self.normalize_orders();
+            }
+        } else if self.next_stroke_order >= ordering::ORDER_LIMIT {
+            self.normalize_orders();
+        }
+        self.sessions_window(ui.ctx());
+        self.layers_window(ui.ctx());
+        self.replace_color_window(ui.ctx(), response.rect);
+        self.journal_window(ui.ctx());
+        self.clusters_window(ui.ctx());
+        self.collab_window(ui.ctx());
+        self.versions_window(ui.ctx());
+        self.diff_window(ui.ctx());
+        self.conflicts_window(ui.ctx());
+        self.handle_clipboard_read();
+        self.import_error_window(ui.ctx());
+        self.action_error_window(ui.ctx());
+
+        let drag_input = self
+            .mouse_mappings
+            .buttons(MouseAction::Pan)
+            .any(|button| response.dragged_by(button) || response.drag_started_by(button));
+        let mut did_drag = false;
+        let mut thickness_multipler = 1.0;
+        let touch_force = ui.input(|input| {
+            input
+                .events
+                .iter()
+                .filter_map(|evt| {
+                    if let egui::Event::Touch { force, .. } = evt {
+                        *force
+                    } else {
+                        None
+                    }
+                })
+                .next()
+        });
+        if let Some(multi_touch) = response.ctx.multi_touch() {
+            self.pan -= multi_touch.translation_delta / response.rect.size();
+            self.zoom *= multi_touch.zoom_delta;
+            did_drag = true;
+        } else if touch_force.unwrap_or(0.0) > 0.0 {
+            thickness_multipler += touch_force.unwrap_or(0.0);
+        } else if let Some(pointer) = ui.ctx().input(|i| i.pointer.hover_pos()) {
+            let from_screen = emath::RectTransform::from_to(
+                response
+                    .rect
+                    .scale_from_center(5.0 * self.zoom)
+                    .translate(self.zoom * -self.pan * response.rect.size()),
+                5.0 / 2.0 * STANDARD_COORD_BOUNDS,
+            );
+            let transformed_pointer_pos = from_screen * pointer;
+            let zoom_delta = ui.ctx().input(|i| i.zoom_delta());
+            if zoom_delta != 1.0 {
+                self.pan += (zoom_delta - 1.0) * (transformed_pointer_pos - self.pan).to_vec2();
+                self.zoom *= zoom_delta;
+                did_drag = true;
+            }
+        }
+        if response.dragged() && drag_input {
+            self.pan -= response.drag_delta() / self.zoom / response.rect.size();
+            did_drag = true;
+        }
+        let pan_delta = ui.ctx().input(|i| i.smooth_scroll_delta);
+        self.pan -= pan_delta / self.zoom / response.rect.size();
+        self.handle_pan_zoom();
+        let world_rect = self.constrain_to_world(response.rect);
+
+        let mut copy = false;
+        if !ui.ctx().wants_keyboard_input() {
+            let (swap, shrink, grow, deselect) = ui.input(|input| {
+                (
+                    input.key_pressed(egui::Key::X),
+                    input.key_pressed(egui::Key::OpenBracket),
+                    input.key_pressed(egui::Key::CloseBracket),
+                    input.key_pressed(egui::Key::Escape),
+                )
+            });
+            let (pasted_text, paste_key);
+            (copy, pasted_text, paste_key) = ui.input(|input| {
+                (
+                    input.events.contains(&egui::Event::Copy),
+                    input.events.iter().find_map(|event| match event {
+                        egui::Event::Paste(text) => Some(text.clone()),
+                        _ => None,
+                    }),
+                    input.modifiers.command && input.key_pressed(egui::Key::V),
+                )
+            });
+            // The clipboard holds either text or an image, so an image is only looked for
+            // when no text was pasted.
+            if pasted_text.is_none() {
+                if let Some(image) = clipboard::pasted_image(ui.ctx(), paste_key) {
+                    let target = response.hover_pos().unwrap_or(response.rect.center());
+                    match self.paste_image(response.rect, &image, target) {
+                        Ok(()) => response.mark_changed(),
+                        Err(err) => log::error!("Failed to paste the image: {err}"),
+                    }
+                }
+            }
+            if let Some(text) = pasted_text.as_deref() {
+                let import: Option<ShapeImport> = if svg::is_svg(text) {
+                    Some(svg::import)
+                } else if excalidraw::is_excalidraw(text) {
+                    Some(excalidraw::import)
+                } else {
+                    None
+                };
+                if let Some(import) = import {
+                    match self.import_shapes(text.as_bytes(), import) {
+                        Ok(()) => response.mark_changed(),
+                        Err(err) => log::error!("Failed to paste shapes: {err}"),
+                    }
+                }
+            }
+            let paste = pasted_text.and_then(|text| CopiedStrokes::from_ron(&text));
+            if let Some(copied) = paste {
+                let target = response.hover_pos().unwrap_or(response.rect.center());
+                if self.paste(response.rect, copied, target, false) {
+                    response.mark_changed();
+                }
+            }
+            if swap {
+                self.swap_colors();
+            }
+            if deselect {
+                self.selection.clear();
+            }
+            let (ungroup, group) = ui.input_mut(|input| {
+                (
+                    input.consume_key(
+                        egui::Modifiers::COMMAND | egui::Modifiers::SHIFT,
+                        egui::Key::G,
+                    ),
+                    input.consume_key(egui::Modifiers::COMMAND, egui::Key::G),
+                )
+            });
+            if ungroup {
+                self.ungroup_selection();
+            } else if group {
+                self.group_selection();
+            }
+            if let Some(width) = self.tool_width_mut() {
+                if shrink {
+                    *width = (*width / BRUSH_RESIZE_STEP).max(MIN_BRUSH_WIDTH);
+                }
+                if grow {
+                    *width = (*width * BRUSH_RESIZE_STEP).min(MAX_BRUSH_WIDTH);
+                }
+            }
+        }
+        let secondary_buttons = self
+            .mouse_mappings
+            .buttons(MouseAction::DrawSecondary)
+            .collect_vec();
+        let drawing_button = std::iter::once(egui::PointerButton::Primary)
+            .chain(secondary_buttons.iter().copied())
+            .find(|button| response.drag_started_by(*button) || response.dragged_by(*button));
+        if let (Some(button), None) = (drawing_button, self.last_cursor_pos) {
+            self.use_secondary = button != egui::PointerButton::Primary;
+        }
+        let (mut draw_stroke, priority) = match self.tool {
+            Tool::Highlighter => (self.highlighter, StrokePriority::Underlay),
+            _ => (self.stroke, StrokePriority::Ink),
+        };
+        draw_stroke.width *= thickness_multipler;
+        if self.use_secondary {
+            draw_stroke.color = self.secondary_color;
+        }
+
+        let outside_world = world_rect
+            .zip(ui.input(|input| input.pointer.latest_pos()))
+            .is_some_and(|(world, pointer)| !world.contains(pointer));
+        let picking_export_area = self.picking_export_area;
+        let drawing_blocked =
+            picking_export_area || self.layers.is_locked(self.layers.active()) || outside_world;
+        if picking_export_area {
+            self.handle_export_area(ui, &response, did_drag);
+        } else if drawing_blocked && response.hovered() {
+            ui.ctx().set_cursor_icon(egui::CursorIcon::NotAllowed);
+        }
+        'input_handler: {
+            if !matches!(self.tool, Tool::Pen | Tool::Highlighter) || drawing_blocked {
+                self.last_cursor_pos = None;
+                break 'input_handler;
+            }
+            if let Some(pointer_pos) = response.interact_pointer_pos() {
+                if drawing_button.is_some() && !did_drag {
+                    let canvas_pos = match self.last_cursor_pos {
+                        Some(last_cursor_pos) if self.tool == Tool::Pen => {
+                            last_cursor_pos.lerp(pointer_pos, 1.0 - self.smoothing)
+                        }
+                        _ => pointer_pos,
+                    };
+                    let Some(last_cursor_pos) = self.last_cursor_pos else {
+                        self.last_cursor_pos = Some(canvas_pos);
+                        self.dash_phase = 0.0;
+                        self.last_width = 1.0;
+                        self.palette.use_color(draw_stroke.color);
+                        if self.low_latency {
+                            self.live_stroke_origin = Some(self.current_location());
+                            let sample = self.to_cells(response.rect, canvas_pos);
+                            self.live_stroke.push((sample, 1.0));
+                        } else {
+                            self.drawn_stroke = vec![(canvas_pos, 1.0)];
+                            self.drawn_stroke_view = (self.pan, self.zoom);
+                        }
+                        break 'input_handler;
+                    };
+                    if last_cursor_pos != canvas_pos {
+                        let width = if self.taper {
+                            let speed = ui.input(|input| input.pointer.velocity().length());
+                            let target = (1.5 / (1.0 + speed / 1000.0)).max(0.25);
+                            self.last_width + (target - self.last_width) * 0.3
+                        } else {
+                            1.0
+                        };
+                        if self.low_latency {
+                            let sample = self.to_cells(response.rect, canvas_pos);
+                            self.live_stroke.push((sample, width));
+                            self.last_width = width;
+                        } else if self.commit_segment(
+                            response.rect,
+                            last_cursor_pos,
+                            canvas_pos,
+                            width,
+                            draw_stroke,
+                            priority,
+                        ) {
+                            self.drawn_stroke.push((canvas_pos, width));
+                            self.record_edit();
+                            response.mark_changed();
+                        } else {
+                            break 'input_handler;
+                        }
+                        self.last_cursor_pos = Some(canvas_pos);
+                    }
+                } else {
+                    self.last_cursor_pos = None
+                }
+            } else {
+                self.last_cursor_pos = None
+            }
+        }
+        if self.last_cursor_pos.is_none() && !self.live_stroke.is_empty() {
+            self.commit_live_stroke(response.rect, draw_stroke, priority);
+            self.record_edit();
+            response.mark_changed();
+        }
+        if was_drawing && self.last_cursor_pos.is_none() {
+            self.simplify_drawn_stroke(response.rect, draw_stroke, priority);
+            self.next_stroke_order += ordering::ORDER_SPACING;
+        }
+        if self.tool == Tool::Select && !picking_export_area && response.clicked() {
+            if let Some(pointer) = response.interact_pointer_pos() {
+                self.select_at(ui, response.rect, pointer);
+            }
+        }
+        if self.tool == Tool::Select && !picking_export_area {
+            self.handle_marquee(ui, &response, did_drag);
+        }
+        if self.tool == Tool::Restyle && !picking_export_area && response.clicked() {
+            if let Some(pointer) = response.interact_pointer_pos() {
+                if self.restyle_at(response.rect, pointer) {
+                    response.mark_changed();
+                }
+            }
+        }
+        if self.tool == Tool::StickyNote && !drawing_blocked {
+            self.handle_note_tool(&response, did_drag);
+        }
+        if self.tool == Tool::PathText && !drawing_blocked {
+            self.handle_path_text_tool(&response, did_drag);
+        }
+        if self.tool == Tool::EraseRegion
+            && !picking_export_area
+            && self.handle_erase_region_tool(&response, did_drag)
+        {
+            response.mark_changed();
+        }
+        let fill = match self.tool {
+            _ if drawing_blocked => None,
+            Tool::Fill if response.clicked() => Some(self.fill_color),
+            Tool::Fill
+                if secondary_buttons
+                    .iter()
+                    .any(|button| response.clicked_by(*button)) =>
+            {
+                Some(self.secondary_color)
+            }
+            _ => None,
+        }
+        .zip(response.interact_pointer_pos());
+
+        if self.debug_render {
+            for (x, y, node) in self.draw_boxes.cells() {
+                let offset = vec2(x as f32, y as f32);
+                let to_screen = emath::RectTransform::from_to(
+                    STANDARD_COORD_BOUNDS,
+                    response
+                        .rect
+                        .scale_from_center(self.zoom)
+                        .translate(self.zoom * (offset - self.pan) * response.rect.size()),
+                );
+                self.draw_boxes.tree.draw_grid(*node, &painter, to_screen);
+            }
+        }
+        let (cells, ancestors) = self.visible_nodes(response.rect);
+        // Only strokes that will be drawn are copied out of the tree, unless an action that can
+        // reach strokes off screen is about to run.
+        let keep_culled = copy
+            || self.journal_action.is_some()
+            || self.cluster_action.is_some()
+            || self.show_clusters;
+        let center = *self.draw_boxes.get(0, 0).unwrap();
+        let render_key = RenderKey::new(&self.layers, self.quality);
+        // Frames are only painted on input or when an edit asks for one, and most of those
+        // leave the tree as it was, so the last frame's strokes are drawn again unless the tree
+        // or the view changed.
+        let outermost = self.outermost();
+        let changed = self.draw_boxes.tree[outermost].take_changed();
+        self.pager.view(
+            &mut self.draw_boxes.tree,
+            cells.iter().map(|(node, _)| *node),
+        );
+        let last_frame = self.last_frame.take().filter(|frame| {
+            !changed
+                && !keep_culled
+                && frame.shows(response.rect, self.pan, self.zoom, center, render_key)
+        });
+        let mut frame = match last_frame {
+            Some(frame) if frame.is_complete() => frame,
+            Some(frame) => StrokeCollector::resume(
+                ui.ctx(),
+                &self.layers,
+                self.quality,
+                &mut self.draw_boxes.tree,
+                frame,
+            ),
+            None => {
+                let mut collector = StrokeCollector::new(
+                    ui.ctx(),
+                    &self.layers,
+                    self.quality,
+                    render_key,
+                    response.rect,
+                    keep_culled,
+                );
+                collector.collect(
+                    &mut self.draw_boxes.tree,
+                    cells
+                        .into_iter()
+                        .map(|(node, screen_rect)| (node, screen_rect, DRAW_DEPTH))
+                        .chain(
+                            ancestors
+                                .into_iter()
+                                .map(|(node, screen_rect)| (node, screen_rect, 0)),
+                        ),
+                );
+                collector.sort();
+                collector.into_frame(self.pan, self.zoom, center)
+            }
+        };
+        if !frame.is_complete() {
+            ui.ctx().request_repaint();
+        }
+        if self.memory_budget > 0 {
+            let outermost = self.outermost();
+            self.pager.enforce(
+                &mut self.draw_boxes.tree,
+                outermost,
+                self.memory_budget * 1024 * 1024,
+                ui.input(|input| input.time),
+            );
+        }
+        let pending = frame.pending();
+        let strokes = &mut frame.strokes;
+        if copy {
+            match self.copy_selection(strokes).map(|copied| copied.to_ron()) {
+                Some(Ok(text)) => ui.output_mut(|output| output.copied_text = text),
+                Some(Err(err)) => log::error!("Failed to copy strokes: {err}"),
+                None => {}
+            }
+        }
+        if let Some(action) = self.journal_action.take() {
+            if self.apply_journal_action(response.rect, strokes, action) {
+                response.mark_changed();
+                ui.ctx().request_repaint();
+            }
+        }
+        if let Some(action) = self.cluster_action.take() {
+            if self.apply_cluster_action(response.rect, strokes, action) {
+                response.mark_changed();
+                ui.ctx().request_repaint();
+            }
+        }
+        let cluster_outlines = if self.show_clusters {
+            self.cluster_framing
+                .clusters
+                .iter()
+                .filter(|cluster| cluster.include)
+                .filter_map(|cluster| Self::orders_bounds(strokes, &cluster.orders))
+                .collect_vec()
+        } else {
+            vec![]
+        };
+        if keep_culled {
+            let min_size = self.quality.min_stroke_size();
+            strokes.retain(|(stroke, screen_rect)| {
+                is_drawn(stroke, *screen_rect, response.rect, min_size)
+            });
+        }
+        let stats = FrameStats {
+            drawn: strokes.len(),
+            culled: frame.loaded - strokes.len(),
+            depth: self.center_depth(),
+            cache_hits: frame.cache_hits,
+            cache_misses: frame.cache_misses,
+            pending,
+        };
+        if let Some((color, pos)) = fill {
+            if self.fill_at(ui.ctx(), response.rect, pos, color, strokes) {
+                self.palette.use_color(color);
+                self.record_edit();
+                response.mark_changed();
+                ui.ctx().request_repaint();
+            }
+        }
+        if let Some(align) = self.align.take() {
+            if self.align_selection(response.rect, strokes, align) {
+                response.mark_changed();
+                ui.ctx().request_repaint();
+            }
+        }
+        if let Some(arrange) = self.arrange.take() {
+            if self.arrange_selection(strokes, arrange) {
+                self.record_edit();
+                response.mark_changed();
+                ui.ctx().request_repaint();
+            }
+        }
+        if self.accessible_objects(ui, response.rect, strokes) {
+            ui.ctx().request_repaint();
+        }
+        let selected_rects = strokes
+            .iter()
+            .filter(|(stroke, _)| self.selection.contains(stroke))
+            .map(|(stroke, screen_rect)| stroke_screen_bounds(stroke, *screen_rect))
+            .collect_vec();
+        self.selection_rect = selected_rects.iter().copied().reduce(Rect::union);
+        for (summary, screen_rect) in frame.summaries() {
+            summary.paint(&painter, &self.layers, screen_rect);
+        }
+        painter.extend(frame.cached.iter().cloned());
+        blend::paint_strokes(&painter, &self.layers, &frame.strokes);
+        self.paint_diff(&painter, response.rect, &frame.strokes);
+        if !keep_culled {
+            self.last_frame = Some(frame);
+        }
+        if let Some(world) = world_rect {
+            Self::paint_world_edges(&painter, response.rect, world);
+        }
+        let selection_stroke = ui.visuals().selection.stroke;
+        for rect in &selected_rects {
+            painter.rect_stroke(rect.expand(2.0), 0.0, selection_stroke);
+        }
+        for bounds in cluster_outlines {
+            painter.add(egui::Shape::dashed_line(
+                &[
+                    bounds.left_top(),
+                    bounds.right_top(),
+                    bounds.right_bottom(),
+                    bounds.left_bottom(),
+                    bounds.left_top(),
+                ]
+                .map(|point| point + (point - bounds.center()).normalized() * FRAME_PADDING),
+                selection_stroke,
+                6.0,
+                4.0,
+            ));
+        }
+        if self.tool == Tool::Select {
+            if let Some(bounds) = selected_rects.into_iter().reduce(Rect::union) {
+                if self.transform_handles(ui, &painter, response.rect, bounds.expand(2.0)) {
+                    response.mark_changed();
+                }
+            }
+        }
+        let eyedrop = self
+            .mouse_mappings
+            .buttons(MouseAction::Eyedropper)
+            .any(|button| response.clicked_by(button));
+        if let (true, Some(pointer)) = (eyedrop, response.interact_pointer_pos()) {
+            if let Some(color) = self.color_at(ui.ctx(), response.rect, pointer) {
+                *self.tool_color_mut() = color;
+                self.palette.use_color(color);
+            }
+        }
+
+        if self.debug_render {
+            if let Some(pointer) = response.hover_pos() {
+                for (stroke, screen_rect) in self.strokes_at(response.rect, pointer, 4.0) {
+                    painter.rect_stroke(
+                        stroke_screen_bounds(&stroke, screen_rect),
+                        0.0,
+                        Stroke::new(1.0, Color32::RED),
+                    );
+                }
+            }
+        }
+        if let (Some(pointer), true) = (
+            response.hover_pos(),
+            matches!(self.tool, Tool::Pen | Tool::Highlighter),
+        ) {
+            // Matches the width strokes are drawn at, since send_stroke scales by 0.005 / zoom in
+            // coordinates where a cell spans zoom * rect.size() points.
+            let screen_width = draw_stroke.width * 0.005 * response.rect.size().max_elem();
+            painter.circle_stroke(
+                pointer,
+                screen_width / 2.0,
+                Stroke::new(1.0, ui.visuals().weak_text_color()),
+            );
+        }
+        if !self.live_stroke.is_empty() {
+            let screen_width = draw_stroke.width * 0.005 * response.rect.size().max_elem();
+            painter.add(egui::Shape::line(
+                self.live_stroke_on_screen(response.rect),
+                Stroke::new(screen_width, draw_stroke.color),
+            ));
+        }
+
+        if let Some((start, end)) = self.note_drag {
+            StickyNote::paint(
+                &painter,
+                Rect::from_two_pos(start, end),
+                self.note_color,
+                "",
+            );
+        }
+        if let Some((start, end)) = self.marquee_drag {
+            let region = Rect::from_two_pos(start, end);
+            let visuals = &ui.visuals().selection;
+            painter.rect_filled(region, 0.0, visuals.bg_fill.gamma_multiply(0.2));
+            painter.rect_stroke(region, 0.0, visuals.stroke);
+        }
+        if let Some((start, end)) = self.export_drag {
+            let region = Rect::from_two_pos(start, end);
+            let visuals = &ui.visuals().selection;
+            painter.rect_stroke(region, 0.0, visuals.stroke);
+        }
+        if let Some((start, end)) = self.erase_drag {
+            let region = Rect::from_two_pos(start, end);
+            let color = ui.visuals().error_fg_color;
+            painter.rect_filled(region, 0.0, color.gamma_multiply(0.1));
+            painter.rect_stroke(region, 0.0, Stroke::new(1.0, color));
+        }
+        if self.path_drag.len() > 1 {
+            painter.add(egui::Shape::line(
+                self.path_drag.clone(),
+                Stroke::new(1.0, ui.visuals().weak_text_color()),
+            ));
+        }
+        if let Some(pending) = &self.pending_path_text {
+            painter.add(egui::Shape::line(
+                pending.screen_points.clone(),
+                Stroke::new(1.0, ui.visuals().weak_text_color()),
+            ));
+            PathText::new(
+                &pending.screen_points,
+                pending.text.clone(),
+                self.text_size,
+                self.text_color,
+            )
+            .draw(&painter, emath::RectTransform::identity(response.rect));
+        }
+        self.collaborate(ui.ctx(), &painter, response.rect);
+        if self.show_hud {
+            self.hud.record_frame(ui.input(|input| input.time));
+            self.hud.paint(&painter, response.rect, &stats);
+        }
+        self.pending_note_window(ui.ctx(), &mut response);
+        self.pending_path_text_window(ui.ctx(), &mut response);
+        self.fitted_paste_prompt(ui.ctx(), response.rect);
+        self.integrity_warning(ui.ctx());
+        self.snapshot_toast(ui.ctx());
+        // Saving follows the frame, and needs the locations to match the tree.
+        self.rebase_locations();
+
+        response
+    }
+
+    /// The loaded cells followed by their ancestors, each with the screen rect it covers.
+    /// Ancestors shared by several cells are only listed once.
+    fn visible_nodes(&self, rect: Rect) -> (Vec<PlacedNode>, Vec<PlacedNode>) {
+        let cells = self
+            .draw_boxes
+            .cells()
+            .into_iter()
+            .map(|(x, y, node)| {
+                let offset = vec2(x as f32, y as f32);
+                (
+                    *node,
+                    rect.scale_from_center(self.zoom)
+                        .translate(self.zoom * (offset - self.pan) * rect.size()),
+                )
+            })
+            .collect_vec();
+        let mut parents_to_draw = cells.clone();
+        let mut ancestors: Vec<PlacedNode> = vec![];
+        for _layer_above in 0..14 {
+            let next_result = parents_to_draw
+                .drain(..)
+                .flat_map(|(node, to_screen)| {
+                    let node = &self.draw_boxes.tree[node];
+                    node.parent
+                        .map(|parent| (parent, node.get_parent_rect(to_screen)))
+                })
+                .collect_vec();
+            for next_element in next_result {
+                if !parents_to_draw.iter().any(|e| e.0 == next_element.0) {
+                    ancestors.push(next_element);
+                    parents_to_draw.push(next_element);
+                }
+            }
+        }
+        (cells, ancestors)
+    }
+
+    /// Shares where the view is looking and pointing with the others collaborating on the
+    /// painting, and shows their cursors labelled with their names, along with their views if
+    /// asked to. Changes they made to the canvas are made here, and changes made here sent.
+    fn collaborate(&mut self, ctx: &egui::Context, painter: &egui::Painter, rect: Rect) {
+        let here = self.current_location().path();
+        let Some(collaboration) = self.collaboration.as_mut() else {
+            return;
+        };
+        let cursor = ctx
+            .pointer_hover_pos()
+            .filter(|pos| rect.contains(*pos))
+            .map(|pos| (self.pan + (pos - rect.center()) / self.zoom / rect.size()).to_pos2());
+        collaboration.update(
+            ctx,
+            Presence {
+                participant: 0,
+                name: self.history.author.clone(),
+                path: here.clone(),
+                pan: self.pan,
+                zoom: self.zoom,
+                cursor,
+            },
+        );
+        let to_screen =
+            |point: Pos2| rect.center() + (point.to_vec2() - self.pan) * self.zoom * rect.size();
+        let font = egui::FontId::proportional(12.0);
+        for peer in collaboration.peers() {
+            let color = participant_color(peer.participant);
+            let name = display_name(&peer.name);
+            let place = |point: Pos2| relocate(&peer.path, point, &here).map(to_screen);
+            if self.collab.show_views {
+                let half = Vec2::splat(0.5 / peer.zoom);
+                let center = peer.pan.to_pos2();
+                if let (Some(min), Some(max)) = (place(center - half), place(center + half)) {
+                    let view = Rect::from_two_pos(min, max);
+                    painter.rect_stroke(view, 0.0, Stroke::new(1.5, color));
+                    painter.text(
+                        view.left_top() + vec2(4.0, 2.0),
+                        egui::Align2::LEFT_TOP,
+                        name,
+                        font.clone(),
+                        color,
+                    );
+                }
+            }
+            let Some(cursor) = peer
+                .cursor
+                .and_then(place)
+                .filter(|pos| rect.contains(*pos))
+            else {
+                continue;
+            };
+            painter.add(egui::Shape::convex_polygon(
+                vec![cursor, cursor + vec2(0.0, 16.0), cursor + vec2(11.0, 11.0)],
+                color,
+                Stroke::new(1.0, Color32::WHITE),
+            ));
+            let label = painter.layout_no_wrap(name.to_owned(), font.clone(), Color32::WHITE);
+            let label_rect = Rect::from_min_size(cursor + vec2(12.0, 14.0), label.size());
+            painter.rect_filled(label_rect.expand(2.0), 3.0, color);
+            painter.galley(label_rect.min, label, Color32::WHITE);
+        }
+        for lock in collaboration
+            .own_lock()
+            .into_iter()
+            .chain(collaboration.locks())
+        {
+            let color = participant_color(lock.participant);
+            let place = |point: Pos2| relocate(&lock.path, point, &here).map(to_screen);
+            if let (Some(min), Some(max)) = (place(lock.rect.min), place(lock.rect.max)) {
+                let region = Rect::from_two_pos(min, max);
+                painter.rect(
+                    region,
+                    0.0,
+                    color.gamma_multiply(0.08),
+                    Stroke::new(2.0, color),
+                );
+                painter.text(
+                    region.left_bottom() + vec2(4.0, -2.0),
+                    egui::Align2::LEFT_BOTTOM,
+                    format!(
+                        "🔒 {}",
+                        if lock.name.is_empty() {
+                            "Anonymous"
+                        } else {
+                            &lock.name
+                        }
+                    ),
+                    font.clone(),
+                    color,
+                );
+            }
+        }
+        if let Some(name) = collaboration.held_by() {
+            let text = format!("Your changes wait for {name} to unlock the region they are in");
+            let label = painter.layout_no_wrap(text, font.clone(), Color32::WHITE);
+            let label_rect =
+                Rect::from_center_size(rect.center_top() + vec2(0.0, 24.0), label.size());
+            painter.rect_filled(
+                label_rect.expand(4.0),
+                3.0,
+                ctx.style().visuals.warn_fg_color,
+            );
+            painter.galley(label_rect.min, label, Color32::WHITE);
+        }
+
+        let Some(mut collaboration) = self.collaboration.take() else {
+            return;
+        };
+        self.note_changes(ObjectChange::Edited);
+        let synced = collaboration.sync(
+            &mut self.draw_boxes.tree,
+            &self.op_log,
+            &mut self.versions,
+            &mut self.next_stroke_order,
+            &mut self.next_group,
+            self.last_cursor_pos.is_some(),
+        );
+        self.collaboration = Some(collaboration);
+        let Some(synced) = synced else {
+            return;
+        };
+        // Others' changes are not edits of this session, and are recorded as theirs.
+        self.edits_noted = Some(self.op_log.count());
+        self.history_noted = Some(self.op_log.count());
+        for (author, ops) in &synced.changes {
+            self.history.follow(
+                ops,
+                ObjectChange::Edited,
+                Some(author.as_deref().unwrap_or_default()),
+            );
+        }
+        for stroke in &synced.arrived {
+            self.next_stroke_order = self.next_stroke_order.max(stroke.order + 1);
+            if let Some(group) = stroke.group {
+                self.next_group = self.next_group.max(group + 1);
+            }
+            self.versions.arrived(stroke);
+        }
+        self.rebase_locations();
+        if synced.rebased {
+            self.selection.clear();
+            self.fitted_paste = None;
+        }
+        if !synced.conflicts.is_empty() {
+            log::info!(
+                "Kept the changes made here to {} objects others changed as well",
+                synced.conflicts.len()
+            );
+            self.conflicts.add(&self.draw_boxes.tree, synced.conflicts);
+        }
+        self.modified = true;
+    }
+
+    /// The color drawn at the screen position `point`, if any stroke covers it.
+    fn color_at(&self, ctx: &egui::Context, rect: Rect, point: Pos2) -> Option<Color32> {
+        let strokes = self.strokes_at(rect, point, 0.0);
+        let shapes = raster::capture_shapes(ctx, rect, |painter| {
+            for (stroke, screen_rect) in strokes {
+                let to_screen = emath::RectTransform::from_to(STANDARD_COORD_BOUNDS, screen_rect);
+                stroke.drawable.draw(painter, to_screen);
+            }
+        });
+        raster::color_at(ctx, shapes, point)
+    }
+
+    /// Strokes on screen whose bounds come within `tolerance` of `point`, topmost last.
+    fn strokes_at(&self, rect: Rect, point: Pos2, tolerance: f32) -> Vec<(StrokeEntry, Rect)> {
+        let (cells, ancestors) = self.visible_nodes(rect);
+        let mut strokes = vec![];
+        for (node, screen_rect) in cells {
+            strokes.extend(self.draw_boxes.tree.strokes_at(
+                node,
+                screen_rect,
+                point,
+                tolerance,
+                14,
+            ));
+        }
+        for (node, screen_rect) in ancestors {
+            strokes.extend(
+                self.draw_boxes
+                    .tree
+                    .strokes_at(node, screen_rect, point, tolerance, 0),
+            );
+        }
+        strokes.retain(|(stroke, _)| self.layers.is_visible(stroke.layer));
+        self.sort_strokes(&mut strokes);
+        strokes
+    }
+
+    /// Selects the topmost stroke on an unlocked layer under the screen position `point`. Shift
+    /// adds it to or removes it from the selection instead, and clicking empty canvas deselects.
+    fn select_at(&mut self, ui: &Ui, rect: Rect, point: Pos2) {
+        let hit = self
+            .stroke_at(rect, point)
+            .map(|stroke| self.selection_unit(&stroke));
+        let shift = ui.input(|input| input.modifiers.shift);
+        match hit {
+            Some(orders) if shift => self.selection.toggle(orders),
+            Some(orders) => self.selection.select(orders),
+            None if shift => {}
+            None => self.selection.clear(),
+        }
+    }
+
+    /// Tracks a rectangle dragged over the canvas with the select tool and selects the strokes
+    /// inside it when released.
+    fn handle_marquee(&mut self, ui: &Ui, response: &egui::Response, did_drag: bool) {
+        if did_drag {
+            self.marquee_drag = None;
+            return;
+        }
+        if response.drag_started_by(egui::PointerButton::Primary) {
+            if let Some(pos) = response.interact_pointer_pos() {
+                self.marquee_drag = Some((pos, pos));
+            }
+        }
+        let Some((start, end)) = self.marquee_drag.as_mut() else {
+            return;
+        };
+        if let Some(pos) = response.interact_pointer_pos() {
+            *end = pos;
+        }
+        if !response.drag_stopped() {
+            return;
+        }
+        let region = Rect::from_two_pos(*start, *end);
+        self.marquee_drag = None;
+        self.select_region(ui, response.rect, region);
+    }
+
+    /// Selects the strokes on visible, unlocked layers lying entirely within the screen rect
+    /// `region`, along with their groups. A pen stroke is picked by any of its segments. Shift
+    /// adds them to the selection instead.
+    fn select_region(&mut self, ui: &Ui, rect: Rect, region: Rect) {
+        let (cells, ancestors) = self.visible_nodes(rect);
+        let nodes = cells
+            .into_iter()
+            .map(|node| (node, 14))
+            .chain(ancestors.into_iter().map(|node| (node, 0)));
+        let mut orders = BTreeSet::new();
+        let mut groups = BTreeSet::new();
+        for ((node, screen_rect), depth) in nodes {
+            let to_screen = emath::RectTransform::from_to(STANDARD_COORD_BOUNDS, screen_rect);
+            let query = to_screen.inverse().transform_rect(region);
+            for stroke_ref in self.draw_boxes.tree.query_rect(node, query, depth) {
+                for stroke in stroke_ref.strokes(&self.draw_boxes.tree) {
+                    let bounds = to_screen.transform_rect(
+                        stroke_ref.to_query.transform_rect(stroke.drawable.bounds()),
+                    );
+                    if !region.contains_rect(bounds)
+                        || !self.layers.is_visible(stroke.layer)
+                        || self.layers.is_locked(stroke.layer)
+                    {
+                        continue;
+                    }
+                    match stroke.group {
+                        Some(group) => groups.insert(group),
+                        None => orders.insert(stroke.order),
+                    };
+                }
+            }
+        }
+        for group in groups {
+            orders.extend(self.group_orders(group));
+        }
+        if ui.input(|input| input.modifiers.shift) {
+            orders.extend(self.selection.orders());
+        }
+        self.selection.select(orders);
+    }
+
+    /// Orders selected together with `stroke`: its whole group, if it is in one.
+    fn selection_unit(&mut self, stroke: &StrokeEntry) -> BTreeSet<u64> {
+        match stroke.group {
+            Some(group) => self.group_orders(group),
+            None => BTreeSet::from([stroke.order]),
+        }
+    }
+
+    /// Adds an invisible, focusable widget over each text object on screen so screen readers
+    /// can read and navigate them in reading order. Activating one selects it for editing in
+    /// the inspector and centers the view on it. Returns true if one was activated.
+    fn accessible_objects(&mut self, ui: &Ui, rect: Rect, strokes: &[(StrokeEntry, Rect)]) -> bool {
+        let objects = strokes
+            .iter()
+            .filter_map(|(stroke, screen_rect)| {
+                let label = stroke.drawable.accessible_label()?;
+                let bounds = stroke_screen_bounds(stroke, *screen_rect).intersect(rect);
+                bounds.is_positive().then_some((stroke, bounds, label))
+            })
+            .sorted_by(|(_, a, _), (_, b, _)| {
+                (a.min.y, a.min.x)
+                    .partial_cmp(&(b.min.y, b.min.x))
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            })
+            .take(MAX_ACCESSIBLE_OBJECTS);
+        let mut activated = None;
+        for (stroke, bounds, label) in objects {
+            let id = ui.id().with(("accessible_object", stroke.order));
+            let response = ui.interact(bounds, id, Sense::focusable_noninteractive());
+            ui.ctx().accesskit_node_builder(id, |node| {
+                node.add_action(egui::accesskit::Action::Click);
+            });
+            response.widget_info(|| {
+                egui::WidgetInfo::labeled(egui::WidgetType::Button, true, label.clone())
+            });
+            let keyboard = response.has_focus()
+                && ui.input(|input| {
+                    input.key_pressed(egui::Key::Enter) || input.key_pressed(egui::Key::Space)
+                });
+            let requested = ui.input(|input| {
+                input.has_accesskit_action_request(id, egui::accesskit::Action::Click)
+            });
+            if keyboard || requested {
+                activated = Some((stroke, bounds));
+            }
+        }
+        let Some((stroke, bounds)) = activated else {
+            return false;
+        };
+        self.tool = Tool::Select;
+        let orders = self.selection_unit(stroke);
+        self.selection.select(orders);
+        self.pan += (bounds.center() - rect.center()) / self.zoom / rect.size();
+        true
+    }
+
+    /// The topmost stroke on an unlocked layer under the screen position `point`.
+    fn stroke_at(&self, rect: Rect, point: Pos2) -> Option<StrokeEntry> {
+        self.strokes_at(rect, point, 4.0)
+            .into_iter()
+            .rev()
+            .map(|(stroke, _)| stroke)
+            .find(|stroke| !self.layers.is_locked(stroke.layer))
+    }
+
+    /// Gives the stroke under the screen position `point` the pen's color and width. Returns
+    /// false if there is no stroke there.
+    fn restyle_at(&mut self, rect: Rect, point: Pos2) -> bool {
+        let Some(stroke) = self.stroke_at(rect, point) else {
+            return false;
+        };
+        let orders = BTreeSet::from([stroke.order]);
+        // Matches the width new strokes are drawn at.
+        let width = self.stroke.width * 0.005 * rect.size().max_elem();
+        self.edit_strokes(&orders, &Edit::Color(self.stroke.color));
+        self.edit_strokes(&orders, &Edit::Width(width));
+        self.palette.use_color(self.stroke.color);
+        true
+    }
+
+    /// Shows the properties of the selection in a side panel while the select tool is active.
+    pub fn inspector_panel(&mut self, ctx: &egui::Context) {
+        if self.tool != Tool::Select || self.selection.is_empty() {
+            return;
+        }
+        egui::SidePanel::right("inspector").show(ctx, |ui| self.inspector_ui(ui));
+    }
+
+    /// Shows a small live picture of what each participant in the room has in view, in a side
+    /// panel while collaborating. Clicking one jumps to where they are looking.
+    pub fn participants_panel(&mut self, ctx: &egui::Context) {
+        let Some(collaboration) = &self.collaboration else {
+            return;
+        };
+        let peers = collaboration.peers().cloned().collect_vec();
+        if !self.collab.show_thumbnails || peers.is_empty() {
+            return;
+        }
+        let mut jump = None;
+        egui::SidePanel::left("participants").show(ctx, |ui| {
+            ui.heading("Participants");
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                for peer in peers {
+                    let color = participant_color(peer.participant);
+                    ui.colored_label(color, display_name(&peer.name));
+                    let (response, painter) =
+                        ui.allocate_painter(vec2(ui.available_width(), 120.0), Sense::click());
+                    let rect = response.rect;
+                    let mut location = ViewLocation::at_path(
+                        &self.draw_boxes.tree,
+                        peer.path.clone(),
+                        peer.pan,
+                        peer.zoom,
+                    );
+                    painter.rect_filled(rect, 0.0, ui.visuals().extreme_bg_color);
+                    paint_location(
+                        ctx,
+                        &painter.with_clip_rect(rect),
+                        rect,
+                        &mut self.draw_boxes.tree,
+                        &self.layers,
+                        self.quality,
+                        &mut location,
+                    );
+                    let width = if response.hovered() { 3.0 } else { 1.5 };
+                    painter.rect_stroke(rect, 0.0, Stroke::new(width, color));
+                    if response
+                        .on_hover_text(format!(
+                            "Go to where {} is looking",
+                            display_name(&peer.name)
+                        ))
+                        .clicked()
+                    {
+                        jump = Some(location);
+                    }
+                }
+            });
+        });
+        if let Some(location) = jump {
+            self.jump_to(&location);
+        }
+    }
+
+    fn inspector_ui(&mut self, ui: &mut Ui) {
+        ui.heading("Properties");
+        ui.label(format!("{} selected", self.selection.len()));
+        ui.separator();
+        // The first selected stroke found stands in for the rest; edits go to all of them.
+        let mut sample = None;
+        self.draw_boxes
+            .tree
+            .for_each_node(self.outermost(), 0, &mut |node, depth| {
+                if sample.is_none() {
+                    sample = node
+                        .own_strokes()
+                        .iter()
+                        .find(|stroke| self.selection.contains(stroke))
+                        .map(|stroke| (stroke.drawable.clone(), depth));
+                }
+            });
+        let Some((mut sample, depth)) = sample else {
+            return;
+        };
+        let scale = self.node_scale(depth).max_elem();
+        let mut edit = None;
+        egui::Grid::new("inspector").num_columns(2).show(ui, |ui| {
+            for property in sample.properties() {
+                match property {
+                    Property::Color(color) => {
+                        ui.label("Color");
+                        if ui.color_edit_button_srgba(color).changed() {
+                            edit = Some(Edit::Color(*color));
+                        }
+                    }
+                    Property::Width(width) => {
+                        ui.label("Width");
+                        let old = *width * scale;
+                        let mut new = old;
+                        ui.add(
+                            egui::DragValue::new(&mut new)
+                                .range(0.0..=f32::INFINITY)
+                                .speed(0.1)
+                                .suffix(" pt"),
+                        );
+                        if new != old && old > 0.0 {
+                            edit = Some(Edit::ScaleWidth(new / old));
+                        }
+                    }
+                    Property::Text(text) => {
+                        ui.label("Text");
+                        if ui.text_edit_multiline(text).changed() {
+                            edit = Some(Edit::Text(text.clone()));
+                        }
+                    }
+                }
+                ui.end_row();
+            }
+            ui.label("Move");
+            let mut offset = Vec2::ZERO;
+            ui.horizontal(|ui| {
+                ui.add(
+                    egui::DragValue::new(&mut offset.x)
+                        .prefix("x ")
+                        .suffix(" pt"),
+                );
+                ui.add(
+                    egui::DragValue::new(&mut offset.y)
+                        .prefix("y ")
+                        .suffix(" pt"),
+                );
+            });
+            if offset != Vec2::ZERO {
+                edit = Some(Edit::Move(offset));
+            }
+            ui.end_row();
+        });
+        if let Some(edit) = edit {
+            self.edit_strokes(&self.selection.orders().clone(), &edit);
+        }
+        ui.separator();
+        ui.collapsing("History", |ui| {
+            let ids = self
+                .selection
+                .orders()
+                .iter()
+                .filter_map(|order| self.versions.id(*order))
+                .collect_vec();
+            self.history.ui(ui, ids);
+        });
+    }
+
+    /// Screen points per unit of node coordinates for nodes `depth` levels below the outermost
+    /// node.
+    fn node_scale(&self, depth: u32) -> Vec2 {
+        let levels_up = self.center_depth() as i32 - depth as i32;
+        self.zoom * self.canvas_rect.size() / 2.0 * 2f32.powi(levels_up)
+    }
+
+    /// Depth of the center cell below the outermost node.
+    fn center_depth(&self) -> usize {
+        let center = *self.draw_boxes.get(0, 0).unwrap();
+        self.draw_boxes.tree.get_top_level_and_path(center).1.len()
+    }
+
+    /// Applies an edit to every stroke on an unlocked layer whose order is in `orders`.
+    fn edit_strokes(&mut self, orders: &BTreeSet<u64>, edit: &Edit) {
+        let scales = (0..=self.depth_range().map_or(0, |(_, max)| max))
+            .map(|depth| self.node_scale(depth))
+            .collect_vec();
+        let Some(top_level) = self.top_level() else {
+            return;
+        };
+        let layers = &self.layers;
+        let mut edited = BTreeSet::new();
+        self.draw_boxes
+            .tree
+            .update_strokes(top_level, 0, &mut |stroke, depth| {
+                if !orders.contains(&stroke.order) || layers.is_locked(stroke.layer) {
+                    return false;
+                }
+                edited.insert(stroke.order);
+                if let Edit::Move(offset) = edit {
+                    stroke
+                        .drawable
+                        .transform(&Affine2::from_translation(*offset / scales[depth as usize]));
+                    return true;
+                }
+                for property in stroke.drawable.properties() {
+                    match (property, edit) {
+                        (Property::Color(color), Edit::Color(new)) => *color = *new,
+                        (Property::Width(width), Edit::ScaleWidth(factor)) => *width *= factor,
+                        (Property::Width(width), Edit::Width(new)) => {
+                            *width = new / scales[depth as usize].max_elem()
+                        }
+                        (Property::Text(text), Edit::Text(new)) => text.clone_from(new),
+                        _ => {}
+                    }
+                }
+                true
+            });
+        let change = match edit {
+            Edit::Color(_) => ObjectChange::Recolored,
+            Edit::ScaleWidth(_) | Edit::Width(_) => ObjectChange::Restyled,
+            Edit::Text(_) => ObjectChange::TextEdited,
+            Edit::Move(_) => ObjectChange::Moved,
+        };
+        self.versions.touch(edited);
+        self.note_changes(change);
+        self.record_edit();
+    }
+
+    /// The selected strokes among `strokes`, positioned around the center of their screen
+    /// bounds. Returns None if none of them are selected.
+    fn copy_selection(&self, strokes: &[(StrokeEntry, Rect)]) -> Option<CopiedStrokes> {
+        let selected = strokes
+            .iter()
+            .filter(|(stroke, _)| self.selection.contains(stroke))
+            .collect_vec();
+        let center = selected
+            .iter()
+            .map(|(stroke, screen_rect)| stroke_screen_bounds(stroke, *screen_rect))
+            .reduce(Rect::union)?
+            .center();
+        let depth = self.center_depth() as u32;
+        let unit = self.node_scale(depth);
+        let strokes = selected
+            .into_iter()
+            .map(|(stroke, screen_rect)| {
+                let mut drawable = stroke.drawable.clone();
+                drawable.transform(
+                    &Affine2::from_scale(Vec2::splat(screen_rect.width() / 2.0 / unit.x)).then(
+                        &Affine2::from_translation((screen_rect.center() - center) / unit),
+                    ),
+                );
+                CopiedStroke {
+                    drawable,
+                    priority: stroke.priority,
+                    group: stroke.group,
+                }
+            })
+            .collect();
+        Some(CopiedStrokes { depth, strokes })
+    }
+
+    /// Adds copied strokes centered on the screen position `target` to the active layer and
+    /// selects them. Pastes too big for the view are shrunk to fit unless `keep_size` is set.
+    /// Returns false if nothing was pasted.
+    fn paste(&mut self, rect: Rect, copied: CopiedStrokes, target: Pos2, keep_size: bool) -> bool {
+        if copied.strokes.is_empty() || self.layers.is_locked(self.layers.active()) {
+            return false;
+        }
+        let Some((parent, center, _)) = self.locate(rect, target, target) else {
+            return false;
+        };
+        let unit = self.node_scale(copied.depth);
+        let fit = (PASTE_FIT * rect.size() / (copied.bounds().size() * unit))
+            .min_elem()
+            .min(1.0);
+        let scale = if keep_size { 1.0 } else { fit };
+        // From copied coordinates to those of the parent of the cell under `target`, where a
+        // cell spans zoom * rect.size() points.
+        let factor = scale * unit.x / (self.zoom * rect.width());
+        let mut orders = BTreeSet::new();
+        let mut groups = BTreeMap::new();
+        for copied_stroke in &copied.strokes {
+            let mut drawable = copied_stroke.drawable.clone();
+            drawable.transform(
+                &Affine2::from_scale(Vec2::splat(factor))
+                    .then(&Affine2::from_translation(center.to_vec2())),
+            );
+            let bounds = drawable.bounds();
+            let group = copied_stroke.group.map(|group| {
+                *groups.entry(group).or_insert_with(|| {
+                    self.next_group += 1;
+                    self.next_group - 1
+                })
+            });
+            let meta = self.stroke_meta(copied_stroke.priority);
+            orders.insert(meta.order);
+            self.draw_boxes.tree.send_drawable(
+                bounds.min,
+                bounds.max,
+                1.0,
+                parent,
+                |new_min, _, node_scale| {
+                    drawable.transform(&Self::rehome(bounds.min, new_min, node_scale));
+                    StrokeEntry {
+                        group,
+                        ..meta.entry(drawable)
+                    }
+                },
+            );
+            self.next_stroke_order += ordering::ORDER_SPACING;
+        }
+        self.selection.select(orders.clone());
+        self.record_edit();
+        self.fitted_paste = (scale < 1.0).then_some(FittedPaste {
+            copied,
+            target,
+            orders,
+            scale,
+        });
+        true
+    }
+
+    /// Adds the shapes `import` reads from `data` to the middle of the view, one unit of the
+    /// file to a point on screen, shrunk to fit if they would not.
+    fn import_shapes(&mut self, data: &[u8], import: ShapeImport) -> Result<(), String> {
+        let depth = self.center_depth() as u32;
+        let copied = import(data, depth, 1.0 / self.node_scale(depth).x)?;
+        let rect = self.canvas_rect;
+        if !self.paste(rect, copied, rect.center(), false) {
+            return Err("The active layer is locked".to_string());
+        }
+        Ok(())
+    }
+
+    /// Adds `image` centered on the screen position `target`, one pixel to a point on screen,
+    /// shrunk to fit if it would not.
+    fn paste_image(&mut self, rect: Rect, image: &ColorImage, target: Pos2) -> Result<(), String> {
+        let depth = self.center_depth() as u32;
+        let size = vec2(image.width() as f32, image.height() as f32) / self.node_scale(depth).x;
+        let drawable = RasterImage::new(Rect::from_center_size(Pos2::ZERO, size), image)
+            .map_err(|err| err.to_string())?;
+        let copied = CopiedStrokes {
+            depth,
+            strokes: vec![CopiedStroke {
+                drawable: Box::new(drawable),
+                priority: StrokePriority::Ink,
+                group: None,
+            }],
+        };
+        if !self.paste(rect, copied, target, false) {
+            return Err("The active layer is locked".to_string());
+        }
+        Ok(())
+    }
+
+    /// Offers to redo a paste that was shrunk to fit the view at its original size.
+    fn fitted_paste_prompt(&mut self, ctx: &egui::Context, rect: Rect) {
+        let Some(fitted) = &self.fitted_paste else {
+            return;
+        };
+        let mut keep_size = false;
+        let mut dismiss = false;
+        egui::Area::new(egui::Id::new("fitted_paste"))
+            .anchor(egui::Align2::CENTER_BOTTOM, vec2(0.0, -16.0))
+            .show(ctx, |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label(format!(
+                            "Pasted at {:.0}% to fit the view",
+                            fitted.scale * 100.0
+                        ));
+                        keep_size = ui.button("Keep original size").clicked();
+                        dismiss = ui.small_button("✕").clicked();
+                    });
+                });
+            });
+        let top_level = if keep_size { self.top_level() } else { None };
+        if let Some(top_level) = top_level {
+            let FittedPaste {
+                copied,
+                target,
+                orders,
+                ..
+            } = self.fitted_paste.take().unwrap();
+            let removed = self
+                .draw_boxes
+                .tree
+                .retain_strokes(top_level, &|stroke| !orders.contains(&stroke.order));
+            self.versions.touch(removed);
+            self.paste(rect, copied, target, true);
+        } else if dismiss {
+            self.fitted_paste = None;
+        }
+    }
+
+    /// Shows handles around the selection's screen `bounds`, previewing a drag and applying it
+    /// on release. Returns true once a transform was applied.
+    fn transform_handles(
+        &mut self,
+        ui: &Ui,
+        painter: &egui::Painter,
+        rect: Rect,
+        bounds: Rect,
+    ) -> bool {
+        let bounds = self.transform_drag.map_or(bounds, |(_, start)| start);
+        let stroke = ui.visuals().selection.stroke;
+        let rotate = TransformHandle::Rotate.position(bounds);
+        painter.line_segment([bounds.center_top(), rotate], stroke);
+        let mut applied = false;
+        for handle in TransformHandle::ALL {
+            let position = handle.position(bounds);
+            let handle_rect = Rect::from_center_size(position, Vec2::splat(HANDLE_SIZE));
+            let response = ui
+                .interact(
+                    handle_rect,
+                    ui.id().with(("transform_handle", handle)),
+                    Sense::drag(),
+                )
+                .on_hover_cursor(match handle {
+                    TransformHandle::Corner(x, y) if x == y => egui::CursorIcon::ResizeNwSe,
+                    TransformHandle::Corner(..) => egui::CursorIcon::ResizeNeSw,
+                    TransformHandle::Rotate => egui::CursorIcon::Grab,
+                });
+            let fill = ui.visuals().extreme_bg_color;
+            match handle {
+                TransformHandle::Corner(..) => {
+                    painter.rect(handle_rect, 0.0, fill, stroke);
+                }
+                TransformHandle::Rotate => {
+                    painter.circle(position, HANDLE_SIZE / 2.0, fill, stroke);
+                }
+            }
+            if response.drag_started() {
+                self.transform_drag = Some((handle, bounds));
+            }
+            if !matches!(self.transform_drag, Some((dragging, _)) if dragging == handle) {
+                continue;
+            }
+            let (Some(pointer), shift) =
+                ui.input(|input| (input.pointer.interact_pos(), input.modifiers.shift))
+            else {
+                continue;
+            };
+            let transform = handle.transform(bounds, pointer, shift);
+            if response.drag_stopped() {
+                self.transform_drag = None;
+                applied = self.transform_selection(rect, &transform);
+            } else {
+                let corners = [(0, 0), (1, 0), (1, 1), (0, 1)]
+                    .map(|(x, y)| transform.transform_point(corner_of(bounds, x, y)));
+                painter.add(egui::Shape::closed_line(corners.to_vec(), stroke));
+            }
+        }
+        applied
+    }
+
+    /// Applies `transform`, in screen points, to the selected strokes. Returns false if the
+    /// transform would collapse them.
+    fn transform_selection(&mut self, rect: Rect, transform: &Affine2) -> bool {
+        let scale = transform.scale_factor();
+        if !scale.is_finite() || scale < 1e-3 {
+            return false;
+        }
+        let transforms = self
+            .selection
+            .orders()
+            .iter()
+            .map(|order| (*order, *transform))
+            .collect();
+        self.transform_strokes(rect, &transforms);
+        true
+    }
+
+    /// Moves the selected objects among `strokes`, as drawn this frame, into line. Returns
+    /// false if fewer than two objects are selected.
+    fn align_selection(
+        &mut self,
+        rect: Rect,
+        strokes: &[(StrokeEntry, Rect)],
+        align: Align,
+    ) -> bool {
+        let mut objects = BTreeMap::<_, (Rect, Vec<u64>)>::new();
+        for (stroke, screen_rect) in strokes {
+            if !self.selection.contains(stroke) || self.layers.is_locked(stroke.layer) {
+                continue;
+            }
+            let key = match stroke.group {
+                Some(group) => (true, u64::from(group)),
+                None => (false, stroke.order),
+            };
+            let (bounds, orders) = objects.entry(key).or_insert((Rect::NOTHING, vec![]));
+            *bounds = bounds.union(stroke_screen_bounds(stroke, *screen_rect));
+            orders.push(stroke.order);
+        }
+        if objects.len() < 2 {
+            return false;
+        }
+        let (bounds, orders): (Vec<_>, Vec<_>) = objects.into_values().unzip();
+        let transforms = align
+            .offsets(&bounds)
+            .into_iter()
+            .zip(orders)
+            .flat_map(|(offset, orders)| {
+                orders
+                    .into_iter()
+                    .map(move |order| (order, Affine2::from_translation(offset)))
+            })
+            .collect();
+        self.transform_strokes(rect, &transforms);
+        true
+    }
+
+    /// Applies to each stroke in the loaded nodes on a visible, unlocked layer the transform
+    /// keyed by its order, in screen points, then moves it to the node that fits it.
+    fn transform_strokes(&mut self, rect: Rect, transforms: &BTreeMap<u64, Affine2>) {
+        let (cells, ancestors) = self.visible_nodes(rect);
+        let layers = &self.layers;
+        let take = |stroke: &StrokeEntry| {
+            transforms.contains_key(&stroke.order)
+                && layers.is_visible(stroke.layer)
+                && !layers.is_locked(stroke.layer)
+        };
+        // Searches as deep as the strokes drawn each frame.
+        let mut pending = cells
+            .into_iter()
+            .map(|(node, screen_rect)| (node, screen_rect, 14))
+            .chain(
+                ancestors
+                    .into_iter()
+                    .map(|(node, screen_rect)| (node, screen_rect, 0)),
+            )
+            .collect_vec();
+        let mut taken = vec![];
+        let tree = &mut self.draw_boxes.tree;
+        while let Some((node, screen_rect, depth)) = pending.pop() {
+            let strokes = tree.take_strokes(node, &take);
+            if !strokes.is_empty() {
+                tree.ancestors_changed(node);
+            }
+            taken.extend(
+                strokes
+                    .into_iter()
+                    .map(|stroke| (node, screen_rect, stroke)),
+            );
+            if depth > 0 {
+                for ((x, y), child) in tree[node].child_nodes() {
+                    pending.push((child, DrawNode::child_rect(screen_rect, x, y), depth - 1));
+                }
+            }
+        }
+        for (node, screen_rect, mut stroke) in taken {
+            let to_screen = Affine2::from_scale(screen_rect.size() / 2.0)
+                .then(&Affine2::from_translation(screen_rect.center().to_vec2()));
+            let transform = &transforms[&stroke.order];
+            self.versions.touch([stroke.order]);
+            stroke
+                .drawable
+                .transform(&to_screen.then(transform).then(&to_screen.inverse()));
+            let bounds = stroke.drawable.bounds();
+            self.draw_boxes.tree.send_drawable(
+                bounds.min,
+                bounds.max,
+                1.0,
+                node,
+                |new_min, _, scale| {
+                    stroke
+                        .drawable
+                        .transform(&Self::rehome(bounds.min, new_min, scale));
+                    stroke
+                },
+            );
+        }
+        let change = if transforms.values().all(Affine2::is_translation) {
+            ObjectChange::Moved
+        } else {
+            ObjectChange::Transformed
+        };
+        self.note_changes(change);
+        self.record_edit();
+    }
+
+    /// Maps a drawable from the node it was built in to the one `send_drawable` stored it in,
+    /// given where the minimum corner of its bounds ended up and the scale between the nodes.
+    fn rehome(old_min: Pos2, new_min: Pos2, scale: f32) -> Affine2 {
+        Affine2::from_scale(Vec2::splat(scale))
+            .then(&Affine2::from_translation(new_min - old_min * scale))
+    }
+
+    /// Orders of the strokes in `group`.
+    fn group_orders(&mut self, group: GroupId) -> BTreeSet<u64> {
+        let mut orders = BTreeSet::new();
+        let Some(top_level) = self.top_level() else {
+            return orders;
+        };
+        self.draw_boxes
+            .tree
+            .for_each_node(top_level, 0, &mut |node, _| {
+                orders.extend(
+                    node.own_strokes()
+                        .iter()
+                        .filter(|stroke| stroke.group == Some(group))
+                        .map(|stroke| stroke.order),
+                );
+            });
+        orders
+    }
+
+    /// Puts the selected strokes in a new group, taking them out of any group they were in.
+    fn group_selection(&mut self) -> Option<GroupId> {
+        if self.selection.len() < 2 {
+            return None;
+        }
+        let group = self.next_group;
+        self.next_group += 1;
+        self.set_selection_group(Some(group));
+        Some(group)
+    }
+
+    fn ungroup_selection(&mut self) {
+        self.set_selection_group(None);
+    }
+
+    fn set_selection_group(&mut self, group: Option<GroupId>) {
+        let Some(top_level) = self.top_level() else {
+            return;
+        };
+        let selection = &self.selection;
+        self.draw_boxes
+            .tree
+            .update_strokes(top_level, 0, &mut |stroke, _| {
+                if selection.contains(stroke) {
+                    stroke.group = group;
+                }
+                false
+            });
+        self.record_edit();
+    }
+
+    /// Moves the selection above or below every stroke on the same layer that it overlaps on
+    /// screen. Returns false if it overlaps nothing.
+    fn arrange_selection(&mut self, strokes: &[(StrokeEntry, Rect)], arrange: Arrange) -> bool {
+        let selected = strokes
+            .iter()
+            .filter(|(stroke, _)| self.selection.contains(stroke))
+            .map(|(stroke, screen_rect)| (stroke.layer, stroke_screen_bounds(stroke, *screen_rect)))
+            .collect_vec();
+        let overlapping = strokes
+            .iter()
+            .filter(|(stroke, screen_rect)| {
+                let bounds = stroke_screen_bounds(stroke, *screen_rect);
+                !self.selection.contains(stroke)
+                    && selected
+                        .iter()
+                        .any(|(layer, rect)| *layer == stroke.layer && rect.intersects(bounds))
+            })
+            .map(|(stroke, _)| stroke.order);
+        let target = match arrange {
+            Arrange::BringToFront => overlapping.max(),
+            Arrange::SendToBack => overlapping.min(),
+        };
+        let (Some(target), Some(top_level)) = (target, self.top_level()) else {
+            return false;
+        };
+        let renumbered = ordering::move_orders(
+            &mut self.draw_boxes.tree,
+            top_level,
+            self.selection.orders(),
+            target,
+            arrange == Arrange::BringToFront,
+            &mut self.next_stroke_order,
+        );
+        self.selection
+            .renumber(|order| renumbered.get(&order).copied().unwrap_or(order));
+        self.versions.renumber(&renumbered);
+        self.versions.touch(self.selection.orders().iter().copied());
+        self.note_changes(ObjectChange::Reordered);
+        true
+    }
+
+    /// Renumbers every stroke to evenly spaced orders, following them in everything that refers
+    /// to strokes by order.
+    fn normalize_orders(&mut self) {
+        let Some(top_level) = self.top_level() else {
+            return;
+        };
+        self.note_changes(ObjectChange::Edited);
+        let renumbered = ordering::normalize(
+            &mut self.draw_boxes.tree,
+            top_level,
+            &mut self.next_stroke_order,
+        );
+        log::info!("Normalized the orders of {} strokes", renumbered.len());
+        self.selection
+            .renumber(|order| renumbered.get(&order).copied().unwrap_or(order));
+        // Renumbering changes no object as far as its history goes.
+        self.history_noted = Some(self.op_log.count());
+        self.versions.renumber_all(&renumbered);
+        self.cluster_framing.renumber(&renumbered);
+        self.fitted_paste = None;
+    }
+
+    /// Inserts the segment between the screen positions `a` and `b` into the tree, continuing
+    /// the dash pattern and taper of the stroke in progress. Returns false if the segment is
+    /// outside of the loaded cells.
+    fn commit_segment(
+        &mut self,
+        rect: Rect,
+        a: Pos2,
+        b: Pos2,
+        width: f32,
+        draw_stroke: Stroke,
+        priority: StrokePriority,
+    ) -> bool {
+        let Some((parent, p1, p2)) = self.locate(rect, a, b) else {
+            return false;
+        };
+        self.insert_segment(
+            parent,
+            p1,
+            p2,
+            0.005 / self.zoom,
+            width,
+            draw_stroke,
+            priority,
+        );
+        let screen_width = draw_stroke.width * 0.005 * rect.size().max_elem();
+        if screen_width > 0.0 {
+            self.dash_phase += (b - a).length() / screen_width;
+        }
+        true
+    }
+
+    /// Adds the segment from `p1` to `p2` in the coordinates of `parent` to it, `scale` being
+    /// the width of the pen's unit width there.
+    #[allow(clippy::too_many_arguments)]
+    fn insert_segment(
+        &mut self,
+        parent: NodeId,
+        p1: Pos2,
+        p2: Pos2,
+        scale: f32,
+        width: f32,
+        draw_stroke: Stroke,
+        priority: StrokePriority,
+    ) {
+        let style = SegmentStyle {
+            stroke: draw_stroke,
+            line_style: self.line_style,
+            dash_phase: self.dash_phase,
+            widths: [self.last_width, width],
+        };
+        if self.taper && self.tool == Tool::Pen {
+            let meta = self.stroke_meta(priority);
+            self.draw_boxes
+                .tree
+                .send_stroke::<TaperedStroke>(p1, p2, scale, &style, meta, parent);
+        } else {
+            let meta = self.stroke_meta(priority);
+            self.draw_boxes
+                .tree
+                .send_stroke::<Line>(p1, p2, scale, &style, meta, parent);
+        }
+        self.last_width = width;
+    }
+
+    /// The screen position `pos` in the units of the view's cells, as `pan` is.
+    fn to_cells(&self, rect: Rect, pos: Pos2) -> Pos2 {
+        (self.pan + (pos - rect.center()) / (self.zoom * rect.size())).to_pos2()
+    }
+
+    /// Where the samples of the stroke being drawn in low latency mode are on the screen now,
+    /// leaving out those too far away to show.
+    fn live_stroke_on_screen(&mut self, rect: Rect) -> Vec<Pos2> {
+        let Some(origin) = &mut self.live_stroke_origin else {
+            return vec![];
+        };
+        origin.rebase(&self.draw_boxes.tree);
+        let from = origin.path();
+        let here = self.current_location().path();
+        self.live_stroke
+            .iter()
+            .filter_map(|(pos, _)| relocate(&from, *pos, &here))
+            .map(|pos| rect.center() + (pos.to_vec2() - self.pan) * self.zoom * rect.size())
+            .collect()
+    }
+
+    /// Inserts the samples collected while drawing in low latency mode into the tree, leaving
+    /// out those the stroke's shape does not need. They are placed from the cell the stroke
+    /// started in, so they land where they were drawn however the view moved meanwhile.
+    fn commit_live_stroke(&mut self, rect: Rect, draw_stroke: Stroke, priority: StrokePriority) {
+        let samples = std::mem::take(&mut self.live_stroke);
+        let Some(mut origin) = self.live_stroke_origin.take() else {
+            return;
+        };
+        // Simplified and dashed at the scale the stroke started being drawn at.
+        let scale = origin.zoom * rect.size();
+        let points = samples
+            .iter()
+            .map(|(pos, _)| (pos.to_vec2() * scale).to_pos2())
+            .collect_vec();
+        let samples = geometry::simplified_indices(&points, STROKE_TOLERANCE)
+            .into_iter()
+            .map(|i| samples[i]);
+        self.dash_phase = 0.0;
+        self.last_width = 1.0;
+        let cell = origin.node(&mut self.draw_boxes.tree);
+        let screen_width = draw_stroke.width * 0.005 * rect.size().max_elem();
+        for ((a, _), (b, width)) in samples.tuple_windows() {
+            let (parent, p1, p2) = self.locate_in_cell(cell, a, b);
+            let unit = 0.005 / origin.zoom;
+            self.insert_segment(parent, p1, p2, unit, width, draw_stroke, priority);
+            if screen_width > 0.0 {
+                self.dash_phase += ((b - a) * scale).length() / screen_width;
+            }
+        }
+    }
+
+    /// Replaces the segments the stroke just drawn added as the pointer moved with a simplified
+    /// copy, if simplifying leaves out any samples. Samples are in screen points, so the stroke
+    /// is left as it is if the view moved while drawing.
+    fn simplify_drawn_stroke(&mut self, rect: Rect, draw_stroke: Stroke, priority: StrokePriority) {
+        let samples = std::mem::take(&mut self.drawn_stroke);
+        if samples.len() <= 2 || self.drawn_stroke_view != (self.pan, self.zoom) {
+            return;
+        }
+        let points = samples.iter().map(|(pos, _)| *pos).collect_vec();
+        if geometry::simplified_indices(&points, STROKE_TOLERANCE).len() == samples.len() {
+            return;
+        }
+        let order = self.next_stroke_order;
+        let screen_width = draw_stroke.width * 0.005 * rect.size().max_elem();
+        let region = Rect::from_points(&points).expand(screen_width);
+        self.delete_in_view(rect, region, &|stroke| stroke.order != order);
+        self.live_stroke = samples
+            .into_iter()
+            .map(|(pos, width)| (self.to_cells(rect, pos), width))
+            .collect();
+        self.live_stroke_origin = Some(self.current_location());
+        self.commit_live_stroke(rect, draw_stroke, priority);
+    }
+
+    /// Maps the screen positions `a` and `b` into the coordinates of the parent of the cell
+    /// containing their midpoint, returning that parent.
+    fn locate(&mut self, rect: Rect, a: Pos2, b: Pos2) -> Option<(NodeId, Pos2, Pos2)> {
+        let from_screen = emath::RectTransform::from_to(
+            rect.scale_from_center(5.0 * self.zoom)
+                .translate(self.zoom * -self.pan * rect.size()),
+            5.0 / 2.0 * STANDARD_COORD_BOUNDS,
+        );
+        let center = from_screen * a.lerp(b, 0.5);
+        let x = center.x.round() as i32;
+        let y = center.y.round() as i32;
+        let node = *self.draw_boxes.get(x, y)?;
+        let tree = &mut self.draw_boxes.tree;
+        let corner = vec2(tree[node].corner.0 as f32, tree[node].corner.1 as f32);
+        let p1 = from_screen * a - vec2(x as f32, y as f32) + corner - vec2(0.5, 0.5);
+        let p2 = from_screen * b - vec2(x as f32, y as f32) + corner - vec2(0.5, 0.5);
+        let parent = tree.get_or_create_parent(node);
+        Some((parent, p1, p2))
+    }
+
+    /// Like `locate`, for `a` and `b` in the units of the cell `cell` as `pan` is, which may be
+    /// far from the cells in view.
+    fn locate_in_cell(&mut self, cell: NodeId, a: Pos2, b: Pos2) -> (NodeId, Pos2, Pos2) {
+        let center = a.lerp(b, 0.5);
+        let x = center.x.round() as i32;
+        let y = center.y.round() as i32;
+        let tree = &mut self.draw_boxes.tree;
+        let mut node = cell;
+        let (along_x, along_y) = (
+            if x > 0 {
+                Direction::PosX
+            } else {
+                Direction::NegX
+            },
+            if y > 0 {
+                Direction::PosY
+            } else {
+                Direction::NegY
+            },
+        );
+        for _ in 0..x.unsigned_abs() {
+            node = tree.get_or_create_neighbor(node, along_x);
+        }
+        for _ in 0..y.unsigned_abs() {
+            node = tree.get_or_create_neighbor(node, along_y);
+        }
+        let corner = vec2(tree[node].corner.0 as f32, tree[node].corner.1 as f32);
+        let p1 = a - vec2(x as f32, y as f32) + corner - vec2(0.5, 0.5);
+        let p2 = b - vec2(x as f32, y as f32) + corner - vec2(0.5, 0.5);
+        let parent = tree.get_or_create_parent(node);
+        (parent, p1, p2)
+    }
+
+    /// Fills the region enclosed by the visible strokes around the screen position `pos` with a
+    /// polygon drawn behind the ink. Returns false if `pos` is not enclosed.
+    fn fill_at(
+        &mut self,
+        ctx: &egui::Context,
+        rect: Rect,
+        pos: Pos2,
+        color: Color32,
+        strokes: &[(StrokeEntry, Rect)],
+    ) -> bool {
+        let shapes = raster::capture_shapes(ctx, rect, |painter| {
+            for (stroke, screen_rect) in strokes {
+                let to_screen = emath::RectTransform::from_to(STANDARD_COORD_BOUNDS, *screen_rect);
+                stroke.drawable.draw(painter, to_screen);
+            }
+        });
+        let mut ink = Mask::new(rect, FILL_CELL_SIZE);
+        ink.cover_shapes(ctx, shapes);
+        // Thin strokes can slip between cell centers, so close those gaps before flooding.
+        ink.dilate();
+        let Some(mut region) = ink
+            .cell_at(pos)
+            .and_then(|start| ink.enclosed_region(start))
+        else {
+            return false;
+        };
+        // Grow back under the ink so no gap shows between the fill and its outline.
+        region.dilate();
+        let outline = geometry::simplify_polygon(&region.outline(), FILL_CELL_SIZE);
+        if outline.len() < 3 {
+            return false;
+        }
+        let Some(shape) = self.locate_shape(rect, &outline, 0.0) else {
+            return false;
+        };
+        let meta = self.stroke_meta(StrokePriority::Underlay);
+        self.send_shape(shape, meta, |points, _| {
+            Box::new(FilledPolygon::new(points, color))
+        });
+        self.next_stroke_order += ordering::ORDER_SPACING;
+        true
+    }
+
+    /// Maps screen points into the tree, with their bounding box grown by `margin` screen points
+    /// deciding which node they go in.
+    fn locate_shape(&mut self, rect: Rect, points: &[Pos2], margin: f32) -> Option<LocatedShape> {
+        let bounds = Rect::from_points(points).expand(margin);
+        let (node, p1, p2) = self.locate(rect, bounds.min, bounds.max)?;
+        let to_parent = Vec2::splat(1.0) / (self.zoom * rect.size());
+        Some(LocatedShape {
+            node,
+            p1,
+            p2,
+            points: points
+                .iter()
+                .map(|point| p1 + (*point - bounds.min) * to_parent)
+                .collect(),
+            point_size: 1.0 / (self.zoom * rect.size().max_elem()),
+        })
+    }
 
-fn structure_serializer<S>(
-    structure: &CircularBuffer2D<Rc<RefCell<DrawNode>>, 5>,
-    serializer: S,
-) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    let Some(center_cell) = structure.get(0, 0) else {
-        panic!("No center cell to serialize from");
-    };
-    let (top_level, path) = DrawNode::get_top_level_and_path(vec![], center_cell.clone());
-    CircularBufferSerialization {
-        center_path: path,
-        top_level_parent: DrawNodeRef(top_level),
+    /// Inserts a drawable built from `shape`, once the node it fits in has been found. `build`
+    /// receives the points and the length of a screen point in that node's coordinates.
+    fn send_shape(
+        &mut self,
+        shape: LocatedShape,
+        meta: StrokeMeta,
+        build: impl FnOnce(&[Pos2], f32) -> Box<dyn CanvasDrawable>,
+    ) {
+        let LocatedShape {
+            node,
+            p1,
+            p2,
+            points,
+            point_size,
+        } = shape;
+        self.draw_boxes
+            .tree
+            .send_drawable(p1, p2, 1.0, node, |new_p1, _, scale| {
+                let points = points
+                    .iter()
+                    .map(|point| new_p1 + (*point - p1) * scale)
+                    .collect_vec();
+                meta.entry(build(&points, point_size * scale))
+            });
     }
-    .serialize(serializer)
-}
 
-fn structure_deserializer<'de, D>(
-    deserializer: D,
-) -> Result<CircularBuffer2D<Rc<RefCell<DrawNode>>, 5>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let serialization = CircularBufferSerialization::deserialize(deserializer)?;
-    let mut draw_boxes = CircularBuffer2D::<Rc<RefCell<DrawNode>>, 5>::default();
-    let mut center_path = serialization.center_path;
-    let center = serialization
-        .top_level_parent
-        .0
-        .borrow()
-        .follow_path(&mut center_path, serialization.top_level_parent.0.clone());
-    unsafe {
-        let ptr = Rc::into_raw(serialization.top_level_parent.0.clone());
-        Rc::increment_strong_count(ptr);
-        Rc::from_raw(ptr);
+    /// The outermost node, with every paged out subtree loaded again. None while some are
+    /// still being fetched, after telling the user to try again once they arrived.
+    fn top_level(&mut self) -> Option<NodeId> {
+        let top_level = self.outermost();
+        if !self.draw_boxes.tree.page_in_all(top_level) {
+            self.action_error = Some(
+                "Part of the canvas is still being read from storage. Try again in a moment."
+                    .to_string(),
+            );
+            return None;
+        }
+        Some(top_level)
     }
-    draw_boxes.set(0, 0, center);
-    draw_boxes.load_all();
-    Ok(draw_boxes)
-}
 
-impl Default for Painting {
-    fn default() -> Self {
-        let mut draw_boxes = CircularBuffer2D::<Rc<RefCell<DrawNode>>, 5>::default();
-        draw_boxes.set(0, 0, DrawNode::top_level());
-        draw_boxes.load_all();
-        Self {
-            draw_boxes,
-            last_cursor_pos: None,
-            zoom: 1.0,
-            pan: vec2(0.0, 0.0),
-            stroke: Stroke::new(1.0, Color32::from_rgb(25, 200, 100)),
-            next_stroke_order: 0,
-            debug_render: false,
+    /// The outermost node, leaving paged out subtrees as they are.
+    fn outermost(&self) -> NodeId {
+        self.draw_boxes.tree.root()
+    }
+
+    /// Extends the paths of remembered locations to the current outermost node, after the tree
+    /// grew outward.
+    fn rebase_locations(&mut self) {
+        self.sessions.rebase(&self.draw_boxes.tree);
+        self.world.rebase(&self.draw_boxes.tree);
+        if let Some(diff) = &mut self.diff {
+            diff.rebase(&self.draw_boxes.tree);
         }
+        self.conflicts.rebase(&self.draw_boxes.tree);
     }
-}
 
-const STANDARD_COORD_BOUNDS: Rect = Rect::from_min_max(pos2(-1.0, -1.0), pos2(1.0, 1.0));
+    /// Records the changes made to objects since last noted in their history, as `change` for
+    /// those changed rather than created or deleted.
+    fn note_changes(&mut self, change: ObjectChange) {
+        let count = self.op_log.count();
+        let ops = self
+            .history_noted
+            .and_then(|noted| self.op_log.ops_in(noted..count))
+            .unwrap_or_default();
+        self.history_noted = Some(count);
+        self.history.follow(&ops, change, None);
+    }
 
-impl Painting {
-    pub fn ui_control(&mut self, ui: &mut egui::Ui) -> egui::Response {
-        ui.horizontal(|ui| {
-            ui.label("Stroke:");
-            ui.add(&mut self.stroke);
-            ui.separator();
-            if ui.button("Clear Painting").clicked() {
-                *self = Self::default();
-            }
-            ui.checkbox(&mut self.debug_render, "Debug render");
-            if ui.button("Export").clicked() {
-                let mut out = Vec::new();
-                let mut serializer = ron::ser::Serializer::with_options(
-                    &mut out,
-                    None,
-                    ron::Options::default().without_recursion_limit(),
-                )
-                .unwrap();
-                let serializer = serde_stacker::Serializer::new(&mut serializer);
-                let export = match self.serialize(serializer) {
-                    Ok(_) => String::from_utf8(out).expect("Ron should be utf-8"),
-                    Err(err) => panic!("eframe failed to encode data using ron: {}", err),
-                };
-                ui.output_mut(|output| output.copied_text = export);
-            }
-            if ui.button("Import").clicked() {
-                let clipboard = get_clipboard();
-                let mut deserializer = ron::de::Deserializer::from_str_with_options(
-                    &clipboard,
-                    ron::Options::default().without_recursion_limit(),
-                )
-                .unwrap();
-                let deserializer = serde_stacker::Deserializer::new(&mut deserializer);
-                println!("Trying import");
-                match Painting::deserialize(deserializer) {
-                    Ok(value) => {
-                        println!("Successful import");
-                        *self = value;
-                    }
-                    Err(err) => {
-                        // This happens on when we break the format, e.g. when updating egui.
-                        log::debug!("Failed to decode RON: {err}");
-                        eprintln!("Failed to decode RON: {err}");
-                    }
-                };
-            }
-        })
-        .response
+    /// Notes an edit at the current view, for the session log and the unsaved changes marker.
+    /// What it changed is found in the operation log.
+    fn record_edit(&mut self) {
+        let count = self.op_log.count();
+        let ops = self
+            .edits_noted
+            .and_then(|noted| self.op_log.ops_in(noted..count))
+            .unwrap_or_default();
+        self.edits_noted = Some(count);
+        let to_view = emath::RectTransform::from_to(
+            STANDARD_COORD_BOUNDS,
+            Rect::from_center_size(Pos2::ZERO, Vec2::splat(1.0)),
+        );
+        let touched = oplog::touched(&ops)
+            .into_iter()
+            .map(|(path, bounds)| {
+                let bounds = bounds.map_or(*to_view.to(), |bounds| to_view.transform_rect(bounds));
+                (path, bounds)
+            })
+            .collect();
+        let location = self.current_location();
+        self.sessions
+            .record_edit(&self.draw_boxes.tree, location, touched);
+        self.modified = true;
     }
 
-    pub fn ui_content(&mut self, ui: &mut Ui) -> egui::Response {
-        let (mut response, painter) =
-            ui.allocate_painter(ui.available_size_before_wrap(), Sense::click_and_drag());
+    /// Opens a window viewing the painting from where the main view is.
+    pub fn open_view(&mut self) {
+        self.views.push(SecondaryView::new(self.current_location()));
+    }
 
-        let drag_input = response.dragged_by(egui::PointerButton::Middle)
-            || response.drag_started_by(egui::PointerButton::Middle);
-        let mut did_drag = false;
-        let mut thickness_multipler = 1.0;
-        let touch_force = ui.input(|input| {
-            input
-                .events
-                .iter()
-                .filter_map(|evt| {
-                    if let egui::Event::Touch { force, .. } = evt {
-                        *force
+    /// Shows the windows viewing the painting, titled after `name`.
+    pub fn show_views(&mut self, ctx: &egui::Context, name: &str) {
+        let tree = &mut self.draw_boxes.tree;
+        let (layers, quality) = (&self.layers, self.quality);
+        self.views.retain_mut(|view| {
+            let title = format!("{name} — View");
+            let mut open = true;
+            ctx.show_viewport_immediate(
+                view.viewport_id(),
+                egui::ViewportBuilder::default()
+                    .with_title(&title)
+                    .with_inner_size([400.0, 300.0]),
+                |ctx, class| {
+                    if class == egui::ViewportClass::Embedded {
+                        // Without support for more windows, the view is shown inside the main
+                        // one.
+                        egui::Window::new(&title)
+                            .id(egui::Id::new(view.viewport_id()))
+                            .open(&mut open)
+                            .default_size([400.0, 300.0])
+                            .show(ctx, |ui| view.ui(ui, tree, layers, quality));
                     } else {
-                        None
+                        egui::CentralPanel::default()
+                            .show(ctx, |ui| view.ui(ui, tree, layers, quality));
+                        open = !ctx.input(|input| input.viewport().close_requested());
                     }
-                })
-                .next()
-        });
-        if let Some(multi_touch) = response.ctx.multi_touch() {
-            self.pan -= multi_touch.translation_delta / response.rect.size();
-            self.zoom *= multi_touch.zoom_delta;
-            did_drag = true;
-        } else if touch_force.unwrap_or(0.0) > 0.0 {
-            thickness_multipler += touch_force.unwrap_or(0.0);
-        } else if let Some(pointer) = ui.ctx().input(|i| i.pointer.hover_pos()) {
-            let from_screen = emath::RectTransform::from_to(
-                response
-                    .rect
-                    .scale_from_center(5.0 * self.zoom)
-                    .translate(self.zoom * -self.pan * response.rect.size()),
-                5.0 / 2.0 * STANDARD_COORD_BOUNDS,
+                },
             );
-            let transformed_pointer_pos = from_screen * pointer;
-            let zoom_delta = ui.ctx().input(|i| i.zoom_delta());
-            if zoom_delta != 1.0 {
-                self.pan += (zoom_delta - 1.0) * (transformed_pointer_pos - self.pan).to_vec2();
-                self.zoom *= zoom_delta;
-                did_drag = true;
+            open
+        });
+    }
+
+    pub fn current_location(&self) -> ViewLocation {
+        ViewLocation::new(
+            &self.draw_boxes.tree,
+            *self.draw_boxes.get(0, 0).unwrap(),
+            self.pan,
+            self.zoom,
+        )
+    }
+
+    pub fn jump_to(&mut self, location: &ViewLocation) {
+        self.jump_to_node(location.path());
+        self.pan = location.pan;
+        self.zoom = location.zoom;
+    }
+
+    /// Centers the view on the node at `path`, as taken by `get_or_create_path`.
+    fn jump_to_node(&mut self, mut path: Vec<(u8, u8)>) {
+        let Some(top_level) = self.top_level() else {
+            return;
+        };
+        self.draw_boxes.clear_all();
+        let center = self
+            .draw_boxes
+            .tree
+            .get_or_create_path(&mut path, top_level);
+        self.draw_boxes.set(0, 0, center);
+        self.draw_boxes.load_all();
+        self.pan = Vec2::ZERO;
+        self.zoom = 1.0;
+        self.last_cursor_pos = None;
+    }
+
+    /// Reports nodes that failed the integrity check on load, offering to show each in turn.
+    fn integrity_warning(&mut self, ctx: &egui::Context) {
+        if self.damaged_nodes.is_empty() {
+            return;
+        }
+        let mut show = false;
+        let mut dismiss = false;
+        egui::Area::new(egui::Id::new("integrity_warning"))
+            .anchor(egui::Align2::LEFT_BOTTOM, vec2(16.0, -16.0))
+            .show(ctx, |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.colored_label(
+                            ui.visuals().warn_fg_color,
+                            format!(
+                                "⚠ {} parts of this painting do not match their saved hash and \
+                                 may be damaged",
+                                self.damaged_nodes.len()
+                            ),
+                        );
+                        show = ui.button("Show").clicked();
+                        dismiss = ui.small_button("✕").clicked();
+                    });
+                });
+            });
+        if show {
+            let path = self.damaged_nodes.remove(0);
+            self.jump_to_node(path);
+        } else if dismiss {
+            self.damaged_nodes.clear();
+        }
+    }
+
+    /// Imports or compares with the text read from the clipboard, once it has arrived.
+    fn handle_clipboard_read(&mut self) {
+        let Some((usage, text)) = self
+            .clipboard_read
+            .as_ref()
+            .and_then(|(usage, read)| Some((*usage, read.take()?)))
+        else {
+            return;
+        };
+        self.clipboard_read = None;
+        match usage {
+            ClipboardUse::Import => match Painting::import_from_str(&text) {
+                Ok(value) => self.replace(value, "Imported painting"),
+                Err(err) => {
+                    // This happens on when we break the format, e.g. when updating egui.
+                    log::warn!("Failed to import painting: {err}");
+                    self.import_error = Some(err);
+                }
+            },
+            ClipboardUse::Merge => match Painting::import_from_str(&text) {
+                Ok(other) => {
+                    self.merge(other);
+                }
+                Err(err) => {
+                    log::warn!("Failed to merge painting: {err}");
+                    self.import_error = Some(err);
+                }
+            },
+            ClipboardUse::Diff => match Painting::import_from_str(&text) {
+                Ok(other) => self.show_diff(other),
+                Err(err) => {
+                    log::warn!("Failed to compare painting: {err}");
+                    self.import_error = Some(err);
+                }
+            },
+            ClipboardUse::Compare => {
+                self.comparison = Some(match Self::from_ron(&text) {
+                    Ok(mut other) => {
+                        let (Some(top_level), Some(other_top_level)) =
+                            (self.top_level(), other.top_level())
+                        else {
+                            return;
+                        };
+                        let (tree, other_tree) = (&self.draw_boxes.tree, &other.draw_boxes.tree);
+                        tree.update_hashes(top_level);
+                        other_tree.update_hashes(other_top_level);
+                        let differing =
+                            tree.differing_nodes(top_level, other_tree, other_top_level);
+                        format!("{} nodes differ", differing.len())
+                    }
+                    Err(err) => format!("Clipboard does not hold a painting: {err}"),
+                });
             }
         }
-        if response.dragged() && drag_input {
-            self.pan -= response.drag_delta() / self.zoom / response.rect.size();
-            did_drag = true;
+    }
+
+    /// Explains why the last import failed. The painting is left as it was.
+    fn import_error_window(&mut self, ctx: &egui::Context) {
+        let Some(err) = &self.import_error else {
+            return;
+        };
+        let mut open = true;
+        let mut close = false;
+        egui::Window::new("Import failed")
+            .open(&mut open)
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.label("The clipboard does not hold a painting this version can read.");
+                ui.label("Your current painting has not been changed.");
+                ui.collapsing("Details", |ui| {
+                    ui.label(egui::RichText::new(err).monospace());
+                });
+                close = ui.button("OK").clicked();
+            });
+        if !open || close {
+            self.import_error = None;
         }
-        let pan_delta = ui.ctx().input(|i| i.smooth_scroll_delta);
-        self.pan -= pan_delta / self.zoom / response.rect.size();
-        self.handle_pan_zoom();
+    }
 
-        let mut draw_stroke = self.stroke;
-        draw_stroke.width *= thickness_multipler;
+    /// Explains why the last action could not be done.
+    fn action_error_window(&mut self, ctx: &egui::Context) {
+        let Some(err) = &self.action_error else {
+            return;
+        };
+        let mut open = true;
+        let mut close = false;
+        egui::Window::new("Action failed")
+            .open(&mut open)
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.label(err);
+                close = ui.button("OK").clicked();
+            });
+        if !open || close {
+            self.action_error = None;
+        }
+    }
 
-        'input_handler: {
-            if let Some(pointer_pos) = response.interact_pointer_pos() {
-                if (response.drag_started_by(egui::PointerButton::Primary)
-                    || response.dragged_by(egui::PointerButton::Primary))
-                    && !did_drag
+    fn sessions_window(&mut self, ctx: &egui::Context) {
+        let mut jump = None;
+        egui::Window::new("Sessions")
+            .open(&mut self.show_sessions)
+            .show(ctx, |ui| {
+                if ui
+                    .add_enabled(
+                        self.sessions.last_edit().is_some(),
+                        egui::Button::new("Resume where I left off"),
+                    )
+                    .clicked()
                 {
-                    let canvas_pos = pointer_pos;
-                    let Some(last_cursor_pos) = self.last_cursor_pos else {
-                        self.last_cursor_pos = Some(canvas_pos);
-                        break 'input_handler;
-                    };
-                    if last_cursor_pos != canvas_pos {
-                        let from_screen = emath::RectTransform::from_to(
-                            response
-                                .rect
-                                .scale_from_center(5.0 * self.zoom)
-                                .translate(self.zoom * -self.pan * response.rect.size()),
-                            5.0 / 2.0 * STANDARD_COORD_BOUNDS,
-                        );
-                        let center = from_screen * last_cursor_pos.lerp(canvas_pos, 0.5);
-                        let x = center.x.round() as i32;
-                        let y = center.y.round() as i32;
-                        let Some(node) = self.draw_boxes.get(x, y) else {
-                            break 'input_handler;
-                        };
-                        let p1 = 2.0 * (from_screen * last_cursor_pos - vec2(x as f32, y as f32));
-                        let p2 = 2.0 * (from_screen * canvas_pos - vec2(x as f32, y as f32));
-                        let p1 = p1 / 2.0
-                            + vec2(node.borrow().corner.0 as f32, node.borrow().corner.1 as f32)
-                            - vec2(0.5, 0.5);
-                        let p2 = p2 / 2.0
-                            + vec2(node.borrow().corner.0 as f32, node.borrow().corner.1 as f32)
-                            - vec2(0.5, 0.5);
-                        let parent = node.borrow_mut().get_or_create_parent(node.clone());
-                        parent.borrow_mut().send_stroke::<Line>(
-                            p1,
-                            p2,
-                            0.005 / self.zoom,
-                            &draw_stroke,
-                            self.next_stroke_order,
-                            node.clone(),
-                        );
-                        self.next_stroke_order += 1;
-                        self.last_cursor_pos = Some(canvas_pos);
-                        response.mark_changed();
-                    }
-                } else {
-                    self.last_cursor_pos = None
+                    jump = self.sessions.last_edit().cloned();
                 }
-            } else {
-                self.last_cursor_pos = None
+                ui.separator();
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    egui::Grid::new("sessions").striped(true).show(ui, |ui| {
+                        ui.strong("Started");
+                        ui.strong("Duration");
+                        ui.strong("Region");
+                        ui.strong("Strokes");
+                        ui.end_row();
+                        for session in self.sessions.sessions().iter().rev() {
+                            ui.label(session.start_label());
+                            ui.label(session.duration_label());
+                            let region = session.region();
+                            if ui
+                                .add_enabled(region.is_some(), egui::Button::new("Show"))
+                                .on_hover_text("Fit the view to what the session changed")
+                                .clicked()
+                            {
+                                jump = region;
+                            }
+                            ui.label(session.stroke_count.to_string());
+                            if ui.button("Start").clicked() {
+                                jump = Some(session.first_edit.clone());
+                            }
+                            if ui.button("End").clicked() {
+                                jump = Some(session.last_edit.clone());
+                            }
+                            ui.end_row();
+                        }
+                    });
+                });
+            });
+        if let Some(location) = jump {
+            self.jump_to(&location);
+        }
+    }
+
+    fn handle_note_tool(&mut self, response: &egui::Response, did_drag: bool) {
+        if did_drag {
+            self.note_drag = None;
+            return;
+        }
+        if response.drag_started_by(egui::PointerButton::Primary) {
+            if let Some(pos) = response.interact_pointer_pos() {
+                self.note_drag = Some((pos, pos));
             }
         }
+        let Some((start, end)) = self.note_drag.as_mut() else {
+            return;
+        };
+        if let Some(pos) = response.interact_pointer_pos() {
+            *end = pos;
+        }
+        if !response.drag_stopped() {
+            return;
+        }
+        let (start, end) = (*start, *end);
+        self.note_drag = None;
+        if (start - end).length() < 4.0 {
+            return;
+        }
+        if let Some((node, p1, p2)) = self.locate(response.rect, start, end) {
+            self.pending_note = Some(PendingNote {
+                node,
+                p1,
+                p2,
+                text: String::new(),
+            });
+        }
+    }
 
-        if self.debug_render {
-            for (x, y, node) in self.draw_boxes.cells() {
-                let offset = vec2(x as f32, y as f32);
-                let to_screen = emath::RectTransform::from_to(
-                    STANDARD_COORD_BOUNDS,
-                    response
-                        .rect
-                        .scale_from_center(self.zoom)
-                        .translate(self.zoom * (offset - self.pan) * response.rect.size()),
-                );
-                node.borrow().draw_grid(&painter, to_screen);
+    /// Tracks the rectangle dragged after choosing to export a dragged area, and exports what it
+    /// covers with the export settings when released. Escape cancels.
+    fn handle_export_area(&mut self, ui: &Ui, response: &egui::Response, did_drag: bool) {
+        if response.hovered() {
+            ui.ctx().set_cursor_icon(egui::CursorIcon::Crosshair);
+        }
+        if ui.input(|input| input.key_pressed(egui::Key::Escape)) {
+            self.export_drag = None;
+            self.picking_export_area = false;
+            return;
+        }
+        if did_drag {
+            self.export_drag = None;
+            return;
+        }
+        if response.drag_started_by(egui::PointerButton::Primary) {
+            if let Some(pos) = response.interact_pointer_pos() {
+                self.export_drag = Some((pos, pos));
             }
         }
-        let mut strokes = vec![];
-        for (x, y, node) in self.draw_boxes.cells() {
-            let offset = vec2(x as f32, y as f32);
-            strokes.extend(
-                node.borrow().get_strokes(
-                    response
-                        .rect
-                        .scale_from_center(self.zoom)
-                        .translate(self.zoom * (offset - self.pan) * response.rect.size()),
-                    14,
-                ),
-            );
+        let Some((start, end)) = self.export_drag.as_mut() else {
+            return;
+        };
+        if let Some(pos) = response.interact_pointer_pos() {
+            *end = pos;
         }
-        let mut parents_to_draw = self
-            .draw_boxes
-            .cells()
-            .into_iter()
-            .map(|(x, y, node)| {
-                let offset = vec2(x as f32, y as f32);
-                (
-                    node.clone(),
-                    response
-                        .rect
-                        .scale_from_center(self.zoom)
-                        .translate(self.zoom * (offset - self.pan) * response.rect.size()),
-                )
-            })
-            .collect_vec();
-        for _layer_above in 0..14 {
-            let next_result = parents_to_draw
-                .drain(..)
-                .flat_map(|(node, to_screen)| {
-                    node.borrow()
-                        .parent
-                        .upgrade()
-                        .map(|parent| (parent, node.borrow().get_parent_rect(to_screen)))
+        if !response.drag_stopped() {
+            return;
+        }
+        let region = Rect::from_two_pos(*start, *end).intersect(response.rect);
+        self.export_drag = None;
+        self.picking_export_area = false;
+        if region.area() < 16.0 {
+            return;
+        }
+        if let Err(err) = self.export_image(ui.ctx(), region) {
+            log::error!("Failed to export an image: {err}");
+        }
+    }
+
+    /// Tracks the rectangle dragged with the erase region tool and deletes the strokes touching
+    /// it when released. Returns true if any were deleted.
+    fn handle_erase_region_tool(&mut self, response: &egui::Response, did_drag: bool) -> bool {
+        if did_drag {
+            self.erase_drag = None;
+            return false;
+        }
+        if response.drag_started_by(egui::PointerButton::Primary) {
+            if let Some(pos) = response.interact_pointer_pos() {
+                self.erase_drag = Some((pos, pos));
+            }
+        }
+        let Some((start, end)) = self.erase_drag.as_mut() else {
+            return false;
+        };
+        if let Some(pos) = response.interact_pointer_pos() {
+            *end = pos;
+        }
+        if !response.drag_stopped() {
+            return false;
+        }
+        let region = Rect::from_two_pos(*start, *end);
+        self.erase_drag = None;
+        if region.area() < 16.0 {
+            return false;
+        }
+        self.delete_region(response.rect, region)
+    }
+
+    /// Deletes the strokes on visible, unlocked layers whose bounds touch the screen rect
+    /// `region`, dropping any nodes this empties. Returns true if any were deleted.
+    fn delete_region(&mut self, rect: Rect, region: Rect) -> bool {
+        let snapshot = self.take_snapshot("Erased region");
+        let layers = self.layers.clone();
+        let keep = |stroke: &StrokeEntry| {
+            !layers.is_visible(stroke.layer) || layers.is_locked(stroke.layer)
+        };
+        let deleted = self.delete_in_view(rect, region, &keep);
+        if deleted.is_empty() {
+            return false;
+        }
+        self.versions.touch(deleted);
+        self.selection.clear();
+        self.snapshot = snapshot;
+        self.record_edit();
+        true
+    }
+
+    /// Deletes the strokes in view whose bounds touch the screen rect `region`, unless `keep`
+    /// accepts them, dropping any nodes this empties. Returns the orders of those deleted.
+    fn delete_in_view(
+        &mut self,
+        rect: Rect,
+        region: Rect,
+        keep: &impl Fn(&StrokeEntry) -> bool,
+    ) -> BTreeSet<u64> {
+        let (cells, ancestors) = self.visible_nodes(rect);
+        let tree = &mut self.draw_boxes.tree;
+        let mut deleted = BTreeSet::new();
+        for (node, screen_rect) in cells {
+            let region = emath::RectTransform::from_to(screen_rect, STANDARD_COORD_BOUNDS)
+                .transform_rect(region);
+            deleted.extend(tree.delete_strokes_in(node, region, keep));
+            tree.ancestors_changed(node);
+        }
+        // Ancestors only lose their own strokes, since their other children are not in view.
+        for (node, screen_rect) in ancestors {
+            deleted.extend(
+                tree.take_strokes(node, &|stroke| {
+                    stroke_screen_bounds(stroke, screen_rect).intersects(region) && !keep(stroke)
                 })
-                .collect_vec();
-            for next_element in next_result {
-                if !parents_to_draw
-                    .iter()
-                    .any(|e| Rc::ptr_eq(&e.0, &next_element.0))
-                {
-                    strokes.extend(next_element.0.borrow().get_own_strokes(next_element.1));
-                    parents_to_draw.push(next_element);
-                }
+                .into_iter()
+                .map(|stroke| stroke.order),
+            );
+            tree.ancestors_changed(node);
+        }
+        deleted
+    }
+
+    fn pending_note_window(&mut self, ctx: &egui::Context, response: &mut egui::Response) {
+        let Some(pending) = self.pending_note.as_mut() else {
+            return;
+        };
+        let mut open = true;
+        let mut place = false;
+        egui::Window::new("Sticky note")
+            .open(&mut open)
+            .collapsible(false)
+            .show(ctx, |ui| {
+                ui.text_edit_multiline(&mut pending.text).request_focus();
+                place = ui.button("Place note").clicked();
+            });
+        if place {
+            let PendingNote { node, p1, p2, text } = self.pending_note.take().unwrap();
+            let color = self.note_color;
+            let meta = self.stroke_meta(StrokePriority::Ink);
+            self.draw_boxes
+                .tree
+                .send_drawable(p1, p2, 1.0, node, |p1, p2, _| {
+                    meta.entry(Box::new(StickyNote::new(p1, p2, color, text)))
+                });
+            self.next_stroke_order += ordering::ORDER_SPACING;
+            self.palette.use_color(color);
+            self.record_edit();
+            response.mark_changed();
+        } else if !open {
+            self.pending_note = None;
+        }
+    }
+
+    fn handle_path_text_tool(&mut self, response: &egui::Response, did_drag: bool) {
+        if did_drag {
+            self.path_drag.clear();
+            return;
+        }
+        if response.drag_started_by(egui::PointerButton::Primary) {
+            self.path_drag.clear();
+            self.pending_path_text = None;
+        }
+        if !response.dragged_by(egui::PointerButton::Primary) && !response.drag_stopped() {
+            return;
+        }
+        if let Some(pos) = response.interact_pointer_pos() {
+            if !matches!(self.path_drag.last(), Some(last) if (*last - pos).length() < 2.0) {
+                self.path_drag.push(pos);
             }
         }
-        strokes.sort_by_key(|(_, order, _)| *order);
-        for (stroke, _, screen_rect) in strokes {
-            let to_screen = emath::RectTransform::from_to(STANDARD_COORD_BOUNDS, screen_rect);
-            stroke.draw(&painter, to_screen);
+        if !response.drag_stopped() {
+            return;
         }
+        let points = geometry::simplify_polyline(&std::mem::take(&mut self.path_drag), 1.0);
+        if points.len() < 2 {
+            return;
+        }
+        if let Some(shape) = self.locate_shape(response.rect, &points, self.text_size) {
+            self.pending_path_text = Some(PendingPathText {
+                shape,
+                screen_points: points,
+                text: String::new(),
+            });
+        }
+    }
 
-        response
+    fn pending_path_text_window(&mut self, ctx: &egui::Context, response: &mut egui::Response) {
+        let Some(pending) = self.pending_path_text.as_mut() else {
+            return;
+        };
+        let mut open = true;
+        let mut place = false;
+        egui::Window::new("Text on path")
+            .open(&mut open)
+            .collapsible(false)
+            .show(ctx, |ui| {
+                let edit = ui.text_edit_singleline(&mut pending.text);
+                edit.request_focus();
+                let entered =
+                    edit.lost_focus() && ui.input(|input| input.key_pressed(egui::Key::Enter));
+                place = ui.button("Place text").clicked() || entered;
+            });
+        if place {
+            let PendingPathText { shape, text, .. } = self.pending_path_text.take().unwrap();
+            if text.is_empty() {
+                return;
+            }
+            let (size, color) = (self.text_size, self.text_color);
+            let meta = self.stroke_meta(StrokePriority::Ink);
+            self.send_shape(shape, meta, |points, point_size| {
+                Box::new(PathText::new(points, text, size * point_size, color))
+            });
+            self.next_stroke_order += ordering::ORDER_SPACING;
+            self.palette.use_color(color);
+            self.record_edit();
+            response.mark_changed();
+        } else if !open {
+            self.pending_path_text = None;
+        }
+    }
+
+    /// Keeps the view inside a bounded world, stopping the world from shrinking below
+    /// `MIN_WORLD_FRACTION` of the view and the view center from leaving it. Returns the
+    /// world's screen rect, or None if the canvas is unbounded.
+    fn constrain_to_world(&mut self, rect: Rect) -> Option<Rect> {
+        let node = self.world.node(&mut self.draw_boxes.tree)?;
+        let mut world = self.node_screen_rect(rect, node);
+        let fraction = (world.size() / rect.size()).max_elem();
+        if fraction < MIN_WORLD_FRACTION {
+            // Zooming about the view center leaves the pan unchanged.
+            let factor = MIN_WORLD_FRACTION / fraction;
+            self.zoom *= factor;
+            world = Rect::from_center_size(
+                rect.center() + (world.center() - rect.center()) * factor,
+                world.size() * factor,
+            );
+        }
+        let target = world.clamp(rect.center());
+        self.pan += (target - rect.center()) / self.zoom / rect.size();
+        world = world.translate(rect.center() - target);
+        self.handle_pan_zoom();
+        Some(world)
+    }
+
+    /// Screen rect covered by `node`, found through the nearest ancestor it shares with the
+    /// center cell.
+    fn node_screen_rect(&self, rect: Rect, node: NodeId) -> Rect {
+        let tree = &self.draw_boxes.tree;
+        let center = *self.draw_boxes.get(0, 0).unwrap();
+        let center_path = tree.get_top_level_and_path(center).1;
+        let node_path = tree.get_top_level_and_path(node).1;
+        // Paths list the deepest corner first, so shared ancestors are at the end.
+        let shared = center_path
+            .iter()
+            .rev()
+            .zip(node_path.iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let mut screen_rect = rect
+            .scale_from_center(self.zoom)
+            .translate(-self.zoom * self.pan * rect.size());
+        let mut current = center;
+        for _ in shared..center_path.len() {
+            screen_rect = tree[current].get_parent_rect(screen_rect);
+            current = tree[current].parent.unwrap();
+        }
+        for (x, y) in node_path[..node_path.len() - shared].iter().rev() {
+            screen_rect = DrawNode::child_rect(screen_rect, *x as usize, *y as usize);
+        }
+        screen_rect
+    }
+
+    /// Dims the canvas outside a bounded world and outlines its edges.
+    fn paint_world_edges(painter: &egui::Painter, rect: Rect, world: Rect) {
+        let outside = Color32::from_black_alpha(40);
+        let above = Rect::from_min_max(rect.min, pos2(rect.max.x, world.min.y));
+        let below = Rect::from_min_max(pos2(rect.min.x, world.max.y), rect.max);
+        let left = Rect::from_min_max(
+            pos2(rect.min.x, world.min.y),
+            pos2(world.min.x, world.max.y),
+        );
+        let right = Rect::from_min_max(
+            pos2(world.max.x, world.min.y),
+            pos2(rect.max.x, world.max.y),
+        );
+        for side in [above, below, left, right] {
+            let side = side.intersect(rect);
+            if side.is_positive() {
+                painter.rect_filled(side, 0.0, outside);
+            }
+        }
+        painter.rect_stroke(world, 0.0, Stroke::new(1.5, Color32::from_gray(120)));
     }
 
     fn handle_pan_zoom(&mut self) {
@@ -333,7 +5304,7 @@ impl Painting {
             changed = true;
         } else if self.zoom < 0.5 {
             self.zoom *= 2.0;
-            let center_corner = self.draw_boxes.get(0, 0).unwrap().borrow().corner;
+            let center_corner = self.draw_boxes.tree[*self.draw_boxes.get(0, 0).unwrap()].corner;
             self.pan.x += center_corner.0 as f32 - 0.5;
             self.pan.y += center_corner.1 as f32 - 0.5;
             self.pan /= 2.0;
@@ -366,15 +5337,85 @@ impl Painting {
     }
 }
 
-#[cfg(not(target_arch = "wasm32"))]
-fn get_clipboard() -> String {
-    use clipboard_rs::{Clipboard, ClipboardContext};
-    let ctx = ClipboardContext::new().unwrap();
-    ctx.get_text().unwrap_or("".to_string())
+/// Draws this copy's and the other copy's version of a conflicting object side by side, at the
+/// same scale, returning which the user chose to keep: `true` for the other copy's.
+fn conflict_previews(ui: &mut Ui, conflict: &Conflict) -> Option<bool> {
+    // Both are drawn from the innermost node holding every stroke of either.
+    let mut paths = conflict
+        .mine
+        .iter()
+        .chain(&conflict.theirs)
+        .map(|(path, _)| path);
+    let first = paths.next().cloned().unwrap_or_default();
+    let frame = paths.fold(first, |frame, path| {
+        let shared = frame
+            .iter()
+            .rev()
+            .zip(path.iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        frame[frame.len() - shared..].to_vec()
+    });
+    let node_rect = |path: &[(u8, u8)]| {
+        Some(Rect::from_two_pos(
+            relocate(path, pos2(-0.5, -0.5), &frame)?,
+            relocate(path, pos2(0.5, 0.5), &frame)?,
+        ))
+    };
+    let bounds = conflict
+        .mine
+        .iter()
+        .chain(&conflict.theirs)
+        .filter_map(|(path, stroke)| Some(stroke_screen_bounds(stroke, node_rect(path)?)))
+        .reduce(Rect::union)
+        .unwrap_or(STANDARD_COORD_BOUNDS);
+    // Squared up, so neither version is stretched.
+    let bounds = Rect::from_center_size(bounds.center(), Vec2::splat(bounds.size().max_elem()));
+
+    let mut chosen = None;
+    ui.horizontal(|ui| {
+        for (label, placed, theirs) in [
+            ("Here", &conflict.mine, false),
+            ("Other copy", &conflict.theirs, true),
+        ] {
+            ui.vertical(|ui| {
+                ui.label(label);
+                let (rect, _) = ui.allocate_exact_size(CONFLICT_PREVIEW_SIZE, Sense::hover());
+                let painter = ui.painter_at(rect);
+                painter.rect_filled(rect, 2.0, ui.visuals().panel_fill);
+                painter.rect_stroke(rect, 2.0, ui.visuals().window_stroke);
+                if placed.is_empty() {
+                    painter.text(
+                        rect.center(),
+                        egui::Align2::CENTER_CENTER,
+                        "Deleted",
+                        egui::FontId::proportional(14.0),
+                        ui.visuals().weak_text_color(),
+                    );
+                }
+                let to_preview = emath::RectTransform::from_to(bounds, rect.shrink(6.0));
+                for (path, stroke) in placed {
+                    if let Some(node) = node_rect(path) {
+                        stroke.drawable.draw(
+                            &painter,
+                            emath::RectTransform::from_to(
+                                STANDARD_COORD_BOUNDS,
+                                to_preview.transform_rect(node),
+                            ),
+                        );
+                    }
+                }
+                if ui.button("Keep this").clicked() {
+                    chosen = Some(theirs);
+                }
+            });
+        }
+    });
+    chosen
 }
 
-// When compiling to web using trunk:
-#[cfg(target_arch = "wasm32")]
-fn get_clipboard() -> String {
-    "".to_string()
+/// The area `stroke` paints on screen, given the screen rect of its node.
+fn stroke_screen_bounds(stroke: &StrokeEntry, screen_rect: Rect) -> Rect {
+    emath::RectTransform::from_to(STANDARD_COORD_BOUNDS, screen_rect)
+        .transform_rect(stroke.drawable.bounds())
 }
@@ -0,0 +1,89 @@
+use egui::{vec2, Color32, Sense, Stroke, Ui};
+use serde::{Deserialize, Serialize};
+
+/// How many recently used colors are remembered.
+const MAX_RECENT: usize = 8;
+const SWATCH_SIZE: f32 = 16.0;
+
+/// Recently used colors followed by colors the user pinned.
+#[derive(Deserialize, Serialize, Default, Clone)]
+#[serde(default)]
+pub struct Palette {
+    recent: Vec<Color32>,
+    pinned: Vec<Color32>,
+}
+
+impl Palette {
+    /// Moves `color` to the front of the recent colors.
+    pub fn use_color(&mut self, color: Color32) {
+        self.recent.retain(|recent| *recent != color);
+        self.recent.insert(0, color);
+        self.recent.truncate(MAX_RECENT);
+    }
+
+    pub fn toggle_pin(&mut self, color: Color32) {
+        if self.pinned.contains(&color) {
+            self.pinned.retain(|pinned| *pinned != color);
+        } else {
+            self.pinned.push(color);
+        }
+    }
+
+    /// Shows the swatches, returning the color the user clicked. Right-clicking a swatch pins or
+    /// unpins it.
+    pub fn ui(&mut self, ui: &mut Ui, current: Color32) -> Option<Color32> {
+        let mut selected = None;
+        let mut toggled = None;
+        let recent = self
+            .recent
+            .iter()
+            .filter(|color| !self.pinned.contains(color));
+        for (color, pinned) in self
+            .pinned
+            .iter()
+            .map(|color| (*color, true))
+            .chain(recent.map(|color| (*color, false)))
+        {
+            let response = swatch(ui, color, color == current, pinned).on_hover_text(if pinned {
+                "Right-click to unpin"
+            } else {
+                "Right-click to pin"
+            });
+            if response.clicked() {
+                selected = Some(color);
+            }
+            if response.secondary_clicked() {
+                toggled = Some(color);
+            }
+        }
+        if let Some(color) = toggled {
+            self.toggle_pin(color);
+        }
+        selected
+    }
+}
+
+fn swatch(ui: &mut Ui, color: Color32, selected: bool, pinned: bool) -> egui::Response {
+    let (rect, response) = ui.allocate_exact_size(vec2(SWATCH_SIZE, SWATCH_SIZE), Sense::click());
+    if ui.is_rect_visible(rect) {
+        let visuals = ui.style().interact_selectable(&response, selected);
+        let painter = ui.painter();
+        painter.rect_filled(rect, 2.0, color);
+        painter.rect_stroke(rect, 2.0, visuals.fg_stroke);
+        if pinned {
+            painter.circle_filled(
+                rect.right_top() + vec2(-3.0, 3.0),
+                2.0,
+                visuals.fg_stroke.color,
+            );
+        }
+        if selected {
+            painter.rect_stroke(
+                rect.expand(1.5),
+                3.0,
+                Stroke::new(1.5, visuals.fg_stroke.color),
+            );
+        }
+    }
+    response
+}
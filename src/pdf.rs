@@ -0,0 +1,220 @@
+use std::collections::BTreeMap;
+
+#[cfg(not(target_arch = "wasm32"))]
+use egui::{pos2, ColorImage};
+use egui::{Color32, Pos2, Vec2};
+use pdf_writer::{
+    types::{LineCapStyle, LineJoinStyle},
+    Content, Finish, Name, Pdf, Rect, Ref, Str,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use pdfium_render::prelude::{PdfRenderConfig, Pdfium};
+
+use crate::structure::VectorShape;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{
+    clipboard::{CopiedStroke, CopiedStrokes},
+    drawables::RasterImage,
+    geometry::Affine2,
+    structure::StrokePriority,
+};
+
+const CATALOG: Ref = Ref::new(1);
+const PAGE_TREE: Ref = Ref::new(2);
+const PAGE: Ref = Ref::new(3);
+const CONTENT: Ref = Ref::new(4);
+const FONT: Ref = Ref::new(5);
+/// Graphics states for each opacity used are numbered from here.
+const FIRST_STATE: i32 = 6;
+
+/// Pixels imported pages are rendered at per point of the page, and the longest side they are
+/// rendered at.
+#[cfg(not(target_arch = "wasm32"))]
+const PAGE_PIXELS_PER_POINT: f32 = 2.0;
+#[cfg(not(target_arch = "wasm32"))]
+const MAX_PAGE_SIDE: f32 = 4096.0;
+/// Space left between imported pages, in multiples of the first page's width.
+#[cfg(not(target_arch = "wasm32"))]
+const PAGE_GAP: f32 = 0.05;
+
+/// Writes `shapes`, bottom to top in points from the top left corner, as a one page PDF of
+/// `size` points on a `background`. Text is set in Helvetica, which only has Latin-1
+/// characters, so others are replaced with question marks.
+pub fn export(shapes: &[VectorShape], size: Vec2, background: Color32) -> Vec<u8> {
+    // PDF pages grow upwards from the bottom left corner.
+    let to_page = |point: Pos2| (point.x, size.y - point.y);
+    let mut states = BTreeMap::new();
+    let mut content = Content::new();
+    content.set_line_cap(LineCapStyle::RoundCap);
+    content.set_line_join(LineJoinStyle::RoundJoin);
+    set_color(&mut content, &mut states, background, false);
+    content.rect(0.0, 0.0, size.x, size.y);
+    content.fill_nonzero();
+    for shape in shapes {
+        match shape {
+            VectorShape::Polyline {
+                points,
+                width,
+                color,
+                closed,
+            } => {
+                if !trace(&mut content, points.iter().map(|point| to_page(*point))) {
+                    continue;
+                }
+                if *closed {
+                    content.close_path();
+                }
+                set_color(&mut content, &mut states, *color, true);
+                content.set_line_width(*width);
+                content.stroke();
+            }
+            VectorShape::Polygon { points, color } => {
+                if !trace(&mut content, points.iter().map(|point| to_page(*point))) {
+                    continue;
+                }
+                content.close_path();
+                set_color(&mut content, &mut states, *color, false);
+                content.fill_nonzero();
+            }
+            VectorShape::Text {
+                origin,
+                angle,
+                text,
+                size,
+                color,
+            } => {
+                let (x, y) = to_page(*origin);
+                let (sin, cos) = angle.sin_cos();
+                set_color(&mut content, &mut states, *color, false);
+                content.begin_text();
+                content.set_font(Name(b"F1"), *size);
+                content.set_text_matrix([cos, -sin, sin, cos, x, y]);
+                content.show(Str(&latin1(text)));
+                content.end_text();
+            }
+        }
+    }
+
+    let mut pdf = Pdf::new();
+    pdf.catalog(CATALOG).pages(PAGE_TREE);
+    pdf.pages(PAGE_TREE).kids([PAGE]).count(1);
+    let mut page = pdf.page(PAGE);
+    page.media_box(Rect::new(0.0, 0.0, size.x, size.y));
+    page.parent(PAGE_TREE);
+    page.contents(CONTENT);
+    let mut resources = page.resources();
+    resources.fonts().pair(Name(b"F1"), FONT);
+    let mut graphics_states = resources.ext_g_states();
+    for (alpha, id) in &states {
+        graphics_states.pair(Name(state_name(*alpha).as_bytes()), *id);
+    }
+    graphics_states.finish();
+    resources.finish();
+    page.finish();
+    pdf.type1_font(FONT)
+        .base_font(Name(b"Helvetica"))
+        .encoding_predefined(Name(b"WinAnsiEncoding"));
+    for (alpha, id) in states {
+        let alpha = f32::from(alpha) / 255.0;
+        pdf.ext_graphics(id)
+            .non_stroking_alpha(alpha)
+            .stroking_alpha(alpha);
+    }
+    pdf.stream(CONTENT, &content.finish());
+    pdf.finish()
+}
+
+/// Starts a path through `points`. Returns false, drawing nothing, if there are none.
+fn trace(content: &mut Content, points: impl IntoIterator<Item = (f32, f32)>) -> bool {
+    let mut points = points.into_iter();
+    let Some((x, y)) = points.next() else {
+        return false;
+    };
+    content.move_to(x, y);
+    for (x, y) in points {
+        content.line_to(x, y);
+    }
+    true
+}
+
+/// Sets the stroke or fill color, along with a graphics state for its opacity, adding one to
+/// `states` for opacities not used before.
+fn set_color(content: &mut Content, states: &mut BTreeMap<u8, Ref>, color: Color32, stroke: bool) {
+    let [r, g, b, alpha] = color.to_srgba_unmultiplied();
+    let [r, g, b] = [r, g, b].map(|channel| f32::from(channel) / 255.0);
+    if stroke {
+        content.set_stroke_rgb(r, g, b);
+    } else {
+        content.set_fill_rgb(r, g, b);
+    }
+    let next = Ref::new(FIRST_STATE + states.len() as i32);
+    states.entry(alpha).or_insert(next);
+    content.set_parameters(Name(state_name(alpha).as_bytes()));
+}
+
+fn state_name(alpha: u8) -> String {
+    format!("A{alpha}")
+}
+
+/// `text` in the Latin-1 characters WinAnsi encoding shares with it.
+fn latin1(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|char| match u32::from(char) {
+            code @ (0x20..=0x7e | 0xa0..=0xff) => code as u8,
+            _ => b'?',
+        })
+        .collect()
+}
+
+/// Renders the pages of the PDF `data` into images laid out left to right in the coordinates
+/// of nodes `depth` levels deep, with `scale` node units per point of the page, centered on the
+/// origin. They go under ink so they can be written on. Rendering uses the Pdfium library,
+/// looked for beside the app and then among the system's libraries.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn import_pages(data: &[u8], depth: u32, scale: f32) -> Result<CopiedStrokes, String> {
+    let bindings = Pdfium::bind_to_library(Pdfium::pdfium_platform_library_name_at_path("./"))
+        .or_else(|_| Pdfium::bind_to_system_library())
+        .map_err(|err| format!("Pdfium is needed to import PDFs: {err}"))?;
+    let pdfium = Pdfium::new(bindings);
+    let document = pdfium
+        .load_pdf_from_byte_slice(data, None)
+        .map_err(|err| err.to_string())?;
+    let mut pages = vec![];
+    let mut left = 0.0;
+    let mut gap = None;
+    for page in document.pages().iter() {
+        let size = Vec2::new(page.width().value, page.height().value);
+        let pixels = (size * PAGE_PIXELS_PER_POINT).min(Vec2::splat(MAX_PAGE_SIDE));
+        let bitmap = page
+            .render_with_config(
+                &PdfRenderConfig::new()
+                    .set_target_width(pixels.x as i32)
+                    .set_maximum_height(pixels.y as i32),
+            )
+            .map_err(|err| err.to_string())?;
+        let image = ColorImage::from_rgba_unmultiplied(
+            [bitmap.width() as usize, bitmap.height() as usize],
+            &bitmap.as_rgba_bytes(),
+        );
+        let rect = egui::Rect::from_min_size(pos2(left, 0.0), size) * scale;
+        let page = RasterImage::new(rect, &image).map_err(|err| err.to_string())?;
+        pages.push(CopiedStroke {
+            drawable: Box::new(page),
+            priority: StrokePriority::Underlay,
+            group: None,
+        });
+        left += size.x + *gap.get_or_insert(PAGE_GAP * size.x);
+    }
+    if pages.is_empty() {
+        return Err("The PDF has no pages".to_string());
+    }
+    let mut copied = CopiedStrokes {
+        depth,
+        strokes: pages,
+    };
+    let recenter = Affine2::from_translation(-copied.bounds().center().to_vec2());
+    for page in &mut copied.strokes {
+        page.drawable.transform(&recenter);
+    }
+    Ok(copied)
+}
@@ -0,0 +1,83 @@
+use egui::Rect;
+
+/// Number of buckets along each axis of a node.
+const GRID_SIZE: usize = 8;
+/// Nodes with fewer strokes than this are scanned directly rather than bucketed.
+const BUCKET_THRESHOLD: usize = 32;
+
+/// Bounding boxes of the strokes in one node, bucketed on a coarse grid over the node's
+/// coordinates so lookups near a point only consider strokes that can reach it.
+#[derive(Default)]
+pub struct StrokeIndex {
+    bounds: Vec<Rect>,
+    /// Union of `bounds`, or None while the node is empty.
+    union: Option<Rect>,
+    /// Stroke indices overlapping each bucket, row major. Empty until the node holds
+    /// `BUCKET_THRESHOLD` strokes.
+    buckets: Vec<Vec<u32>>,
+}
+
+impl StrokeIndex {
+    pub fn new(bounds: impl IntoIterator<Item = Rect>) -> Self {
+        let mut index = Self::default();
+        for rect in bounds {
+            index.push(rect);
+        }
+        index
+    }
+
+    /// Adds the bounds of the stroke that was just appended to the node.
+    pub fn push(&mut self, rect: Rect) {
+        let stroke = self.bounds.len() as u32;
+        self.bounds.push(rect);
+        self.union = Some(self.union.map_or(rect, |union| union.union(rect)));
+        if self.buckets.is_empty() {
+            if self.bounds.len() < BUCKET_THRESHOLD {
+                return;
+            }
+            self.buckets = vec![vec![]; GRID_SIZE * GRID_SIZE];
+            for (stroke, rect) in self.bounds.iter().enumerate() {
+                for bucket in bucket_range(*rect) {
+                    self.buckets[bucket].push(stroke as u32);
+                }
+            }
+            return;
+        }
+        for bucket in bucket_range(rect) {
+            self.buckets[bucket].push(stroke);
+        }
+    }
+
+    /// Union of the bounds of every stroke in the node.
+    pub fn union(&self) -> Option<Rect> {
+        self.union
+    }
+
+    /// Indices of strokes whose bounds intersect `rect`, in insertion order.
+    pub fn query(&self, rect: Rect) -> Vec<usize> {
+        if self.buckets.is_empty() {
+            return (0..self.bounds.len())
+                .filter(|stroke| self.bounds[*stroke].intersects(rect))
+                .collect();
+        }
+        let mut result = bucket_range(rect)
+            .flat_map(|bucket| self.buckets[bucket].iter())
+            .map(|stroke| *stroke as usize)
+            .filter(|stroke| self.bounds[*stroke].intersects(rect))
+            .collect::<Vec<_>>();
+        result.sort_unstable();
+        result.dedup();
+        result
+    }
+}
+
+/// Buckets covered by `rect`. Bounds reaching past the node's edges land in the border buckets.
+fn bucket_range(rect: Rect) -> impl Iterator<Item = usize> {
+    let to_bucket = |value: f32| {
+        (((value + 1.0) / 2.0 * GRID_SIZE as f32).floor() as isize).clamp(0, GRID_SIZE as isize - 1)
+            as usize
+    };
+    let (min_x, max_x) = (to_bucket(rect.min.x), to_bucket(rect.max.x));
+    (to_bucket(rect.min.y)..=to_bucket(rect.max.y))
+        .flat_map(move |y| (min_x..=max_x).map(move |x| y * GRID_SIZE + x))
+}
@@ -0,0 +1,27 @@
+use egui::Stroke;
+use serde::{Deserialize, Serialize};
+
+use crate::structure::LineStyle;
+
+/// Number keys `1` to `9` select the preset at the matching position.
+pub const PRESET_KEYS: [egui::Key; 9] = [
+    egui::Key::Num1,
+    egui::Key::Num2,
+    egui::Key::Num3,
+    egui::Key::Num4,
+    egui::Key::Num5,
+    egui::Key::Num6,
+    egui::Key::Num7,
+    egui::Key::Num8,
+    egui::Key::Num9,
+];
+
+/// A named set of pen settings.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct BrushPreset {
+    pub name: String,
+    pub stroke: Stroke,
+    pub line_style: LineStyle,
+    pub taper: bool,
+    pub smoothing: f32,
+}
@@ -0,0 +1,75 @@
+use serde::{Deserialize, Serialize};
+
+/// Trades drawing fidelity for frame rate on slow hardware.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum RenderQuality {
+    Low,
+    Medium,
+    #[default]
+    High,
+}
+
+impl RenderQuality {
+    pub const ALL: [RenderQuality; 3] = [
+        RenderQuality::Low,
+        RenderQuality::Medium,
+        RenderQuality::High,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RenderQuality::Low => "Low",
+            RenderQuality::Medium => "Medium",
+            RenderQuality::High => "High",
+        }
+    }
+
+    /// Screen points a stroke's bounds must span along some axis for it to be drawn. Smaller
+    /// strokes are details of content far below the current zoom level.
+    pub fn min_stroke_size(&self) -> f32 {
+        match self {
+            RenderQuality::Low => 2.0,
+            RenderQuality::Medium => 0.75,
+            RenderQuality::High => 0.0,
+        }
+    }
+
+    /// Screen points a node must span for its children to be drawn stroke by stroke. Smaller
+    /// nodes are drawn as a summary of their content.
+    pub fn lod_node_size(&self) -> f32 {
+        match self {
+            RenderQuality::Low => 16.0,
+            RenderQuality::Medium => 8.0,
+            RenderQuality::High => 3.0,
+        }
+    }
+
+    /// Screen points below which a node is drawn as a low resolution image of its content,
+    /// made once and kept until the content changes.
+    pub fn thumbnail_node_size(&self) -> f32 {
+        match self {
+            RenderQuality::Low => 64.0,
+            RenderQuality::Medium => 48.0,
+            RenderQuality::High => 32.0,
+        }
+    }
+
+    /// Screen points below which a node is tessellated once and its meshes reused while it
+    /// stays about the same size, rather than drawn stroke by stroke. Content in such nodes is
+    /// drawn beneath the strokes drawn individually.
+    pub fn cache_node_size(&self) -> f32 {
+        match self {
+            RenderQuality::Low => 256.0,
+            RenderQuality::Medium => 128.0,
+            RenderQuality::High => 0.0,
+        }
+    }
+
+    /// Whether shapes get feathered edges. Without them egui tessellates far fewer vertices.
+    pub fn anti_alias(&self) -> bool {
+        match self {
+            RenderQuality::Low => false,
+            RenderQuality::Medium | RenderQuality::High => true,
+        }
+    }
+}
@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+
+use egui::{
+    epaint::{ClippedShape, Mesh, Primitive},
+    pos2, Color32, ColorImage, Context, Id, LayerId, Order, Painter, Pos2, Rect, Rgba,
+};
+
+/// Runs `draw` against a painter on a private layer and returns what it painted instead of
+/// showing it on screen. Each thread captures to a layer of its own, so nodes rendered in
+/// parallel do not pick up each other's shapes.
+pub fn capture_shapes(
+    ctx: &Context,
+    clip_rect: Rect,
+    draw: impl FnOnce(&Painter),
+) -> Vec<ClippedShape> {
+    let layer_id = LayerId::new(
+        Order::Background,
+        Id::new(("capture_shapes", std::thread::current().id())),
+    );
+    draw(&Painter::new(ctx.clone(), layer_id, clip_rect));
+    ctx.graphics_mut(|graphics| {
+        std::mem::take(graphics.entry(layer_id))
+            .all_entries()
+            .cloned()
+            .collect()
+    })
+}
+
+/// Color of the topmost visible triangle of the tessellated `shapes` covering `point`.
+pub fn color_at(ctx: &Context, shapes: Vec<ClippedShape>, point: Pos2) -> Option<Color32> {
+    let mut color = None;
+    for primitive in ctx.tessellate(shapes, ctx.pixels_per_point()) {
+        let Primitive::Mesh(mesh) = primitive.primitive else {
+            continue;
+        };
+        for triangle in mesh.indices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| mesh.vertices[triangle[i] as usize]);
+            let area = edge(a.pos, b.pos, c.pos);
+            let weights = [
+                edge(b.pos, c.pos, point),
+                edge(c.pos, a.pos, point),
+                edge(a.pos, b.pos, point),
+            ];
+            if area == 0.0 || weights.iter().any(|w| w * area < 0.0) {
+                continue;
+            }
+            // Feathered edges fade out towards some vertices, so take the most opaque one.
+            let vertex = [a, b, c].into_iter().max_by_key(|vertex| vertex.color.a());
+            if let Some(vertex) = vertex.filter(|vertex| vertex.color.a() > 0) {
+                color = Some(vertex.color);
+            }
+        }
+    }
+    color
+}
+
+/// Draws the triangles of `meshes`, positioned in pixels, onto a transparent image of `size`.
+/// Textures are ignored, so text comes out as solid blocks.
+pub fn rasterize(meshes: &[Mesh], size: [usize; 2]) -> ColorImage {
+    let mut image = ColorImage::new(size, Color32::TRANSPARENT);
+    rasterize_onto(&mut image, meshes);
+    image
+}
+
+/// Like `rasterize`, but draws over what `image` already holds.
+pub fn rasterize_onto(image: &mut ColorImage, meshes: &[Mesh]) {
+    let [width, height] = image.size;
+    for mesh in meshes {
+        for triangle in mesh.indices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| mesh.vertices[triangle[i] as usize]);
+            let area = edge(a.pos, b.pos, c.pos);
+            if area == 0.0 {
+                continue;
+            }
+            let min_x = a.pos.x.min(b.pos.x).min(c.pos.x).floor().max(0.0) as usize;
+            let min_y = a.pos.y.min(b.pos.y).min(c.pos.y).floor().max(0.0) as usize;
+            let max_x = (a.pos.x.max(b.pos.x).max(c.pos.x).ceil().max(0.0) as usize).min(width);
+            let max_y = (a.pos.y.max(b.pos.y).max(c.pos.y).ceil().max(0.0) as usize).min(height);
+            for y in min_y..max_y {
+                for x in min_x..max_x {
+                    let p = pos2(x as f32 + 0.5, y as f32 + 0.5);
+                    let weights = [
+                        edge(b.pos, c.pos, p),
+                        edge(c.pos, a.pos, p),
+                        edge(a.pos, b.pos, p),
+                    ];
+                    if weights.iter().any(|w| w * area < 0.0) {
+                        continue;
+                    }
+                    let color = Rgba::from(a.color) * (weights[0] / area)
+                        + Rgba::from(b.color) * (weights[1] / area)
+                        + Rgba::from(c.color) * (weights[2] / area);
+                    let pixel = &mut image.pixels[y * width + x];
+                    *pixel = Color32::from(color + Rgba::from(*pixel) * (1.0 - color.a()));
+                }
+            }
+        }
+    }
+}
+
+/// Multiplies `layer` onto `image`, which must be as large, darkening it by the color of each
+/// pixel as far as that pixel covers it. Works on the stored values, as the multiply blend of
+/// the screen does.
+pub fn multiply(image: &mut ColorImage, layer: &ColorImage) {
+    for (pixel, over) in image.pixels.iter_mut().zip(&layer.pixels) {
+        let uncovered = 255 - over.a() as u32;
+        let blend = |below: u8, above: u8| (below as u32 * (above as u32 + uncovered) / 255) as u8;
+        *pixel = Color32::from_rgba_premultiplied(
+            blend(pixel.r(), over.r()),
+            blend(pixel.g(), over.g()),
+            blend(pixel.b(), over.b()),
+            pixel.a(),
+        );
+    }
+}
+
+/// Averages each `factor` by `factor` block of pixels of `image` into one. Sides that do not
+/// divide evenly lose their last pixels.
+pub fn downsample(image: &ColorImage, factor: usize) -> ColorImage {
+    if factor <= 1 {
+        return image.clone();
+    }
+    let [width, height] = [image.width() / factor, image.height() / factor];
+    let weight = 1.0 / (factor * factor) as f32;
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let mut sum = Rgba::TRANSPARENT;
+            for dy in 0..factor {
+                let row = (y * factor + dy) * image.width();
+                for dx in 0..factor {
+                    sum = sum + Rgba::from(image.pixels[row + x * factor + dx]);
+                }
+            }
+            pixels.push(Color32::from(sum * weight));
+        }
+    }
+    ColorImage {
+        size: [width, height],
+        pixels,
+    }
+}
+
+/// Encodes `image` as an 8-bit RGBA PNG.
+pub fn encode_png(image: &ColorImage) -> Result<Vec<u8>, png::EncodingError> {
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, image.width() as u32, image.height() as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(image.as_raw())?;
+    Ok(png)
+}
+
+/// Decodes a PNG of any color type and bit depth into an image.
+pub fn decode_png(data: &[u8]) -> Result<ColorImage, png::DecodingError> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(
+        png::Transformations::normalize_to_color8() | png::Transformations::ALPHA,
+    );
+    let mut reader = decoder.read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+    let size = [info.width as usize, info.height as usize];
+    let bytes = &buffer[..info.buffer_size()];
+    Ok(match info.color_type {
+        png::ColorType::GrayscaleAlpha => ColorImage {
+            size,
+            pixels: bytes
+                .chunks_exact(2)
+                .map(|pixel| {
+                    Color32::from_rgba_unmultiplied(pixel[0], pixel[0], pixel[0], pixel[1])
+                })
+                .collect(),
+        },
+        _ => ColorImage::from_rgba_unmultiplied(size, bytes),
+    })
+}
+
+/// A boolean grid laid over a screen area, one cell per `cell_size` points.
+pub struct Mask {
+    pub width: usize,
+    pub height: usize,
+    cells: Vec<bool>,
+    origin: Pos2,
+    cell_size: f32,
+}
+
+impl Mask {
+    pub fn new(area: Rect, cell_size: f32) -> Self {
+        let width = (area.width() / cell_size).ceil().max(1.0) as usize;
+        let height = (area.height() / cell_size).ceil().max(1.0) as usize;
+        Self {
+            width,
+            height,
+            cells: vec![false; width * height],
+            origin: area.min,
+            cell_size,
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> bool {
+        self.cells[y * self.width + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, value: bool) {
+        self.cells[y * self.width + x] = value;
+    }
+
+    pub fn cell_at(&self, pos: Pos2) -> Option<(usize, usize)> {
+        let local = (pos - self.origin) / self.cell_size;
+        if local.x < 0.0 || local.y < 0.0 {
+            return None;
+        }
+        let (x, y) = (local.x as usize, local.y as usize);
+        (x < self.width && y < self.height).then_some((x, y))
+    }
+
+    /// Screen position of the top left corner of cell `(x, y)`.
+    pub fn corner(&self, x: i32, y: i32) -> Pos2 {
+        self.origin + self.cell_size * egui::vec2(x as f32, y as f32)
+    }
+
+    /// Sets every cell whose center is covered by a visible triangle of the tessellated
+    /// `shapes`.
+    pub fn cover_shapes(&mut self, ctx: &Context, shapes: Vec<ClippedShape>) {
+        for primitive in ctx.tessellate(shapes, ctx.pixels_per_point()) {
+            let Primitive::Mesh(mesh) = primitive.primitive else {
+                continue;
+            };
+            for triangle in mesh.indices.chunks_exact(3) {
+                let [a, b, c] = [0, 1, 2].map(|i| mesh.vertices[triangle[i] as usize]);
+                if [a, b, c].iter().all(|vertex| vertex.color.a() == 0) {
+                    continue;
+                }
+                self.cover_triangle(a.pos, b.pos, c.pos);
+            }
+        }
+    }
+
+    fn cover_triangle(&mut self, a: Pos2, b: Pos2, c: Pos2) {
+        let to_grid = |p: Pos2| ((p - self.origin) / self.cell_size).to_pos2();
+        let (a, b, c) = (to_grid(a), to_grid(b), to_grid(c));
+        let area = edge(a, b, c);
+        if area == 0.0 {
+            return;
+        }
+        let min_x = a.x.min(b.x).min(c.x).floor().max(0.0) as usize;
+        let min_y = a.y.min(b.y).min(c.y).floor().max(0.0) as usize;
+        let max_x = (a.x.max(b.x).max(c.x).ceil().max(0.0) as usize).min(self.width);
+        let max_y = (a.y.max(b.y).max(c.y).ceil().max(0.0) as usize).min(self.height);
+        for y in min_y..max_y {
+            for x in min_x..max_x {
+                let p = pos2(x as f32 + 0.5, y as f32 + 0.5);
+                let weights = [edge(b, c, p), edge(c, a, p), edge(a, b, p)];
+                if weights.iter().all(|w| w * area >= 0.0) {
+                    self.set(x, y, true);
+                }
+            }
+        }
+    }
+
+    /// Grows the set cells by one cell in each of the four directions.
+    pub fn dilate(&mut self) {
+        let source = self.cells.clone();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let i = y * self.width + x;
+                self.cells[i] = source[i]
+                    || x > 0 && source[i - 1]
+                    || x + 1 < self.width && source[i + 1]
+                    || y > 0 && source[i - self.width]
+                    || y + 1 < self.height && source[i + self.width];
+            }
+        }
+    }
+
+    /// The 4-connected region of unset cells around `start`, or `None` if it reaches the edge of
+    /// the grid and so is not enclosed.
+    pub fn enclosed_region(&self, start: (usize, usize)) -> Option<Mask> {
+        if self.get(start.0, start.1) {
+            return None;
+        }
+        let mut region = Mask {
+            cells: vec![false; self.cells.len()],
+            ..*self
+        };
+        let mut stack = vec![start];
+        region.set(start.0, start.1, true);
+        while let Some((x, y)) = stack.pop() {
+            if x == 0 || y == 0 || x + 1 == self.width || y + 1 == self.height {
+                return None;
+            }
+            for (nx, ny) in [(x - 1, y), (x + 1, y), (x, y - 1), (x, y + 1)] {
+                if !self.get(nx, ny) && !region.get(nx, ny) {
+                    region.set(nx, ny, true);
+                    stack.push((nx, ny));
+                }
+            }
+        }
+        Some(region)
+    }
+
+    /// The longest closed loop of cell edges separating set cells from unset ones, in screen
+    /// coordinates with collinear corners removed.
+    pub fn outline(&self) -> Vec<Pos2> {
+        let inside = |x: i32, y: i32| {
+            x >= 0
+                && y >= 0
+                && (x as usize) < self.width
+                && (y as usize) < self.height
+                && self.get(x as usize, y as usize)
+        };
+        // Directed boundary edges keep the set cells on their right.
+        let mut edges: HashMap<(i32, i32), Vec<(i32, i32)>> = HashMap::new();
+        for y in 0..self.height as i32 {
+            for x in 0..self.width as i32 {
+                if !inside(x, y) {
+                    continue;
+                }
+                let mut add = |from: (i32, i32), to: (i32, i32)| {
+                    edges.entry(from).or_default().push(to);
+                };
+                if !inside(x, y - 1) {
+                    add((x, y), (x + 1, y));
+                }
+                if !inside(x + 1, y) {
+                    add((x + 1, y), (x + 1, y + 1));
+                }
+                if !inside(x, y + 1) {
+                    add((x + 1, y + 1), (x, y + 1));
+                }
+                if !inside(x - 1, y) {
+                    add((x, y + 1), (x, y));
+                }
+            }
+        }
+        let mut longest: Vec<(i32, i32)> = vec![];
+        while let Some(&start) = edges.keys().next() {
+            let mut contour = vec![start];
+            let mut current = start;
+            while let Some(next) = edges.get_mut(&current).and_then(|targets| targets.pop()) {
+                if edges.get(&current).is_some_and(Vec::is_empty) {
+                    edges.remove(&current);
+                }
+                if next == start {
+                    break;
+                }
+                contour.push(next);
+                current = next;
+            }
+            if contour.len() > longest.len() {
+                longest = contour;
+            }
+        }
+        let n = longest.len();
+        (0..n)
+            .filter(|&i| {
+                let (prev, point, next) =
+                    (longest[(i + n - 1) % n], longest[i], longest[(i + 1) % n]);
+                (point.0 - prev.0) * (next.1 - point.1) != (point.1 - prev.1) * (next.0 - point.0)
+            })
+            .map(|i| self.corner(longest[i].0, longest[i].1))
+            .collect()
+    }
+}
+
+fn edge(a: Pos2, b: Pos2, p: Pos2) -> f32 {
+    (b.x - a.x) * (p.y - a.y) - (b.y - a.y) * (p.x - a.x)
+}
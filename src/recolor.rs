@@ -0,0 +1,229 @@
+use egui::{emath::RectTransform, Color32, Rect, Ui};
+
+use crate::{
+    layers::Layers,
+    structure::{CanvasTree, DrawNode, NodeId, Property, NODE_BOUNDS},
+};
+
+/// How many nodes are searched each frame, so large canvases stay responsive while recoloring.
+const NODES_PER_FRAME: usize = 256;
+
+/// Where colors are replaced.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ReplaceScope {
+    Canvas,
+    /// Only strokes reaching into the visible part of the canvas.
+    View,
+}
+
+/// A node still to be searched.
+struct PendingNode {
+    node: NodeId,
+    /// The screen rect the node covers, when only strokes in view are replaced.
+    screen_rect: Option<Rect>,
+    /// Whether the node's children should be searched too. Ancestors of the view only
+    /// contribute their own strokes.
+    descend: bool,
+}
+
+/// A replacement spread over several frames.
+struct ReplaceJob {
+    pending: Vec<PendingNode>,
+    view: Rect,
+    searched: usize,
+    replaced: usize,
+}
+
+/// What the replace color window asks the painting to do.
+pub enum ReplaceAction {
+    Start,
+    Cancel,
+}
+
+/// Settings and progress of the replace color action.
+pub struct ColorReplace {
+    pub from: Color32,
+    pub to: Color32,
+    /// Largest difference per channel that still counts as a match.
+    pub tolerance: u8,
+    pub scope: ReplaceScope,
+    job: Option<ReplaceJob>,
+    /// Strokes recolored by the last finished replacement.
+    last_replaced: Option<usize>,
+}
+
+impl Default for ColorReplace {
+    fn default() -> Self {
+        Self {
+            from: Color32::BLACK,
+            to: Color32::RED,
+            tolerance: 16,
+            scope: ReplaceScope::Canvas,
+            job: None,
+            last_replaced: None,
+        }
+    }
+}
+
+impl ColorReplace {
+    pub fn is_running(&self) -> bool {
+        self.job.is_some()
+    }
+
+    /// Begins a replacement over `nodes`, each with its screen rect and whether to search its
+    /// children. The rects are only used when replacing within `view`.
+    pub fn start(&mut self, nodes: Vec<(NodeId, Rect, bool)>, view: Rect) {
+        let pending = nodes
+            .into_iter()
+            .map(|(node, screen_rect, descend)| PendingNode {
+                node,
+                screen_rect: (self.scope == ReplaceScope::View).then_some(screen_rect),
+                descend,
+            })
+            .collect();
+        self.job = Some(ReplaceJob {
+            pending,
+            view,
+            searched: 0,
+            replaced: 0,
+        });
+        self.last_replaced = None;
+    }
+
+    pub fn cancel(&mut self) {
+        if let Some(job) = self.job.take() {
+            self.last_replaced = Some(job.replaced);
+        }
+    }
+
+    /// Searches the next batch of nodes of `tree`, skipping strokes on locked layers and nodes
+    /// removed since the replacement started. Returns the orders of the strokes recolored.
+    pub fn step(&mut self, layers: &Layers, tree: &mut CanvasTree) -> Vec<u64> {
+        let (from, to, tolerance) = (self.from, self.to, self.tolerance);
+        let Some(job) = self.job.as_mut() else {
+            return vec![];
+        };
+        let mut recolored = vec![];
+        for _ in 0..NODES_PER_FRAME {
+            let Some(PendingNode {
+                node,
+                screen_rect,
+                descend,
+            }) = job.pending.pop()
+            else {
+                break;
+            };
+            if !tree.contains(node) {
+                continue;
+            }
+            job.searched += 1;
+            tree.update_own_strokes(node, &mut |stroke| {
+                if layers.is_locked(stroke.layer) {
+                    return false;
+                }
+                if let Some(screen_rect) = screen_rect {
+                    let bounds = RectTransform::from_to(NODE_BOUNDS, screen_rect)
+                        .transform_rect(stroke.drawable.bounds());
+                    if !bounds.intersects(job.view) {
+                        return false;
+                    }
+                }
+                let mut changed = false;
+                for property in stroke.drawable.properties() {
+                    if let Property::Color(color) = property {
+                        if within_tolerance(*color, from, tolerance) {
+                            *color = to;
+                            changed = true;
+                        }
+                    }
+                }
+                if changed {
+                    job.replaced += 1;
+                    recolored.push(stroke.order);
+                }
+                false
+            });
+            tree.ancestors_changed(node);
+            if descend {
+                for ((x, y), child) in tree[node].child_nodes() {
+                    let child_rect = screen_rect.map(|rect| DrawNode::child_rect(rect, x, y));
+                    if matches!(child_rect, Some(rect) if !rect.expand2(rect.size() / 4.0).intersects(job.view))
+                    {
+                        continue;
+                    }
+                    job.pending.push(PendingNode {
+                        node: child,
+                        screen_rect: child_rect,
+                        descend: true,
+                    });
+                }
+            }
+        }
+        if job.pending.is_empty() {
+            self.cancel();
+        }
+        recolored
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui) -> Option<ReplaceAction> {
+        let mut action = None;
+        ui.add_enabled_ui(!self.is_running(), |ui| {
+            egui::Grid::new("replace_color").show(ui, |ui| {
+                ui.label("Replace");
+                ui.color_edit_button_srgba(&mut self.from);
+                ui.end_row();
+                ui.label("With");
+                ui.color_edit_button_srgba(&mut self.to);
+                ui.end_row();
+                ui.label("Tolerance");
+                ui.add(egui::Slider::new(&mut self.tolerance, 0..=255))
+                    .on_hover_text("Largest difference per color channel that still matches");
+                ui.end_row();
+                ui.label("In");
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut self.scope, ReplaceScope::Canvas, "Whole canvas");
+                    ui.radio_value(&mut self.scope, ReplaceScope::View, "Current view");
+                });
+                ui.end_row();
+            });
+        });
+        ui.separator();
+        match &self.job {
+            Some(job) => {
+                // Children are only discovered as their parents are searched, so this
+                // underestimates progress until the queue drains.
+                let progress = job.searched as f32 / (job.searched + job.pending.len()) as f32;
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::ProgressBar::new(progress)
+                            .desired_width(160.0)
+                            .text(format!("{} recolored", job.replaced)),
+                    );
+                    if ui.button("Cancel").clicked() {
+                        action = Some(ReplaceAction::Cancel);
+                    }
+                });
+            }
+            None => {
+                ui.horizontal(|ui| {
+                    if ui.button("Replace").clicked() {
+                        action = Some(ReplaceAction::Start);
+                    }
+                    if let Some(replaced) = self.last_replaced {
+                        ui.label(format!("{replaced} strokes recolored"));
+                    }
+                });
+            }
+        }
+        action
+    }
+}
+
+/// Whether every channel of `a` is within `tolerance` of `b`, comparing unmultiplied values so
+/// translucent strokes match their opaque color.
+fn within_tolerance(a: Color32, b: Color32, tolerance: u8) -> bool {
+    a.to_srgba_unmultiplied()
+        .into_iter()
+        .zip(b.to_srgba_unmultiplied())
+        .all(|(a, b)| a.abs_diff(b) <= tolerance)
+}
@@ -0,0 +1,466 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{Seek as _, Write as _},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{oplog::Op, painting::Painting};
+
+/// Written when the app starts and removed when it exits cleanly, so finding it on startup
+/// means the last run crashed.
+const RUNNING_MARKER: &str = "running";
+/// The latest autosaved copy of the painting in each tab, in its save format.
+const RECOVERY_FILE: &str = "recovery";
+/// Changes made to the painting in each tab since it was last saved, one entry per line.
+const JOURNAL_FILE: &str = "changes";
+
+/// The name of the file `name` for the tab at `index`, counting the first painting as 0, whose
+/// files keep the names they had before there were tabs.
+fn tab_file(name: &str, index: usize) -> String {
+    match index {
+        0 => name.to_string(),
+        _ => format!("{name}-{index}"),
+    }
+}
+
+/// The tabs whose file `name` is in `dir`, with their paths, by tab index.
+fn tab_files(dir: &Path, name: &str) -> Vec<(usize, PathBuf)> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return vec![];
+    };
+    let mut files = entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let file_name = path.file_name()?.to_str()?;
+            let index = match file_name.strip_prefix(name)? {
+                "" => 0,
+                index => index
+                    .strip_prefix('-')?
+                    .parse()
+                    .ok()
+                    .filter(|index| *index > 0)?,
+            };
+            path.is_file().then_some((index, path))
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+/// Removes the file at `path` if it is there.
+fn remove(path: &Path) {
+    match std::fs::remove_file(path) {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => log::error!("Failed to remove {}: {err}", path.display()),
+    }
+}
+
+/// Longest the journal's entries wait to be forced out to disk, past which a crash of the whole
+/// system could lose them.
+const JOURNAL_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Copies of the painting in each tab written every few minutes, apart from the app state, so
+/// that work survives the app crashing before it saves.
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct Autosave {
+    /// Minutes between copies, or 0 to not make any.
+    pub minutes: u32,
+    /// Where the copies are written, once started.
+    #[serde(skip)]
+    dir: Option<PathBuf>,
+    #[serde(skip)]
+    last_save: Option<Instant>,
+}
+
+impl Default for Autosave {
+    fn default() -> Self {
+        Self {
+            minutes: 2,
+            dir: None,
+            last_save: None,
+        }
+    }
+}
+
+impl Autosave {
+    /// Starts writing copies to `dir`. Returns the last copies written there, by tab index, if
+    /// the app did not exit cleanly after writing them.
+    pub fn start(&mut self, dir: PathBuf) -> Vec<(usize, PathBuf)> {
+        let marker = dir.join(RUNNING_MARKER);
+        let copies = match marker.is_file() {
+            true => tab_files(&dir, RECOVERY_FILE),
+            false => vec![],
+        };
+        if let Err(err) = std::fs::create_dir_all(&dir).and_then(|()| std::fs::write(&marker, [])) {
+            log::error!("Failed to mark the app as running: {err}");
+        }
+        self.dir = Some(dir);
+        self.last_save = Some(Instant::now());
+        copies
+    }
+
+    /// Writes a copy of the painting in each tab once the interval has passed since the last
+    /// ones, removing those of tabs since closed.
+    pub fn update(&mut self, paintings: &[&Painting]) {
+        let (Some(dir), Some(last_save)) = (&self.dir, self.last_save) else {
+            return;
+        };
+        let interval = Duration::from_secs(60 * u64::from(self.minutes));
+        if self.minutes == 0 || last_save.elapsed() < interval {
+            return;
+        }
+        self.last_save = Some(Instant::now());
+        for (index, painting) in paintings.iter().enumerate() {
+            match painting.write_file(&dir.join(tab_file(RECOVERY_FILE, index))) {
+                Ok(()) => log::debug!("Autosaved the painting in tab {index}"),
+                Err(err) => log::error!("Failed to autosave the painting in tab {index}: {err}"),
+            }
+        }
+        for (_, path) in tab_files(dir, RECOVERY_FILE)
+            .into_iter()
+            .filter(|(index, _)| *index >= paintings.len())
+        {
+            remove(&path);
+        }
+    }
+
+    /// Removes the copies and the running marker once the app has saved and is exiting, so no
+    /// recovery is offered next time.
+    pub fn finish(&mut self) {
+        let Some(dir) = self.dir.take() else {
+            return;
+        };
+        for (_, path) in tab_files(&dir, RECOVERY_FILE) {
+            remove(&path);
+        }
+        remove(&dir.join(RUNNING_MARKER));
+    }
+}
+
+/// A line of the change journal.
+#[derive(Deserialize, Serialize)]
+enum JournalEntry {
+    /// The whole painting, as `Painting::to_ron` writes it, after it was replaced rather than
+    /// changed, such as by restoring a snapshot.
+    Painting(String),
+    /// The change at `index` of the operation log with the id `log`.
+    Op { log: u64, index: usize, op: Op },
+}
+
+/// Appends each change to the painting in each tab to a file of the tab's own as soon as it is
+/// made, so a crash loses at most the stroke being drawn. The files start over whenever the
+/// paintings are saved, and are removed once the app exits cleanly.
+#[derive(Default)]
+pub struct ChangeJournal {
+    dir: Option<PathBuf>,
+    /// The journal of each tab, by tab index.
+    tabs: Vec<TabJournal>,
+}
+
+impl ChangeJournal {
+    /// Starts journaling to `dir`, first making the changes the journals left there by a run
+    /// that crashed hold to `paintings`, those of the tabs as loaded from the last save.
+    /// Returns the paintings journaled whole for tabs opened after the last save, to be opened
+    /// again.
+    pub fn start(&mut self, dir: &Path, paintings: Vec<&mut Painting>) -> Vec<Painting> {
+        let mut journals = tab_files(dir, JOURNAL_FILE)
+            .into_iter()
+            .filter_map(|(index, path)| match std::fs::read_to_string(&path) {
+                Ok(journal) => Some((index, journal)),
+                Err(err) => {
+                    log::error!("Failed to read the change journal of tab {index}: {err}");
+                    None
+                }
+            })
+            .collect::<BTreeMap<_, _>>();
+        if let Err(err) = std::fs::create_dir_all(dir) {
+            log::error!("Failed to start the change journal: {err}");
+            return vec![];
+        }
+        for (index, painting) in paintings.into_iter().enumerate() {
+            let recovered = journals
+                .remove(&index)
+                .is_some_and(|journal| recover(painting, &journal));
+            let path = dir.join(tab_file(JOURNAL_FILE, index));
+            // Recovered changes are not saved yet, so the journal starts with them.
+            self.tabs
+                .push(TabJournal::start(path, painting, !recovered));
+        }
+        let mut reopened = vec![];
+        for (index, journal) in journals {
+            let mut painting = Painting::default();
+            if recover(&mut painting, &journal) {
+                reopened.push(painting);
+            }
+            remove(&dir.join(tab_file(JOURNAL_FILE, index)));
+        }
+        self.dir = Some(dir.to_path_buf());
+        reopened
+    }
+
+    /// Appends the changes made to the painting in each tab since the last call, starting the
+    /// journals of tabs opened since and removing those of tabs closed.
+    pub fn update(&mut self, paintings: &[&Painting]) {
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        for mut closed in self.tabs.drain(paintings.len().min(self.tabs.len())..) {
+            closed.finish();
+        }
+        for (index, painting) in paintings.iter().enumerate().skip(self.tabs.len()) {
+            // Opened since the last save, so the painting is journaled whole to start with.
+            let path = dir.join(tab_file(JOURNAL_FILE, index));
+            self.tabs.push(TabJournal::start(path, painting, false));
+        }
+        for (journal, painting) in self.tabs.iter_mut().zip(paintings) {
+            journal.update(painting);
+        }
+    }
+
+    /// Empties the journals once `paintings` have been saved with every change in them.
+    pub fn saved(&mut self, paintings: &[&Painting]) {
+        for (journal, painting) in self.tabs.iter_mut().zip(paintings) {
+            journal.saved(painting);
+        }
+    }
+
+    /// Removes the journals once the app has saved and is exiting.
+    pub fn finish(&mut self) {
+        for mut journal in self.tabs.drain(..) {
+            journal.finish();
+        }
+        self.dir = None;
+    }
+}
+
+/// The change journal of one tab.
+#[derive(Default)]
+struct TabJournal {
+    file: Option<File>,
+    path: Option<PathBuf>,
+    /// The id of the operation log journaled, and how many of its changes are saved or
+    /// journaled. None until the log is known.
+    written: Option<(u64, usize)>,
+    /// When entries were last forced out to disk, if any were written since.
+    unsynced: Option<Instant>,
+}
+
+impl TabJournal {
+    /// Starts journaling `painting` to `path`, which is emptied. Unless the painting is
+    /// `saved` as it is, it is journaled whole first.
+    fn start(path: PathBuf, painting: &Painting, saved: bool) -> Self {
+        let mut journal = Self::default();
+        match File::create(&path) {
+            Ok(file) => journal.file = Some(file),
+            Err(err) => {
+                log::error!("Failed to start the change journal: {err}");
+                return journal;
+            }
+        }
+        journal.path = Some(path);
+        let op_log = painting.op_log();
+        journal.written = saved.then(|| (op_log.id(), op_log.count()));
+        if !saved {
+            journal.append(painting);
+        }
+        journal
+    }
+
+    /// Appends the changes made to `painting` since the last call.
+    fn update(&mut self, painting: &Painting) {
+        if self.file.is_none() {
+            return;
+        }
+        self.append(painting);
+        if self
+            .unsynced
+            .is_some_and(|since| since.elapsed() >= JOURNAL_SYNC_INTERVAL)
+        {
+            self.sync();
+        }
+    }
+
+    fn append(&mut self, painting: &Painting) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        let op_log = painting.op_log();
+        let (id, count) = (op_log.id(), op_log.count());
+        let since = self
+            .written
+            .filter(|(written_id, written)| *written_id == id && *written <= count)
+            .and_then(|(_, written)| Some((written, op_log.ops_since(written)?)));
+        let entries = match since {
+            Some((written, ops)) => ops
+                .into_iter()
+                .zip(written..)
+                .map(|(op, index)| JournalEntry::Op { log: id, index, op })
+                .collect(),
+            None => match painting.to_ron() {
+                Ok(data) => vec![JournalEntry::Painting(data)],
+                Err(err) => {
+                    log::error!("Failed to journal the painting: {err}");
+                    return;
+                }
+            },
+        };
+        self.written = Some((id, count));
+        if entries.is_empty() {
+            return;
+        }
+        let mut lines = String::new();
+        for entry in &entries {
+            match ron::to_string(entry) {
+                Ok(line) => {
+                    lines.push_str(&line);
+                    lines.push('\n');
+                }
+                Err(err) => log::error!("Failed to encode a journal entry: {err}"),
+            }
+        }
+        if let Err(err) = file.write_all(lines.as_bytes()) {
+            log::error!("Failed to write to the change journal: {err}");
+        }
+        self.unsynced.get_or_insert_with(Instant::now);
+    }
+
+    fn sync(&mut self) {
+        self.unsynced = None;
+        if let Some(Err(err)) = self.file.as_ref().map(File::sync_data) {
+            log::error!("Failed to flush the change journal: {err}");
+        }
+    }
+
+    /// Empties the journal once `painting` has been saved with every change in it.
+    fn saved(&mut self, painting: &Painting) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        // Opened for writing rather than appending, so it writes from its start again.
+        if let Err(err) = file.set_len(0).and_then(|()| file.rewind()) {
+            log::error!("Failed to empty the change journal: {err}");
+            return;
+        }
+        self.unsynced = None;
+        let op_log = painting.op_log();
+        self.written = Some((op_log.id(), op_log.count()));
+    }
+
+    /// Removes the journal once the app has saved and is exiting, or the tab was closed.
+    fn finish(&mut self) {
+        self.file = None;
+        if let Some(path) = self.path.take() {
+            remove(&path);
+        }
+    }
+}
+
+/// Makes the changes in `journal` that `painting` lacks, replacing it with the last painting
+/// journaled whole if there is one. Entries past the first that fails to read, as the last may
+/// have been cut short, are ignored. Returns whether the painting changed.
+fn recover(painting: &mut Painting, journal: &str) -> bool {
+    let mut replacement = None;
+    let mut ops = vec![];
+    for line in journal.lines() {
+        match ron::from_str(line) {
+            Ok(JournalEntry::Painting(data)) => match Painting::from_ron(&data) {
+                Ok(journaled) => {
+                    replacement = Some(journaled);
+                    ops.clear();
+                }
+                Err(err) => {
+                    log::warn!("Failed to read a journaled painting: {err}");
+                    break;
+                }
+            },
+            Ok(JournalEntry::Op { log, index, op }) => ops.push((log, index, op)),
+            Err(err) => {
+                log::warn!("Stopped reading the change journal at a damaged entry: {err}");
+                break;
+            }
+        }
+    }
+    let replaced = replacement.is_some();
+    if let Some(journaled) = replacement {
+        painting.replace(journaled, "Recovered painting");
+    }
+    // Changes from before the last save are in the painting already.
+    let op_log = painting.op_log();
+    let (id, count) = (op_log.id(), op_log.count());
+    let missing = ops
+        .into_iter()
+        .filter(|(from, index, _)| *from == id && *index >= count)
+        .zip(count..)
+        .take_while(|((_, index, _), expected)| index == expected)
+        .map(|((_, _, op), _)| op)
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        log::info!("Recovered {} unsaved changes", missing.len());
+        painting.recover_changes(&missing);
+    }
+    replaced || !missing.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use egui::{pos2, Color32};
+
+    use super::*;
+    use crate::{
+        drawables::FilledPolygon,
+        structure::{StrokeMeta, StrokePriority},
+    };
+
+    fn draw(painting: &mut Painting) {
+        let points = [pos2(0.0, 0.0), pos2(0.5, 0.0), pos2(0.0, 0.5)];
+        let stroke = StrokeMeta {
+            order: 1,
+            priority: StrokePriority::Ink,
+            layer: 0,
+            created: 0,
+            author: None,
+            id: 1,
+        }
+        .entry(Box::new(FilledPolygon::new(&points, Color32::RED)));
+        painting.recover_changes(&[Op::Add {
+            path: vec![],
+            strokes: vec![stroke],
+        }]);
+    }
+
+    #[test]
+    fn journals_each_tab_apart() {
+        let dir = std::env::temp_dir().join(format!("tic-journal-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (mut first, mut second) = (Painting::default(), Painting::default());
+        let mut journal = ChangeJournal::default();
+        assert!(journal
+            .start(&dir, vec![&mut first, &mut second])
+            .is_empty());
+        draw(&mut second);
+        let mut third = Painting::default();
+        draw(&mut third);
+        journal.update(&[&first, &second, &third]);
+        drop(journal);
+        let files = tab_files(&dir, JOURNAL_FILE)
+            .into_iter()
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        assert_eq!(files, [0, 1, 2]);
+
+        // Started again as if after a crash, with the tabs as last saved.
+        let (mut first, mut second) = (Painting::default(), Painting::default());
+        let reopened = ChangeJournal::default().start(&dir, vec![&mut first, &mut second]);
+        assert_eq!(first.stroke_count(), 0);
+        assert_eq!(second.stroke_count(), 1);
+        assert_eq!(reopened.len(), 1);
+        assert_eq!(reopened[0].stroke_count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
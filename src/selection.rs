@@ -0,0 +1,51 @@
+use std::collections::BTreeSet;
+
+use crate::structure::StrokeEntry;
+
+/// Strokes picked with the select tool, identified by their order. Every entry sharing a
+/// selected order, such as all the segments of one pen stroke, is part of the selection.
+#[derive(Default, Clone)]
+pub struct Selection {
+    orders: BTreeSet<u64>,
+}
+
+impl Selection {
+    pub fn is_empty(&self) -> bool {
+        self.orders.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.orders.len()
+    }
+
+    pub fn contains(&self, stroke: &StrokeEntry) -> bool {
+        self.orders.contains(&stroke.order)
+    }
+
+    pub fn orders(&self) -> &BTreeSet<u64> {
+        &self.orders
+    }
+
+    pub fn clear(&mut self) {
+        self.orders.clear();
+    }
+
+    /// Replaces the selection with the strokes with the given orders.
+    pub fn select(&mut self, orders: BTreeSet<u64>) {
+        self.orders = orders;
+    }
+
+    /// Removes the strokes with the given orders if they are all selected, otherwise adds them.
+    pub fn toggle(&mut self, orders: BTreeSet<u64>) {
+        if orders.is_subset(&self.orders) {
+            self.orders.retain(|order| !orders.contains(order));
+        } else {
+            self.orders.extend(orders);
+        }
+    }
+
+    /// Follows strokes whose order was changed by `renumber`.
+    pub fn renumber(&mut self, renumber: impl Fn(u64) -> u64) {
+        self.orders = self.orders.iter().map(|order| renumber(*order)).collect();
+    }
+}
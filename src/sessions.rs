@@ -0,0 +1,323 @@
+use chrono::{DateTime, Local, Utc};
+use egui::{pos2, Rect, Vec2};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    collab::relocate,
+    structure::{CanvasTree, NodeId},
+};
+
+/// How much of the view a region jumped to fills.
+const REGION_FILL: f32 = 0.8;
+/// Edits following one another more closely are noted as one, as drawing a stroke edits the
+/// canvas every frame.
+const EDIT_GAP_MILLIS: i64 = 1000;
+
+/// A viewport position: the center cell node plus the pan and zoom relative to it.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(from = "SerializedViewLocation", into = "SerializedViewLocation")]
+pub struct ViewLocation {
+    /// The outermost node as of the last `rebase`, None if the location was loaded since.
+    root: Option<NodeId>,
+    /// Path from `root`, or from the outermost node the location was saved with.
+    path: Vec<(u8, u8)>,
+    pub pan: Vec2,
+    pub zoom: f32,
+}
+
+#[derive(Deserialize, Serialize)]
+struct SerializedViewLocation {
+    path: Vec<(u8, u8)>,
+    pan: Vec2,
+    zoom: f32,
+}
+
+impl From<SerializedViewLocation> for ViewLocation {
+    fn from(value: SerializedViewLocation) -> Self {
+        Self {
+            root: None,
+            path: value.path,
+            pan: value.pan,
+            zoom: value.zoom,
+        }
+    }
+}
+
+impl From<ViewLocation> for SerializedViewLocation {
+    fn from(value: ViewLocation) -> Self {
+        Self {
+            path: value.path(),
+            pan: value.pan,
+            zoom: value.zoom,
+        }
+    }
+}
+
+impl ViewLocation {
+    /// The location of `node`, a node of `tree`.
+    pub fn new(tree: &CanvasTree, node: NodeId, pan: Vec2, zoom: f32) -> Self {
+        let (root, path) = tree.get_top_level_and_path(node);
+        Self {
+            root: Some(root),
+            path,
+            pan,
+            zoom,
+        }
+    }
+
+    /// The location at `path` from the outermost node of `tree`, in the order
+    /// `CanvasTree::get_or_create_path` takes it.
+    pub fn at_path(tree: &CanvasTree, path: Vec<(u8, u8)>, pan: Vec2, zoom: f32) -> Self {
+        Self {
+            root: Some(tree.root()),
+            path,
+            pan,
+            zoom,
+        }
+    }
+
+    /// The location at `path` from the outermost node, in the order
+    /// `CanvasTree::get_or_create_path` takes it.
+    #[cfg(target_arch = "wasm32")]
+    pub fn from_path(path: Vec<(u8, u8)>, pan: Vec2, zoom: f32) -> Self {
+        Self {
+            root: None,
+            path,
+            pan,
+            zoom,
+        }
+    }
+
+    /// Path from the outermost node as of the last `rebase`, in the order
+    /// `CanvasTree::get_or_create_path` takes it.
+    pub fn path(&self) -> Vec<(u8, u8)> {
+        self.path.clone()
+    }
+
+    /// The node of `tree` the location is in, creating any nodes missing along its path.
+    pub fn node(&mut self, tree: &mut CanvasTree) -> NodeId {
+        self.rebase(tree);
+        let root = tree.root();
+        tree.get_or_create_path(&mut self.path(), root)
+    }
+
+    /// Extends the path by the nodes created above the outermost node it starts from, so it
+    /// still leads to the same node. A location loaded from storage starts from the outermost
+    /// node of `tree`.
+    pub fn rebase(&mut self, tree: &CanvasTree) {
+        if let Some(root) = self.root.filter(|root| *root != tree.root()) {
+            if tree.contains(root) {
+                self.path.extend(tree.get_top_level_and_path(root).1);
+            }
+        }
+        self.root = Some(tree.root());
+    }
+
+    /// The location viewing `bounds` of the node at `path` from the same outermost node as this
+    /// one, in view units, where the node spans -0.5 to 0.5.
+    fn framing(&self, path: Vec<(u8, u8)>, bounds: Rect) -> Self {
+        Self {
+            root: self.root,
+            path,
+            pan: bounds.center().to_vec2(),
+            zoom: REGION_FILL / bounds.size().max_elem().max(1e-4),
+        }
+    }
+}
+
+/// An edit made during a session.
+#[derive(Deserialize, Serialize)]
+pub struct Edit {
+    /// Unix timestamp in milliseconds.
+    pub time: i64,
+    /// Path from the outermost node to the innermost node holding what the edit changed.
+    node: ViewLocation,
+    /// What the edit changed in that node, in view units, where the node spans -0.5 to 0.5.
+    bounds: Rect,
+}
+
+impl Edit {
+    /// Widens the edit to cover `later` as well, an edit made since.
+    fn extend(&mut self, later: Edit) {
+        let frame = shared_path([self.node.path(), later.node.path()]);
+        let bounds = [&*self, &later]
+            .into_iter()
+            .filter_map(|edit| edit.bounds_in(&frame))
+            .reduce(Rect::union);
+        if let Some(bounds) = bounds {
+            self.node.path = frame;
+            self.bounds = bounds;
+        }
+        self.time = later.time;
+    }
+
+    /// What the edit changed, in the view units of the node at `frame`, which holds it.
+    fn bounds_in(&self, frame: &[(u8, u8)]) -> Option<Rect> {
+        let path = self.node.path();
+        Some(Rect::from_two_pos(
+            relocate(&path, self.bounds.min, frame)?,
+            relocate(&path, self.bounds.max, frame)?,
+        ))
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Session {
+    /// Unix timestamps in seconds.
+    pub start: i64,
+    pub end: i64,
+    pub stroke_count: u32,
+    pub first_edit: ViewLocation,
+    pub last_edit: ViewLocation,
+    /// Missing from sessions recorded before edits were.
+    #[serde(default)]
+    pub edits: Vec<Edit>,
+}
+
+impl Session {
+    pub fn start_label(&self) -> String {
+        DateTime::<Utc>::from_timestamp(self.start, 0)
+            .map(|time| {
+                time.with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn duration_label(&self) -> String {
+        let seconds = (self.end - self.start).max(0);
+        format!("{}h {:02}m", seconds / 3600, (seconds / 60) % 60)
+    }
+
+    /// The location framing everything the session's edits changed, if any were recorded.
+    pub fn region(&self) -> Option<ViewLocation> {
+        let first = self.edits.first()?;
+        let frame = shared_path(self.edits.iter().map(|edit| edit.node.path()));
+        let bounds = self
+            .edits
+            .iter()
+            .filter_map(|edit| edit.bounds_in(&frame))
+            .reduce(Rect::union)?;
+        Some(first.node.framing(frame, bounds))
+    }
+}
+
+/// The path to the innermost node the nodes at `paths` share, all from the same outermost
+/// node.
+fn shared_path(paths: impl IntoIterator<Item = Vec<(u8, u8)>>) -> Vec<(u8, u8)> {
+    let mut paths = paths.into_iter();
+    let first = paths.next().unwrap_or_default();
+    // Paths list corners innermost first, so they share their ends.
+    paths.fold(first, |shared, path| {
+        let count = shared
+            .iter()
+            .rev()
+            .zip(path.iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        shared[shared.len() - count..].to_vec()
+    })
+}
+
+/// Summaries of past editing sessions. A new session starts with the first edit after launch.
+#[derive(Deserialize, Serialize, Default)]
+pub struct SessionLog {
+    sessions: Vec<Session>,
+    #[serde(skip)]
+    recording: bool,
+}
+
+impl SessionLog {
+    pub fn sessions(&self) -> &[Session] {
+        &self.sessions
+    }
+
+    pub fn last_edit(&self) -> Option<&ViewLocation> {
+        self.sessions.last().map(|session| &session.last_edit)
+    }
+
+    /// Notes an edit made viewing `location`, which changed the areas of `touched`, each given
+    /// by the path from the outermost node of `tree` to a node and bounds within it in view
+    /// units. An edit whose changes are unknown is taken to have changed what was in view.
+    pub fn record_edit(
+        &mut self,
+        tree: &CanvasTree,
+        location: ViewLocation,
+        touched: Vec<(Vec<(u8, u8)>, Rect)>,
+    ) {
+        let now = Utc::now();
+        let frame = shared_path(touched.iter().map(|(path, _)| path.clone()));
+        let bounds = touched
+            .iter()
+            .filter_map(|(path, bounds)| {
+                Some(Rect::from_two_pos(
+                    relocate(path, bounds.min, &frame)?,
+                    relocate(path, bounds.max, &frame)?,
+                ))
+            })
+            .reduce(Rect::union);
+        let edit = match bounds {
+            Some(bounds) => Edit {
+                time: now.timestamp_millis(),
+                node: ViewLocation::at_path(tree, frame, Vec2::ZERO, 1.0),
+                bounds,
+            },
+            None => Edit {
+                time: now.timestamp_millis(),
+                node: location.clone(),
+                bounds: Rect::from_center_size(
+                    pos2(location.pan.x, location.pan.y),
+                    Vec2::splat(1.0 / location.zoom),
+                ),
+            },
+        };
+        let now = now.timestamp();
+        match self.sessions.last_mut() {
+            Some(session) if self.recording => {
+                session.end = now;
+                session.stroke_count += 1;
+                session.last_edit = location;
+                match session.edits.last_mut() {
+                    Some(last) if edit.time - last.time < EDIT_GAP_MILLIS => last.extend(edit),
+                    _ => session.edits.push(edit),
+                }
+            }
+            _ => {
+                self.recording = true;
+                self.sessions.push(Session {
+                    start: now,
+                    end: now,
+                    stroke_count: 1,
+                    first_edit: location.clone(),
+                    last_edit: location,
+                    edits: vec![edit],
+                });
+            }
+        }
+    }
+
+    /// Keeps the locations leading to the same nodes as `tree` grows outward.
+    pub fn rebase(&mut self, tree: &CanvasTree) {
+        for session in self.sessions.iter_mut() {
+            session.first_edit.rebase(tree);
+            session.last_edit.rebase(tree);
+            for edit in &mut session.edits {
+                edit.node.rebase(tree);
+            }
+        }
+    }
+
+    /// Keeps the paths of the locations as they are from the outermost node, for the painting's
+    /// tree being replaced with another.
+    pub fn detach(&mut self) {
+        for session in self.sessions.iter_mut() {
+            for location in [&mut session.first_edit, &mut session.last_edit]
+                .into_iter()
+                .chain(session.edits.iter_mut().map(|edit| &mut edit.node))
+            {
+                location.root = None;
+            }
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::BTreeMap;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// A portable bundle of app settings. Each section is stored as its own RON string so that
+/// profiles from other versions can still be partially applied.
+#[derive(Deserialize, Serialize, Default, Clone)]
+pub struct SettingsProfile {
+    sections: BTreeMap<String, String>,
+}
+
+impl SettingsProfile {
+    pub fn insert<T: Serialize>(&mut self, key: &str, value: &T) {
+        match ron::to_string(value) {
+            Ok(value) => {
+                self.sections.insert(key.to_string(), value);
+            }
+            Err(err) => log::error!("Failed to encode setting {key}: {err}"),
+        }
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let value = self.sections.get(key)?;
+        match ron::from_str(value) {
+            Ok(value) => Some(value),
+            Err(err) => {
+                log::debug!("Failed to decode setting {key}: {err}");
+                None
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.sections.remove(key);
+    }
+
+    pub fn raw(&self, key: &str) -> Option<&str> {
+        self.sections.get(key).map(String::as_str)
+    }
+
+    pub fn to_ron(&self) -> String {
+        ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+            .expect("Settings profile should always be serializable")
+    }
+
+    pub fn from_ron(value: &str) -> Result<Self, ron::error::SpannedError> {
+        ron::from_str(value)
+    }
+
+    /// Keys present in both profiles whose values differ.
+    pub fn conflicts(&self, incoming: &SettingsProfile) -> Vec<String> {
+        incoming
+            .sections
+            .iter()
+            .filter(|(key, value)| {
+                self.sections
+                    .get(*key)
+                    .is_some_and(|current| current != *value)
+            })
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Builds the profile that results from merging `incoming` into `self`, taking the incoming
+    /// value for every key that is not a conflict or that `use_incoming` accepts.
+    pub fn merged(
+        &self,
+        incoming: &SettingsProfile,
+        use_incoming: impl Fn(&str) -> bool,
+    ) -> SettingsProfile {
+        let mut result = self.clone();
+        for (key, value) in incoming.sections.iter() {
+            if !self.sections.contains_key(key) || use_incoming(key) {
+                result.sections.insert(key.clone(), value.clone());
+            }
+        }
+        result
+    }
+}
+
+/// An imported profile waiting for the user to resolve conflicts with the current settings.
+pub struct PendingProfileImport {
+    pub current: SettingsProfile,
+    pub incoming: SettingsProfile,
+    pub use_incoming: BTreeMap<String, bool>,
+}
+
+impl PendingProfileImport {
+    pub fn new(current: SettingsProfile, incoming: SettingsProfile) -> Self {
+        let use_incoming = current
+            .conflicts(&incoming)
+            .into_iter()
+            .map(|key| (key, true))
+            .collect();
+        Self {
+            current,
+            incoming,
+            use_incoming,
+        }
+    }
+
+    pub fn resolved(&self) -> SettingsProfile {
+        self.current.merged(&self.incoming, |key| {
+            self.use_incoming.get(key).copied().unwrap_or(true)
+        })
+    }
+}
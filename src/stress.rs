@@ -0,0 +1,142 @@
+use egui::{ecolor::Hsva, pos2, Color32, Stroke, Vec2};
+
+use crate::{
+    ordering,
+    structure::{CanvasTree, Line, LineStyle, NodeId, SegmentStyle, StrokeMeta, StrokePriority},
+};
+
+/// Parameters for procedurally generated test canvases. The same config always produces the
+/// same canvas.
+#[derive(Clone)]
+pub struct StressConfig {
+    pub seed: u64,
+    pub stroke_count: u32,
+    /// Deepest level below the center cell strokes are placed at.
+    pub max_depth: u32,
+    /// Relative weight of each level compared to the one above it. Values below 1 favor shallow
+    /// strokes, values above 1 favor deep ones.
+    pub depth_falloff: f32,
+    /// Number of clusters strokes are grouped into, or 0 to spread them uniformly.
+    pub clusters: u32,
+    /// How many levels each cluster's shared path prefix spans.
+    pub cluster_depth: u32,
+}
+
+impl Default for StressConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            stroke_count: 10_000,
+            max_depth: 12,
+            depth_falloff: 1.0,
+            clusters: 8,
+            cluster_depth: 4,
+        }
+    }
+}
+
+/// SplitMix64, so generated canvases don't depend on an external RNG's stability.
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    fn below(&mut self, n: u32) -> u32 {
+        (self.next_u64() % n as u64) as u32
+    }
+
+    fn corner(&mut self) -> (u8, u8) {
+        let bits = self.next_u64();
+        ((bits & 1) as u8, ((bits >> 1) & 1) as u8)
+    }
+}
+
+/// Adds `config.stroke_count` strokes beneath the node `center` of `tree`, numbering them from
+/// `first_order`. Returns the next free stroke order.
+pub fn populate(
+    tree: &mut CanvasTree,
+    center: NodeId,
+    config: &StressConfig,
+    first_order: u64,
+) -> u64 {
+    let mut rng = Rng(config.seed);
+    let depth_weights = (0..=config.max_depth)
+        .map(|depth| config.depth_falloff.powi(depth as i32))
+        .collect::<Vec<_>>();
+    let total_weight: f32 = depth_weights.iter().sum();
+    let cluster_paths = (0..config.clusters)
+        .map(|_| {
+            (0..config.cluster_depth)
+                .map(|_| rng.corner())
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let mut order = first_order;
+    for _ in 0..config.stroke_count {
+        let mut target = rng.next_f32() * total_weight;
+        let depth = depth_weights
+            .iter()
+            .position(|weight| {
+                target -= weight;
+                target <= 0.0
+            })
+            .unwrap_or(config.max_depth as usize);
+
+        // Paths are followed by popping, so the step nearest the center goes last.
+        let mut steps = if cluster_paths.is_empty() {
+            vec![]
+        } else {
+            cluster_paths[rng.below(cluster_paths.len() as u32) as usize].clone()
+        };
+        steps.truncate(depth);
+        while steps.len() < depth {
+            steps.push(rng.corner());
+        }
+        steps.reverse();
+        let node = tree.get_or_create_path(&mut steps, center);
+
+        // Placed by its center so both ends stay within the node's bounds.
+        let angle = rng.next_f32() * std::f32::consts::TAU;
+        let half = (0.25 + rng.next_f32() / 2.0) * Vec2::angled(angle);
+        let room = Vec2::splat(1.0) - half.abs();
+        let center = pos2(
+            (2.0 * rng.next_f32() - 1.0) * room.x,
+            (2.0 * rng.next_f32() - 1.0) * room.y,
+        );
+        let (start, end) = (center - half, center + half);
+        let color = Color32::from(Hsva::new(rng.next_f32(), 0.8, 0.8, 1.0));
+        tree.send_stroke::<Line>(
+            start,
+            end,
+            1.0,
+            &SegmentStyle {
+                stroke: Stroke::new(0.01, color),
+                line_style: LineStyle::Solid,
+                dash_phase: 0.0,
+                widths: [1.0, 1.0],
+            },
+            StrokeMeta {
+                order,
+                priority: StrokePriority::Ink,
+                layer: 0,
+                created: 0,
+                author: None,
+                id: 0,
+            },
+            node,
+        );
+        order += ordering::ORDER_SPACING;
+    }
+    order
+}
@@ -1,13 +1,50 @@
 use std::{
-    cell::RefCell,
-    rc::{Rc, Weak},
+    cell::Cell,
+    collections::BTreeSet,
+    hash::Hasher,
+    ops::{Index, IndexMut},
+    sync::{Mutex, OnceLock},
 };
 
-use egui::{emath::RectTransform, pos2, vec2, Color32, Painter, Pos2, Rect, Stroke};
+use egui::{emath::RectTransform, pos2, vec2, Color32, Painter, Pos2, Rect, Stroke, Vec2};
 use itertools::Itertools;
-use serde::{Deserialize, Serialize};
-use tailcall::tailcall;
+use serde::{
+    de::{
+        value::{MapAccessDeserializer, SeqAccessDeserializer},
+        MapAccess, SeqAccess, Visitor,
+    },
+    ser::Error,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use slotmap::SlotMap;
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::format::SaveFormat;
+use crate::{
+    collect::{NodeRender, Thumbnail},
+    geometry::{self, Affine2},
+    integrity::ContentHasher,
+    layers::LayerId,
+    lod::NodeSummary,
+    oplog::{Op, OpSink},
+    paging::Page,
+    picking::StrokeIndex,
+};
+
+/// The area a node covers in its own coordinates.
+pub const NODE_BOUNDS: Rect = Rect::from_min_max(pos2(-1.0, -1.0), pos2(1.0, 1.0));
 
+/// Most strokes a node holds before those that fit within one of its children are moved down.
+const MAX_NODE_STROKES: usize = 256;
+
+slotmap::new_key_type! {
+    /// Identifies a node of a `CanvasTree`. Ids of removed nodes are never handed out again, so
+    /// ids kept outside the tree stop resolving once their node is gone rather than pointing at
+    /// another one.
+    pub struct NodeId;
+}
+
+#[derive(Clone, Copy)]
 pub enum Direction {
     PosX,
     PosY,
@@ -30,122 +67,399 @@ impl Direction {
     }
 }
 
-#[derive(Serialize)]
 pub struct DrawNode {
-    #[serde(skip)]
-    pub parent: Weak<RefCell<DrawNode>>,
-    pub children: [[Option<Rc<RefCell<DrawNode>>>; 2]; 2],
-    strokes: Vec<(Box<dyn CanvasDrawable>, u32)>,
-    #[serde(skip)]
+    pub parent: Option<NodeId>,
+    pub children: [[Option<NodeId>; 2]; 2],
+    strokes: Vec<StrokeEntry>,
+    index: StrokeIndex,
     pub corner: (u8, u8),
-    #[serde(skip)]
-    neighbors: (Weak<RefCell<DrawNode>>, Weak<RefCell<DrawNode>>),
+    /// The horizontal and vertical neighbors across the node's outer edges, as linked when
+    /// they were created. They may have been removed since.
+    neighbors: (Option<NodeId>, Option<NodeId>),
+    /// Hash of the subtree's content, refreshed by `update_hashes` before saving so damage can
+    /// be found after loading.
+    hash: Mutex<Option<u64>>,
+    /// Summary of the subtree's content, dropped whenever it changes. While a node's summary is
+    /// set, so are its descendants'.
+    summary: OnceLock<NodeSummary>,
+    /// Union of the bounds of the subtree's strokes, grown as strokes are added and dropped when
+    /// they are removed or moved. While a node's bounds are set, so are its descendants'.
+    bounds: OnceLock<Option<Rect>>,
+    /// The subtree tessellated while the node was small on screen, dropped along with the
+    /// summary.
+    render: Mutex<Option<NodeRender>>,
+    thumbnail: Mutex<Option<Thumbnail>>,
+    /// Set whenever the look of the subtree changes, until `take_changed` clears it.
+    changed: bool,
+    /// Set whenever the subtree changes, until `mark_saved` clears it once it is saved.
+    unsaved: bool,
+    /// Whether the chunk store holds a chunk rooted at this node.
+    pub stored_chunk: bool,
+    /// Where the children and strokes went while the subtree is paged out. The cached summary
+    /// and bounds are kept meanwhile, so the node can still be drawn from a distance.
+    page: Option<Page>,
+    /// Last frame the node was a cell in view, as counted by the `Pager`.
+    pub last_viewed: u64,
+}
+
+/// A node and its descendants, serialized as nested nodes. Paged out subtrees are read back
+/// from their pages. Descendants without strokes are left out, as which of those exist depends
+/// on where the canvas was viewed, so the same strokes are always written the same way.
+pub struct Subtree<'a> {
+    tree: &'a CanvasTree,
+    id: NodeId,
+}
+
+impl Serialize for Subtree<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let node = &self.tree[self.id];
+        if let Some(page) = &node.page {
+            let loaded = page.load().map_err(S::Error::custom)?;
+            return loaded.subtree(loaded.root).serialize(serializer);
+        }
+        SerializedDrawNodeFields {
+            children: node.children.map(|row| {
+                row.map(|child| {
+                    child
+                        .filter(|child| self.tree.has_content(*child))
+                        .map(|child| self.tree.subtree(child))
+                })
+            }),
+            strokes: &node.strokes,
+            hash: node.hash(),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// The subtree `CanvasTree::trimmed` found, saved like a whole tree.
+pub enum Trimmed<'a> {
+    Loaded(Subtree<'a>),
+    /// Found within a paged out subtree, read back from its page.
+    Paged(CanvasTree, NodeId),
+}
+
+impl Serialize for Trimmed<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Wrapped like a whole tree, so it loads as one.
+        match self {
+            Trimmed::Loaded(subtree) => serializer.serialize_newtype_struct("DrawNodeRef", subtree),
+            Trimmed::Paged(tree, id) => {
+                serializer.serialize_newtype_struct("DrawNodeRef", &tree.subtree(*id))
+            }
+        }
+    }
+}
+
+/// The fields of a loaded `DrawNode` that are saved.
+#[derive(Serialize)]
+struct SerializedDrawNodeFields<'a> {
+    children: [[Option<Subtree<'a>>; 2]; 2],
+    strokes: &'a Vec<StrokeEntry>,
+    hash: Option<u64>,
 }
 
 #[derive(Deserialize, Serialize)]
 struct SerializedDrawNode {
     pub children: [[Option<Box<SerializedDrawNode>>; 2]; 2],
-    strokes: Vec<(Box<dyn CanvasDrawable>, u32)>,
+    strokes: Vec<StrokeEntry>,
+    /// Missing from files saved before hashes were added.
+    #[serde(default)]
+    hash: Option<u64>,
 }
 
-impl From<SerializedDrawNode> for DrawNodeRef {
+impl From<SerializedDrawNode> for CanvasTree {
+    /// Adds the nodes one at a time, as converting a deep tree recursively would overflow the
+    /// stack.
     fn from(value: SerializedDrawNode) -> Self {
-        let children: [[Option<Rc<RefCell<DrawNode>>>; 2]; 2] = value
-            .children
-            .map(|row| row.map(|child| child.map(|child| DrawNodeRef::from(*child).0)));
-        let result = DrawNodeRef(Rc::new(RefCell::new(DrawNode {
-            children,
-            strokes: value.strokes,
-            ..Default::default()
-        })));
-        for x in 0..=1 {
-            for y in 0..=1 {
-                if let Some(ref child) = result.0.borrow().children[y][x] {
-                    child.borrow_mut().corner = (x as u8, y as u8);
-                    child.borrow_mut().parent = Rc::downgrade(&result.0)
+        let mut tree = CanvasTree::with_root(DrawNode::new(value.strokes, value.hash));
+        let mut pending = vec![(tree.root, value.children)];
+        while let Some((parent, children)) = pending.pop() {
+            for (y, row) in children.into_iter().enumerate() {
+                for (x, child) in row.into_iter().enumerate() {
+                    let Some(child) = child else {
+                        continue;
+                    };
+                    let SerializedDrawNode {
+                        children,
+                        strokes,
+                        hash,
+                    } = *child;
+                    let child = tree.insert_child(parent, (x, y), DrawNode::new(strokes, hash));
+                    pending.push((child, children));
                 }
             }
         }
         //TODO stitch neighbors
-        result
+        tree
     }
 }
 
 #[derive(Deserialize, Serialize)]
 struct WrappedSerializedDrawNode(SerializedDrawNode);
-impl From<WrappedSerializedDrawNode> for DrawNodeRef {
+impl From<WrappedSerializedDrawNode> for CanvasTree {
     fn from(value: WrappedSerializedDrawNode) -> Self {
-        DrawNodeRef::from(value.0)
+        CanvasTree::from(value.0)
     }
 }
-#[derive(Deserialize, Serialize)]
+
+/// Every node of a canvas, linked to each other by id. The outermost node, the root, is the
+/// only one without a parent, and growing the canvas outward replaces it.
+///
+/// The tree is `Send` and `Sync`, so it can be saved, exported or drawn from other threads.
+/// The caches filled in through shared references are behind locks for that reason.
+#[derive(Deserialize)]
 #[serde(from = "WrappedSerializedDrawNode")]
-pub struct DrawNodeRef(pub Rc<RefCell<DrawNode>>);
+pub struct CanvasTree {
+    nodes: SlotMap<NodeId, DrawNode>,
+    root: NodeId,
+    /// Where changes to the strokes are recorded, once `record_ops` is called. Loading and
+    /// paging, which leave the content as it was, are not recorded.
+    ops: Option<OpSink>,
+}
+
+const _: () = {
+    const fn assert_thread_safe<T: Send + Sync>() {}
+    assert_thread_safe::<CanvasTree>();
+};
+
+impl Serialize for CanvasTree {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Wrapped like the reference to the outermost node saved before nodes were kept in a
+        // tree.
+        serializer.serialize_newtype_struct("DrawNodeRef", &self.subtree(self.root))
+    }
+}
+
+impl Default for CanvasTree {
+    fn default() -> Self {
+        CanvasTree::with_root(DrawNode::default())
+    }
+}
+
+impl Index<NodeId> for CanvasTree {
+    type Output = DrawNode;
+
+    fn index(&self, id: NodeId) -> &DrawNode {
+        &self.nodes[id]
+    }
+}
+
+impl IndexMut<NodeId> for CanvasTree {
+    fn index_mut(&mut self, id: NodeId) -> &mut DrawNode {
+        &mut self.nodes[id]
+    }
+}
 
 impl Default for DrawNode {
     fn default() -> Self {
         Self {
-            parent: Weak::new(),
-            children: [(); 2].map(|_| [(); 2].map(|_| None)),
+            parent: None,
+            children: [[None; 2]; 2],
             strokes: vec![],
+            index: StrokeIndex::default(),
             corner: (0, 0),
-            neighbors: (Weak::new(), Weak::new()),
+            neighbors: (None, None),
+            hash: Mutex::new(None),
+            summary: OnceLock::new(),
+            bounds: OnceLock::new(),
+            render: Mutex::new(None),
+            thumbnail: Mutex::new(None),
+            changed: true,
+            unsaved: true,
+            stored_chunk: false,
+            page: None,
+            last_viewed: 0,
         }
     }
 }
 
 impl DrawNode {
-    pub fn top_level() -> Rc<RefCell<Self>> {
-        let result = Self {
-            parent: Weak::new(),
-            children: [(); 2].map(|_| [(); 2].map(|_| None)),
-            strokes: vec![],
-            corner: (0, 0),
-            neighbors: (Weak::new(), Weak::new()),
-        };
-        let ref_cell = Rc::new(RefCell::new(result));
-        unsafe {
-            let ptr = Rc::into_raw(ref_cell.clone());
-            Rc::increment_strong_count(ptr);
-            Rc::from_raw(ptr);
+    /// A node holding `strokes`, not yet part of a tree.
+    pub fn new(strokes: Vec<StrokeEntry>, hash: Option<u64>) -> Self {
+        DrawNode {
+            index: StrokeIndex::new(strokes.iter().map(|stroke| stroke.drawable.bounds())),
+            strokes,
+            hash: Mutex::new(hash),
+            ..Default::default()
         }
-        ref_cell
     }
 
-    pub fn get_own_strokes(&self, screen_rect: Rect) -> Vec<(Box<dyn CanvasDrawable>, u32, Rect)> {
-        self.strokes
-            .iter()
-            .map(|(stroke, order)| (stroke.clone(), *order, screen_rect))
-            .collect_vec()
+    /// A node whose content is still in `page`, drawn from `summary` until loaded.
+    pub fn stub(page: Page, summary: NodeSummary, bounds: Option<Rect>, hash: Option<u64>) -> Self {
+        DrawNode {
+            hash: Mutex::new(hash),
+            summary: OnceLock::from(summary),
+            bounds: OnceLock::from(bounds),
+            page: Some(page),
+            ..Default::default()
+        }
     }
 
-    pub fn get_strokes(
+    pub fn is_paged(&self) -> bool {
+        self.page.is_some()
+    }
+
+    pub fn page(&self) -> Option<&Page> {
+        self.page.as_ref()
+    }
+
+    pub fn page_mut(&mut self) -> Option<&mut Page> {
+        self.page.as_mut()
+    }
+
+    pub fn read_ron<T: for<'de> Deserialize<'de>>(data: &str) -> Result<T, ron::Error> {
+        let mut deserializer = ron::de::Deserializer::from_str_with_options(
+            data,
+            ron::Options::default().without_recursion_limit(),
+        )
+        .map_err(|err| err.code)?;
+        T::deserialize(serde_stacker::Deserializer::new(&mut deserializer))
+    }
+
+    /// The summary of the subtree, if it is already computed.
+    pub fn cached_summary(&self) -> Option<&NodeSummary> {
+        self.summary.get()
+    }
+
+    /// The cached render of the subtree, if one was made since its content last changed.
+    pub fn render(&self) -> &Mutex<Option<NodeRender>> {
+        &self.render
+    }
+
+    /// The thumbnail of the subtree, if one was made since its content last changed.
+    pub fn thumbnail(&self) -> &Mutex<Option<Thumbnail>> {
+        &self.thumbnail
+    }
+
+    /// Drops the cached summary, render and thumbnail, which depend on the look of every stroke.
+    fn appearance_changed(&mut self) {
+        self.summary.take();
+        self.render.get_mut().unwrap().take();
+        self.thumbnail.get_mut().unwrap().take();
+        self.changed = true;
+        self.unsaved = true;
+    }
+
+    pub fn is_unsaved(&self) -> bool {
+        self.unsaved
+    }
+
+    /// Whether the look of the subtree changed since this was last called. Every change
+    /// reaches the outermost node, so checking it covers the whole tree.
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::take(&mut self.changed)
+    }
+
+    /// Drops the cached summary, render and bounds after this node's own content changed.
+    fn content_changed(&mut self) {
+        self.appearance_changed();
+        self.bounds.take();
+    }
+
+    /// Updates the cached summary, render and bounds after a stroke covering `rect` was added.
+    fn content_grown(&mut self, rect: Rect) {
+        self.appearance_changed();
+        if let Some(bounds) = self.bounds.get_mut() {
+            *bounds = Some(bounds.map_or(rect, |bounds| bounds.union(rect)));
+        }
+    }
+
+    /// `rect` in the coordinates of the child at `x`, `y`, in this node's coordinates.
+    fn from_child(rect: Rect, x: usize, y: usize) -> Rect {
+        let offset = vec2(x as f32 - 0.5, y as f32 - 0.5);
+        Rect::from_min_max(rect.min / 2.0 + offset, rect.max / 2.0 + offset)
+    }
+
+    /// Strokes stored directly in this node that `include` accepts.
+    pub fn get_own_strokes(
         &self,
         screen_rect: Rect,
-        depth: u32,
-    ) -> Vec<(Box<dyn CanvasDrawable>, u32, Rect)> {
-        let inner_to_rect = screen_rect.scale_from_center(0.5);
-        let mut strokes = self.get_own_strokes(screen_rect);
-        if depth == 0 {
-            return strokes;
+        include: &impl Fn(&StrokeEntry) -> bool,
+    ) -> Vec<(StrokeEntry, Rect)> {
+        self.strokes
+            .iter()
+            .filter(|stroke| include(stroke))
+            .map(|stroke| (stroke.clone(), screen_rect))
+            .collect()
+    }
+
+    /// Removes and returns the strokes stored directly in this node that `take` accepts.
+    pub fn take_strokes(&mut self, take: &impl Fn(&StrokeEntry) -> bool) -> Vec<StrokeEntry> {
+        let (taken, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.strokes)
+            .into_iter()
+            .partition(|stroke| take(stroke));
+        self.strokes = kept;
+        if !taken.is_empty() {
+            self.index =
+                StrokeIndex::new(self.strokes.iter().map(|stroke| stroke.drawable.bounds()));
+            self.content_changed();
         }
-        for y in 0..=1 {
-            for x in 0..=1 {
-                if self.children[y][x].is_none() {
-                    continue;
-                };
+        taken
+    }
 
-                strokes.extend(self.children[y][x].as_ref().unwrap().borrow().get_strokes(
-                    inner_to_rect.translate(vec2(
-                        (x as f32 - 0.5) * 0.5 * screen_rect.width(),
-                        (y as f32 - 0.5) * 0.5 * screen_rect.height(),
-                    )),
-                    depth - 1,
-                ));
-            }
+    /// `rect`, in this node's coordinates, in those of the child at `x`, `y`. None if no stroke
+    /// stored in the child can reach it.
+    fn child_query_rect(rect: Rect, x: usize, y: usize) -> Option<Rect> {
+        let offset = vec2(x as f32 - 0.5, y as f32 - 0.5);
+        let child_rect = Rect::from_min_max((rect.min - offset) * 2.0, (rect.max - offset) * 2.0);
+        // Strokes can overhang the node they are stored in.
+        NODE_BOUNDS
+            .expand(0.5)
+            .intersects(child_rect)
+            .then_some(child_rect)
+    }
+
+    /// Whether the node has neither strokes nor children.
+    fn is_empty(&self) -> bool {
+        !self.is_paged() && self.strokes.is_empty() && self.child_nodes().next().is_none()
+    }
+
+    /// Hash of the strokes stored directly in this node.
+    fn strokes_hash(&self) -> Option<u64> {
+        let mut hasher = ContentHasher::default();
+        hasher.write(ron::to_string(&self.strokes).ok()?.as_bytes());
+        Some(hasher.finish())
+    }
+
+    /// The children that exist, with their `(x, y)` corner.
+    pub fn child_nodes(&self) -> impl Iterator<Item = ((usize, usize), NodeId)> + '_ {
+        (0..=1)
+            .flat_map(|y| (0..=1).map(move |x| (x, y)))
+            .filter_map(|(x, y)| Some(((x, y), self.children[y][x]?)))
+    }
+
+    /// The screen rect of the child at corner `(x, y)` of a node covering `screen_rect`.
+    pub fn child_rect(screen_rect: Rect, x: usize, y: usize) -> Rect {
+        screen_rect.scale_from_center(0.5).translate(vec2(
+            (x as f32 - 0.5) * 0.5 * screen_rect.width(),
+            (y as f32 - 0.5) * 0.5 * screen_rect.height(),
+        ))
+    }
+
+    /// Calls `update` on every stroke stored directly in this node. It must return true if it
+    /// changed the stroke's bounds, so the index gets rebuilt.
+    pub fn update_own_strokes(&mut self, update: &mut impl FnMut(&mut StrokeEntry) -> bool) {
+        let mut changed = false;
+        for stroke in &mut self.strokes {
+            changed |= update(stroke);
+        }
+        // Updates that keep the bounds can still change the color.
+        if !self.strokes.is_empty() {
+            self.appearance_changed();
         }
+        if changed {
+            self.bounds.take();
+            self.index =
+                StrokeIndex::new(self.strokes.iter().map(|stroke| stroke.drawable.bounds()));
+        }
+    }
 
-        strokes
+    fn push_stroke(&mut self, stroke: StrokeEntry) {
+        let bounds = stroke.drawable.bounds();
+        self.index.push(bounds);
+        self.strokes.push(stroke);
+        self.content_grown(bounds);
     }
 
     pub fn get_parent_rect(&self, rect: Rect) -> Rect {
@@ -156,503 +470,1518 @@ impl DrawNode {
         ))
     }
 
-    pub fn draw_grid(&self, painter: &Painter, to_screen: RectTransform) {
-        let inner_to_rect = to_screen.to().scale_from_center(0.5);
-        for y in 0..=1 {
-            for x in 0..=1 {
-                if self.children[y][x].is_none() {
-                    continue;
-                };
-
-                self.children[y][x].as_ref().unwrap().borrow().draw_grid(
-                    painter,
-                    RectTransform::from_to(
-                        *to_screen.from(),
-                        inner_to_rect.translate(vec2(
-                            (x as f32 - 0.5) * 0.5 * to_screen.to().width(),
-                            (y as f32 - 0.5) * 0.5 * to_screen.to().height(),
-                        )),
-                    ),
-                );
+    /// The child whose quadrant holds all of `bounds`, in this node's coordinates.
+    fn fitting_child(bounds: Rect) -> Option<(usize, usize)> {
+        let side = |min: f32, max: f32| {
+            if max <= 0.0 {
+                Some(0)
+            } else if min >= 0.0 {
+                Some(1)
+            } else {
+                None
             }
-        }
+        };
+        Some((
+            side(bounds.min.x, bounds.max.x)?,
+            side(bounds.min.y, bounds.max.y)?,
+        ))
+    }
 
-        painter.rect_stroke(
-            Rect::from_min_max(to_screen * pos2(-1.0, -1.0), to_screen * pos2(1.0, 1.0)),
-            0.0,
-            Stroke::new(2.0, Color32::BLACK),
-        );
+    pub fn own_stroke_count(&self) -> usize {
+        self.strokes.len()
     }
 
-    pub fn send_stroke<T: CanvasDrawableGenerator + 'static>(
-        &mut self,
-        p1: Pos2,
-        p2: Pos2,
-        scale: f32,
-        stroke: &Stroke,
-        order: u32,
-        ref_self: Rc<RefCell<DrawNode>>,
-    ) {
-        if (p1 - p2).abs().max_elem() >= 0.5 {
-            self.strokes
-                .push((T::from_points(p1, p2, scale, stroke), order));
-            return;
-        }
-        let center = p1.lerp(p2, 0.5);
-        let x = if center.x > 0.0 { 1 } else { 0 };
-        let y = if center.y > 0.0 { 1 } else { 0 };
-        let mut new_p1 = p1;
-        let mut new_p2 = p2;
-        if x == 0 {
-            new_p1.x = p1.x + 0.5;
-            new_p2.x = p2.x + 0.5;
-        } else {
-            new_p1.x = p1.x - 0.5;
-            new_p2.x = p2.x - 0.5;
-        }
-        if y == 0 {
-            new_p1.y = p1.y + 0.5;
-            new_p2.y = p2.y + 0.5;
-        } else {
-            new_p1.y = p1.y - 0.5;
-            new_p2.y = p2.y - 0.5;
-        }
-        new_p1 = 2.0 * new_p1;
-        new_p2 = 2.0 * new_p2;
-        if self.children[y][x].is_none() {
-            self.create_child_wo_ref(x, y, ref_self);
-        }
-        let ref_child = self.children[y][x].as_ref().unwrap().clone();
-        self.children[y][x]
-            .as_mut()
-            .unwrap()
-            .clone()
-            .borrow_mut()
-            .send_stroke_w_ref::<T>(self, new_p1, new_p2, 2.0 * scale, stroke, order, ref_child);
+    pub fn hash(&self) -> Option<u64> {
+        *self.hash.lock().unwrap()
     }
 
-    #[allow(clippy::too_many_arguments)]
-    pub fn send_stroke_w_ref<T: CanvasDrawableGenerator + 'static>(
-        &mut self,
-        parent: &DrawNode,
-        p1: Pos2,
-        p2: Pos2,
-        scale: f32,
-        stroke: &Stroke,
-        order: u32,
-        ref_self: Rc<RefCell<DrawNode>>,
-    ) {
-        if (p1 - p2).abs().max_elem() >= 0.5 {
-            self.strokes
-                .push((T::from_points(p1, p2, scale, stroke), order));
-            return;
-        }
-        let center = p1.lerp(p2, 0.5);
-        let x = if center.x > 0.0 { 1 } else { 0 };
-        let y = if center.y > 0.0 { 1 } else { 0 };
-        let mut new_p1 = p1;
-        let mut new_p2 = p2;
-        if x == 0 {
-            new_p1.x = p1.x + 0.5;
-            new_p2.x = p2.x + 0.5;
-        } else {
-            new_p1.x = p1.x - 0.5;
-            new_p2.x = p2.x - 0.5;
-        }
-        if y == 0 {
-            new_p1.y = p1.y + 0.5;
-            new_p2.y = p2.y + 0.5;
-        } else {
-            new_p1.y = p1.y - 0.5;
-            new_p2.y = p2.y - 0.5;
-        }
-        new_p1 = 2.0 * new_p1;
-        new_p2 = 2.0 * new_p2;
-        if self.children[y][x].is_none() {
-            self.create_child(x, y, ref_self, parent);
-        }
-        let ref_child = self.children[y][x].as_ref().unwrap().clone();
-        self.children[y][x]
-            .as_mut()
-            .unwrap()
-            .clone()
-            .borrow_mut()
-            .send_stroke_w_ref::<T>(self, new_p1, new_p2, 2.0 * scale, stroke, order, ref_child);
+    pub fn own_strokes(&self) -> &[StrokeEntry] {
+        &self.strokes
     }
 
-    fn create_child(
-        &mut self,
-        x: usize,
-        y: usize,
-        ref_self: Rc<RefCell<DrawNode>>,
-        parent: &DrawNode,
-    ) {
-        self.children[y][x] = Some(Rc::new(RefCell::new(DrawNode::default())));
-        self.children[y][x].as_mut().unwrap().borrow_mut().parent = Rc::downgrade(&ref_self);
-        self.children[y][x].as_mut().unwrap().borrow_mut().corner = (x as u8, y as u8);
-        let horizontal_neighbor = self.get_neighbor_w_parent_ref(
-            parent,
-            if x == 1 {
-                Direction::PosX
-            } else {
-                Direction::NegX
-            },
-        );
-        let vertical_neighbor = self.get_neighbor_w_parent_ref(
-            parent,
-            if y == 1 {
-                Direction::PosY
-            } else {
-                Direction::NegY
-            },
-        );
-        self.children[y][x]
-            .as_mut()
-            .unwrap()
-            .borrow_mut()
-            .neighbors
-            .0 = horizontal_neighbor
-            .and_then(|neighbor| {
-                neighbor.borrow().children[y][1 - x]
-                    .as_ref()
-                    .map(Rc::downgrade)
-            })
-            .unwrap_or_default();
-        self.children[y][x]
-            .as_mut()
-            .unwrap()
-            .borrow_mut()
-            .neighbors
-            .1 = vertical_neighbor
-            .and_then(|neighbor| {
-                neighbor.borrow().children[1 - y][x]
-                    .as_ref()
-                    .map(Rc::downgrade)
-            })
-            .unwrap_or_default();
-        if let Some(ref horizontal_neighbor) = self.children[y][x]
-            .as_ref()
-            .unwrap()
-            .borrow()
-            .neighbors
-            .0
-            .upgrade()
-        {
-            horizontal_neighbor.borrow_mut().neighbors.0 = self.children[y][x]
-                .clone()
-                .map(|child| Rc::downgrade(&child))
-                .unwrap_or_default();
-        }
-        if let Some(ref vertical_neighbor) = self.children[y][x]
-            .as_ref()
-            .unwrap()
-            .borrow()
-            .neighbors
-            .1
-            .upgrade()
-        {
-            vertical_neighbor.borrow_mut().neighbors.1 = self.children[y][x]
-                .clone()
-                .map(|child| Rc::downgrade(&child))
-                .unwrap_or_default();
-        }
+    /// Union of the bounds of the strokes stored directly in this node, kept up to date as
+    /// strokes are added and removed.
+    pub fn own_bounds(&self) -> Option<Rect> {
+        self.index.union()
     }
+}
 
-    fn create_child_wo_ref(&mut self, x: usize, y: usize, ref_self: Rc<RefCell<DrawNode>>) {
-        self.children[y][x] = Some(Rc::new(RefCell::new(DrawNode::default())));
-        self.children[y][x].as_mut().unwrap().borrow_mut().parent = Rc::downgrade(&ref_self);
-        self.children[y][x].as_mut().unwrap().borrow_mut().corner = (x as u8, y as u8);
-        let horizontal_neighbor = self.get_neighbor(if x == 1 {
-            Direction::PosX
-        } else {
-            Direction::NegX
-        });
-        let vertical_neighbor = self.get_neighbor(if y == 1 {
-            Direction::PosY
-        } else {
-            Direction::NegY
-        });
-        self.children[y][x]
-            .as_mut()
-            .unwrap()
-            .borrow_mut()
-            .neighbors
-            .0 = horizontal_neighbor
-            .and_then(|neighbor| {
-                neighbor.borrow().children[y][1 - x]
-                    .as_ref()
-                    .map(Rc::downgrade)
-            })
-            .unwrap_or_default();
-        self.children[y][x]
-            .as_mut()
-            .unwrap()
-            .borrow_mut()
-            .neighbors
-            .1 = vertical_neighbor
-            .and_then(|neighbor| {
-                neighbor.borrow().children[1 - y][x]
-                    .as_ref()
-                    .map(Rc::downgrade)
-            })
-            .unwrap_or_default();
-        if let Some(ref horizontal_neighbor) = self.children[y][x]
-            .as_ref()
-            .unwrap()
-            .borrow()
-            .neighbors
-            .0
-            .upgrade()
-        {
-            horizontal_neighbor.borrow_mut().neighbors.0 = self.children[y][x]
-                .clone()
-                .map(|child| Rc::downgrade(&child))
-                .unwrap_or_default();
-        }
-        if let Some(ref vertical_neighbor) = self.children[y][x]
-            .as_ref()
-            .unwrap()
-            .borrow()
-            .neighbors
-            .1
-            .upgrade()
-        {
-            vertical_neighbor.borrow_mut().neighbors.1 = self.children[y][x]
-                .clone()
-                .map(|child| Rc::downgrade(&child))
-                .unwrap_or_default();
+impl CanvasTree {
+    /// A tree of just `root`.
+    pub fn with_root(root: DrawNode) -> Self {
+        let mut nodes = SlotMap::with_key();
+        let root = nodes.insert(root);
+        CanvasTree {
+            nodes,
+            root,
+            ops: None,
         }
     }
 
-    fn create_neighborless_child_wo_ref(
-        &mut self,
-        x: usize,
-        y: usize,
-        ref_self: Rc<RefCell<DrawNode>>,
-    ) {
-        self.children[y][x] = Some(Rc::new(RefCell::new(DrawNode::default())));
-        self.children[y][x].as_mut().unwrap().borrow_mut().parent = Rc::downgrade(&ref_self);
-        self.children[y][x].as_mut().unwrap().borrow_mut().corner = (x as u8, y as u8);
+    /// Records the changes made to the strokes from now on in `ops`.
+    pub fn record_ops(&mut self, ops: &OpSink) {
+        self.ops = Some(ops.clone());
     }
 
-    pub fn get_or_create_parent(
-        &mut self,
-        ref_self: Rc<RefCell<DrawNode>>,
-    ) -> Rc<RefCell<DrawNode>> {
-        if let Some(parent) = self.parent.upgrade() {
-            return parent.clone();
+    /// Records the change `op` makes if changes are being recorded.
+    fn record(&self, op: impl FnOnce() -> Op) {
+        if let Some(ops) = &self.ops {
+            ops.push(op());
         }
-        let mut parent = DrawNode {
-            corner: (1 - self.corner.0, 1 - self.corner.1),
-            ..DrawNode::default()
-        };
-        parent.children[self.corner.1 as usize][self.corner.0 as usize] = Some(ref_self.clone());
+    }
 
-        let parent = Rc::new(RefCell::new(parent));
-        unsafe {
-            let ptr = Rc::into_raw(parent.clone());
-            Rc::increment_strong_count(ptr);
-            Rc::from_raw(ptr);
-        }
-        self.parent = Rc::downgrade(&parent);
+    /// The path to `id` from the outermost node, in the order `get_or_create_path` takes it.
+    fn path_to(&self, id: NodeId) -> Vec<(u8, u8)> {
+        self.get_top_level_and_path(id).1
+    }
 
-        unsafe {
-            let ptr = Rc::into_raw(ref_self.clone());
-            Rc::decrement_strong_count(ptr);
-            Rc::from_raw(ptr);
-        }
+    /// The outermost node.
+    pub fn root(&self) -> NodeId {
+        self.root
+    }
 
-        parent
+    /// The node `id` refers to, unless it was removed.
+    pub fn get(&self, id: NodeId) -> Option<&DrawNode> {
+        self.nodes.get(id)
     }
 
-    pub fn get_or_create_child_from_corner(
-        &mut self,
-        corner: (u8, u8),
-        ref_self: Rc<RefCell<DrawNode>>,
-    ) -> Rc<RefCell<DrawNode>> {
-        if self.children[corner.1 as usize][corner.0 as usize].is_none() {
-            self.create_child_wo_ref(corner.0 as usize, corner.1 as usize, ref_self);
-        }
-        self.children[corner.1 as usize][corner.0 as usize]
-            .clone()
-            .unwrap()
+    pub fn get_mut(&mut self, id: NodeId) -> Option<&mut DrawNode> {
+        self.nodes.get_mut(id)
     }
 
-    pub fn get_or_create_neighborless_child_from_corner(
-        &mut self,
-        corner: (u8, u8),
-        ref_self: Rc<RefCell<DrawNode>>,
-    ) -> Rc<RefCell<DrawNode>> {
-        if self.children[corner.1 as usize][corner.0 as usize].is_none() {
-            self.create_neighborless_child_wo_ref(corner.0 as usize, corner.1 as usize, ref_self);
-        }
-        self.children[corner.1 as usize][corner.0 as usize]
-            .clone()
-            .unwrap()
+    pub fn contains(&self, id: NodeId) -> bool {
+        self.nodes.contains_key(id)
     }
 
-    fn get_neighbor_w_parent_ref(
-        &self,
-        parent: &DrawNode,
-        direction: Direction,
-    ) -> Option<Rc<RefCell<DrawNode>>> {
-        if direction.is_vertical() {
-            if self.corner.1 != direction.is_positive() as u8 {
-                parent.children[(1 - self.corner.1) as usize][self.corner.0 as usize].clone()
-            } else {
-                self.neighbors.1.upgrade().clone()
-            }
-        } else if self.corner.0 != direction.is_positive() as u8 {
-            parent.children[self.corner.1 as usize][(1 - self.corner.0) as usize].clone()
-        } else {
-            self.neighbors.0.upgrade().clone()
-        }
+    /// The node `id` and its descendants, for serializing.
+    pub fn subtree(&self, id: NodeId) -> Subtree<'_> {
+        Subtree { tree: self, id }
     }
 
-    pub fn get_neighbor(&self, direction: Direction) -> Option<Rc<RefCell<DrawNode>>> {
-        if direction.is_vertical() {
-            if self.corner.1 != direction.is_positive() as u8 {
-                let parent = self.parent.upgrade()?;
-                parent.clone().borrow().children[(1 - self.corner.1) as usize]
-                    [self.corner.0 as usize]
-                    .clone()
-            } else {
-                self.neighbors.1.upgrade().clone()
+    /// The smallest subtree of `id` holding all its strokes, and the corners leading to it from
+    /// `id`, innermost first. Saved, it depends only on the strokes, not on how far the tree
+    /// grew around them. Paged out subtrees along the way are read back from their pages.
+    pub fn trimmed(&self, id: NodeId) -> Result<(Trimmed<'_>, Vec<(u8, u8)>), String> {
+        let mut above = vec![];
+        let mut loaded: Option<CanvasTree> = None;
+        let mut node = id;
+        loop {
+            let tree = loaded.as_ref().unwrap_or(self);
+            if let Some(page) = tree[node].page() {
+                let page = page.load()?;
+                node = page.root;
+                loaded = Some(page);
+                continue;
+            }
+            let mut holding = tree[node]
+                .child_nodes()
+                .filter(|(_, child)| tree.has_content(*child));
+            match (
+                tree[node].strokes.is_empty(),
+                holding.next(),
+                holding.next(),
+            ) {
+                (true, Some(((x, y), child)), None) => {
+                    above.insert(0, (x as u8, y as u8));
+                    node = child;
+                }
+                _ => break,
             }
-        } else if self.corner.0 != direction.is_positive() as u8 {
-            let parent = self.parent.upgrade()?;
-            parent.clone().borrow().children[self.corner.1 as usize][(1 - self.corner.0) as usize]
-                .clone()
-        } else {
-            self.neighbors.0.upgrade().clone()
         }
+        let trimmed = match loaded {
+            Some(tree) => Trimmed::Paged(tree, node),
+            None => Trimmed::Loaded(self.subtree(node)),
+        };
+        Ok((trimmed, above))
     }
 
-    pub fn get_or_create_neighbor(
+    /// Adds `node` as the child at `(x, y)` of `parent`, without linking its neighbors.
+    pub fn insert_child(
         &mut self,
-        direction: Direction,
-        ref_self: Rc<RefCell<DrawNode>>,
-    ) -> Rc<RefCell<DrawNode>> {
-        let parent = self.get_or_create_parent(ref_self.clone());
-        if direction.is_vertical() {
-            if self.corner.1 != direction.is_positive() as u8 {
-                if parent.clone().borrow().children[(1 - self.corner.1) as usize]
-                    [self.corner.0 as usize]
-                    .is_none()
-                {
-                    parent.clone().borrow_mut().create_child_wo_ref(
-                        self.corner.0 as usize,
-                        (1 - self.corner.1) as usize,
-                        parent.clone(),
-                    );
-                }
-                parent.clone().borrow().children[(1 - self.corner.1) as usize]
-                    [self.corner.0 as usize]
-                    .clone()
-                    .unwrap()
-            } else {
-                if self.neighbors.1.upgrade().is_none() {
-                    let parent_neighbor = parent
-                        .borrow_mut()
-                        .get_or_create_neighbor(direction, parent.clone());
-                    let new_neighbor = parent_neighbor
-                        .borrow_mut()
-                        .get_or_create_neighborless_child_from_corner(
-                            (self.corner.0, 1 - self.corner.1),
-                            parent_neighbor.clone(),
-                        );
-                    new_neighbor.borrow_mut().neighbors.1 = Rc::downgrade(&ref_self);
-                    self.neighbors.1 = Rc::downgrade(&new_neighbor);
-                }
-                self.neighbors.1.upgrade().clone().unwrap()
-            }
-        } else if self.corner.0 != direction.is_positive() as u8 {
-            if parent.clone().borrow().children[self.corner.1 as usize]
-                [(1 - self.corner.0) as usize]
-                .is_none()
-            {
-                parent.clone().borrow_mut().create_child_wo_ref(
-                    (1 - self.corner.0) as usize,
-                    self.corner.1 as usize,
-                    parent.clone(),
-                );
+        parent: NodeId,
+        (x, y): (usize, usize),
+        node: DrawNode,
+    ) -> NodeId {
+        let child = self.nodes.insert(DrawNode {
+            parent: Some(parent),
+            corner: (x as u8, y as u8),
+            ..node
+        });
+        self[parent].children[y][x] = Some(child);
+        child
+    }
+
+    /// Removes `id` and its descendants from the tree.
+    fn remove_subtree(&mut self, id: NodeId) {
+        if let Some(node) = self.get(id) {
+            let (parent, (x, y)) = (node.parent, node.corner);
+            if let Some(parent) = parent {
+                self[parent].children[y as usize][x as usize] = None;
             }
-            parent.clone().borrow().children[self.corner.1 as usize][(1 - self.corner.0) as usize]
-                .clone()
-                .unwrap()
-        } else {
-            if self.neighbors.0.upgrade().is_none() {
-                let parent_neighbor = parent
-                    .borrow_mut()
-                    .get_or_create_neighbor(direction, parent.clone());
-                let new_neighbor = parent_neighbor
-                    .borrow_mut()
-                    .get_or_create_neighborless_child_from_corner(
-                        (1 - self.corner.0, self.corner.1),
-                        parent_neighbor.clone(),
-                    );
-                new_neighbor.borrow_mut().neighbors.0 = Rc::downgrade(&ref_self);
-                self.neighbors.0 = Rc::downgrade(&new_neighbor);
+        }
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            if let Some(node) = self.nodes.remove(id) {
+                stack.extend(node.child_nodes().map(|(_, child)| child));
             }
-            self.neighbors.0.upgrade().clone().unwrap()
         }
     }
 
-    pub fn try_cleanup(&self) {
-        if self.children.iter().flatten().any(|child| child.is_some()) {
-            return;
+    /// Passes each stroke in the node `id` and up to `depth` levels below it to `visitor`,
+    /// paired with the screen rect of its node, without copying them. Children without content
+    /// are skipped, as are those `visitor` declines to enter.
+    pub fn for_each_stroke(
+        &self,
+        id: NodeId,
+        screen_rect: Rect,
+        depth: u32,
+        visitor: &mut impl StrokeVisitor,
+    ) {
+        let node = &self[id];
+        for stroke in &node.strokes {
+            visitor.stroke(stroke, screen_rect);
         }
-        if !self.strokes.is_empty() {
+        if depth == 0 {
             return;
         }
-        let Some(parent) = self.parent.upgrade() else {
-            return;
-        };
-        parent.borrow_mut().children[self.corner.1 as usize][self.corner.0 as usize] = None;
+        for ((x, y), child) in node.child_nodes() {
+            let child_rect = DrawNode::child_rect(screen_rect, x, y);
+            if self.content_bounds(child).is_none() || !visitor.enter(self, child, child_rect) {
+                continue;
+            }
+            self.for_each_stroke(child, child_rect, depth - 1, visitor);
+        }
     }
 
-    #[tailcall]
-    pub fn get_top_level_and_path(
-        mut path: Vec<(u8, u8)>,
-        ref_self: Rc<RefCell<DrawNode>>,
-    ) -> (Rc<RefCell<DrawNode>>, Vec<(u8, u8)>) {
-        let Some(parent) = ref_self.borrow().parent.upgrade() else {
-            return (ref_self, path);
-        };
-        path.push(ref_self.borrow().corner);
-        DrawNode::get_top_level_and_path(path, parent.clone())
+    /// Writes the children and strokes of `id` out to a page and removes them, keeping the
+    /// summary and bounds needed to draw it from a distance.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn page_out(&mut self, id: NodeId) -> Result<(), String> {
+        self.page_out_with(id, |tree| {
+            // Pages never outlive the process, so they take the format fastest to read back.
+            let data = SaveFormat::Binary.encode(&tree.subtree(id))?;
+            Page::write(&data).map_err(|err| err.to_string())
+        })
     }
 
-    pub fn follow_path(
-        &self,
-        path: &mut Vec<(u8, u8)>,
-        ref_self: Rc<RefCell<DrawNode>>,
-    ) -> Rc<RefCell<DrawNode>> {
-        let Some(corner) = path.pop() else {
-            return ref_self;
-        };
-        return self.children[corner.1 as usize][corner.0 as usize]
-            .as_ref()
-            .unwrap()
-            .borrow()
-            .follow_path(
-                path,
-                self.children[corner.1 as usize][corner.0 as usize]
-                    .as_ref()
-                    .unwrap()
-                    .clone(),
-            );
+    /// Replaces the children and strokes of `id` with the page `write` keeps them in, keeping
+    /// the summary and bounds needed to draw it from a distance.
+    pub fn page_out_with(
+        &mut self,
+        id: NodeId,
+        write: impl FnOnce(&Self) -> Result<Page, String>,
+    ) -> Result<(), String> {
+        if self[id].is_paged() {
+            return Ok(());
+        }
+        self.summary(id);
+        let nodes = self.stats(id).nodes;
+        if let Some(summary) = self[id].summary.get_mut() {
+            summary.set_node_count(nodes);
+        }
+        self.content_bounds(id);
+        self.update_hashes(id);
+        let page = write(self)?;
+        for (_, child) in self[id].child_nodes().collect_vec() {
+            self.remove_subtree(child);
+        }
+        let node = &mut self[id];
+        node.page = Some(page);
+        node.strokes = vec![];
+        node.index = StrokeIndex::default();
+        Ok(())
     }
-}
 
-#[allow(private_bounds)]
-pub trait CanvasDrawableGenerator: CanvasDrawable {
-    fn from_points(p1: Pos2, p2: Pos2, scale: f32, stroke: &Stroke) -> Box<Self>;
-}
+    /// Loads the subtree of `id` back if it is paged out. Cached renders of its ancestors were
+    /// drawn with the node's summary in its place, so they are dropped. Returns whether it is
+    /// loaded, which pages read asynchronously only are on a later frame.
+    pub fn page_in(&mut self, id: NodeId) -> bool {
+        if !self[id].is_paged() {
+            return true;
+        }
+        if !self.load_page(id) {
+            return false;
+        }
+        let mut parent = self[id].parent;
+        while let Some(id) = parent {
+            let node = &mut self[id];
+            node.render.get_mut().unwrap().take();
+            node.thumbnail.get_mut().unwrap().take();
+            node.changed = true;
+            parent = node.parent;
+        }
+        true
+    }
+
+    /// Loads every paged out subtree below `id`, for actions that need the whole tree. Returns
+    /// whether all of them are loaded.
+    pub fn page_in_all(&mut self, id: NodeId) -> bool {
+        let mut loaded = true;
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            loaded &= self.page_in(id);
+            stack.extend(self[id].child_nodes().map(|(_, child)| child));
+        }
+        loaded
+    }
+
+    /// Pages in `id` and the nodes up to `depth` levels below it that overlap `rect`, in its
+    /// coordinates, so that `query_rect` sees every stroke there.
+    pub fn page_in_rect(&mut self, id: NodeId, rect: Rect, depth: u32) {
+        let mut pending = vec![(id, rect, depth)];
+        while let Some((id, rect, depth)) = pending.pop() {
+            self.page_in(id);
+            if depth == 0 {
+                continue;
+            }
+            for ((x, y), child) in self[id].child_nodes().collect_vec() {
+                if let Some(child_rect) = DrawNode::child_query_rect(rect, x, y) {
+                    pending.push((child, child_rect, depth - 1));
+                }
+            }
+        }
+    }
+
+    /// Loads the paged out children and strokes of `id`, returning whether it did. Pages still
+    /// being fetched are left as they are.
+    fn load_page(&mut self, id: NodeId) -> bool {
+        let Some(page) = self[id].page.take() else {
+            return false;
+        };
+        if !page.fetch() {
+            self[id].page = Some(page);
+            return false;
+        }
+        match page.load() {
+            Ok(loaded) => {
+                page.loaded();
+                self.graft(id, loaded);
+                true
+            }
+            Err(err) => {
+                log::error!("Failed to load paged out subtree: {err}");
+                self[id].page = Some(page);
+                false
+            }
+        }
+    }
+
+    /// Moves the strokes of the root of `other` into the node `id`, and the root's descendants
+    /// below it. Nodes created below `id` while its page was being fetched keep their ids,
+    /// taking in the nodes loaded in their place.
+    fn graft(&mut self, id: NodeId, mut other: CanvasTree) {
+        let root = other.nodes.remove(other.root).expect("A tree has a root");
+        let mut pending = root
+            .child_nodes()
+            .map(|(corner, child)| (id, corner, child))
+            .collect_vec();
+        let node = &mut self[id];
+        node.strokes.extend(root.strokes);
+        node.index = StrokeIndex::new(node.strokes.iter().map(|stroke| stroke.drawable.bounds()));
+        node.hash = root.hash;
+        while let Some((parent, corner, child)) = pending.pop() {
+            let Some(mut child) = other.nodes.remove(child) else {
+                continue;
+            };
+            let children = std::mem::take(&mut child.children);
+            let child = match self[parent].children[corner.1][corner.0] {
+                Some(existing) => {
+                    self.take_in(existing, child);
+                    existing
+                }
+                None => {
+                    child.neighbors = (None, None);
+                    self.insert_child(parent, corner, child)
+                }
+            };
+            for (y, row) in children.into_iter().enumerate() {
+                for (x, grandchild) in row.into_iter().enumerate() {
+                    pending.extend(grandchild.map(|grandchild| (child, (x, y), grandchild)));
+                }
+            }
+        }
+    }
+
+    /// Makes the node `id`, created while the page holding it was being fetched, the node
+    /// `loaded` read from the page in its place, keeping its children and the strokes added to
+    /// it meanwhile.
+    fn take_in(&mut self, id: NodeId, loaded: DrawNode) {
+        let node = &mut self[id];
+        let added = std::mem::take(&mut node.strokes);
+        *node = DrawNode {
+            parent: node.parent,
+            corner: node.corner,
+            neighbors: node.neighbors,
+            children: node.children,
+            unsaved: node.unsaved,
+            ..loaded
+        };
+        if added.is_empty() {
+            return;
+        }
+        node.strokes.extend(added);
+        node.index = StrokeIndex::new(node.strokes.iter().map(|stroke| stroke.drawable.bounds()));
+        *node.hash.get_mut().unwrap() = None;
+        node.content_changed();
+        self.ancestors_changed(id);
+    }
+
+    /// Summary of the content of the node `id` and its descendants, computed on first use and
+    /// kept until it changes.
+    pub fn summary(&self, id: NodeId) -> &NodeSummary {
+        let node = &self[id];
+        node.summary.get_or_init(|| {
+            let children = node
+                .child_nodes()
+                .map(|(corner, child)| (corner, self.summary(child)))
+                .collect_vec();
+            NodeSummary::new(&node.strokes, children)
+        })
+    }
+
+    /// Marks the subtree of `id` as saved. Every change reaches the outermost node, so only the
+    /// nodes marked unsaved are visited.
+    pub fn mark_saved(&mut self, id: NodeId) {
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            let node = &mut self[id];
+            if std::mem::take(&mut node.unsaved) {
+                stack.extend(node.child_nodes().map(|(_, child)| child));
+            }
+        }
+    }
+
+    /// Marks `id` and its ancestors unsaved again, after saving them failed.
+    pub fn mark_unsaved(&mut self, id: NodeId) {
+        let mut node = Some(id);
+        while let Some(current) = node.and_then(|id| self.get_mut(id)) {
+            current.unsaved = true;
+            node = current.parent;
+        }
+    }
+
+    /// Updates the cached summary and bounds of `id` after strokes were added to its child at
+    /// `x`, `y`.
+    fn child_grown(&mut self, id: NodeId, x: usize, y: usize) {
+        let child_bounds =
+            self[id].children[y][x].and_then(|child| self[child].bounds.get().copied());
+        let node = &mut self[id];
+        match child_bounds {
+            Some(Some(child_bounds)) => {
+                node.content_grown(DrawNode::from_child(child_bounds, x, y))
+            }
+            _ => node.content_changed(),
+        }
+    }
+
+    /// Drops the cached summary, render and bounds of `id` if a child's summary or bounds were
+    /// dropped, after changing its children.
+    fn children_changed(&mut self, id: NodeId) {
+        let (mut summary, mut bounds) = (false, false);
+        for (_, child) in self[id].child_nodes() {
+            let child = &self[child];
+            summary |= child.summary.get().is_none();
+            bounds |= child.bounds.get().is_none();
+        }
+        let node = &mut self[id];
+        if summary {
+            node.appearance_changed();
+        }
+        if bounds {
+            node.bounds.take();
+        }
+    }
+
+    /// Drops the cached summaries and bounds of the ancestors of `id`, after changing its
+    /// content directly. Methods changing a node's descendants keep the caches in between
+    /// current themselves.
+    pub fn ancestors_changed(&mut self, id: NodeId) {
+        let mut parent = self[id].parent;
+        while let Some(id) = parent {
+            let node = &mut self[id];
+            node.content_changed();
+            parent = node.parent;
+        }
+    }
+
+    /// Grows the cached bounds of the ancestors of `id` to cover it, after strokes were added
+    /// to it.
+    fn ancestors_grown(&mut self, id: NodeId) {
+        let mut node = id;
+        while let Some(parent) = self[node].parent {
+            let corner = self[node].corner;
+            self.child_grown(parent, corner.0 as usize, corner.1 as usize);
+            node = parent;
+        }
+    }
+
+    pub fn get_strokes(
+        &self,
+        id: NodeId,
+        screen_rect: Rect,
+        depth: u32,
+        include: &impl Fn(&StrokeEntry) -> bool,
+    ) -> Vec<(StrokeEntry, Rect)> {
+        let mut strokes = vec![];
+        self.for_each_stroke(
+            id,
+            screen_rect,
+            depth,
+            &mut |stroke: &StrokeEntry, screen_rect| {
+                if include(stroke) {
+                    strokes.push((stroke.clone(), screen_rect));
+                }
+            },
+        );
+        strokes
+    }
+
+    /// Strokes in the node `id` and up to `depth` levels below it that paint within
+    /// `tolerance` of the screen position `point`, paired with the screen rect of their node.
+    pub fn strokes_at(
+        &self,
+        id: NodeId,
+        screen_rect: Rect,
+        point: Pos2,
+        tolerance: f32,
+        depth: u32,
+    ) -> Vec<(StrokeEntry, Rect)> {
+        let node = &self[id];
+        let from_screen = RectTransform::from_to(screen_rect, NODE_BOUNDS);
+        let query =
+            Rect::from_center_size(from_screen * point, 2.0 * tolerance * from_screen.scale());
+        let node_tolerance = tolerance * from_screen.scale().max_elem();
+        let mut strokes = node
+            .index
+            .query(query)
+            .into_iter()
+            .map(|stroke| &node.strokes[stroke])
+            .filter(|stroke| {
+                stroke
+                    .drawable
+                    .hit_test(from_screen * point, node_tolerance)
+            })
+            .map(|stroke| (stroke.clone(), screen_rect))
+            .collect_vec();
+        if depth == 0 {
+            return strokes;
+        }
+        for ((x, y), child) in node.child_nodes() {
+            let child_rect = DrawNode::child_rect(screen_rect, x, y);
+            // Strokes are stored in the child containing their center, so they can reach a
+            // quarter of the child's size past its edges.
+            if !child_rect
+                .expand2(child_rect.size() / 4.0 + Vec2::splat(tolerance))
+                .contains(point)
+            {
+                continue;
+            }
+            strokes.extend(self.strokes_at(child, child_rect, point, tolerance, depth - 1));
+        }
+        strokes
+    }
+
+    /// Removes the strokes in the node `id` and its descendants that `keep` rejects. Returns
+    /// the orders of the strokes removed.
+    pub fn retain_strokes(
+        &mut self,
+        id: NodeId,
+        keep: &impl Fn(&StrokeEntry) -> bool,
+    ) -> BTreeSet<u64> {
+        let mut removed: BTreeSet<u64> = self
+            .take_strokes(id, &|stroke| !keep(stroke))
+            .into_iter()
+            .map(|stroke| stroke.order)
+            .collect();
+        for child in self[id].children.into_iter().flatten().flatten() {
+            removed.extend(self.retain_strokes(child, keep));
+        }
+        self.children_changed(id);
+        removed
+    }
+
+    /// Removes the strokes in the node `id` and its descendants whose bounds intersect `rect`,
+    /// in that node's coordinates, unless `keep` accepts them. Descendants left without
+    /// strokes or children are removed. Returns the orders of the strokes removed.
+    pub fn delete_strokes_in(
+        &mut self,
+        id: NodeId,
+        rect: Rect,
+        keep: &impl Fn(&StrokeEntry) -> bool,
+    ) -> BTreeSet<u64> {
+        let mut deleted = BTreeSet::new();
+        if !self
+            .content_bounds(id)
+            .is_some_and(|bounds| bounds.intersects(rect))
+        {
+            return deleted;
+        }
+        if self[id]
+            .own_bounds()
+            .is_some_and(|bounds| bounds.intersects(rect))
+        {
+            deleted.extend(
+                self.take_strokes(id, &|stroke| {
+                    stroke.drawable.bounds().intersects(rect) && !keep(stroke)
+                })
+                .into_iter()
+                .map(|stroke| stroke.order),
+            );
+        }
+        for ((x, y), child) in self[id].child_nodes().collect_vec() {
+            let Some(child_rect) = DrawNode::child_query_rect(rect, x, y) else {
+                continue;
+            };
+            self.load_page(child);
+            deleted.extend(self.delete_strokes_in(child, child_rect, keep));
+            if self[child].is_empty() {
+                self.remove_subtree(child);
+            }
+        }
+        self.children_changed(id);
+        deleted
+    }
+
+    /// Handles to the strokes in the node `id` and up to `depth` levels below it whose bounds
+    /// intersect `rect`, in that node's coordinates. Only the children overlapping `rect` are
+    /// searched.
+    pub fn query_rect(&self, id: NodeId, rect: Rect, depth: u32) -> Vec<StrokeRef> {
+        let mut refs = vec![];
+        let mut pending = vec![(id, rect, RectTransform::identity(NODE_BOUNDS), depth)];
+        while let Some((id, rect, to_query, depth)) = pending.pop() {
+            let node = &self[id];
+            let orders = node
+                .index
+                .query(rect)
+                .into_iter()
+                .map(|stroke| node.strokes[stroke].order)
+                .unique();
+            refs.extend(orders.map(|order| StrokeRef {
+                node: id,
+                order,
+                to_query,
+            }));
+            if depth == 0 {
+                continue;
+            }
+            for ((x, y), child) in node.child_nodes() {
+                let Some(child_rect) = DrawNode::child_query_rect(rect, x, y) else {
+                    continue;
+                };
+                let quadrant =
+                    Rect::from_center_size(pos2(x as f32 - 0.5, y as f32 - 0.5), Vec2::splat(1.0));
+                pending.push((
+                    child,
+                    child_rect,
+                    RectTransform::from_to(NODE_BOUNDS, to_query.transform_rect(quadrant)),
+                    depth - 1,
+                ));
+            }
+        }
+        refs
+    }
+
+    /// Combines the hash of the strokes of `id` with its children's saved hashes. Children
+    /// without strokes count as missing, as they are not saved. None if a child has no hash or
+    /// the strokes could not be serialized.
+    fn subtree_hash(&self, id: NodeId) -> Option<u64> {
+        self.combined_hash(id, |child| self.has_content(child))
+    }
+
+    /// `subtree_hash` as it was before children without strokes were left out of saves, which
+    /// the hashes in files saved then match.
+    fn legacy_subtree_hash(&self, id: NodeId) -> Option<u64> {
+        self.combined_hash(id, |_| true)
+    }
+
+    /// Combines the hash of the strokes of `id` with the saved hashes of the children `counts`
+    /// accepts.
+    fn combined_hash(&self, id: NodeId, counts: impl Fn(NodeId) -> bool) -> Option<u64> {
+        let node = &self[id];
+        if node.is_paged() {
+            return node.hash();
+        }
+        let mut hasher = ContentHasher::default();
+        hasher.write_u64(node.strokes_hash()?);
+        for child in node.children.iter().flatten() {
+            match child.filter(|child| counts(*child)) {
+                Some(child) => {
+                    hasher.write_u8(1);
+                    hasher.write_u64(self[child].hash()?);
+                }
+                None => hasher.write_u8(0),
+            }
+        }
+        Some(hasher.finish())
+    }
+
+    /// Recomputes the hash of every node in the subtree of `id` that changed since it was
+    /// saved, returning that node's. Only the cached hashes change, so this can run while the
+    /// tree is being serialized.
+    pub fn update_hashes(&self, id: NodeId) -> Option<u64> {
+        let node = &self[id];
+        if node.is_paged() || (!node.unsaved && node.hash().is_some()) {
+            return node.hash();
+        }
+        for child in node.children.iter().flatten().flatten() {
+            self.update_hashes(*child);
+        }
+        let hash = self.subtree_hash(id);
+        *node.hash.lock().unwrap() = hash;
+        hash
+    }
+
+    /// Paths, in the order `get_or_create_path` takes them, to the nodes in the subtree of `id`
+    /// whose content does not match the hash they were saved with. Nodes saved without a hash
+    /// are not checked. Hashes saved before children without strokes were left out are
+    /// replaced with current ones once they are found to match.
+    pub fn damaged_nodes(&self, id: NodeId) -> Vec<Vec<(u8, u8)>> {
+        let (mut matching, mut legacy) = (vec![], false);
+        let damaged = self.check_hashes(id, &mut matching, &mut legacy);
+        if legacy {
+            // Children come after their parents, whose hashes build on theirs.
+            for node in matching.into_iter().rev() {
+                *self[node].hash.lock().unwrap() = self.subtree_hash(node);
+            }
+        }
+        damaged
+    }
+
+    /// `damaged_nodes` without replacing old hashes, collecting the nodes whose hashes match
+    /// parents first, and noting whether any matched only as they were saved before.
+    fn check_hashes(
+        &self,
+        id: NodeId,
+        matching: &mut Vec<NodeId>,
+        legacy: &mut bool,
+    ) -> Vec<Vec<(u8, u8)>> {
+        let node = &self[id];
+        let mut damaged = vec![];
+        if node.hash().is_some() && !node.is_paged() {
+            if self.subtree_hash(id) == node.hash() {
+                matching.push(id);
+            } else if self.legacy_subtree_hash(id) == node.hash() {
+                matching.push(id);
+                *legacy = true;
+            } else {
+                damaged.push(vec![]);
+            }
+        }
+        for ((x, y), child) in node.child_nodes() {
+            damaged.extend(self.check_hashes(child, matching, legacy).into_iter().map(
+                |mut path| {
+                    path.push((x as u8, y as u8));
+                    path
+                },
+            ));
+        }
+        damaged
+    }
+
+    /// Paths, in the order `get_or_create_path` takes them, to the nodes whose own strokes
+    /// differ between the subtree of `id` and that of `other_id` in `other`, skipping subtrees
+    /// whose hashes match. Both trees need up to date hashes.
+    pub fn differing_nodes(
+        &self,
+        id: NodeId,
+        other: &CanvasTree,
+        other_id: NodeId,
+    ) -> Vec<Vec<(u8, u8)>> {
+        let (a, b) = (&self[id], &other[other_id]);
+        if a.hash().is_some() && a.hash() == b.hash() {
+            return vec![];
+        }
+        let mut differing = vec![];
+        if a.strokes_hash() != b.strokes_hash() {
+            differing.push(vec![]);
+        }
+        for y in 0..=1 {
+            for x in 0..=1 {
+                let (a, b) = (
+                    a.children[y][x].filter(|child| self.has_content(*child)),
+                    b.children[y][x].filter(|child| other.has_content(*child)),
+                );
+                let paths = match (a, b) {
+                    (Some(a), Some(b)) => self.differing_nodes(a, other, b),
+                    (None, None) => continue,
+                    _ => vec![vec![]],
+                };
+                differing.extend(paths.into_iter().map(|mut path| {
+                    path.push((x as u8, y as u8));
+                    path
+                }));
+            }
+        }
+        differing
+    }
+
+    /// Like `DrawNode::update_own_strokes` for the node `id` and its descendants, also passing
+    /// the depth of each stroke's node below `id`.
+    pub fn update_strokes(
+        &mut self,
+        id: NodeId,
+        depth: u32,
+        update: &mut impl FnMut(&mut StrokeEntry, u32) -> bool,
+    ) {
+        self.update_own_strokes(id, &mut |stroke| update(stroke, depth));
+        for child in self[id].children.into_iter().flatten().flatten() {
+            self.update_strokes(child, depth + 1, update);
+        }
+        self.children_changed(id);
+    }
+
+    /// Like `DrawNode::update_own_strokes` for the node `id`, recording the strokes it changes.
+    pub fn update_own_strokes(
+        &mut self,
+        id: NodeId,
+        update: &mut impl FnMut(&mut StrokeEntry) -> bool,
+    ) {
+        let Some(ops) = self.ops.clone() else {
+            self[id].update_own_strokes(update);
+            return;
+        };
+        // Updates only report changes to bounds, so the strokes are compared as written out.
+        let written = |stroke: &StrokeEntry| ron::to_string(stroke).unwrap_or_default();
+        let before = self[id].strokes.iter().map(written).collect_vec();
+        self[id].update_own_strokes(update);
+        let changed = self[id]
+            .strokes
+            .iter()
+            .zip(before)
+            .positions(|(stroke, before)| written(stroke) != before)
+            .collect_vec();
+        if changed.is_empty() {
+            return;
+        }
+        let path = self.path_to(id);
+        for index in changed {
+            ops.push(Op::Update {
+                path: path.clone(),
+                index,
+                stroke: self[id].strokes[index].clone(),
+            });
+        }
+    }
+
+    /// Like `DrawNode::take_strokes` for the node `id`, recording the strokes it removes.
+    pub fn take_strokes(
+        &mut self,
+        id: NodeId,
+        take: &impl Fn(&StrokeEntry) -> bool,
+    ) -> Vec<StrokeEntry> {
+        let indices = self[id].strokes.iter().positions(take).collect_vec();
+        self.take_strokes_at(id, &indices)
+    }
+
+    /// Removes and returns the strokes at `indices`, in ascending order, of those stored
+    /// directly in the node `id`, recording the change.
+    pub fn take_strokes_at(&mut self, id: NodeId, indices: &[usize]) -> Vec<StrokeEntry> {
+        if indices.is_empty() {
+            return vec![];
+        }
+        self.record(|| Op::Remove {
+            path: self.path_to(id),
+            indices: indices.to_vec(),
+            ids: indices
+                .iter()
+                .filter_map(|index| Some(self[id].strokes.get(*index)?.id))
+                .collect(),
+        });
+        // Strokes are offered to `take` in the order they are stored.
+        let index = Cell::new(0);
+        self[id].take_strokes(&|_| {
+            index.set(index.get() + 1);
+            indices.binary_search(&(index.get() - 1)).is_ok()
+        })
+    }
+
+    /// Replaces the strokes stored directly in the node `id` with `strokes`, which stay there
+    /// as they are, recording the change.
+    pub fn replace_own_strokes(&mut self, id: NodeId, strokes: Vec<StrokeEntry>) {
+        self.record(|| Op::Set {
+            path: self.path_to(id),
+            strokes: strokes.clone(),
+        });
+        self.load_page(id);
+        self[id].take_strokes(&|_| true);
+        for stroke in strokes {
+            self[id].push_stroke(stroke);
+        }
+        self.ancestors_changed(id);
+    }
+
+    pub fn draw_grid(&self, id: NodeId, painter: &Painter, to_screen: RectTransform) {
+        let inner_to_rect = to_screen.to().scale_from_center(0.5);
+        for ((x, y), child) in self[id].child_nodes() {
+            self.draw_grid(
+                child,
+                painter,
+                RectTransform::from_to(
+                    *to_screen.from(),
+                    inner_to_rect.translate(vec2(
+                        (x as f32 - 0.5) * 0.5 * to_screen.to().width(),
+                        (y as f32 - 0.5) * 0.5 * to_screen.to().height(),
+                    )),
+                ),
+            );
+        }
+
+        painter.rect_stroke(
+            Rect::from_min_max(to_screen * pos2(-1.0, -1.0), to_screen * pos2(1.0, 1.0)),
+            0.0,
+            Stroke::new(2.0, Color32::BLACK),
+        );
+    }
+
+    pub fn send_stroke<T: CanvasDrawableGenerator + 'static>(
+        &mut self,
+        p1: Pos2,
+        p2: Pos2,
+        scale: f32,
+        style: &SegmentStyle,
+        meta: StrokeMeta,
+        id: NodeId,
+    ) {
+        self.send_drawable(p1, p2, scale, id, |p1, p2, scale| {
+            meta.entry(T::from_points(p1, p2, scale, style))
+        });
+    }
+
+    /// Stores the drawable spanning `p1` to `p2` in the deepest node it is at least half as large
+    /// as. `build` receives the points and scale in that node's coordinates.
+    ///
+    /// Points outside of the node `id` are first carried up to the nearest ancestor that
+    /// contains them, creating ancestors as needed, so long segments are never stored in a node
+    /// that does not cover them.
+    pub fn send_drawable<F: FnOnce(Pos2, Pos2, f32) -> StrokeEntry>(
+        &mut self,
+        mut p1: Pos2,
+        mut p2: Pos2,
+        mut scale: f32,
+        id: NodeId,
+        build: F,
+    ) {
+        if !p1.is_finite() || !p2.is_finite() {
+            log::warn!("Dropping drawable with non-finite points {p1:?} {p2:?}");
+            return;
+        }
+        if !self.contains(id) {
+            log::warn!("Dropping drawable sent to a node that was removed");
+            return;
+        }
+        let mut node = id;
+        while !NODE_BOUNDS.contains(p1) || !NODE_BOUNDS.contains(p2) {
+            let corner = self[node].corner;
+            let offset = vec2(corner.0 as f32 - 0.5, corner.1 as f32 - 0.5);
+            p1 = p1 / 2.0 + offset;
+            p2 = p2 / 2.0 + offset;
+            scale /= 2.0;
+            node = self.get_or_create_parent(node);
+        }
+        let target = self.store_drawable(node, p1, p2, scale, build);
+        self.ancestors_grown(node);
+        if self[target].strokes.len() > MAX_NODE_STROKES {
+            self.subdivide(target);
+        }
+    }
+
+    /// Adds `strokes`, already in the coordinates of the node `id`, to it as they are.
+    pub fn insert_strokes(&mut self, id: NodeId, strokes: Vec<StrokeEntry>) {
+        self.record(|| Op::Add {
+            path: self.path_to(id),
+            strokes: strokes.clone(),
+        });
+        for stroke in strokes {
+            self[id].push_stroke(stroke);
+        }
+        self.ancestors_grown(id);
+        if self[id].strokes.len() > MAX_NODE_STROKES {
+            self.subdivide(id);
+        }
+    }
+
+    /// Moves the strokes of `id` that fit within one of its children down into that child, so
+    /// no node's stroke list grows without bound. Children left with too many strokes are
+    /// subdivided in turn.
+    fn subdivide(&mut self, id: NodeId) {
+        // Not recorded, as adding the strokes again subdivides the node the same way.
+        let taken = self[id]
+            .take_strokes(&|stroke| DrawNode::fitting_child(stroke.drawable.bounds()).is_some());
+        if taken.is_empty() {
+            return;
+        }
+        let mut children = vec![];
+        for mut stroke in taken {
+            let (x, y) = DrawNode::fitting_child(stroke.drawable.bounds()).unwrap();
+            let child = self.get_or_create_child_from_corner(id, (x as u8, y as u8));
+            let to_child = Affine2::from_scale(Vec2::splat(2.0)).then(&Affine2::from_translation(
+                vec2(1.0 - 2.0 * x as f32, 1.0 - 2.0 * y as f32),
+            ));
+            stroke.drawable.transform(&to_child);
+            self[child].push_stroke(stroke);
+            if !children.contains(&child) {
+                children.push(child);
+            }
+        }
+        for &child in &children {
+            self.ancestors_changed(child);
+        }
+        for child in children {
+            if self[child].strokes.len() > MAX_NODE_STROKES {
+                self.subdivide(child);
+            }
+        }
+    }
+
+    /// Stores the drawable in `id` if it spans at least half of it, and otherwise in the child
+    /// holding its center, returning the node it went to.
+    fn store_drawable<F: FnOnce(Pos2, Pos2, f32) -> StrokeEntry>(
+        &mut self,
+        id: NodeId,
+        p1: Pos2,
+        p2: Pos2,
+        scale: f32,
+        build: F,
+    ) -> NodeId {
+        self.load_page(id);
+        if (p1 - p2).abs().max_elem() >= 0.5 {
+            let stroke = build(p1, p2, scale);
+            self.record(|| Op::Add {
+                path: self.path_to(id),
+                strokes: vec![stroke.clone()],
+            });
+            self[id].push_stroke(stroke);
+            return id;
+        }
+        let center = p1.lerp(p2, 0.5);
+        let x = if center.x > 0.0 { 1 } else { 0 };
+        let y = if center.y > 0.0 { 1 } else { 0 };
+        let offset = vec2(
+            if x == 0 { 0.5 } else { -0.5 },
+            if y == 0 { 0.5 } else { -0.5 },
+        );
+        let child = self.get_or_create_child_from_corner(id, (x as u8, y as u8));
+        let target = self.store_drawable(
+            child,
+            2.0 * (p1 + offset),
+            2.0 * (p2 + offset),
+            2.0 * scale,
+            build,
+        );
+        self.child_grown(id, x, y);
+        target
+    }
+
+    /// The child at `(x, y)` of `id`, created if missing. New children are linked to their
+    /// neighbors across the edges of `id` if `link_neighbors` is set.
+    fn create_child(&mut self, id: NodeId, x: usize, y: usize, link_neighbors: bool) -> NodeId {
+        self.load_page(id);
+        if let Some(child) = self[id].children[y][x] {
+            return child;
+        }
+        let child = self.insert_child(id, (x, y), DrawNode::default());
+        if !link_neighbors {
+            return child;
+        }
+        let horizontal_neighbor = self
+            .get_neighbor(
+                id,
+                if x == 1 {
+                    Direction::PosX
+                } else {
+                    Direction::NegX
+                },
+            )
+            .and_then(|neighbor| self[neighbor].children[y][1 - x]);
+        let vertical_neighbor = self
+            .get_neighbor(
+                id,
+                if y == 1 {
+                    Direction::PosY
+                } else {
+                    Direction::NegY
+                },
+            )
+            .and_then(|neighbor| self[neighbor].children[1 - y][x]);
+        self[child].neighbors = (horizontal_neighbor, vertical_neighbor);
+        if let Some(horizontal_neighbor) = horizontal_neighbor {
+            self[horizontal_neighbor].neighbors.0 = Some(child);
+        }
+        if let Some(vertical_neighbor) = vertical_neighbor {
+            self[vertical_neighbor].neighbors.1 = Some(child);
+        }
+        child
+    }
+
+    pub fn get_or_create_parent(&mut self, id: NodeId) -> NodeId {
+        if let Some(parent) = self[id].parent {
+            return parent;
+        }
+        let corner = self[id].corner;
+        let mut parent = DrawNode {
+            corner: (1 - corner.0, 1 - corner.1),
+            ..DrawNode::default()
+        };
+        parent.children[corner.1 as usize][corner.0 as usize] = Some(id);
+        let parent = self.nodes.insert(parent);
+        self[id].parent = Some(parent);
+        // Only the outermost node is without a parent.
+        self.root = parent;
+        self.record(|| Op::Grow);
+        parent
+    }
+
+    pub fn get_or_create_child_from_corner(&mut self, id: NodeId, corner: (u8, u8)) -> NodeId {
+        self.create_child(id, corner.0 as usize, corner.1 as usize, true)
+    }
+
+    pub fn get_or_create_neighborless_child_from_corner(
+        &mut self,
+        id: NodeId,
+        corner: (u8, u8),
+    ) -> NodeId {
+        self.create_child(id, corner.0 as usize, corner.1 as usize, false)
+    }
+
+    pub fn get_neighbor(&self, id: NodeId, direction: Direction) -> Option<NodeId> {
+        let node = &self[id];
+        let neighbor = if direction.is_vertical() {
+            if node.corner.1 != direction.is_positive() as u8 {
+                self[node.parent?].children[(1 - node.corner.1) as usize][node.corner.0 as usize]
+            } else {
+                node.neighbors.1
+            }
+        } else if node.corner.0 != direction.is_positive() as u8 {
+            self[node.parent?].children[node.corner.1 as usize][(1 - node.corner.0) as usize]
+        } else {
+            node.neighbors.0
+        };
+        neighbor.filter(|neighbor| self.contains(*neighbor))
+    }
+
+    pub fn get_or_create_neighbor(&mut self, id: NodeId, direction: Direction) -> NodeId {
+        let parent = self.get_or_create_parent(id);
+        let corner = self[id].corner;
+        if direction.is_vertical() {
+            if corner.1 != direction.is_positive() as u8 {
+                return self.get_or_create_child_from_corner(parent, (corner.0, 1 - corner.1));
+            }
+            if let Some(neighbor) = self[id].neighbors.1.filter(|id| self.contains(*id)) {
+                return neighbor;
+            }
+            let parent_neighbor = self.get_or_create_neighbor(parent, direction);
+            let neighbor = self.get_or_create_neighborless_child_from_corner(
+                parent_neighbor,
+                (corner.0, 1 - corner.1),
+            );
+            self[neighbor].neighbors.1 = Some(id);
+            self[id].neighbors.1 = Some(neighbor);
+            neighbor
+        } else {
+            if corner.0 != direction.is_positive() as u8 {
+                return self.get_or_create_child_from_corner(parent, (1 - corner.0, corner.1));
+            }
+            if let Some(neighbor) = self[id].neighbors.0.filter(|id| self.contains(*id)) {
+                return neighbor;
+            }
+            let parent_neighbor = self.get_or_create_neighbor(parent, direction);
+            let neighbor = self.get_or_create_neighborless_child_from_corner(
+                parent_neighbor,
+                (1 - corner.0, corner.1),
+            );
+            self[neighbor].neighbors.0 = Some(id);
+            self[id].neighbors.0 = Some(neighbor);
+            neighbor
+        }
+    }
+
+    /// Calls `f` with the node `id` and every descendant, along with its depth below `id`.
+    pub fn for_each_node(&self, id: NodeId, depth: u32, f: &mut impl FnMut(&DrawNode, u32)) {
+        let node = &self[id];
+        f(node, depth);
+        for (_, child) in node.child_nodes() {
+            self.for_each_node(child, depth + 1, f);
+        }
+    }
+
+    /// Size and shape of the subtree below the node `id`.
+    pub fn stats(&self, id: NodeId) -> TreeStats {
+        let mut stats = TreeStats::default();
+        self.for_each_node(id, 0, &mut |node, depth| {
+            stats.nodes += 1;
+            stats.max_depth = stats.max_depth.max(depth);
+            stats.strokes += node.strokes.len();
+            stats.memory += std::mem::size_of::<DrawNode>()
+                + node.strokes.capacity() * std::mem::size_of::<StrokeEntry>()
+                + node
+                    .strokes
+                    .iter()
+                    .map(|stroke| {
+                        std::mem::size_of_val(&*stroke.drawable)
+                            + stroke.author.as_ref().map_or(0, String::capacity)
+                    })
+                    .sum::<usize>();
+        });
+        stats
+    }
+
+    /// Union of the bounds of every stroke in the node `id` and its descendants, in that node's
+    /// coordinates. Computed on first use and kept up to date as strokes are added.
+    pub fn content_bounds(&self, id: NodeId) -> Option<Rect> {
+        let node = &self[id];
+        *node.bounds.get_or_init(|| {
+            node.child_nodes()
+                .filter_map(|((x, y), child)| {
+                    Some(DrawNode::from_child(self.content_bounds(child)?, x, y))
+                })
+                .chain(node.own_bounds())
+                .reduce(Rect::union)
+        })
+    }
+
+    /// Whether the subtree of `id` holds any strokes.
+    pub fn has_content(&self, id: NodeId) -> bool {
+        self.content_bounds(id).is_some()
+    }
+
+    /// Removes `id` if it is empty and has a parent.
+    pub fn try_cleanup(&mut self, id: NodeId) {
+        if self
+            .get(id)
+            .is_some_and(|node| node.is_empty() && node.parent.is_some())
+        {
+            self.remove_subtree(id);
+        }
+    }
+
+    /// The outermost node, and the path from it to `id` in the order `get_or_create_path`
+    /// takes it.
+    pub fn get_top_level_and_path(&self, id: NodeId) -> (NodeId, Vec<(u8, u8)>) {
+        let mut node = id;
+        let mut path = vec![];
+        while let Some(parent) = self[node].parent {
+            path.push(self[node].corner);
+            node = parent;
+        }
+        (node, path)
+    }
+
+    /// The node at `path` below `id`, taking corners from the end of `path`, creating any
+    /// nodes missing along the way.
+    pub fn get_or_create_path(&mut self, path: &mut Vec<(u8, u8)>, id: NodeId) -> NodeId {
+        let mut node = id;
+        while let Some(corner) = path.pop() {
+            node = self.get_or_create_child_from_corner(node, corner);
+        }
+        node
+    }
+}
+
+/// Drawing pass a stroke is rendered in. Strokes in an earlier pass always render beneath later
+/// ones, whatever their order.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum StrokePriority {
+    Underlay,
+    #[default]
+    Ink,
+}
+
+/// Derived as inherent functions, wrapped by the trait impls below so strokes saved as the
+/// `(drawable, order)` tuples of earlier versions still load.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(remote = "Self")]
+pub struct StrokeEntry {
+    pub drawable: Box<dyn CanvasDrawable>,
+    pub order: u64,
+    #[serde(default)]
+    pub priority: StrokePriority,
+    #[serde(default)]
+    pub layer: LayerId,
+    /// Strokes sharing a group are selected, and so edited, together.
+    #[serde(default)]
+    pub group: Option<GroupId>,
+    /// Unix timestamp in seconds of when the stroke was drawn. 0 for strokes saved before it
+    /// was recorded.
+    #[serde(default)]
+    pub created: i64,
+    /// Who drew the stroke, if they gave a name.
+    #[serde(default)]
+    pub author: Option<String>,
+    /// Shared by all strokes of one object, and kept when its order changes.
+    #[serde(default)]
+    pub id: StrokeId,
+}
+
+impl Serialize for StrokeEntry {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        StrokeEntry::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for StrokeEntry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(StrokeEntryVisitor)
+    }
+}
+
+/// Reads a stroke written either as a struct or as a sequence of its fields in order, the
+/// latter covering the tuples of earlier versions, whose missing fields take their defaults.
+struct StrokeEntryVisitor;
+
+impl<'de> Visitor<'de> for StrokeEntryVisitor {
+    type Value = StrokeEntry;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        formatter.write_str("a stroke")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<StrokeEntry, A::Error> {
+        StrokeEntry::deserialize(MapAccessDeserializer::new(map))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<StrokeEntry, A::Error> {
+        StrokeEntry::deserialize(SeqAccessDeserializer::new(seq))
+    }
+}
+
+/// A handle to the strokes sharing an order within one node, as found by
+/// `CanvasTree::query_rect`. It stays valid as other strokes come and go, and resolves to
+/// nothing once its strokes are moved or their node is removed.
+#[derive(Clone)]
+pub struct StrokeRef {
+    node: NodeId,
+    pub order: u64,
+    /// Maps the node's coordinates to those of the node the query started from.
+    pub to_query: RectTransform,
+}
+
+impl StrokeRef {
+    pub fn strokes(&self, tree: &CanvasTree) -> Vec<StrokeEntry> {
+        let Some(node) = tree.get(self.node) else {
+            return vec![];
+        };
+        node.strokes
+            .iter()
+            .filter(|stroke| stroke.order == self.order)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Counts describing a subtree, from `CanvasTree::stats`.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct TreeStats {
+    pub nodes: usize,
+    /// Levels from the node to its deepest descendant.
+    pub max_depth: u32,
+    pub strokes: usize,
+    /// Rough bytes held by the nodes and their strokes. Drawables' own heap allocations, the
+    /// spatial indexes and render caches are left out.
+    pub memory: usize,
+}
+
+/// Receives the strokes `CanvasTree::for_each_stroke` walks over and decides which nodes it
+/// descends into. Closures taking a stroke and its node's screen rect visit every stroke.
+pub trait StrokeVisitor {
+    fn stroke(&mut self, stroke: &StrokeEntry, screen_rect: Rect);
+
+    /// Called with each child holding content, and the screen rect it covers, before its
+    /// strokes are visited. Returning false skips the child and its descendants.
+    fn enter(&mut self, _tree: &CanvasTree, _node: NodeId, _screen_rect: Rect) -> bool {
+        true
+    }
+}
+
+impl<F: FnMut(&StrokeEntry, Rect)> StrokeVisitor for F {
+    fn stroke(&mut self, stroke: &StrokeEntry, screen_rect: Rect) {
+        self(stroke, screen_rect)
+    }
+}
+
+/// Identifies a group of strokes. Ids are never reused within a painting.
+pub type GroupId = u32;
+
+/// Identifies an object across copies of a painting, as made by `merge::new_stroke_id`. 0 for
+/// strokes drawn before objects were given ids.
+pub type StrokeId = u64;
+
+/// Everything stored alongside a drawable in a `StrokeEntry`.
+#[derive(Clone)]
+pub struct StrokeMeta {
+    pub order: u64,
+    pub priority: StrokePriority,
+    pub layer: LayerId,
+    pub created: i64,
+    pub author: Option<String>,
+    pub id: StrokeId,
+}
+
+impl StrokeMeta {
+    pub fn entry(self, drawable: Box<dyn CanvasDrawable>) -> StrokeEntry {
+        StrokeEntry {
+            drawable,
+            order: self.order,
+            priority: self.priority,
+            layer: self.layer,
+            group: None,
+            created: self.created,
+            author: self.author,
+            id: self.id,
+        }
+    }
+}
+
+impl StrokeEntry {
+    pub fn sort_key(&self) -> (StrokePriority, u64) {
+        (self.priority, self.order)
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum LineStyle {
+    #[default]
+    Solid,
+    Dashed,
+    Dotted,
+}
+
+impl LineStyle {
+    /// Dash and gap lengths in multiples of the stroke width. A dash length of zero draws dots.
+    fn pattern(&self) -> Option<(f32, f32)> {
+        match self {
+            LineStyle::Solid => None,
+            LineStyle::Dashed => Some((3.0, 2.0)),
+            LineStyle::Dotted => Some((0.0, 2.0)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LineStyle::Solid => "Solid",
+            LineStyle::Dashed => "Dashed",
+            LineStyle::Dotted => "Dotted",
+        }
+    }
+}
+
+/// Everything about a stroke segment besides its end points.
+pub struct SegmentStyle {
+    pub stroke: Stroke,
+    pub line_style: LineStyle,
+    /// Distance along the stroke before this segment, in multiples of the stroke width, so dash
+    /// patterns continue across segments.
+    pub dash_phase: f32,
+    /// Width multipliers at the start and end of the segment, for generators that taper.
+    pub widths: [f32; 2],
+}
+
+#[allow(private_bounds)]
+pub trait CanvasDrawableGenerator: CanvasDrawable {
+    fn from_points(p1: Pos2, p2: Pos2, scale: f32, style: &SegmentStyle) -> Box<Self>;
+}
+
+/// A value of a drawable that can be edited in place.
+pub enum Property<'a> {
+    Color(&'a mut Color32),
+    /// Outline width in node coordinates.
+    Width(&'a mut f32),
+    Text(&'a mut String),
+}
+
+/// A piece of a drawable in plain vector terms, for converting it to other formats. Sizes are
+/// in the same coordinates as the points.
+#[derive(Clone)]
+pub enum VectorShape {
+    /// An outline of even `width` through `points`, or the thinnest line the format allows when
+    /// `width` is 0.
+    Polyline {
+        points: Vec<Pos2>,
+        width: f32,
+        color: Color32,
+        closed: bool,
+    },
+    /// A solid polygon.
+    Polygon { points: Vec<Pos2>, color: Color32 },
+    /// A line of text whose baseline starts at `origin` and runs at `angle` radians clockwise
+    /// from the x axis.
+    Text {
+        origin: Pos2,
+        angle: f32,
+        text: String,
+        size: f32,
+        color: Color32,
+    },
+}
+
+impl VectorShape {
+    /// Maps the shape out of node coordinates with `to_screen`, scaling its sizes by its
+    /// largest scale.
+    pub fn transformed(self, to_screen: RectTransform) -> Self {
+        let scale = to_screen.scale().max_elem();
+        let map = |points: Vec<Pos2>| points.into_iter().map(|point| to_screen * point).collect();
+        match self {
+            VectorShape::Polyline {
+                points,
+                width,
+                color,
+                closed,
+            } => VectorShape::Polyline {
+                points: map(points),
+                width: width * scale,
+                color,
+                closed,
+            },
+            VectorShape::Polygon { points, color } => VectorShape::Polygon {
+                points: map(points),
+                color,
+            },
+            VectorShape::Text {
+                origin,
+                angle,
+                text,
+                size,
+                color,
+            } => VectorShape::Text {
+                origin: to_screen * origin,
+                angle,
+                text,
+                size: size * scale,
+                color,
+            },
+        }
+    }
+}
 
 #[typetag::serde(tag = "type")]
-pub trait CanvasDrawable {
+pub trait CanvasDrawable: Send + Sync {
     fn draw(&self, painter: &Painter, to_screen: RectTransform);
+    /// Area the drawable paints in node coordinates, including the width of its outline.
+    fn bounds(&self) -> Rect;
+    /// Whether the drawable paints within `tolerance` of `point`, both in node coordinates.
+    /// Defaults to testing against its bounds.
+    fn hit_test(&self, point: Pos2, tolerance: f32) -> bool {
+        self.bounds().expand(tolerance).contains(point)
+    }
+    /// Applies `transform` in node coordinates, scaling outline widths and text by its average
+    /// scale factor.
+    fn transform(&mut self, transform: &Affine2);
+    /// Values shown in the properties inspector.
+    fn properties(&mut self) -> Vec<Property<'_>> {
+        vec![]
+    }
+    /// Text read out by screen readers, for drawables whose content is textual.
+    fn accessible_label(&self) -> Option<String> {
+        None
+    }
+    /// Name of the region, for drawables framing part of the canvas.
+    fn frame_name(&self) -> Option<&str> {
+        None
+    }
+    /// The drawable as vector shapes in node coordinates, bottom to top, for exporting it.
+    /// Drawables without a vector form return none.
+    fn vector_shapes(&self) -> Vec<VectorShape> {
+        vec![]
+    }
     fn box_clone(&self) -> Box<dyn CanvasDrawable>;
 }
 
@@ -669,19 +1998,102 @@ pub struct Line {
     end_x: f32,
     end_y: f32,
     stroke: Stroke,
+    #[serde(default)]
+    line_style: LineStyle,
+    #[serde(default)]
+    dash_phase: f32,
 }
 
+/// Upper bound on dashes drawn for a single segment.
+const MAX_DASHES: usize = 4096;
+
 #[typetag::serde]
 impl CanvasDrawable for Line {
     fn draw(&self, painter: &Painter, to_screen: RectTransform) {
         let scale_factor = to_screen.scale().max_elem();
-        painter.line_segment(
-            [
-                to_screen * pos2(self.start_x, self.start_y),
-                to_screen * pos2(self.end_x, self.end_y),
-            ],
-            Stroke::new(self.stroke.width * scale_factor, self.stroke.color),
+        let start = to_screen * pos2(self.start_x, self.start_y);
+        let end = to_screen * pos2(self.end_x, self.end_y);
+        let stroke = Stroke::new(self.stroke.width * scale_factor, self.stroke.color);
+        let Some((dash, gap)) = self.line_style.pattern() else {
+            painter.line_segment([start, end], stroke);
+            return;
+        };
+        let length = (end - start).length();
+        if stroke.width <= 0.0 || length <= 0.0 {
+            return;
+        }
+        let direction = (end - start) / length;
+        let (dash, period) = (dash * stroke.width, (dash + gap) * stroke.width);
+        let mut position = -(self.dash_phase * stroke.width).rem_euclid(period);
+        for _ in 0..MAX_DASHES {
+            if position >= length {
+                break;
+            }
+            if dash == 0.0 {
+                if position >= 0.0 {
+                    painter.circle_filled(
+                        start + position * direction,
+                        stroke.width / 2.0,
+                        stroke.color,
+                    );
+                }
+            } else {
+                let dash_start = position.max(0.0);
+                let dash_end = (position + dash).min(length);
+                if dash_end > dash_start {
+                    painter.line_segment(
+                        [start + dash_start * direction, start + dash_end * direction],
+                        stroke,
+                    );
+                }
+            }
+            position += period;
+        }
+    }
+
+    fn bounds(&self) -> Rect {
+        Rect::from_two_pos(
+            pos2(self.start_x, self.start_y),
+            pos2(self.end_x, self.end_y),
+        )
+        .expand(self.stroke.width / 2.0)
+    }
+
+    fn hit_test(&self, point: Pos2, tolerance: f32) -> bool {
+        let distance = geometry::segment_distance(
+            point,
+            pos2(self.start_x, self.start_y),
+            pos2(self.end_x, self.end_y),
         );
+        distance <= self.stroke.width / 2.0 + tolerance
+    }
+
+    fn transform(&mut self, transform: &Affine2) {
+        let start = transform.transform_point(pos2(self.start_x, self.start_y));
+        let end = transform.transform_point(pos2(self.end_x, self.end_y));
+        (self.start_x, self.start_y) = (start.x, start.y);
+        (self.end_x, self.end_y) = (end.x, end.y);
+        self.stroke.width *= transform.scale_factor();
+    }
+
+    fn properties(&mut self) -> Vec<Property<'_>> {
+        vec![
+            Property::Color(&mut self.stroke.color),
+            Property::Width(&mut self.stroke.width),
+        ]
+    }
+
+    /// Dashes are left out, so dashed and dotted segments come out solid.
+    fn vector_shapes(&self) -> Vec<VectorShape> {
+        vec![VectorShape::Polyline {
+            points: vec![
+                pos2(self.start_x, self.start_y),
+                pos2(self.end_x, self.end_y),
+            ],
+            width: self.stroke.width,
+            color: self.stroke.color,
+            closed: false,
+        }]
     }
 
     fn box_clone(&self) -> Box<dyn CanvasDrawable> {
@@ -690,13 +2102,15 @@ impl CanvasDrawable for Line {
 }
 
 impl CanvasDrawableGenerator for Line {
-    fn from_points(p1: Pos2, p2: Pos2, scale: f32, stroke: &Stroke) -> Box<Self> {
+    fn from_points(p1: Pos2, p2: Pos2, scale: f32, style: &SegmentStyle) -> Box<Self> {
         Box::new(Line {
             start_x: p1.x,
             start_y: p1.y,
             end_x: p2.x,
             end_y: p2.y,
-            stroke: Stroke::new(stroke.width * scale, stroke.color),
+            stroke: Stroke::new(style.stroke.width * scale, style.stroke.color),
+            line_style: style.line_style,
+            dash_phase: style.dash_phase,
         })
     }
 }
@@ -0,0 +1,153 @@
+use egui::{pos2, Color32, Pos2, Rect};
+use usvg::tiny_skia_path::{self, PathSegment};
+
+use crate::{
+    clipboard::{CopiedStroke, CopiedStrokes},
+    drawables::{FilledPolygon, TaperedStroke},
+    geometry::Affine2,
+    structure::{CanvasDrawable, StrokePriority},
+};
+
+/// Straight segments each curve of a path is flattened into.
+const CURVE_STEPS: usize = 16;
+
+/// Whether `text` looks like an SVG document rather than other pasted text.
+pub fn is_svg(text: &str) -> bool {
+    let text = text.trim_start();
+    (text.starts_with("<svg") || text.starts_with("<?xml")) && text.contains("<svg")
+}
+
+/// Converts the paths and basic shapes of the SVG document `data` into strokes in the
+/// coordinates of nodes `depth` levels deep, with `scale` node units per unit of the document,
+/// centered on the origin and grouped together. Fills become filled polygons, one for each
+/// subpath, so holes are filled in. Outlines become strokes of their width. Text, images,
+/// gradients and patterns are left out.
+pub fn import(data: &[u8], depth: u32, scale: f32) -> Result<CopiedStrokes, String> {
+    let tree =
+        usvg::Tree::from_data(data, &usvg::Options::default()).map_err(|err| err.to_string())?;
+    let mut strokes = vec![];
+    collect(tree.root(), scale, &mut strokes);
+    if strokes.is_empty() {
+        return Err("The SVG has no paths or shapes to import".to_string());
+    }
+    let mut copied = CopiedStrokes { depth, strokes };
+    let recenter = Affine2::from_translation(-copied.bounds().center().to_vec2());
+    for stroke in &mut copied.strokes {
+        stroke.drawable.transform(&recenter);
+    }
+    Ok(copied)
+}
+
+fn collect(group: &usvg::Group, scale: f32, strokes: &mut Vec<CopiedStroke>) {
+    for node in group.children() {
+        match node {
+            usvg::Node::Group(group) => collect(group, scale, strokes),
+            usvg::Node::Path(path) if path.is_visible() => add_path(path, scale, strokes),
+            _ => {}
+        }
+    }
+}
+
+fn add_path(path: &usvg::Path, scale: f32, strokes: &mut Vec<CopiedStroke>) {
+    let transform = path.abs_transform();
+    let Some(data) = path.data().clone().transform(transform) else {
+        return;
+    };
+    let subpaths = flatten(&data, scale);
+    let mut add = |drawable: Box<dyn CanvasDrawable>| {
+        strokes.push(CopiedStroke {
+            drawable,
+            priority: StrokePriority::Ink,
+            group: Some(0),
+        })
+    };
+    if let Some(color) = path
+        .fill()
+        .and_then(|fill| paint_color(fill.paint(), fill.opacity()))
+    {
+        for (points, _) in &subpaths {
+            if points.len() >= 3 && Rect::from_points(points).is_positive() {
+                add(Box::new(FilledPolygon::new(points, color)));
+            }
+        }
+    }
+    if let Some(stroke) = path.stroke() {
+        let Some(color) = paint_color(stroke.paint(), stroke.opacity()) else {
+            return;
+        };
+        let transform_scale = (transform.sx * transform.sy - transform.kx * transform.ky)
+            .abs()
+            .sqrt();
+        let width = stroke.width().get() * transform_scale * scale;
+        for (mut points, closed) in subpaths {
+            if closed {
+                points.extend(points.first().copied());
+            }
+            add(Box::new(TaperedStroke::new(&points, width, color)));
+        }
+    }
+}
+
+/// The solid color of `paint`, or None for gradients and patterns.
+fn paint_color(paint: &usvg::Paint, opacity: usvg::Opacity) -> Option<Color32> {
+    let usvg::Paint::Color(color) = paint else {
+        return None;
+    };
+    let alpha = (opacity.get() * 255.0).round() as u8;
+    Some(Color32::from_rgba_unmultiplied(
+        color.red,
+        color.green,
+        color.blue,
+        alpha,
+    ))
+}
+
+/// Splits `path` into its subpaths as polylines scaled by `scale`, each with whether it was
+/// closed.
+fn flatten(path: &tiny_skia_path::Path, scale: f32) -> Vec<(Vec<Pos2>, bool)> {
+    let mut subpaths = vec![];
+    let mut points: Vec<Pos2> = vec![];
+    let to_pos = |point: tiny_skia_path::Point| pos2(point.x * scale, point.y * scale);
+    for segment in path.segments() {
+        let start = points.last().copied().unwrap_or(Pos2::ZERO);
+        match segment {
+            PathSegment::MoveTo(point) => {
+                if !points.is_empty() {
+                    subpaths.push((std::mem::take(&mut points), false));
+                }
+                points.push(to_pos(point));
+            }
+            PathSegment::LineTo(point) => points.push(to_pos(point)),
+            PathSegment::QuadTo(control, end) => {
+                let (control, end) = (to_pos(control), to_pos(end));
+                points.extend((1..=CURVE_STEPS).map(|step| {
+                    let t = step as f32 / CURVE_STEPS as f32;
+                    let (a, b) = (start.lerp(control, t), control.lerp(end, t));
+                    a.lerp(b, t)
+                }));
+            }
+            PathSegment::CubicTo(first, second, end) => {
+                let (first, second, end) = (to_pos(first), to_pos(second), to_pos(end));
+                points.extend((1..=CURVE_STEPS).map(|step| {
+                    let t = step as f32 / CURVE_STEPS as f32;
+                    let (a, b, c) = (
+                        start.lerp(first, t),
+                        first.lerp(second, t),
+                        second.lerp(end, t),
+                    );
+                    let (d, e) = (a.lerp(b, t), b.lerp(c, t));
+                    d.lerp(e, t)
+                }));
+            }
+            PathSegment::Close => {
+                if !points.is_empty() {
+                    subpaths.push((std::mem::take(&mut points), true));
+                }
+            }
+        }
+    }
+    if !points.is_empty() {
+        subpaths.push((points, false));
+    }
+    subpaths
+}
@@ -0,0 +1,191 @@
+use chrono::{DateTime, Local, Utc};
+use egui::Ui;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    oplog::{self, Op, OpLog},
+    structure::{CanvasTree, StrokeEntry},
+};
+
+/// A named copy of the canvas's strokes, kept as the changes that led to it.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct NamedVersion {
+    pub name: String,
+    /// Unix timestamp in seconds.
+    pub created: i64,
+    /// The operation log the version was taken from, and how many of its changes it covers.
+    log: u64,
+    count: usize,
+    /// `OpLog::root_level` when the version was taken, to line its tree up with the canvas's.
+    pub level: u32,
+    /// Corner of the only node of the empty canvas `ops` start from, or None if they follow
+    /// the previous version's, which is from the same log.
+    start: Option<(u8, u8)>,
+    ops: Vec<Op>,
+}
+
+/// Strokes of a node, with the path to it in the order `CanvasTree::get_or_create_path` takes
+/// it.
+pub type PlacedStrokes = Vec<(Vec<(u8, u8)>, Vec<StrokeEntry>)>;
+
+/// Requests from the version history window.
+pub enum VersionAction {
+    Take(String),
+    /// Replace the canvas's strokes with the version at this index.
+    Restore(usize),
+    /// Open the version at this index in a new tab.
+    Branch(usize),
+    Delete(usize),
+}
+
+/// Named versions of the canvas, oldest first. Each keeps only the changes made since the one
+/// before it where it can, so versions cost little more than the operation log.
+#[derive(Deserialize, Serialize, Default)]
+#[serde(default)]
+pub struct VersionHistory {
+    versions: Vec<NamedVersion>,
+    #[serde(skip)]
+    new_name: String,
+}
+
+impl VersionHistory {
+    /// Keeps `tree`, whose changes `op_log` records, as a version called `name`. It is kept as
+    /// the changes since the last version where the log still holds them, and otherwise whole.
+    /// `tree` must be paged in.
+    pub fn take(&mut self, name: String, op_log: &OpLog, tree: &CanvasTree) {
+        let (log, count) = (op_log.id(), op_log.count());
+        let since_last = self
+            .versions
+            .last()
+            .filter(|last| last.log == log && last.count <= count)
+            .and_then(|last| op_log.ops_in(last.count..count));
+        let (start, ops) = match since_last {
+            Some(ops) => (None, ops),
+            None => {
+                let (corner, ops) = oplog::snapshot(tree);
+                (Some(corner), ops)
+            }
+        };
+        self.versions.push(NamedVersion {
+            name,
+            created: Utc::now().timestamp(),
+            log,
+            count,
+            level: op_log.root_level(),
+            start,
+            ops,
+        });
+    }
+
+    pub fn get(&self, index: usize) -> Option<&NamedVersion> {
+        self.versions.get(index)
+    }
+
+    /// The strokes of the version at `index`, placed from its outermost node.
+    pub fn strokes(&self, index: usize) -> PlacedStrokes {
+        let tree = self.rebuild(index);
+        let mut strokes = vec![];
+        let mut pending = vec![(tree.root(), vec![])];
+        while let Some((node, path)) = pending.pop() {
+            let own = tree[node].own_strokes();
+            if !own.is_empty() {
+                strokes.push((path.clone(), own.to_vec()));
+            }
+            for ((x, y), child) in tree[node].child_nodes() {
+                let mut child_path = vec![(x as u8, y as u8)];
+                child_path.extend(&path);
+                pending.push((child, child_path));
+            }
+        }
+        strokes
+    }
+
+    /// The tree of the version at `index`, replayed from the first version it follows.
+    fn rebuild(&self, index: usize) -> CanvasTree {
+        let first = self.versions[..=index]
+            .iter()
+            .rposition(|version| version.start.is_some())
+            .unwrap_or(0);
+        let chain = &self.versions[first..=index];
+        oplog::replay(
+            chain[0].start.unwrap_or_default(),
+            chain.iter().flat_map(|version| &version.ops),
+        )
+    }
+
+    /// Forgets the version at `index`, handing its changes on to the next if it follows it.
+    pub fn delete(&mut self, index: usize) {
+        let removed = self.versions.remove(index);
+        if let Some(next) = self.versions.get_mut(index) {
+            if next.start.is_none() {
+                let mut ops = removed.ops;
+                ops.append(&mut next.ops);
+                next.ops = ops;
+                next.start = removed.start;
+            }
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui) -> Option<VersionAction> {
+        let mut action = None;
+        ui.horizontal(|ui| {
+            ui.text_edit_singleline(&mut self.new_name)
+                .on_hover_text("Name of the version to keep");
+            if ui
+                .add_enabled(
+                    !self.new_name.trim().is_empty(),
+                    egui::Button::new("Keep version"),
+                )
+                .clicked()
+            {
+                action = Some(VersionAction::Take(
+                    std::mem::take(&mut self.new_name).trim().to_string(),
+                ));
+            }
+        });
+        ui.separator();
+        if self.versions.is_empty() {
+            ui.weak("No versions kept");
+            return action;
+        }
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            egui::Grid::new("version_history")
+                .num_columns(3)
+                .striped(true)
+                .show(ui, |ui| {
+                    for (index, version) in self.versions.iter().enumerate().rev() {
+                        ui.label(&version.name);
+                        let time = DateTime::<Utc>::from_timestamp(version.created, 0)
+                            .map(|time| {
+                                time.with_timezone(&Local)
+                                    .format("%Y-%m-%d %H:%M:%S")
+                                    .to_string()
+                            })
+                            .unwrap_or_default();
+                        ui.weak(time);
+                        ui.horizontal(|ui| {
+                            if ui
+                                .button("Restore")
+                                .on_hover_text("Bring the canvas back to this version")
+                                .clicked()
+                            {
+                                action = Some(VersionAction::Restore(index));
+                            }
+                            if ui
+                                .button("Branch")
+                                .on_hover_text("Open this version in a new tab")
+                                .clicked()
+                            {
+                                action = Some(VersionAction::Branch(index));
+                            }
+                            if ui.small_button("🗑").on_hover_text("Forget").clicked() {
+                                action = Some(VersionAction::Delete(index));
+                            }
+                        });
+                        ui.end_row();
+                    }
+                });
+        });
+        action
+    }
+}
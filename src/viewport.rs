@@ -0,0 +1,157 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use egui::{vec2, Painter, Rect, Sense, Ui, Vec2};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    blend,
+    collect::{RenderKey, StrokeCollector, DRAW_DEPTH},
+    layers::Layers,
+    quality::RenderQuality,
+    sessions::ViewLocation,
+    structure::{CanvasTree, NodeId},
+};
+
+/// Numbers the views opened by this process, so their windows never collide.
+static NEXT_VIEW: AtomicU64 = AtomicU64::new(0);
+
+fn next_view_id() -> u64 {
+    NEXT_VIEW.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Another look at a painting, in a window of its own with a pan and zoom apart from the main
+/// view's, such as an overview beside a detailed view. Drawing still happens in the main view.
+#[derive(Deserialize, Serialize)]
+pub struct SecondaryView {
+    #[serde(skip, default = "next_view_id")]
+    id: u64,
+    location: ViewLocation,
+}
+
+impl SecondaryView {
+    /// A view starting at `location`.
+    pub fn new(location: ViewLocation) -> Self {
+        Self {
+            id: next_view_id(),
+            location,
+        }
+    }
+
+    pub fn viewport_id(&self) -> egui::ViewportId {
+        egui::ViewportId::from_hash_of(("secondary_view", self.id))
+    }
+
+    /// Shows the view in the rest of `ui`. Dragging pans it and zooming works as in the main
+    /// view.
+    pub fn ui(
+        &mut self,
+        ui: &mut Ui,
+        tree: &mut CanvasTree,
+        layers: &Layers,
+        quality: RenderQuality,
+    ) {
+        let (response, painter) =
+            ui.allocate_painter(ui.available_size_before_wrap(), Sense::drag());
+        let rect = response.rect;
+        let size = rect.size();
+        let mut pan = self.location.pan;
+        let mut zoom = self.location.zoom;
+        if let Some(pointer) = response.hover_pos() {
+            let (zoom_delta, scroll_delta) =
+                ui.input(|input| (input.zoom_delta(), input.smooth_scroll_delta));
+            if zoom_delta != 1.0 {
+                let offset = (pointer - rect.center()) / (zoom * size);
+                pan += offset * (1.0 - 1.0 / zoom_delta);
+                zoom *= zoom_delta;
+            }
+            pan -= scroll_delta / zoom / size;
+        }
+        pan -= response.drag_delta() / zoom / size;
+        self.location.pan = pan;
+        self.location.zoom = zoom;
+        paint_location(
+            ui.ctx(),
+            &painter,
+            rect,
+            tree,
+            layers,
+            quality,
+            &mut self.location,
+        );
+    }
+}
+
+/// Draws `tree` as seen from `location` into `rect`, as a view beside the main one does.
+/// The location is moved into the node its center is in, which is created if missing.
+pub fn paint_location(
+    ctx: &egui::Context,
+    painter: &Painter,
+    rect: Rect,
+    tree: &mut CanvasTree,
+    layers: &Layers,
+    quality: RenderQuality,
+    location: &mut ViewLocation,
+) {
+    let size = rect.size();
+    let (mut pan, mut zoom) = (location.pan, location.zoom);
+    let node = location.node(tree);
+    let node = settle(tree, node, &mut pan, &mut zoom);
+    *location = ViewLocation::new(tree, node, pan, zoom);
+
+    // Climb until the node covers the view, drawing the same levels below the view as the
+    // main view does.
+    let mut node = node;
+    let mut node_rect = rect.scale_from_center(zoom).translate(zoom * -pan * size);
+    let mut depth = DRAW_DEPTH;
+    while !node_rect.contains_rect(rect) {
+        let Some(parent) = tree[node].parent else {
+            break;
+        };
+        node_rect = tree[node].get_parent_rect(node_rect);
+        node = parent;
+        depth += 1;
+    }
+    let mut ancestors = vec![];
+    let (mut ancestor, mut ancestor_rect) = (node, node_rect);
+    while let Some(parent) = tree[ancestor].parent {
+        ancestor_rect = tree[ancestor].get_parent_rect(ancestor_rect);
+        ancestors.push((parent, ancestor_rect, 0));
+        ancestor = parent;
+    }
+
+    let key = RenderKey::new(layers, quality);
+    let mut collector =
+        StrokeCollector::new(ctx, layers, quality, key, rect, false).for_secondary_view();
+    collector.collect(
+        tree,
+        std::iter::once((node, node_rect, depth)).chain(ancestors),
+    );
+    collector.sort();
+    for (summary, screen_rect) in &collector.summaries {
+        summary.paint(painter, layers, *screen_rect);
+    }
+    painter.extend(collector.cached);
+    blend::paint_strokes(painter, layers, &collector.strokes);
+}
+
+/// Moves the view from `node` into the node its center is in, at a zoom between 0.5 and 2 as
+/// the main view keeps itself, so it stays precise however far it is zoomed. Beyond the
+/// outermost node the view stays on it.
+fn settle(tree: &mut CanvasTree, mut node: NodeId, pan: &mut Vec2, zoom: &mut f32) -> NodeId {
+    while pan.x.abs() > 0.5 || pan.y.abs() > 0.5 || *zoom < 0.5 {
+        let Some(parent) = tree[node].parent else {
+            break;
+        };
+        let corner = tree[node].corner;
+        *pan = (*pan + vec2(corner.0 as f32 - 0.5, corner.1 as f32 - 0.5)) / 2.0;
+        *zoom *= 2.0;
+        node = parent;
+    }
+    while *zoom > 2.0 && pan.x.abs() <= 0.5 && pan.y.abs() <= 0.5 {
+        let corner = (u8::from(pan.x > 0.0), u8::from(pan.y > 0.0));
+        *pan = *pan * 2.0 - vec2(corner.0 as f32 - 0.5, corner.1 as f32 - 0.5);
+        *zoom /= 2.0;
+        node = tree.get_or_create_child_from_corner(node, corner);
+    }
+    node
+}
@@ -0,0 +1,61 @@
+use std::{cell::RefCell, rc::Rc};
+
+use eframe::wasm_bindgen::{closure::Closure, JsCast as _, JsValue};
+use itertools::Itertools;
+
+/// The name and contents of a file, once read.
+type Contents = Option<(String, Vec<u8>)>;
+
+/// A file being picked and read in the browser, which finishes some time after the frame that
+/// asked for it, if the user picks one at all.
+#[derive(Clone, Default)]
+pub struct PickedFile(Rc<RefCell<Contents>>);
+
+impl PickedFile {
+    /// Asks the user for a file whose name ends in one of `extensions`, through a file input
+    /// element as the browser's own dialog, repainting `ctx` once the file has been read.
+    pub fn pick(ctx: &egui::Context, extensions: &[&str]) -> Result<Self, String> {
+        let js_error = |err: JsValue| format!("{err:?}");
+        let input = web_sys::window()
+            .and_then(|window| window.document())
+            .ok_or("No document")?
+            .create_element("input")
+            .map_err(js_error)?
+            .dyn_into::<web_sys::HtmlInputElement>()
+            .map_err(|_| "Not an input element")?;
+        input.set_type("file");
+        input.set_accept(
+            &extensions
+                .iter()
+                .map(|extension| format!(".{extension}"))
+                .join(","),
+        );
+        let picked = Self::default();
+        let (answer, ctx, chooser) = (picked.clone(), ctx.clone(), input.clone());
+        // Freed once called. Browsers fire no event when the dialog is cancelled, so then it
+        // lives on with the input element.
+        let on_change = Closure::once_into_js(move || {
+            let Some(file) = chooser.files().and_then(|files| files.get(0)) else {
+                return;
+            };
+            wasm_bindgen_futures::spawn_local(async move {
+                match wasm_bindgen_futures::JsFuture::from(file.array_buffer()).await {
+                    Ok(buffer) => {
+                        let data = js_sys::Uint8Array::new(&buffer).to_vec();
+                        *answer.0.borrow_mut() = Some((file.name(), data));
+                        ctx.request_repaint();
+                    }
+                    Err(err) => log::error!("Failed to read {}: {err:?}", file.name()),
+                }
+            });
+        });
+        input.set_onchange(Some(on_change.unchecked_ref()));
+        input.click();
+        Ok(picked)
+    }
+
+    /// The name and contents of the file once it has been read. They are handed out only once.
+    pub fn take(&self) -> Option<(String, Vec<u8>)> {
+        self.0.borrow_mut().take()
+    }
+}
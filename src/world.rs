@@ -0,0 +1,103 @@
+use egui::{Ui, Vec2};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    sessions::ViewLocation,
+    structure::{CanvasTree, NodeId},
+};
+
+/// Sides, in cells of the current zoom level, a bounded world can be made with.
+const WORLD_SIZES: [u32; 5] = [1, 2, 4, 8, 16];
+
+/// Smallest fraction of the view the world may shrink to when zooming out.
+pub const MIN_WORLD_FRACTION: f32 = 0.5;
+
+/// What the world bounds menu asks the painting to do.
+pub enum WorldAction {
+    /// Limit the painting to the node `levels` above the center cell.
+    Bound {
+        levels: u32,
+    },
+    Unbound,
+}
+
+/// Optionally limits a painting to a single node of the tree, so it has edges.
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct WorldBounds {
+    /// The node the painting is limited to, while bounded.
+    region: Option<ViewLocation>,
+    /// Side of the world chosen in the menu, in cells.
+    size: u32,
+}
+
+impl Default for WorldBounds {
+    fn default() -> Self {
+        Self {
+            region: None,
+            size: 4,
+        }
+    }
+}
+
+impl WorldBounds {
+    pub fn is_bounded(&self) -> bool {
+        self.region.is_some()
+    }
+
+    pub fn bound(&mut self, tree: &CanvasTree, node: NodeId) {
+        self.region = Some(ViewLocation::new(tree, node, Vec2::ZERO, 1.0));
+    }
+
+    pub fn unbound(&mut self) {
+        self.region = None;
+    }
+
+    /// The node the painting is limited to, if it is bounded.
+    pub fn node(&mut self, tree: &mut CanvasTree) -> Option<NodeId> {
+        Some(self.region.as_mut()?.node(tree))
+    }
+
+    /// Keeps the region on the same node as `tree` grows outward.
+    pub fn rebase(&mut self, tree: &CanvasTree) {
+        if let Some(region) = &mut self.region {
+            region.rebase(tree);
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui) -> Option<WorldAction> {
+        let mut action = None;
+        ui.horizontal(|ui| {
+            ui.label("Size");
+            egui::ComboBox::from_id_salt("world_size")
+                .selected_text(format!("{0} × {0} screens", self.size))
+                .show_ui(ui, |ui| {
+                    for size in WORLD_SIZES {
+                        ui.selectable_value(&mut self.size, size, format!("{size} × {size}"));
+                    }
+                });
+        });
+        if ui
+            .button(if self.is_bounded() {
+                "Move bounds here"
+            } else {
+                "Bound canvas here"
+            })
+            .on_hover_text(
+                "Limit drawing and panning to the region of this size containing the current view",
+            )
+            .clicked()
+        {
+            action = Some(WorldAction::Bound {
+                levels: self.size.ilog2(),
+            });
+        }
+        if ui
+            .add_enabled(self.is_bounded(), egui::Button::new("Remove bounds"))
+            .clicked()
+        {
+            action = Some(WorldAction::Unbound);
+        }
+        action
+    }
+}
@@ -0,0 +1,330 @@
+//! Relays messages between the participants of each room of True Infinite Canvas, and keeps a
+//! log of the changes made to each room's canvas, so those joining later start from it and it
+//! outlives the server. Run as `tic-server [address] [directory]`, listening on `0.0.0.0:9000`
+//! and keeping rooms in `rooms` by default. Participants join a room by connecting to
+//! `ws://address/room`.
+//!
+//! Each batch of changes names the log it was made to and how many changes the log held then.
+//! Only batches made to the log as it is are taken, and passed on to everyone in the room, the
+//! sender included, so every participant makes the same changes in the same order. The log is
+//! kept as one batch per line, appended as batches are taken.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::Write,
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    sync::{
+        mpsc::{self, Receiver, Sender, TryRecvError},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tungstenite::{
+    handshake::server::{Callback, ErrorResponse, Request, Response},
+    Message, WebSocket,
+};
+
+const DEFAULT_ADDRESS: &str = "0.0.0.0:9000";
+const DEFAULT_DIRECTORY: &str = "rooms";
+
+/// How long each connection's thread waits for a message before sending those queued for it.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Longest room name accepted.
+const MAX_ROOM_NAME: usize = 64;
+
+/// The message telling a participant joining a room that every change the room holds was sent.
+const JOINED: &str = "\"Joined\"";
+
+#[derive(Default)]
+struct Room {
+    /// Where to send messages for each participant, by connection number.
+    participants: BTreeMap<u64, Sender<String>>,
+    /// Whether the log has been read from its file.
+    loaded: bool,
+    /// The id of the log, once it has changes.
+    log: Option<u64>,
+    /// How many changes the log holds.
+    count: u64,
+    /// The messages adding each batch of changes to the log, in order.
+    batches: Vec<String>,
+    /// The file the log is appended to, once opened.
+    file: Option<File>,
+}
+
+/// A room, shared by the connections in it.
+type SharedRoom = Arc<Mutex<Room>>;
+
+struct Server {
+    /// Rooms with anyone in them, each with how many connections are in it or joining it.
+    rooms: Mutex<BTreeMap<String, (SharedRoom, usize)>>,
+    /// Where the log of each room is kept.
+    directory: PathBuf,
+}
+
+/// A batch of changes as the relay reads it, the changes themselves passed on untouched.
+struct Batch {
+    log: u64,
+    index: u64,
+    count: u64,
+}
+
+impl Batch {
+    /// The batch the message `text` adds, if it adds one.
+    fn parse(text: &str) -> Option<Self> {
+        let message = serde_json::from_str::<serde_json::Value>(text).ok()?;
+        let changes = message.get("Changes")?;
+        Some(Self {
+            log: changes.get("log")?.as_u64()?,
+            index: changes.get("index")?.as_u64()?,
+            count: changes.get("ops")?.as_array()?.len() as u64,
+        })
+    }
+}
+
+/// Takes the room named by the address a connection asks for during the handshake.
+struct RoomRequest<'a>(&'a mut String);
+
+impl Callback for RoomRequest<'_> {
+    fn on_request(self, request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+        *self.0 = request.uri().path().to_owned();
+        Ok(response)
+    }
+}
+
+fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let mut args = std::env::args().skip(1);
+    let address = args.next().unwrap_or_else(|| DEFAULT_ADDRESS.to_string());
+    let directory = PathBuf::from(args.next().unwrap_or_else(|| DEFAULT_DIRECTORY.to_string()));
+    if let Err(err) = std::fs::create_dir_all(&directory) {
+        log::error!("Failed to create {}: {err}", directory.display());
+        std::process::exit(1);
+    }
+    let listener = match TcpListener::bind(&address) {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::error!("Failed to listen on {address}: {err}");
+            std::process::exit(1);
+        }
+    };
+    log::info!(
+        "Listening on {address}, keeping rooms in {}",
+        directory.display()
+    );
+
+    let server = Arc::new(Server {
+        rooms: Mutex::new(BTreeMap::new()),
+        directory,
+    });
+    for (connection, stream) in (0..).zip(listener.incoming()) {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::warn!("Failed to accept a connection: {err}");
+                continue;
+            }
+        };
+        let server = server.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = server.serve(connection, stream) {
+                log::warn!("Connection {connection} failed: {err}");
+            }
+        });
+    }
+}
+
+impl Server {
+    /// Takes part of the connection `connection` in the room named by the address it asked for
+    /// until it closes.
+    fn serve(&self, connection: u64, stream: TcpStream) -> Result<(), String> {
+        let mut path = String::new();
+        let mut socket = tungstenite::accept_hdr(stream, RoomRequest(&mut path))
+            .map_err(|err| err.to_string())?;
+        let Some(name) = room_name(&path) else {
+            let _ = socket.close(None);
+            let _ = socket.flush();
+            return Err(format!("Invalid room {path:?}"));
+        };
+        socket
+            .get_ref()
+            .set_read_timeout(Some(POLL_INTERVAL))
+            .map_err(|err| err.to_string())?;
+        let (sender, outgoing) = mpsc::channel();
+        let room = self.join(&name, connection, sender);
+        log::info!("Connection {connection} joined {name}");
+        let result = self.relay(&name, &room, connection, &mut socket, &outgoing);
+        self.leave(&name, &room, connection);
+        log::info!("Connection {connection} left {name}");
+        result
+    }
+
+    /// Adds a participant to the room `name`, reading the room's log if nobody was in it, and
+    /// queues every batch of changes in the log for them.
+    fn join(&self, name: &str, connection: u64, sender: Sender<String>) -> SharedRoom {
+        // Only the room is locked while its log is read, so other rooms carry on meanwhile.
+        let room = {
+            let mut rooms = self.rooms.lock().unwrap();
+            let (room, connections) = rooms.entry(name.to_owned()).or_default();
+            *connections += 1;
+            room.clone()
+        };
+        let mut entry = room.lock().unwrap();
+        if !entry.loaded {
+            entry.loaded = true;
+            self.load(name, &mut entry);
+        }
+        for batch in &entry.batches {
+            let _ = sender.send(batch.clone());
+        }
+        let _ = sender.send(JOINED.to_owned());
+        entry.participants.insert(connection, sender);
+        drop(entry);
+        room
+    }
+
+    /// Reads the log of the room `name` into `room`. A batch left half written when the server
+    /// stopped is cut off the file, along with anything after it.
+    fn load(&self, name: &str, room: &mut Room) {
+        let path = self.log_path(name);
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return,
+            Err(err) => {
+                log::error!("Failed to read the log of {name}: {err}");
+                return;
+            }
+        };
+        let mut kept = 0;
+        for line in contents.split_inclusive('\n') {
+            let Some(batch) = line.strip_suffix('\n').and_then(Batch::parse) else {
+                break;
+            };
+            room.log = Some(batch.log);
+            room.count += batch.count;
+            room.batches.push(line.trim_end().to_owned());
+            kept += line.len();
+        }
+        if kept < contents.len() {
+            log::warn!("Cutting off the end of the log of {name}, which was left half written");
+            let cut = File::options()
+                .write(true)
+                .open(&path)
+                .and_then(|file| file.set_len(kept as u64));
+            if let Err(err) = cut {
+                log::error!("Failed to cut off the end of the log of {name}: {err}");
+            }
+        }
+    }
+
+    /// Removes a participant from the room `name`, dropping the room once nobody is in it.
+    fn leave(&self, name: &str, room: &Mutex<Room>, connection: u64) {
+        room.lock().unwrap().participants.remove(&connection);
+        let mut rooms = self.rooms.lock().unwrap();
+        if let Some((_, connections)) = rooms.get_mut(name) {
+            *connections -= 1;
+            if *connections == 0 {
+                rooms.remove(name);
+            }
+        }
+    }
+
+    /// Passes messages from the participant on to the others in `room`, and sends those queued
+    /// for it, until the connection closes.
+    fn relay(
+        &self,
+        name: &str,
+        room: &Mutex<Room>,
+        connection: u64,
+        socket: &mut WebSocket<TcpStream>,
+        outgoing: &Receiver<String>,
+    ) -> Result<(), String> {
+        loop {
+            loop {
+                match outgoing.try_recv() {
+                    Ok(text) => socket
+                        .send(Message::text(text))
+                        .map_err(|err| err.to_string())?,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            }
+            match socket.read() {
+                Ok(Message::Text(text)) => self.received(name, room, connection, text.as_str()),
+                Ok(Message::Close(_)) | Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+                Ok(_) => {}
+                Err(tungstenite::Error::Io(err))
+                    if matches!(
+                        err.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) => {}
+                Err(err) => return Err(err.to_string()),
+            }
+        }
+    }
+
+    /// Passes `text` from a participant on to the others in `room`. A batch of changes is
+    /// instead added to the room's log and passed on to everyone, if it was made to the log as
+    /// it is, and otherwise dropped, the sender making its changes again once it has the
+    /// changes that got in first.
+    fn received(&self, name: &str, room: &Mutex<Room>, connection: u64, text: &str) {
+        let mut room = room.lock().unwrap();
+        let Some(batch) = Batch::parse(text) else {
+            for (participant, sender) in &room.participants {
+                if *participant != connection {
+                    let _ = sender.send(text.to_owned());
+                }
+            }
+            return;
+        };
+        if room.log.is_some_and(|log| log != batch.log) || batch.index != room.count {
+            log::debug!("Turned away changes to {name} made to an outdated canvas");
+            return;
+        }
+        // Appended while the room is locked, so batches are kept in the order they are taken.
+        if let Err(err) = self.append(name, &mut room, text) {
+            log::error!("Failed to keep changes to {name}: {err}");
+            return;
+        }
+        room.log = Some(batch.log);
+        room.count += batch.count;
+        room.batches.push(text.to_owned());
+        for sender in room.participants.values() {
+            let _ = sender.send(text.to_owned());
+        }
+    }
+
+    /// Adds the batch `text` to the end of the log file of the room `name`.
+    fn append(&self, name: &str, room: &mut Room, text: &str) -> std::io::Result<()> {
+        let file = match &mut room.file {
+            Some(file) => file,
+            None => room.file.insert(
+                File::options()
+                    .create(true)
+                    .append(true)
+                    .open(self.log_path(name))?,
+            ),
+        };
+        file.write_all(format!("{text}\n").as_bytes())?;
+        file.flush()
+    }
+
+    fn log_path(&self, room: &str) -> PathBuf {
+        self.directory.join(format!("{room}.jsonl"))
+    }
+}
+
+/// The room named by the address `path`, if it names one. Names are kept to letters, digits,
+/// `-` and `_`, as they name files.
+fn room_name(path: &str) -> Option<String> {
+    let name = path.trim_start_matches('/');
+    (!name.is_empty()
+        && name.len() <= MAX_ROOM_NAME
+        && name
+            .chars()
+            .all(|char| char.is_ascii_alphanumeric() || char == '-' || char == '_'))
+    .then(|| name.to_owned())
+}